@@ -0,0 +1,297 @@
+//! Access control lists. Rules allow or deny reading (subscribing) and
+//! writing (publishing) on topic filters for everyone, one client id or one
+//! username. The first rule that matches decides, and the list's default
+//! covers everything else. `%c` and `%u` in a rule's filter stand for the
+//! client id and the username, so `devices/%c/#` gives every device its own
+//! subtree. Ids and usernames with `/`, `+` or `#` in them never match a
+//! filter with their placeholder, they would reach beyond their own subtree
+//!
+//! Acl files use mosquitto's format. Anything they don't allow is denied:
+//!
+//! ```text
+//! # clients without a username, until the first user line
+//! topic read public/#
+//! user backend
+//! topic read devices/#
+//! # every client
+//! pattern readwrite devices/%c/#
+//! ```
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use mqtt3::Publish;
+
+use error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    /// Subscribing
+    Read,
+    /// Publishing
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn covers(self, access: Access) -> bool {
+        self == Access::ReadWrite || self == access
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    Allow,
+    Deny,
+}
+
+/// Clients a rule applies to
+#[derive(Debug, Clone, PartialEq)]
+pub enum AclSubject {
+    Everyone,
+    /// Clients that connected without a username
+    Anonymous,
+    ClientId(String),
+    Username(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AclRule {
+    pub subject: AclSubject,
+    pub permission: Permission,
+    pub access: Access,
+    /// Topic filter with MQTT wildcards
+    pub filter: String,
+}
+
+/// What happens to a publish its client isn't allowed to make
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum UnauthorizedPublish {
+    /// Acknowledge it as usual but don't forward it
+    Drop,
+    /// Close the connection
+    Disconnect,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Acl {
+    rules: Vec<AclRule>,
+    default: Permission,
+}
+
+/// True if `topic` matches the MQTT topic filter `filter`. Subscription
+/// filters are matched as topics, so `+` and `#` in them are taken literally
+pub fn matches(filter: &str, topic: &str) -> bool {
+    let mut topic = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => (),
+            (level, Some(t)) if level == t => (),
+            _ => return false,
+        }
+    }
+    topic.next().is_none()
+}
+
+/// Like `matches` but, as for subscriptions, filters starting with a
+/// wildcard don't match `$` topics
+pub fn matches_published(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    matches(filter, topic)
+}
+
+/// `filter` with the placeholders replaced. `None` if a value is missing or
+/// has topic separators or wildcards in it
+pub fn substitute(filter: &str, client_id: &str, username: Option<&str>) -> Option<String> {
+    let unsafe_value = |value: &str| value.contains(|c| c == '/' || c == '+' || c == '#');
+
+    let mut filter = filter.to_owned();
+    if filter.contains("%c") {
+        if unsafe_value(client_id) {
+            return None;
+        }
+        filter = filter.replace("%c", client_id);
+    }
+    if filter.contains("%u") {
+        match username {
+            Some(username) if !unsafe_value(username) => filter = filter.replace("%u", username),
+            _ => return None,
+        }
+    }
+    Some(filter)
+}
+
+impl Acl {
+    /// Empty list. `default` decides every request
+    pub fn new(default: Permission) -> Self {
+        Acl {
+            rules: Vec::new(),
+            default: default,
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut text = String::new();
+        File::open(path.as_ref())?.read_to_string(&mut text)?;
+        Acl::parse(&text).map_err(|e| Error::Config(format!("{}: {}", path.as_ref().display(), e)))
+    }
+
+    /// Parses `user <username>`, `topic [read|write|readwrite|deny] <filter>`
+    /// and `pattern [read|write|readwrite|deny] <filter>` lines. Topic lines
+    /// are for the user above them, or for anonymous clients before the
+    /// first user line. Pattern lines are for everyone. Without an access
+    /// the rule is readwrite. Empty lines and `#` comments are skipped
+    pub fn parse(text: &str) -> ::std::result::Result<Self, String> {
+        let mut acl = Acl::new(Permission::Deny);
+        let mut user = AclSubject::Anonymous;
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (subject, rule) = match fields.as_slice() {
+                ["user", username] => {
+                    user = AclSubject::Username((*username).to_owned());
+                    continue;
+                }
+                ["topic", rule @ ..] => (user.clone(), rule),
+                ["pattern", rule @ ..] => (AclSubject::Everyone, rule),
+                _ => return Err(format!("invalid entry on line {}", n + 1)),
+            };
+
+            let (permission, access, filter) = match rule {
+                [filter] => (Permission::Allow, Access::ReadWrite, filter),
+                ["read", filter] => (Permission::Allow, Access::Read, filter),
+                ["write", filter] => (Permission::Allow, Access::Write, filter),
+                ["readwrite", filter] => (Permission::Allow, Access::ReadWrite, filter),
+                ["deny", filter] => (Permission::Deny, Access::ReadWrite, filter),
+                _ => return Err(format!("invalid rule on line {}", n + 1)),
+            };
+            acl = acl.rule(subject, permission, access, filter);
+        }
+
+        Ok(acl)
+    }
+
+    pub fn allow(self, subject: AclSubject, access: Access, filter: &str) -> Self {
+        self.rule(subject, Permission::Allow, access, filter)
+    }
+
+    pub fn deny(self, subject: AclSubject, access: Access, filter: &str) -> Self {
+        self.rule(subject, Permission::Deny, access, filter)
+    }
+
+    /// Appends a rule. Earlier rules take precedence
+    pub fn rule(mut self, subject: AclSubject, permission: Permission, access: Access, filter: &str) -> Self {
+        self.rules.push(AclRule {
+                            subject: subject,
+                            permission: permission,
+                            access: access,
+                            filter: filter.to_owned(),
+                        });
+        self
+    }
+
+    pub fn rules(&self) -> &[AclRule] {
+        &self.rules
+    }
+
+    /// Permission of requests no rule matches
+    pub fn default_permission(&self) -> Permission {
+        self.default
+    }
+
+    /// True if the client may use `topic` for `access`
+    pub fn check(&self, client_id: &str, username: Option<&str>, topic: &str, access: Access) -> bool {
+        let rule = self.rules.iter().find(|rule| {
+            let subject = match rule.subject {
+                AclSubject::Everyone => true,
+                AclSubject::Anonymous => username.is_none(),
+                AclSubject::ClientId(ref id) => id == client_id,
+                AclSubject::Username(ref name) => username == Some(name.as_str()),
+            };
+            if !subject || !rule.access.covers(access) {
+                return false;
+            }
+
+            match substitute(&rule.filter, client_id, username) {
+                Some(filter) => matches(&filter, topic),
+                None => false,
+            }
+        });
+
+        rule.map_or(self.default, |rule| rule.permission) == Permission::Allow
+    }
+
+    /// Shorthand for a write check of a publish
+    pub fn may_publish(&self, client_id: &str, username: Option<&str>, publish: &Publish) -> bool {
+        self.check(client_id, username, &publish.topic_name, Access::Write)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{matches, Access, Acl, AclSubject, Permission};
+
+    #[test]
+    fn first_matching_rule_decides() {
+        assert!(matches("devices/+/telemetry/#", "devices/d7/telemetry/cpu"));
+        assert!(matches("devices/#", "devices"));
+        assert!(!matches("devices/+", "devices/d7/telemetry"));
+
+        let acl = Acl::new(Permission::Deny)
+            .deny(AclSubject::ClientId("d13".to_owned()), Access::ReadWrite, "#")
+            .allow(AclSubject::Everyone, Access::ReadWrite, "devices/%c/#")
+            .allow(AclSubject::Username("backend".to_owned()), Access::Read, "devices/#")
+            .allow(AclSubject::Everyone, Access::Write, "users/%u/outbox");
+
+        assert!(acl.check("d7", None, "devices/d7/telemetry", Access::Write));
+        assert!(!acl.check("d7", None, "devices/d8/telemetry", Access::Write));
+        assert!(!acl.check("d13", None, "devices/d13/telemetry", Access::Write));
+
+        assert!(acl.check("a1", Some("backend"), "devices/d8/telemetry", Access::Read));
+        assert!(!acl.check("a1", Some("backend"), "devices/d8/telemetry", Access::Write));
+
+        assert!(acl.check("a1", Some("alice"), "users/alice/outbox", Access::Write));
+        assert!(!acl.check("a1", Some("alice"), "users/alice/outbox", Access::Read));
+        assert!(!acl.check("a1", None, "users/%u/outbox", Access::Write));
+    }
+
+    #[test]
+    fn patterns_confine_clients_to_their_namespace() {
+        let acl = Acl::parse("topic read public/#\n\
+                              user backend\n\
+                              topic read devices/#\n\
+                              topic deny devices/admin/#\n\
+                              # every device\n\
+                              pattern readwrite devices/%c/#\n\
+                              pattern write users/%u/outbox\n")
+                .unwrap();
+
+        assert!(acl.check("d7", None, "devices/d7/telemetry", Access::Write));
+        assert!(acl.check("d7", None, "devices/d7/commands", Access::Read));
+        assert!(!acl.check("d7", None, "devices/d8/telemetry", Access::Read));
+        assert!(acl.check("d7", None, "public/news", Access::Read));
+        assert!(!acl.check("d7", Some("alice"), "public/news", Access::Read));
+        assert!(acl.check("d7", Some("alice"), "users/alice/outbox", Access::Write));
+
+        assert!(acl.check("a1", Some("backend"), "devices/d8/telemetry", Access::Read));
+        assert!(!acl.check("admin", Some("backend"), "devices/admin/keys", Access::Write));
+
+        // wildcards and separators in ids can't widen a pattern
+        assert!(!acl.check("#", None, "devices/d8/telemetry", Access::Read));
+        assert!(!acl.check("d7/..", None, "devices/d7/../telemetry", Access::Read));
+        assert!(!acl.check("d7", Some("+"), "users/bob/outbox", Access::Write));
+
+        assert!(Acl::parse("topic lots devices/#").is_err());
+        assert!(Acl::parse("user").is_err());
+    }
+}
@@ -0,0 +1,310 @@
+//! Built in alerting for deployments without a monitoring stack. Rules
+//! compare a broker metric, or its rate per second, against a threshold on
+//! every evaluation. A rule that starts matching fires an alert and one that
+//! stops matching resolves it. Both go out as JSON
+//!
+//! ```text
+//! {"alert":"drops","state":"firing","metric":"dropped_messages/s","value":12.5,"threshold":10.0}
+//! ```
+//!
+//! to the alert topic and/or the alert webhook
+
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use error::{Error, Result};
+
+/// Longest wait for the alert webhook's answer
+pub const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Broker metrics rules can watch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Metric {
+    /// Connected clients
+    Connections,
+    Subscriptions,
+    /// Persistent sessions of disconnected clients
+    OfflineSessions,
+    /// Publishes received since the broker started
+    Published,
+    /// Publishes handed to connections since the broker started
+    Delivered,
+    /// Publishes lost by failed connections, missed by full offline queues or
+    /// over the per session limits since the broker started
+    DroppedMessages,
+    /// Publishes that sat in offline queues past the message ttl since the
+    /// broker started
+    ExpiredMessages,
+    /// Unacknowledged qos 1 and 2 flows
+    OpenFlows,
+    /// Connections accepted by the listeners, including ones that haven't
+    /// sent CONNECT yet
+    OpenConnections,
+    /// Connections closed for going over the connect rates since the broker
+    /// started
+    RateLimitedConnections,
+}
+
+impl Metric {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Metric::Connections => "connections",
+            Metric::Subscriptions => "subscriptions",
+            Metric::OfflineSessions => "offline_sessions",
+            Metric::Published => "published",
+            Metric::Delivered => "delivered",
+            Metric::DroppedMessages => "dropped_messages",
+            Metric::ExpiredMessages => "expired_messages",
+            Metric::OpenFlows => "open_flows",
+            Metric::OpenConnections => "open_connections",
+            Metric::RateLimitedConnections => "rate_limited_connections",
+        }
+    }
+}
+
+impl FromStr for Metric {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let all = [Metric::Connections,
+                   Metric::Subscriptions,
+                   Metric::OfflineSessions,
+                   Metric::Published,
+                   Metric::Delivered,
+                   Metric::DroppedMessages,
+                   Metric::ExpiredMessages,
+                   Metric::OpenFlows,
+                   Metric::OpenConnections,
+                   Metric::RateLimitedConnections];
+        all.iter()
+            .find(|metric| metric.name() == s)
+            .cloned()
+            .ok_or_else(|| Error::Config(format!("unknown metric {:?}", s)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Above,
+    Below,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlertRule {
+    /// Names the alert in notifications
+    pub name: String,
+    pub metric: Metric,
+    /// Compare the change per second since the last evaluation instead of the value
+    pub rate: bool,
+    pub comparison: Comparison,
+    pub threshold: f64,
+}
+
+impl AlertRule {
+    /// `metric` or `metric/s` for its rate
+    pub fn watched(&self) -> String {
+        if self.rate { format!("{}/s", self.metric.name()) } else { self.metric.name().to_owned() }
+    }
+
+    fn matches(&self, value: f64) -> bool {
+        match self.comparison {
+            Comparison::Above => value > self.threshold,
+            Comparison::Below => value < self.threshold,
+        }
+    }
+}
+
+/// Parses `<name> <metric>[/s] <'>'|'<'> <threshold>`, e.g. `drops dropped_messages/s > 10`
+impl FromStr for AlertRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::Config(format!("invalid alert rule {:?}. Expected <name> <metric>[/s] <'>'|'<'> <threshold>", s));
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        if parts.len() != 4 {
+            return Err(invalid());
+        }
+
+        let (metric, rate) = match parts[1].find("/s") {
+            Some(i) if i + 2 == parts[1].len() => (&parts[1][..i], true),
+            _ => (parts[1], false),
+        };
+        let comparison = match parts[2] {
+            ">" => Comparison::Above,
+            "<" => Comparison::Below,
+            _ => return Err(invalid()),
+        };
+
+        Ok(AlertRule {
+               name: parts[0].to_owned(),
+               metric: metric.parse()?,
+               rate: rate,
+               comparison: comparison,
+               threshold: parts[3].parse().map_err(|_| invalid())?,
+           })
+    }
+}
+
+/// Metric values at one evaluation
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Sample {
+    pub connections: u64,
+    pub subscriptions: u64,
+    pub offline_sessions: u64,
+    pub published: u64,
+    pub delivered: u64,
+    pub dropped_messages: u64,
+    pub expired_messages: u64,
+    pub open_flows: u64,
+    pub open_connections: u64,
+    pub rate_limited_connections: u64,
+}
+
+impl Sample {
+    pub fn get(&self, metric: Metric) -> u64 {
+        match metric {
+            Metric::Connections => self.connections,
+            Metric::Subscriptions => self.subscriptions,
+            Metric::OfflineSessions => self.offline_sessions,
+            Metric::Published => self.published,
+            Metric::Delivered => self.delivered,
+            Metric::DroppedMessages => self.dropped_messages,
+            Metric::ExpiredMessages => self.expired_messages,
+            Metric::OpenFlows => self.open_flows,
+            Metric::OpenConnections => self.open_connections,
+            Metric::RateLimitedConnections => self.rate_limited_connections,
+        }
+    }
+}
+
+/// A rule that started or stopped matching
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alert {
+    pub rule: String,
+    pub firing: bool,
+    /// `metric` or `metric/s`
+    pub metric: String,
+    pub value: f64,
+    pub threshold: f64,
+}
+
+impl Alert {
+    pub fn payload(&self) -> Vec<u8> {
+        json!({
+            "alert": self.rule,
+            "state": if self.firing { "firing" } else { "resolved" },
+            "metric": self.metric,
+            "value": self.value,
+            "threshold": self.threshold,
+        })
+            .to_string()
+            .into_bytes()
+    }
+}
+
+/// Rules with the previous sample and the alerts currently firing
+#[derive(Debug)]
+pub struct AlertEngine {
+    rules: Vec<AlertRule>,
+    previous: Option<(Instant, Sample)>,
+    firing: HashSet<String>,
+}
+
+impl AlertEngine {
+    pub fn new(rules: Vec<AlertRule>) -> Self {
+        AlertEngine {
+            rules: rules,
+            previous: None,
+            firing: HashSet::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Alerts of the rules that changed state with `sample`. Rate rules start
+    /// with the second sample
+    pub fn evaluate(&mut self, sample: Sample, now: Instant) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        for rule in self.rules.iter() {
+            let value = if rule.rate {
+                match self.previous {
+                    Some((at, ref previous)) if now > at => {
+                        let elapsed = now.duration_since(at);
+                        let change = sample.get(rule.metric).saturating_sub(previous.get(rule.metric));
+                        change as f64 / seconds(elapsed)
+                    }
+                    _ => continue,
+                }
+            } else {
+                sample.get(rule.metric) as f64
+            };
+
+            let firing = rule.matches(value);
+            if firing == self.firing.contains(&rule.name) {
+                continue;
+            }
+            if firing {
+                self.firing.insert(rule.name.clone());
+            } else {
+                self.firing.remove(&rule.name);
+            }
+            alerts.push(Alert {
+                            rule: rule.name.clone(),
+                            firing: firing,
+                            metric: rule.watched(),
+                            value: value,
+                            threshold: rule.threshold,
+                        });
+        }
+
+        self.previous = Some((now, sample));
+        alerts
+    }
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+    use super::{AlertEngine, AlertRule, Comparison, Metric, Sample};
+
+    #[test]
+    fn alerts_fire_once_and_resolve() {
+        let rule: AlertRule = "drops dropped_messages/s > 10".parse().unwrap();
+        assert_eq!(rule.metric, Metric::DroppedMessages);
+        assert!(rule.rate);
+        assert_eq!(rule.comparison, Comparison::Above);
+        assert!("drops dropped_messages >= 10".parse::<AlertRule>().is_err());
+        assert!("drops drops > 10".parse::<AlertRule>().is_err());
+
+        let mut engine = AlertEngine::new(vec![rule, "lonely connections < 1".parse().unwrap()]);
+        let start = Instant::now();
+        let sample = |dropped, connections| {
+            Sample {
+                dropped_messages: dropped,
+                connections: connections,
+                ..Sample::default()
+            }
+        };
+
+        let alerts = engine.evaluate(sample(0, 0), start);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!((alerts[0].rule.as_str(), alerts[0].firing), ("lonely", true));
+
+        // 50 drops in 2s
+        let alerts = engine.evaluate(sample(50, 3), start + Duration::from_secs(2));
+        let changes: Vec<(&str, bool)> = alerts.iter().map(|a| (a.rule.as_str(), a.firing)).collect();
+        assert_eq!(changes, vec![("drops", true), ("lonely", false)]);
+        assert_eq!(alerts[0].value, 25.0);
+
+        assert!(engine.evaluate(sample(80, 3), start + Duration::from_secs(4)).is_empty());
+        let alerts = engine.evaluate(sample(80, 3), start + Duration::from_secs(6));
+        assert_eq!((alerts[0].rule.as_str(), alerts[0].firing), ("drops", false));
+    }
+}
@@ -0,0 +1,115 @@
+//! Credential checks of CONNECT. Embedders plug in their own `Authenticator`
+//! with `Broker::set_authenticator`. Without one every client that passes
+//! the protocol and listener checks is let in
+
+use std::net::SocketAddr;
+
+use futures::{future, Future};
+use mqtt3::ConnectReturnCode;
+use tokio_core::reactor::Handle;
+
+use error::Result;
+
+/// Outcome of the credential check of a CONNECT
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verdict {
+    /// `Accepted` lets the client in. Any other code is sent back in CONNACK
+    /// and the connection is closed
+    pub code: ConnectReturnCode,
+    /// Topic prefixes an accepted client is confined to for publishes and
+    /// subscriptions. `None` leaves it to the acl
+    pub topic_prefixes: Option<Vec<String>>,
+}
+
+/// Verdict of an authenticator, once it's made
+pub type Verification = Box<Future<Item = Verdict, Error = ()>>;
+
+impl Verdict {
+    /// Verification of a verdict made right away
+    pub fn ready(self) -> Verification {
+        Box::new(future::ok(self))
+    }
+}
+
+impl From<ConnectReturnCode> for Verdict {
+    fn from(code: ConnectReturnCode) -> Verdict {
+        Verdict {
+            code: code,
+            topic_prefixes: None,
+        }
+    }
+}
+
+/// Decides whether a client may connect. Called once per CONNECT on the
+/// event loop of `handle`, so slow checks, like hashing a password or asking
+/// another service, run elsewhere or asynchronously on that event loop
+pub trait Authenticator {
+    fn authenticate(&self, client_id: &str, username: Option<&str>, password: Option<&str>, addr: SocketAddr, handle: &Handle) -> Verification;
+
+    /// Picks up changed credentials. Returns true if anything was reloaded
+    fn reload(&self) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+/// Default authenticator. Accepts everyone
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllowAll;
+
+impl Authenticator for AllowAll {
+    fn authenticate(&self, _: &str, _: Option<&str>, _: Option<&str>, _: SocketAddr, _: &Handle) -> Verification {
+        Verdict::from(ConnectReturnCode::Accepted).ready()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use mqtt3::*;
+    use broker::Broker;
+    use futures::Future;
+    use tokio_core::reactor::{Core, Handle};
+    use super::{Authenticator, Verdict, Verification};
+
+    struct Passwords;
+
+    impl Authenticator for Passwords {
+        fn authenticate(&self, _: &str, username: Option<&str>, password: Option<&str>, addr: SocketAddr, _: &Handle) -> Verification {
+            let code = match (username, password) {
+                (Some("device"), Some("secret")) if addr.ip().is_loopback() => ConnectReturnCode::Accepted,
+                (Some("device"), Some("secret")) => ConnectReturnCode::NotAuthorized,
+                _ => ConnectReturnCode::BadUsernamePassword,
+            };
+            Verdict::from(code).ready()
+        }
+    }
+
+    fn connect(username: Option<&str>, password: Option<&str>) -> Connect {
+        Connect {
+            protocol: Protocol::MQTT(4),
+            keep_alive: 10,
+            client_id: "device-7".to_owned(),
+            clean_session: true,
+            last_will: None,
+            username: username.map(|u| u.to_owned()),
+            password: password.map(|p| p.to_owned()),
+        }
+    }
+
+    #[test]
+    fn plugged_in_authenticator_decides_connects() {
+        let core = Core::new().unwrap();
+        let handle = core.handle();
+        let broker = Broker::new();
+        let local = "127.0.0.1:40000".parse().unwrap();
+        let remote = "10.0.0.7:40000".parse().unwrap();
+
+        // allow all by default
+        assert_eq!(broker.authenticate(&connect(None, None), remote, &handle).wait().unwrap().code, ConnectReturnCode::Accepted);
+
+        broker.set_authenticator(Box::new(Passwords));
+        assert_eq!(broker.authenticate(&connect(Some("device"), Some("secret")), local, &handle).wait().unwrap().code, ConnectReturnCode::Accepted);
+        assert_eq!(broker.authenticate(&connect(Some("device"), Some("secret")), remote, &handle).wait().unwrap().code, ConnectReturnCode::NotAuthorized);
+        assert_eq!(broker.authenticate(&connect(Some("device"), Some("guess")), local, &handle).wait().unwrap().code, ConnectReturnCode::BadUsernamePassword);
+    }
+}
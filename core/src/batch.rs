@@ -0,0 +1,228 @@
+//! Batched deliveries for backend consumers. A subscription to
+//! `$batch/<topic>` collects the publishes on `<topic>` and delivers them
+//! together as one publish on `$batch/<topic>` whenever the flush interval
+//! passes or enough messages pile up. The payload is the messages'
+//! payloads in order, each prefixed with its length as a big endian u32.
+//! Batch subscriptions end with the connection
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use mqtt3::{Publish, QoS};
+
+use client::Client;
+
+/// Filter prefix of batch subscriptions
+pub const BATCH_PREFIX: &str = "$batch/";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatchConfig {
+    /// How often collected messages are delivered
+    pub interval: Duration,
+    /// Messages that make a batch go out before the interval is up
+    pub max_messages: usize,
+}
+
+/// Topic a batch subscription collects. `None` for ordinary filters
+pub fn batched_topic(filter: &str) -> Option<&str> {
+    if filter.starts_with(BATCH_PREFIX) && filter.len() > BATCH_PREFIX.len() {
+        Some(&filter[BATCH_PREFIX.len()..])
+    } else {
+        None
+    }
+}
+
+pub fn encode(payloads: &[Arc<Vec<u8>>]) -> Vec<u8> {
+    let size = payloads.iter().map(|p| p.len() + 4).sum();
+    let mut frame = Vec::with_capacity(size);
+    for payload in payloads {
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+    }
+    frame
+}
+
+/// Splits a batch payload back into the messages. `None` if it's truncated
+pub fn decode(mut frame: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut payloads = Vec::new();
+    while !frame.is_empty() {
+        if frame.len() < 4 {
+            return None;
+        }
+        let len = (u32::from(frame[0]) << 24 | u32::from(frame[1]) << 16 | u32::from(frame[2]) << 8 | u32::from(frame[3])) as usize;
+        if frame.len() < 4 + len {
+            return None;
+        }
+        payloads.push(frame[4..4 + len].to_vec());
+        frame = &frame[4 + len..];
+    }
+    Some(payloads)
+}
+
+#[derive(Debug)]
+struct Batch {
+    client: Client,
+    /// Granted qos of the subscription
+    qos: QoS,
+    /// Lowest qos among the collected publishes. Batches aren't delivered above it
+    lowest: QoS,
+    payloads: Vec<Arc<Vec<u8>>>,
+}
+
+/// A batch that's due for delivery
+#[derive(Debug)]
+pub struct Ready {
+    pub client: Client,
+    /// `$batch/<topic>`
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+}
+
+/// Batch subscriptions by the topic they collect
+#[derive(Debug)]
+pub struct Batches {
+    subscriptions: HashMap<String, Vec<Batch>>,
+    max_messages: usize,
+}
+
+impl Batches {
+    pub fn new(max_messages: usize) -> Self {
+        Batches {
+            subscriptions: HashMap::new(),
+            max_messages: max_messages,
+        }
+    }
+
+    /// Subscribes `client` to batches of `topic`. Subscribing again only updates the qos
+    pub fn subscribe(&mut self, topic: &str, client: Client, qos: QoS) {
+        let batches = self.subscriptions.entry(topic.to_owned()).or_insert_with(Vec::new);
+        if let Some(batch) = batches.iter_mut().find(|b| b.client.id == client.id) {
+            batch.client = client;
+            batch.qos = qos;
+            return;
+        }
+
+        batches.push(Batch {
+                         client: client,
+                         qos: qos,
+                         lowest: QoS::ExactlyOnce,
+                         payloads: Vec::new(),
+                     });
+    }
+
+    /// Drops the batch subscription along with the messages collected for it
+    pub fn unsubscribe(&mut self, topic: &str, id: &str) {
+        if let Some(batches) = self.subscriptions.get_mut(topic) {
+            batches.retain(|b| b.client.id != id);
+        }
+        self.subscriptions.retain(|_, batches| !batches.is_empty());
+    }
+
+    pub fn remove_client(&mut self, id: &str) {
+        for batches in self.subscriptions.values_mut() {
+            batches.retain(|b| b.client.id != id);
+        }
+        self.subscriptions.retain(|_, batches| !batches.is_empty());
+    }
+
+    /// Collects the publish for every batch subscription of its topic.
+    /// Returns the batches it filled up
+    pub fn push(&mut self, publish: &Publish) -> Vec<Ready> {
+        let max_messages = self.max_messages;
+        let batches = match self.subscriptions.get_mut(&publish.topic_name) {
+            Some(batches) => batches,
+            None => return vec![],
+        };
+
+        let mut ready = Vec::new();
+        for batch in batches.iter_mut() {
+            batch.payloads.push(publish.payload.clone());
+            if publish.qos.to_u8() < batch.lowest.to_u8() {
+                batch.lowest = publish.qos;
+            }
+            if batch.payloads.len() >= max_messages {
+                ready.push(take(&publish.topic_name, batch));
+            }
+        }
+        ready
+    }
+
+    /// Takes every batch with messages in it
+    pub fn flush(&mut self) -> Vec<Ready> {
+        let mut ready = Vec::new();
+        for (topic, batches) in self.subscriptions.iter_mut() {
+            for batch in batches.iter_mut().filter(|b| !b.payloads.is_empty()) {
+                ready.push(take(topic, batch));
+            }
+        }
+        ready
+    }
+}
+
+fn take(topic: &str, batch: &mut Batch) -> Ready {
+    let qos = if batch.lowest.to_u8() < batch.qos.to_u8() { batch.lowest } else { batch.qos };
+    let ready = Ready {
+        client: batch.client.clone(),
+        topic: format!("{}{}", BATCH_PREFIX, topic),
+        payload: encode(&batch.payloads),
+        qos: qos,
+    };
+
+    batch.payloads.clear();
+    batch.lowest = QoS::ExactlyOnce;
+    ready
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use futures::sync::mpsc;
+    use mqtt3::{Publish, QoS};
+    use client::Client;
+    use super::{batched_topic, decode, Batches};
+
+    fn publish(qos: QoS, payload: u8) -> Publish {
+        Publish {
+            dup: false,
+            qos: qos,
+            retain: false,
+            pid: None,
+            topic_name: "metrics/cpu".to_owned(),
+            payload: Arc::new(vec![payload; payload as usize]),
+        }
+    }
+
+    #[test]
+    fn batches_fill_up_and_flush() {
+        let (tx, _rx) = mpsc::channel(8);
+        let client = Client::new("analytics", "127.0.0.1:80".parse().unwrap(), tx);
+
+        assert_eq!(batched_topic("$batch/metrics/cpu"), Some("metrics/cpu"));
+        assert_eq!(batched_topic("$batch/"), None);
+        assert_eq!(batched_topic("metrics/cpu"), None);
+
+        let mut batches = Batches::new(3);
+        batches.subscribe("metrics/cpu", client, QoS::AtLeastOnce);
+
+        assert!(batches.push(&publish(QoS::AtLeastOnce, 1)).is_empty());
+        assert!(batches.push(&publish(QoS::AtMostOnce, 2)).is_empty());
+        let ready = batches.push(&publish(QoS::AtLeastOnce, 3));
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].topic, "$batch/metrics/cpu");
+        // never above the qos a message was published with
+        assert_eq!(ready[0].qos, QoS::AtMostOnce);
+        assert_eq!(decode(&ready[0].payload), Some(vec![vec![1], vec![2, 2], vec![3, 3, 3]]));
+
+        assert!(batches.flush().is_empty());
+        batches.push(&publish(QoS::AtLeastOnce, 4));
+        let ready = batches.flush();
+        assert_eq!(ready[0].qos, QoS::AtLeastOnce);
+        assert_eq!(decode(&ready[0].payload), Some(vec![vec![4; 4]]));
+
+        batches.remove_client("analytics");
+        assert!(batches.push(&publish(QoS::AtLeastOnce, 5)).is_empty());
+        assert!(batches.flush().is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+//! Routing benchmarks. Drives publishes through the broker with in memory
+//! clients and prints one `bench <name> <deliveries per second>` line per
+//! scenario. Run with `cargo test --release -- --ignored bench::`, or
+//! through `cargo xtask bench` which compares them against a baseline
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::executor;
+use futures::sync::mpsc::Receiver;
+
+use mqtt3::*;
+
+use broker::Broker;
+use client::{self, Client};
+use codec::Frame;
+
+/// Publishes per scenario after the warm up
+const PUBLISHES: usize = 20_000;
+const WARM_UP: usize = 1_000;
+
+fn publish(topic: &str, qos: QoS, pkid: u16) -> Box<Publish> {
+    Box::new(Publish {
+                 dup: false,
+                 qos: qos,
+                 retain: false,
+                 pid: if qos == QoS::AtMostOnce { None } else { Some(PacketIdentifier(pkid)) },
+                 topic_name: topic.to_owned(),
+                 payload: Arc::new(vec![0; 128]),
+             })
+}
+
+/// Connected client with its connack taken off the queue
+fn client(broker: &Broker, id: &str) -> (Client, executor::Spawn<Receiver<Frame>>) {
+    let (tx, rx) = client::outgoing_queue(16);
+    let client = Client::new(id, "127.0.0.1:80".parse().unwrap(), tx);
+    broker.connect(client.clone());
+
+    let mut rx = executor::spawn(rx);
+    drain(&mut rx, 1);
+    (client, rx)
+}
+
+fn subscribe(broker: &Broker, client: &Client, topic: &str, qos: QoS) {
+    let subscribe = Box::new(Subscribe {
+                                 pid: PacketIdentifier(1),
+                                 topics: vec![SubscribeTopic {
+                                                  topic_path: topic.to_owned(),
+                                                  qos: qos,
+                                              }],
+                             });
+    broker.handle_subscribe(subscribe, client);
+}
+
+/// Takes `n` frames off a queue
+fn drain(rx: &mut executor::Spawn<Receiver<Frame>>, n: usize) {
+    for _ in 0..n {
+        rx.wait_stream().expect("queue closed").expect("queue failed");
+    }
+}
+
+fn report(name: &str, deliveries: usize, elapsed: Duration) {
+    let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+    println!("bench {} {:.0}", name, deliveries as f64 / secs);
+}
+
+/// One qos 0 publish delivered to 100 subscribers of the topic
+#[test]
+#[ignore]
+fn fanout_qos0() {
+    let broker = Broker::new();
+    let (publisher, _publisher_rx) = client(&broker, "bench-publisher");
+    let mut subscribers: Vec<_> = (0..100).map(|i| client(&broker, &format!("bench-{}", i))).collect();
+    for &mut (ref subscriber, ref mut rx) in subscribers.iter_mut() {
+        subscribe(&broker, subscriber, "bench/fanout", QoS::AtMostOnce);
+        drain(rx, 1);
+    }
+
+    let mut start = Instant::now();
+    for n in 0..WARM_UP + PUBLISHES {
+        if n == WARM_UP {
+            start = Instant::now();
+        }
+
+        broker.handle_publish(publish("bench/fanout", QoS::AtMostOnce, 0), &publisher);
+        for &mut (_, ref mut rx) in subscribers.iter_mut() {
+            drain(rx, 1);
+        }
+    }
+
+    report("fanout_qos0", PUBLISHES * subscribers.len(), start.elapsed());
+}
+
+/// qos 1 publishes on 1000 topics with one subscriber each, acknowledged
+/// on both sides
+#[test]
+#[ignore]
+fn unique_topics_qos1() {
+    let broker = Broker::new();
+    let (publisher, mut publisher_rx) = client(&broker, "bench-publisher");
+    let mut subscribers: Vec<_> = (0..1000).map(|i| client(&broker, &format!("bench-{}", i))).collect();
+    for (i, &mut (ref subscriber, ref mut rx)) in subscribers.iter_mut().enumerate() {
+        subscribe(&broker, subscriber, &format!("bench/{}", i), QoS::AtLeastOnce);
+        drain(rx, 1);
+    }
+
+    let topics: Vec<String> = (0..subscribers.len()).map(|i| format!("bench/{}", i)).collect();
+    let mut start = Instant::now();
+    for n in 0..WARM_UP + PUBLISHES {
+        if n == WARM_UP {
+            start = Instant::now();
+        }
+
+        let i = n % subscribers.len();
+        let pkid = (n % 60_000 + 1) as u16;
+        broker.handle_publish(publish(&topics[i], QoS::AtLeastOnce, pkid), &publisher);
+        drain(&mut publisher_rx, 1);
+
+        let (ref subscriber, ref mut rx) = subscribers[i];
+        match rx.wait_stream() {
+            Some(Ok(Frame::Packet(Packet::Publish(publish)))) => broker.handle_puback(publish.pid.unwrap(), subscriber),
+            frame => panic!("Expected a publish. Got {:?}", frame),
+        }
+    }
+
+    report("unique_topics_qos1", PUBLISHES, start.elapsed());
+}
+
+/// qos 0 publishes from 50000 devices on topics of their own, all going to
+/// one wildcard subscriber. More topics than the single subscriber cache
+/// holds, so it keeps filling up and starting over
+#[test]
+#[ignore]
+fn unique_topics_wildcard() {
+    let broker = Broker::new();
+    let (publisher, _publisher_rx) = client(&broker, "bench-publisher");
+    let (subscriber, mut rx) = client(&broker, "bench-backend");
+    subscribe(&broker, &subscriber, "bench/devices/+", QoS::AtMostOnce);
+    drain(&mut rx, 1);
+
+    let topics: Vec<String> = (0..50_000).map(|i| format!("bench/devices/{}", i)).collect();
+    let mut start = Instant::now();
+    for n in 0..WARM_UP + PUBLISHES {
+        if n == WARM_UP {
+            start = Instant::now();
+        }
+
+        broker.handle_publish(publish(&topics[n % topics.len()], QoS::AtMostOnce, 0), &publisher);
+        drain(&mut rx, 1);
+    }
+
+    report("unique_topics_wildcard", PUBLISHES, start.elapsed());
+}
@@ -0,0 +1,83 @@
+//! Birth messages. The counterpart of the will: the broker publishes on
+//! behalf of every client once its connection is accepted, so presence
+//! topics stay consistent across a fleet whatever the firmware does. `%c`
+//! and `%u` in the topic and the payload stand for the client id and the
+//! username, as in acl filters
+//!
+//! ```text
+//! topic   devices/%c/status
+//! payload online
+//! ```
+
+use std::sync::Arc;
+
+use mqtt3::{Publish, QoS};
+
+use acl;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BirthConfig {
+    pub topic: String,
+    pub payload: String,
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+impl BirthConfig {
+    /// Retained qos 1 publish of `payload` on `topic`
+    pub fn new<S: Into<String>>(topic: S, payload: S) -> Self {
+        BirthConfig {
+            topic: topic.into(),
+            payload: payload.into(),
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        }
+    }
+
+    pub fn qos(mut self, qos: QoS) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    pub fn retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    /// Birth message of a client. `None` if the topic needs a username the
+    /// client didn't give, or a value with topic separators or wildcards in it
+    pub fn publish(&self, client_id: &str, username: Option<&str>) -> Option<Box<Publish>> {
+        let topic = acl::substitute(&self.topic, client_id, username)?;
+        let mut payload = self.payload.replace("%c", client_id);
+        if let Some(username) = username {
+            payload = payload.replace("%u", username);
+        }
+
+        Some(Box::new(Publish {
+                          dup: false,
+                          qos: self.qos,
+                          retain: self.retain,
+                          pid: None,
+                          topic_name: topic,
+                          payload: Arc::new(payload.into_bytes()),
+                      }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BirthConfig;
+
+    #[test]
+    fn birth_messages_are_templated_per_client() {
+        let birth = BirthConfig::new("devices/%c/status", "online %u");
+        let publish = birth.publish("d7", Some("fleet")).unwrap();
+        assert_eq!(publish.topic_name, "devices/d7/status");
+        assert_eq!(&publish.payload[..], b"online fleet");
+        assert!(publish.retain);
+
+        // would land outside the client's own topic
+        assert!(birth.publish("d7/#", None).is_none());
+        assert!(BirthConfig::new("users/%u/status", "online").publish("d7", None).is_none());
+    }
+}
@@ -0,0 +1,202 @@
+//! Outgoing bridge to a remote broker, e.g. from an edge broker to a central
+//! one. The bridge keeps one MQTT connection to the remote broker. Topics
+//! configured `in` are subscribed there and its publishes are routed here,
+//! topics configured `out` are subscribed here and local publishes are
+//! passed on. Unlike a leaf, publishes keep their topic and travel under the
+//! bridge's client id. Publishes received from the remote broker aren't sent
+//! back to it, so `both` doesn't loop
+
+use std::cmp;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use mqtt3::*;
+
+use error::{Error, Result};
+use leaf::LEAF_KEEP_ALIVE;
+
+/// Id of the remote connection among the local clients
+pub const BRIDGE_CLIENT_ID: &str = "$bridge";
+
+/// Wait before the first reconnect after losing the remote broker
+pub const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest wait between two connection attempts
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BridgeConfig {
+    /// Broker to connect to
+    pub remote: SocketAddr,
+    /// Client id of the bridge at the remote broker
+    pub client_id: String,
+}
+
+impl BridgeConfig {
+    /// CONNECT sent to the remote broker. Subscriptions are made again on
+    /// every connection so the session doesn't have to persist
+    pub fn connect_packet(&self) -> Packet {
+        Packet::Connect(Box::new(Connect {
+                                     protocol: Protocol::MQTT(4),
+                                     keep_alive: LEAF_KEEP_ALIVE,
+                                     client_id: self.client_id.clone(),
+                                     clean_session: true,
+                                     last_will: None,
+                                     username: None,
+                                     password: None,
+                                 }))
+    }
+}
+
+/// Which way publishes on a bridged topic travel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// From the remote broker to local subscribers
+    In,
+    /// From local publishers to the remote broker
+    Out,
+    Both,
+}
+
+impl Direction {
+    pub fn inbound(&self) -> bool {
+        *self != Direction::Out
+    }
+
+    pub fn outbound(&self) -> bool {
+        *self != Direction::In
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BridgeTopic {
+    pub filter: String,
+    pub direction: Direction,
+    /// Qos of the subscription made for the topic, here or at the remote broker
+    pub qos: QoS,
+}
+
+/// Parses `<filter> <in|out|both> [qos]`, e.g. `telemetry/# out 1`. Qos defaults to 0
+impl FromStr for BridgeTopic {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::Config(format!("invalid bridge topic {:?}. Expected <filter> <in|out|both> [qos]", s));
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        if parts.len() < 2 || parts.len() > 3 {
+            return Err(invalid());
+        }
+
+        let direction = match parts[1] {
+            "in" => Direction::In,
+            "out" => Direction::Out,
+            "both" => Direction::Both,
+            _ => return Err(invalid()),
+        };
+        let qos = match parts.get(2) {
+            Some(qos) => QoS::from_u8(qos.parse().map_err(|_| invalid())?).map_err(|_| invalid())?,
+            None => QoS::AtMostOnce,
+        };
+
+        Ok(BridgeTopic {
+               filter: parts[0].to_owned(),
+               direction: direction,
+               qos: qos,
+           })
+    }
+}
+
+/// Subscription at the remote broker to the topics coming in. `None` when
+/// the bridge only sends
+pub fn subscribe_packet(topics: &[BridgeTopic]) -> Option<Packet> {
+    let topics: Vec<SubscribeTopic> = topics
+        .iter()
+        .filter(|t| t.direction.inbound())
+        .map(|t| {
+                 SubscribeTopic {
+                     topic_path: t.filter.clone(),
+                     qos: t.qos,
+                 }
+             })
+        .collect();
+    if topics.is_empty() {
+        return None;
+    }
+
+    Some(Packet::Subscribe(Box::new(Subscribe {
+                                        pid: PacketIdentifier(1),
+                                        topics: topics,
+                                    })))
+}
+
+/// Exponential wait between connection attempts, reset once a connection is
+/// accepted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Backoff { next: MIN_BACKOFF }
+    }
+
+    /// Wait before the next attempt. Doubles up to `MAX_BACKOFF`
+    pub fn next(&mut self) -> Duration {
+        let wait = self.next;
+        self.next = cmp::min(self.next * 2, MAX_BACKOFF);
+        wait
+    }
+
+    pub fn reset(&mut self) {
+        self.next = MIN_BACKOFF;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use mqtt3::*;
+    use super::{subscribe_packet, Backoff, BridgeTopic, Direction, MAX_BACKOFF};
+
+    #[test]
+    fn topics_are_parsed() {
+        let topic: BridgeTopic = "telemetry/# out 1".parse().unwrap();
+        assert_eq!(topic.filter, "telemetry/#");
+        assert_eq!(topic.direction, Direction::Out);
+        assert_eq!(topic.qos, QoS::AtLeastOnce);
+        assert_eq!("commands/+ in".parse::<BridgeTopic>().unwrap().qos, QoS::AtMostOnce);
+
+        assert!("telemetry/#".parse::<BridgeTopic>().is_err());
+        assert!("telemetry/# up".parse::<BridgeTopic>().is_err());
+        assert!("telemetry/# out 3".parse::<BridgeTopic>().is_err());
+    }
+
+    #[test]
+    fn only_inbound_topics_are_subscribed_remotely() {
+        let topics: Vec<BridgeTopic> = vec!["telemetry/# out".parse().unwrap(), "commands/+ in 1".parse().unwrap(), "config both".parse().unwrap()];
+        match subscribe_packet(&topics) {
+            Some(Packet::Subscribe(s)) => {
+                let filters: Vec<&str> = s.topics.iter().map(|t| t.topic_path.as_str()).collect();
+                assert_eq!(filters, vec!["commands/+", "config"]);
+            }
+            packet => panic!("unexpected packet {:?}", packet),
+        }
+        assert_eq!(subscribe_packet(&topics[..1]), None);
+    }
+
+    #[test]
+    fn backoff_doubles_until_reset() {
+        let mut backoff = Backoff::new();
+        assert_eq!(backoff.next(), Duration::from_secs(1));
+        assert_eq!(backoff.next(), Duration::from_secs(2));
+        for _ in 0..10 {
+            backoff.next();
+        }
+        assert_eq!(backoff.next(), MAX_BACKOFF);
+
+        backoff.reset();
+        assert_eq!(backoff.next(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,4567 @@
+use std::rc::Rc;
+use std::sync::Arc;
+use std::collections::HashMap;
+use std::cmp;
+use std::fmt::{self, Debug};
+#[cfg(feature = "admin")]
+use std::env;
+use std::net::SocketAddr;
+#[cfg(any(feature = "admin", feature = "persistence"))]
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+#[cfg(feature = "admin")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use slog::{Logger, Drain};
+
+use bytes::Bytes;
+#[cfg(all(feature = "metrics", feature = "connectors"))]
+use futures::Future;
+#[cfg(all(feature = "metrics", feature = "connectors"))]
+use hyper::Client as HttpClient;
+#[cfg(all(feature = "metrics", feature = "connectors"))]
+use hyper::client::HttpConnector;
+use mqtt3::*;
+use serde_json::Value;
+use tokio_core::reactor::Handle;
+
+use acl::{self, Access, Acl, UnauthorizedPublish};
+#[cfg(feature = "metrics")]
+use alert::{Alert, AlertEngine, Sample};
+#[cfg(all(feature = "metrics", feature = "connectors"))]
+use alert;
+use auth::{AllowAll, Authenticator, Verification};
+use batch::{self, Batches};
+use share::{self, SharedSubscriptions};
+use registry::{TopicRegistry, TopicTemplate};
+use txn::{self, Marker, OpenGroups};
+use client::{Client, ClientStats, Delivery, Pending};
+use clock::{self, Clock};
+use catalog::{TopicCatalog, TopicInfo};
+use debounce::Debouncer;
+use downgrade::{DowngradeStats, Mismatch};
+use pause::{PausedTopics, PausePolicy};
+use config::{self, BrokerConfig, DuplicatePkidPolicy, SettingChange};
+use group::ClientGroups;
+use session::{SessionStats, SessionTable};
+use disconnect::DisconnectReason;
+use offline::{OfflineSessions, Queued};
+use storage::{self, Connector, StoragePolicy};
+#[cfg(feature = "persistence")]
+use storage::{LoggedSession, Wal};
+#[cfg(feature = "kafka")]
+use kafka::KafkaSink;
+use codec;
+#[cfg(feature = "admin")]
+use dump;
+use handles;
+use logging::{self, RotatingFile};
+#[cfg(all(feature = "metrics", feature = "connectors"))]
+use http;
+use leaf::{self, Uplink, UPLINK_CLIENT_ID};
+use bridge::BRIDGE_CLIENT_ID;
+use listener::TotalSlots;
+use rate::{AcceptLimiter, Limiter};
+#[cfg(feature = "connectors")]
+use hook::MessageHook;
+use observer::{self, MIRROR_FILTER, PRIMARY_CLIENT_ID};
+use selftest::SELFTEST_CLIENT_ID;
+#[cfg(feature = "metrics")]
+use sys::{self, Retained};
+use trie::{self, TopicTrie};
+use profile::{tracked, Tracked};
+use reload::{self, LevelSwitch, Reload};
+use error::Error;
+
+#[derive(Clone)]
+pub struct Broker {
+    /// All the active clients mapped to their IDs
+    clients: Rc<Tracked<HashMap<String, Client>>>,
+    /// Subscriptions mapped to interested clients, indexed by filter level
+    subscriptions: Rc<Tracked<TopicTrie<Vec<Client>>>>,
+    /// Topics with exactly one subscriber mapped to that subscriber. Lets the
+    /// common device -> backend case skip the subscription lookups. Entries
+    /// are invalidated whenever the topic's subscriptions change, and all of
+    /// them once `SINGLE_SUBSCRIBER_CACHE_SIZE` topics are held
+    single_subscriber: Rc<Tracked<HashMap<String, (Client, QoS)>>>,
+    /// Optional catalog of published topics
+    catalog: Rc<Tracked<Option<TopicCatalog>>>,
+    /// Forwards below the publish qos. `None` unless the downgrade report is on
+    downgrades: Rc<Tracked<Option<DowngradeStats>>>,
+    /// Topic subtrees whose delivery is on hold
+    paused: Rc<Tracked<PausedTopics>>,
+    /// Clients auto-subscribed to fleet wide command topics
+    groups: Rc<Tracked<ClientGroups>>,
+    /// Per client id statistics that outlive the connections
+    sessions: Rc<Tracked<SessionTable>>,
+    /// Persistent sessions of disconnected clients
+    offline: Rc<Tracked<OfflineSessions>>,
+    /// Connection to the hub in leaf node mode. `None` while it's down
+    uplink: Rc<Tracked<Option<Uplink>>>,
+    /// Log of the offline sessions and their `Wal` messages. `None` keeps everything in memory
+    #[cfg(feature = "persistence")]
+    wal: Rc<Tracked<Option<Wal>>>,
+    /// Write ahead log still being read in the background
+    #[cfg(feature = "persistence")]
+    wal_loading: Rc<Tracked<Option<storage::Loading>>>,
+    /// Destination of publishes on `Archive` prefixes
+    connector: Rc<Tracked<Option<Box<Connector>>>>,
+    /// Producer thread mirroring publishes into Kafka
+    #[cfg(feature = "kafka")]
+    kafka: Rc<Tracked<Option<KafkaSink>>>,
+    /// Credential check of every CONNECT
+    authenticator: Rc<Tracked<Box<Authenticator>>>,
+    /// `$batch/` subscriptions and the messages collected for them
+    batches: Rc<Tracked<Batches>>,
+    /// `$share/` groups and their members
+    shared: Rc<Tracked<SharedSubscriptions>>,
+    /// Latest `$SYS` diagnostics, for new subscribers
+    #[cfg(feature = "metrics")]
+    sys: Rc<Tracked<Retained>>,
+    /// Last deliveries and held publishes of debounced topics
+    debouncer: Rc<Tracked<Debouncer>>,
+    /// Alerting rules with the alerts currently firing
+    #[cfg(feature = "metrics")]
+    alerts: Rc<Tracked<AlertEngine>>,
+    /// Made on the first alert sent to the alert webhook
+    #[cfg(all(feature = "metrics", feature = "connectors"))]
+    alert_client: Rc<Tracked<Option<HttpClient<HttpConnector>>>>,
+    /// Parts of the `$txn/` groups that aren't committed yet
+    transactions: Rc<Tracked<OpenGroups>>,
+    /// Declared topics in strict mode. `None` allows any topic
+    registry: Rc<Tracked<Option<TopicRegistry>>>,
+    /// Read and write rules of clients. `None` allows everything
+    acl: Rc<Tracked<Option<Acl>>>,
+    /// Replaced as a whole by reloads
+    config: Rc<Tracked<Rc<BrokerConfig>>>,
+    /// Level of the broker's logger, and of any other logger sharing it
+    log_level: LevelSwitch,
+    /// Connections accepted by all listeners, up to `max_connections`
+    total_slots: TotalSlots,
+    /// Buckets of the connect rates
+    accept_limiter: Rc<Tracked<AcceptLimiter>>,
+    /// Request thread of the message webhook
+    #[cfg(feature = "connectors")]
+    message_hook: Rc<Tracked<Option<MessageHook>>>,
+    /// Bucket of the message webhook rate
+    #[cfg(feature = "connectors")]
+    hook_limiter: Rc<Tracked<Limiter>>,
+    started: Instant,
+    /// Source of every timestamp the broker and its clients keep
+    clock: Rc<Clock>,
+    logger: Logger,
+}
+
+/// Topics held by the single subscriber fast path. Publishers on unique
+/// topics, e.g. one per device under a wildcard subscription, would grow it
+/// without end
+pub const SINGLE_SUBSCRIBER_CACHE_SIZE: usize = 10_000;
+
+/// Lower of the two qos levels
+fn min_qos(a: QoS, b: QoS) -> QoS {
+    if a.to_u8() <= b.to_u8() { a } else { b }
+}
+
+/// Sizes of the broker's internal maps
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BrokerSizes {
+    pub clients: usize,
+    pub subscriptions: usize,
+    /// Client handles held by all the subscriptions together
+    pub subscribers: usize,
+    pub single_subscriber: usize,
+    pub sessions: usize,
+    pub offline_sessions: usize,
+}
+
+impl Broker {
+    pub fn new() -> Self {
+        Broker::with_config(BrokerConfig::default())
+    }
+
+    pub fn with_config(config: BrokerConfig) -> Self {
+        Broker::with_clock(config, clock::system())
+    }
+
+    pub fn with_clock(config: BrokerConfig, clock: Rc<Clock>) -> Self {
+        Broker::with_log_file(config, clock, None)
+    }
+
+    /// Broker logging to `file` instead of stderr. The configured `log_file`
+    /// is opened by `BrokerBuilder::build`, which hands it in here
+    #[doc(hidden)]
+    pub fn with_log_file(config: BrokerConfig, clock: Rc<Clock>, file: Option<RotatingFile>) -> Self {
+        let log_level = LevelSwitch::new(config.log_level);
+        let drain = log_level.filter(logging::drain(config.log_output, file)).fuse();
+
+        let catalog = if config.catalog { Some(TopicCatalog::new()) } else { None };
+        let downgrades = config.downgrade_report.map(|_| DowngradeStats::new());
+        let offline_queue_size = config.max_queued_messages.map_or(config.offline_queue_size, |max| cmp::min(max, config.offline_queue_size));
+        let offline = OfflineSessions::new(offline_queue_size, config.offline_overflow)
+            .max_bytes(config.max_queued_bytes)
+            .expiry(config.session_expiry);
+
+        Broker {
+            clients: Rc::new(tracked("broker.clients", HashMap::new())),
+            subscriptions: Rc::new(tracked("broker.subscriptions", TopicTrie::new())),
+            single_subscriber: Rc::new(tracked("broker.single_subscriber", HashMap::new())),
+            catalog: Rc::new(tracked("broker.catalog", catalog)),
+            downgrades: Rc::new(tracked("broker.downgrades", downgrades)),
+            paused: Rc::new(tracked("broker.paused", PausedTopics::new())),
+            groups: Rc::new(tracked("broker.groups", ClientGroups::new(config.groups.clone()))),
+            sessions: Rc::new(tracked("broker.sessions", SessionTable::new(clock.clone()))),
+            offline: Rc::new(tracked("broker.offline", offline)),
+            uplink: Rc::new(tracked("broker.uplink", None)),
+            #[cfg(feature = "persistence")]
+            wal: Rc::new(tracked("broker.wal", None)),
+            #[cfg(feature = "persistence")]
+            wal_loading: Rc::new(tracked("broker.wal_loading", None)),
+            connector: Rc::new(tracked("broker.connector", None)),
+            #[cfg(feature = "kafka")]
+            kafka: Rc::new(tracked("broker.kafka", None)),
+            authenticator: Rc::new(tracked("broker.authenticator", Box::new(AllowAll))),
+            batches: Rc::new(tracked("broker.batches", Batches::new(config.batch.map_or(0, |b| b.max_messages)))),
+            shared: Rc::new(tracked("broker.shared", SharedSubscriptions::new())),
+            #[cfg(feature = "metrics")]
+            sys: Rc::new(tracked("broker.sys", Retained::new())),
+            debouncer: Rc::new(tracked("broker.debouncer", Debouncer::new(config.debounce.clone()))),
+            #[cfg(feature = "metrics")]
+            alerts: Rc::new(tracked("broker.alerts", AlertEngine::new(config.alerts.clone()))),
+            #[cfg(all(feature = "metrics", feature = "connectors"))]
+            alert_client: Rc::new(tracked("broker.alert_client", None)),
+            transactions: Rc::new(tracked("broker.transactions", OpenGroups::new(config.transactions.unwrap_or(0)))),
+            registry: Rc::new(tracked("broker.registry", config.topic_registry.clone())),
+            acl: Rc::new(tracked("broker.acl", config.acl.clone())),
+            config: Rc::new(tracked("broker.config", Rc::new(config))),
+            log_level: log_level,
+            total_slots: TotalSlots::new(config.max_connections),
+            accept_limiter: Rc::new(tracked("broker.accept_limiter", AcceptLimiter::new())),
+            #[cfg(feature = "connectors")]
+            message_hook: Rc::new(tracked("broker.message_hook", None)),
+            #[cfg(feature = "connectors")]
+            hook_limiter: Rc::new(tracked("broker.hook_limiter", Limiter::new())),
+            started: clock.now(),
+            clock: clock,
+            logger: Logger::root(Arc::new(drain), o!("version" => env!("CARGO_PKG_VERSION"))),
+        }
+    }
+
+    pub fn config(&self) -> Rc<BrokerConfig> {
+        self.config.borrow().clone()
+    }
+
+    /// Checks a connection just accepted from `addr` against the connect
+    /// rates. False if it has to be closed right away
+    pub fn admit_connection(&self, addr: SocketAddr) -> bool {
+        let config = self.config();
+        if config.connect_rate.is_none() && config.connect_rate_per_ip.is_none() {
+            return true;
+        }
+
+        self.accept_limiter
+            .borrow_mut()
+            .admit(addr.ip(), self.clock.now(), config.connect_rate, config.connect_rate_per_ip)
+    }
+
+    /// Shared by the listeners to count their connections against `max_connections`
+    pub fn total_slots(&self) -> TotalSlots {
+        self.total_slots.clone()
+    }
+
+    pub fn log_level(&self) -> LevelSwitch {
+        self.log_level.clone()
+    }
+
+    /// Logger of the broker, for front-ends logging alongside it
+    pub fn logger(&self) -> Logger {
+        self.logger.clone()
+    }
+
+    /// Admin operation to apply the reloadable settings of `config`. Connected
+    /// clients stay, and are checked against the new acl from their next
+    /// packet on. Credentials are loaded again even if their settings didn't
+    /// change, so edited files are picked up
+    pub fn reload(&self, config: BrokerConfig) -> ::error::Result<Reload> {
+        config.validate()?;
+        let (config, reload) = reload::merge(&self.config(), config);
+
+        let authenticator = config.authenticator(self.clock())?;
+        self.set_authenticator(authenticator);
+        self.set_acl(config.acl.clone());
+        self.log_level.set(config.log_level);
+        *self.config.borrow_mut() = Rc::new(config);
+
+        for change in reload.applied.iter() {
+            info!(self.logger, "Reloaded {}. Before = {:?}, After = {:?}", change.name, change.configured, change.effective);
+        }
+        for name in reload.needs_restart.iter() {
+            warn!(self.logger, "Changed {} needs a restart", name);
+        }
+        Ok(reload)
+    }
+
+    /// Clients should be created with this clock
+    pub fn clock(&self) -> Rc<Clock> {
+        self.clock.clone()
+    }
+
+    /// Replaces the credential check of new connections. Connected clients aren't checked again
+    pub fn set_authenticator(&self, authenticator: Box<Authenticator>) {
+        *self.authenticator.borrow_mut() = authenticator;
+    }
+
+    /// Lets the authenticator pick up changed credentials. Connected clients aren't affected
+    pub fn reload_authenticator(&self) -> ::error::Result<bool> {
+        self.authenticator.borrow().reload()
+    }
+
+    /// Runs the authenticator on a CONNECT from `addr`. Resolves to the code
+    /// to send back in CONNACK and what an accepted client is confined to
+    pub fn authenticate(&self, connect: &Connect, addr: SocketAddr, handle: &Handle) -> Verification {
+        self.authenticator.borrow().authenticate(&connect.client_id,
+                                                 connect.username.as_ref().map(|u| u.as_str()),
+                                                 connect.password.as_ref().map(|p| p.as_str()),
+                                                 addr,
+                                                 handle)
+    }
+
+    /// Registers an accepted connection and answers its CONNECT. Messages
+    /// queued for a persistent session are delivered right after the CONNACK
+    pub fn connect(&self, client: Client) {
+        self.finish_wal_load();
+        let config = self.config();
+        client.set_outgoing_overflow(config.outgoing_overflow, config.outgoing_queue_size);
+        let backlog = self.add_client(client.clone());
+        self.connection_event(&client, None);
+
+        // the flag is a reserved bit in 3.1
+        let connack = Packet::Connack(Connack {
+                                          session_present: backlog.is_some() && !client.mqtt31(),
+                                          code: ConnectReturnCode::Accepted,
+                                      });
+        self.send(&client, connack);
+
+        let backlog = backlog.unwrap_or_default();
+        self.sessions
+            .borrow_mut()
+            .stats_mut(&client.id)
+            .delivered_after_resume += backlog.len() as u64;
+
+        // the backlog goes out as the inflight window allows, mixed with live
+        // messages by the drain policy
+        for (publish, qos) in backlog {
+            client.queue_backlog(Pending {
+                                     topic: publish.topic_name.clone(),
+                                     payload: publish.payload.clone(),
+                                     delivery: Delivery {
+                                         qos: qos,
+                                         dup: false,
+                                         retain: false,
+                                     },
+                                 });
+        }
+        self.release_pending(&client);
+        self.publish_birth(&client);
+    }
+
+    /// Adds a new client to the broker. An existing connection with the same
+    /// id is taken over and its subscriptions are dropped, unless the new
+    /// connection continues a persistent session. Returns the messages queued
+    /// for the session when one is resumed
+    pub fn add_client(&self, client: Client) -> Option<Vec<(Box<Publish>, QoS)>> {
+        let existing = self.get_client(&client.id);
+        if let Some(ref existing) = existing {
+            self.disconnect(existing, DisconnectReason::Takeover);
+        }
+
+        self.sessions
+            .borrow_mut()
+            .connected(&client.id, existing.is_some());
+        self.groups.borrow_mut().join(&client);
+        self.clients
+            .borrow_mut()
+            .insert(client.id.clone(), client.clone());
+
+        if client.clean_session() {
+            if self.offline.borrow_mut().discard(&client.id) {
+                #[cfg(feature = "persistence")]
+                self.log_wal(|wal| wal.resumed(&client.id));
+            }
+            return None;
+        }
+
+        self.expire_offline(&client.id);
+        let resumed = self.offline.borrow_mut().resume(&client.id);
+        #[cfg(feature = "persistence")]
+        {
+            if self.config().session_checkpoint.is_some() {
+                // stays logged while connected. checkpoints keep it up to date
+                let session = match resumed {
+                    Some((ref subscriptions, ref backlog)) => {
+                        LoggedSession {
+                            id: client.id.clone(),
+                            subscriptions: subscriptions.clone(),
+                            queue: self.durable(backlog),
+                        }
+                    }
+                    None => {
+                        LoggedSession {
+                            id: client.id.clone(),
+                            subscriptions: Vec::new(),
+                            queue: Vec::new(),
+                        }
+                    }
+                };
+                self.log_wal(|wal| wal.session(&session));
+            } else if resumed.is_some() {
+                self.log_wal(|wal| wal.resumed(&client.id));
+            }
+        }
+        resumed.map(|(subscriptions, backlog)| {
+                        for topic in subscriptions {
+                            let filter = topic.topic_path.clone();
+                            self.add_subscription_client(topic, client.clone());
+                            self.sync_upstream(&filter);
+                        }
+                        backlog
+                    })
+    }
+
+    pub fn get_client(&self, id: &str) -> Option<Client> {
+        self.clients.borrow().get(id).cloned()
+    }
+
+    /// True if this connection is the one currently registered for its client id.
+    /// Turns false once a newer connection takes over the session
+    pub fn is_current(&self, client: &Client) -> bool {
+        match self.clients.borrow().get(&client.id) {
+            Some(c) => c.same_connection(client),
+            None => false,
+        }
+    }
+
+    /// Ends the connection for `reason`, unless a newer connection has
+    /// already taken over its id. The will is published if the reason calls
+    /// for it
+    pub fn disconnect(&self, client: &Client, reason: DisconnectReason) {
+        client.close();
+        if !self.is_current(client) {
+            return;
+        }
+
+        info!(self.logger, "Connection closed. ID = {:?}, Reason = {}", client.id, reason.name());
+        self.end_session(client, reason);
+        self.remove_client(&client.id);
+
+        if reason.publishes_will() {
+            self.publish_will(client);
+        } else {
+            client.take_last_will();
+        }
+        self.connection_event(client, Some(reason));
+    }
+
+    /// Admin operation to close the connection of client `id` for `reason`.
+    /// False if it isn't connected
+    pub fn disconnect_client(&self, id: &str, reason: DisconnectReason) -> bool {
+        match self.get_client(id) {
+            Some(client) => {
+                self.disconnect(&client, reason);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Qos 1 and 2 flows that haven't completed yet, in both directions, over
+    /// every connection
+    pub fn open_flows(&self) -> usize {
+        self.clients
+            .borrow()
+            .values()
+            .map(|client| client.inflight() + client.incoming_inflight())
+            .sum()
+    }
+
+    /// Closes every connection for a shutdown. Persistent sessions are parked
+    /// with their unacknowledged and held back messages, which the write ahead
+    /// log keeps on `Wal` topics. Wills aren't published. Returns the number of
+    /// connections closed
+    pub fn shutdown(&self) -> usize {
+        let clients: Vec<Client> = self.clients.borrow().values().cloned().collect();
+        for client in clients.iter() {
+            client.close();
+            let unfinished = if client.clean_session() { Vec::new() } else { client.take_unfinished() };
+            self.end_session(client, DisconnectReason::Shutdown);
+            self.remove_client(&client.id);
+
+            for (publish, qos) in unfinished {
+                let queued = self.offline.borrow_mut().queue(&client.id, publish.clone(), qos, self.clock.now());
+                if queued != Queued::Dropped && storage::policy(&self.config().storage, &publish.topic_name) == StoragePolicy::Wal {
+                    #[cfg(feature = "persistence")]
+                    self.log_wal(|wal| wal.queued(&client.id, &publish, qos));
+                }
+            }
+        }
+        clients.len()
+    }
+
+    /// Publishes still waiting for an acknowledgement or an inflight slot are
+    /// lost with the connection. Persistent sessions keep their subscriptions offline
+    fn end_session(&self, client: &Client, reason: DisconnectReason) {
+        if !client.clean_session() {
+            let subscriptions = self.client_subscriptions(&client.id);
+            #[cfg(feature = "persistence")]
+            self.log_wal(|wal| wal.parked(&client.id, &subscriptions));
+            self.offline
+                .borrow_mut()
+                .park(&client.id, subscriptions, self.clock.now());
+        }
+
+        let lost = {
+            let state = client.state.borrow();
+            state.outgoing_pub.len() + state.outgoing_rec.len() + state.pending.len() + state.backlog.len()
+        };
+        client.count_dropped(lost as u64);
+
+        self.sessions
+            .borrow_mut()
+            .disconnected(&client.id, lost as u64, reason);
+    }
+
+    /// Periodic keep alive check of a connection. Clients that have been quiet
+    /// for too long are disconnected. Returns false once the connection no
+    /// longer needs to be checked
+    pub fn check_keep_alive(&self, client: &Client) -> bool {
+        if !self.is_current(client) {
+            return false;
+        }
+
+        if client.keep_alive_expired() {
+            warn!(self.logger, "Keep alive timeout. ID = {:?}", client.id);
+            self.disconnect(client, DisconnectReason::KeepAliveTimeout);
+            return false;
+        }
+
+        true
+    }
+
+    /// Periodic liveness probe of a connection that's quiet for `interval`.
+    /// A probe that isn't answered by the next check disconnects the client.
+    /// Returns false once the connection no longer needs to be checked
+    pub fn probe(&self, client: &Client, interval: Duration) -> bool {
+        if !self.is_current(client) {
+            return false;
+        }
+
+        if client.probe_unanswered() {
+            warn!(self.logger, "Liveness probe unanswered. ID = {:?}", client.id);
+            self.disconnect(client, DisconnectReason::KeepAliveTimeout);
+            return false;
+        }
+
+        if client.idle() >= interval {
+            if let Err(e) = client.send_probe() {
+                warn!(self.logger, "Unable to probe connection. ID = {:?}, Error = {:?}", client.id, e);
+                self.disconnect(client, DisconnectReason::ConnectionLost);
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Probes `existing`, whose client id a new connection asks for, at the
+    /// start of the takeover grace. WebSocket clients answer the ping with a
+    /// pong. MQTT has no ping from the broker, so on plain and TLS connections
+    /// only packets the client sends on its own, e.g. its next PINGREQ, count
+    /// as an answer. False if the probe couldn't be sent, i.e. the connection
+    /// is gone already
+    pub fn probe_for_takeover(&self, existing: &Client) -> bool {
+        match existing.send_probe() {
+            Ok(()) => true,
+            Err(e) => {
+                debug!(self.logger, "Unable to probe connection for takeover. ID = {:?}, Error = {:?}", existing.id, e);
+                false
+            }
+        }
+    }
+
+    /// True if `existing` answered the takeover probe, which refuses the new
+    /// connection for its client id
+    pub fn survives_takeover(&self, existing: &Client) -> bool {
+        self.is_current(existing) && !existing.probe_unanswered()
+    }
+
+    /// Periodic retransmission of the client's unacknowledged packets. Returns
+    /// false once the connection no longer needs to be checked
+    pub fn retransmit(&self, client: &Client) -> bool {
+        let interval = match self.config().retransmit_interval {
+            Some(interval) => interval,
+            None => return false,
+        };
+
+        if !self.is_current(client) {
+            return false;
+        }
+
+        let retransmits = client.retransmits(interval, self.config().max_retransmits);
+        if retransmits.given_up > 0 {
+            warn!(self.logger, "Giving up on unacknowledged packets. ID = {:?}, Count = {}", client.id, retransmits.given_up);
+            self.sessions.borrow_mut().stats_mut(&client.id).lost += retransmits.given_up as u64;
+            client.count_dropped(retransmits.given_up as u64);
+        }
+
+        for packet in retransmits.packets {
+            self.send(client, packet);
+        }
+
+        if retransmits.given_up > 0 {
+            self.release_pending(client);
+        }
+
+        self.is_current(client)
+    }
+
+    /// Starts tracking published topics in the topic catalog
+    pub fn enable_catalog(&self) {
+        let mut catalog = self.catalog.borrow_mut();
+        if catalog.is_none() {
+            *catalog = Some(TopicCatalog::new());
+        }
+    }
+
+    /// Admin query for topics starting with `prefix`. Returns an empty
+    /// list when the catalog isn't enabled
+    pub fn topics(&self, prefix: &str, offset: usize, limit: usize) -> Vec<TopicInfo> {
+        match *self.catalog.borrow() {
+            Some(ref catalog) => catalog.query(prefix, offset, limit),
+            None => vec![],
+        }
+    }
+
+    /// Topic/subscriber pairs systematically forwarded below their publish
+    /// qos and the total number of downgraded forwards. Empty when
+    /// downgrades aren't tracked
+    pub fn downgrades(&self) -> (Vec<Mismatch>, u64) {
+        match *self.downgrades.borrow() {
+            Some(ref downgrades) => (downgrades.report(), downgrades.total()),
+            None => (vec![], 0),
+        }
+    }
+
+    /// Publishes conflated so far per debounced topic
+    pub fn conflated(&self) -> Vec<(String, u64)> {
+        self.debouncer.borrow().conflated()
+    }
+
+    /// Turns on strict mode. Until topics are declared nothing can be published or subscribed to
+    pub fn enable_topic_registry(&self) {
+        let mut registry = self.registry.borrow_mut();
+        if registry.is_none() {
+            *registry = Some(TopicRegistry::new());
+        }
+    }
+
+    /// Admin operation to declare the topics matching `pattern` for `role`,
+    /// or for everyone. Returns false when strict mode is off
+    pub fn declare_topic(&self, role: Option<&str>, pattern: &str) -> bool {
+        match *self.registry.borrow_mut() {
+            Some(ref mut registry) => {
+                registry.declare(role, pattern);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Admin operation to remove a declared template. Existing subscriptions stay
+    pub fn retract_topic(&self, role: Option<&str>, pattern: &str) -> bool {
+        match *self.registry.borrow_mut() {
+            Some(ref mut registry) => registry.retract(role, pattern),
+            None => false,
+        }
+    }
+
+    /// Admin operation to give clients connecting as `username` the templates of `role`
+    pub fn assign_role(&self, username: &str, role: &str) -> bool {
+        match *self.registry.borrow_mut() {
+            Some(ref mut registry) => {
+                registry.assign(username, role);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Admin query for the declared templates. Empty when strict mode is off
+    pub fn topic_templates(&self) -> Vec<TopicTemplate> {
+        match *self.registry.borrow() {
+            Some(ref registry) => registry.templates().to_vec(),
+            None => vec![],
+        }
+    }
+
+    /// True if the client may use `topic`. The hub connection of a leaf and
+    /// the bridge are trusted
+    fn declared(&self, topic: &str, client: &Client) -> bool {
+        match *self.registry.borrow() {
+            Some(ref registry) if client.id != UPLINK_CLIENT_ID && client.id != BRIDGE_CLIENT_ID => {
+                registry.allows(topic, &client.id, client.username().as_ref().map(|u| u.as_str()))
+            }
+            _ => true,
+        }
+    }
+
+    /// Admin operation to replace the acl. `None` allows everything again.
+    /// Existing subscriptions stay
+    pub fn set_acl(&self, acl: Option<Acl>) {
+        *self.acl.borrow_mut() = acl;
+    }
+
+    /// True if the acl and the client's topic prefixes let it use `topic`
+    /// for `access`. The hub connection of a leaf and the bridge are trusted
+    fn authorized(&self, topic: &str, client: &Client, access: Access) -> bool {
+        if client.id == UPLINK_CLIENT_ID || client.id == BRIDGE_CLIENT_ID {
+            return true;
+        }
+        if let Some(prefixes) = client.topic_prefixes() {
+            if !prefixes.iter().any(|prefix| topic.starts_with(prefix.as_str())) {
+                return false;
+            }
+        }
+
+        match *self.acl.borrow() {
+            Some(ref acl) => {
+                let username = client.username().or_else(|| self.config().anonymous_role.clone());
+                acl.check(&client.id, username.as_ref().map(|u| u.as_str()), topic, access)
+            }
+            None => true,
+        }
+    }
+
+    /// Admin operation to hold delivery of publishes on `prefix` and its
+    /// subtree. Publishers are still acknowledged as usual
+    pub fn pause(&self, prefix: &str, policy: PausePolicy) {
+        info!(self.logger, "Pausing delivery on {:?}. Policy = {:?}", prefix, policy);
+        self.paused.borrow_mut().pause(prefix, policy);
+    }
+
+    /// Admin operation to resume delivery on `prefix`. Messages queued while
+    /// paused are forwarded in the order they arrived
+    pub fn resume(&self, prefix: &str) {
+        let queued = self.paused.borrow_mut().resume(prefix);
+        info!(self.logger, "Resuming delivery on {:?}. Queued = {}", prefix, queued.len());
+
+        for publish in queued {
+            self.forward_to_subscribers(publish, None);
+        }
+    }
+
+    /// Admin query for borrow statistics of the broker's shared state
+    #[cfg(feature = "contention-profiler")]
+    pub fn contention_report(&self) -> Vec<(&'static str, ::profile::CellStats)> {
+        ::profile::report()
+    }
+
+    /// Removed clients whose handles are still alive after the grace period
+    #[cfg(feature = "handle-tracker")]
+    pub fn leaked_handles(&self) -> Vec<::handles::LeakedHandle> {
+        ::handles::leaked(::handles::LEAK_GRACE)
+    }
+
+    /// Admin query for where the connected client `id` comes from. `None`
+    /// until its lookups are done
+    #[cfg(feature = "enrichment")]
+    pub fn connection_metadata(&self, id: &str) -> Option<::enrich::ConnectMetadata> {
+        self.get_client(id).and_then(|client| client.metadata())
+    }
+
+    /// Client groups with their member counts
+    pub fn groups(&self) -> Vec<(String, usize)> {
+        self.groups.borrow().list()
+    }
+
+    /// Admin query for the statistics of a client id across all its connections
+    pub fn session_stats(&self, id: &str) -> Option<SessionStats> {
+        self.sessions.borrow().get(id)
+    }
+
+    /// Admin query for session statistics of every client id seen, the ones
+    /// losing the most messages first
+    pub fn sessions(&self) -> Vec<(String, SessionStats)> {
+        self.sessions.borrow().list()
+    }
+
+    /// Admin query for the connections ended per reason since the broker started
+    pub fn disconnects(&self) -> Vec<(DisconnectReason, u64)> {
+        self.sessions.borrow().disconnects()
+    }
+
+    /// Admin query for the traffic of a connected client
+    pub fn client_stats(&self, id: &str) -> Option<ClientStats> {
+        self.clients.borrow().get(id).map(|client| client.stats())
+    }
+
+    /// Current values of the metrics alerting rules watch
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> Sample {
+        let sizes = self.sizes();
+        let mut sample = Sample {
+            connections: sizes.clients as u64,
+            subscriptions: sizes.subscriptions as u64,
+            offline_sessions: sizes.offline_sessions as u64,
+            open_flows: self.open_flows() as u64,
+            open_connections: self.total_slots.live() as u64,
+            rate_limited_connections: self.accept_limiter.borrow().refused,
+            ..Sample::default()
+        };
+        for (_, stats) in self.sessions.borrow().list() {
+            sample.published += stats.published;
+            sample.delivered += stats.delivered;
+            sample.dropped_messages += stats.lost + stats.missed_offline + stats.over_limit;
+            sample.expired_messages += stats.expired_offline;
+        }
+        sample
+    }
+
+    /// Evaluates the alerting rules and sends out the alerts that fired or
+    /// resolved. Called every alert interval. Requests to the alert webhook
+    /// are made on the event loop of `handle` without waiting for the answer
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(not(feature = "connectors"), allow(unused_variables))]
+    pub fn check_alerts(&self, handle: &Handle) -> Vec<Alert> {
+        if self.alerts.borrow().is_empty() {
+            return Vec::new();
+        }
+
+        let alerts = self.alerts.borrow_mut().evaluate(self.metrics(), self.clock.now());
+        let config = self.config();
+        for alert in alerts.iter() {
+            let state = if alert.firing { "Firing" } else { "Resolved" };
+            warn!(self.logger, "{} alert. Alert = {}, Metric = {}, Value = {}, Threshold = {}", state, alert.rule, alert.metric, alert.value, alert.threshold);
+
+            let payload = alert.payload();
+            #[cfg(feature = "connectors")]
+            {
+                if let Some(ref url) = config.alert_webhook {
+                    self.send_alert(url, alert, &payload, handle);
+                }
+            }
+            if let Some(ref topic) = config.alert_topic {
+                let publish = Box::new(Publish {
+                                           dup: false,
+                                           qos: QoS::AtLeastOnce,
+                                           retain: false,
+                                           pid: None,
+                                           topic_name: topic.clone(),
+                                           payload: Arc::new(payload),
+                                       });
+                self.forward_to_subscribers(publish, None);
+            }
+        }
+        alerts
+    }
+
+    /// POSTs `alert` to the alert webhook at `url`. The answer is only logged
+    #[cfg(all(feature = "metrics", feature = "connectors"))]
+    fn send_alert(&self, url: &str, alert: &Alert, payload: &[u8], handle: &Handle) {
+        let uri = match http::uri(url) {
+            Ok(uri) => uri,
+            Err(e) => {
+                error!(self.logger, "Unable to call the alert webhook. Alert = {}, Error = {}", alert.rule, e);
+                return;
+            }
+        };
+        let client = self.alert_client
+            .borrow_mut()
+            .get_or_insert_with(|| HttpClient::new(handle))
+            .clone();
+
+        let body = String::from_utf8_lossy(payload).into_owned();
+        let logger = self.logger.clone();
+        let rule = alert.rule.clone();
+        let answer = http::send(&client, uri, body, alert::WEBHOOK_TIMEOUT, handle).then(move |answer| {
+            match answer {
+                Ok(200..=299) => (),
+                Ok(status) => error!(logger, "Alert webhook refused the alert. Alert = {}, Status = {}", rule, status),
+                Err(e) => error!(logger, "Unable to call the alert webhook. Alert = {}, Error = {}", rule, e),
+            }
+            Ok(())
+        });
+        handle.spawn(answer);
+    }
+
+    /// Reports the connections that have stayed at the slow consumer depth
+    /// for the configured duration, once per stretch behind. Called every
+    /// slow consumer interval. Returns the ids reported
+    pub fn check_slow_consumers(&self) -> Vec<String> {
+        let slow = match self.config().slow_consumer {
+            Some(slow) => slow,
+            None => return Vec::new(),
+        };
+
+        let now = self.clock.now();
+        let clients: Vec<Client> = self.clients.borrow().values().cloned().collect();
+        let mut reported = Vec::new();
+        for client in clients {
+            let backlog = client.backlog();
+            let behind = {
+                let mut state = client.state.borrow_mut();
+                if backlog.depth < slow.depth {
+                    state.behind_since = None;
+                    state.slow_reported = false;
+                    continue;
+                }
+
+                let since = *state.behind_since.get_or_insert(now);
+                if state.slow_reported || now.duration_since(since) < slow.duration {
+                    continue;
+                }
+                state.slow_reported = true;
+                now.duration_since(since)
+            };
+
+            warn!(self.logger, "Slow consumer. ID = {:?}, Depth = {}, Stall = {:?}, Behind = {:?}", client.id, backlog.depth, backlog.stall, behind);
+            #[cfg(feature = "metrics")]
+            self.publish_sys(sys::slow_topic(&client.id), Arc::new(sys::slow_payload(&client.id, backlog, behind, slow.disconnect)), false);
+            if slow.disconnect {
+                self.disconnect(&client, DisconnectReason::SlowConsumer);
+            }
+            reported.push(client.id.clone());
+        }
+        reported
+    }
+
+    /// Admin query for the sizes of the internal maps. Meant for spotting leaks
+    pub fn sizes(&self) -> BrokerSizes {
+        let subscriptions = self.subscriptions.borrow();
+
+        BrokerSizes {
+            clients: self.clients.borrow().len(),
+            subscriptions: subscriptions.len(),
+            subscribers: subscriptions.iter().map(|(_, clients)| clients.len()).sum(),
+            single_subscriber: self.single_subscriber.borrow().len(),
+            sessions: self.sessions.borrow().len(),
+            offline_sessions: self.offline.borrow().len(),
+        }
+    }
+
+    /// Admin query for a snapshot of the broker's internals, for post-mortem
+    /// debugging
+    #[cfg(feature = "admin")]
+    pub fn state_dump(&self) -> Value {
+        let now = self.clock.now();
+        let clients: Vec<Value> = self.clients
+            .borrow()
+            .values()
+            .map(|client| {
+                     let stats = client.stats();
+                     let state = client.state.borrow();
+                     json!({
+                         "id": client.id,
+                         "addr": client.addr.to_string(),
+                         "clean_session": state.clean_session,
+                         "idle_ms": clock::millis(now.duration_since(state.last_activity)),
+                         "inflight": state.outgoing_pub.len() + state.outgoing_rec.len() + state.outgoing_rel.len(),
+                         "incoming_inflight": state.incoming_rec.len(),
+                         "pending": state.pending.len(),
+                         "backlog": state.backlog.len(),
+                         "stats": stats.to_json(now),
+                     })
+                 })
+            .collect();
+
+        let offline = dump::top(self.offline.borrow().queue_depths(), usize::max_value(), |&(_, depth)| depth as u64);
+        let sessions = self.sessions();
+        let talkers = |key: fn(&SessionStats) -> u64| -> Vec<Value> {
+            dump::top(sessions.clone(), dump::TOP_TALKERS, |&(_, ref stats)| key(stats))
+                .into_iter()
+                .map(|(id, stats)| json!([id, key(&stats)]))
+                .collect()
+        };
+        let sizes = self.sizes();
+
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "sizes": {
+                "clients": sizes.clients,
+                "subscriptions": sizes.subscriptions,
+                "subscribers": sizes.subscribers,
+                "sessions": sizes.sessions,
+                "offline_sessions": sizes.offline_sessions,
+            },
+            "clients": clients,
+            "subscriptions": dump::subscription_counts(self.subscriptions.borrow().iter().map(|(topic, clients)| (&topic.topic_path, clients.len()))),
+            "offline_queues": offline,
+            "open_flows": self.open_flows(),
+            "disconnects": self.disconnects().into_iter().map(|(reason, n)| (reason.name().to_owned(), n.into())).collect::<::serde_json::Map<String, Value>>(),
+            "top_publishers": talkers(|stats| stats.published),
+            "top_receivers": talkers(|stats| stats.delivered),
+            "top_losers": talkers(|stats| stats.lost + stats.missed_offline),
+            "paused": self.paused().into_iter().map(|(prefix, queued, dropped)| json!([prefix, queued, dropped])).collect::<Vec<_>>(),
+        })
+    }
+
+    /// Admin operation to write `state_dump` to the dump directory. Returns the file's path
+    #[cfg(feature = "admin")]
+    pub fn dump_state(&self) -> ::error::Result<PathBuf> {
+        let dir = self.config().dump_dir.clone().unwrap_or_else(env::temp_dir);
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let path = dump::write(&dir, &self.state_dump(), timestamp)?;
+        info!(self.logger, "State dumped to {}", path.display());
+        Ok(path)
+    }
+
+    /// Admin query for the settings the broker is enforcing. Starts out as
+    /// `BrokerConfig::settings` and follows the admin operations that change
+    /// them at runtime
+    pub fn effective_config(&self) -> Vec<(&'static str, String)> {
+        let mut settings = self.config().settings();
+        let mut overlay = |name, value: String| for setting in settings.iter_mut().filter(|s| s.0 == name) {
+            setting.1 = value.clone();
+        };
+
+        overlay("catalog", self.catalog.borrow().is_some().to_string());
+        overlay("topic_registry", config::registry_setting(self.registry.borrow().as_ref()));
+        overlay("acl", config::acl_setting(self.acl.borrow().as_ref()));
+        #[cfg(feature = "persistence")]
+        if let Some(ref wal) = *self.wal.borrow() {
+            overlay("wal_path", format!("{:?}", wal.path().display()));
+        }
+
+        let paused: Vec<String> = self.paused().into_iter().map(|(prefix, ..)| prefix).collect();
+        if !paused.is_empty() {
+            settings.push(("paused", paused.join(", ")));
+        }
+        settings
+    }
+
+    /// Admin query for the settings changed at runtime since the broker was configured
+    pub fn config_diff(&self) -> Vec<SettingChange> {
+        config::diff(&self.config().settings(), &self.effective_config())
+    }
+
+    /// Paused prefixes with their queued and dropped message counts
+    pub fn paused(&self) -> Vec<(String, usize, u64)> {
+        self.paused.borrow().list()
+    }
+
+    /// Restores the offline sessions logged at `path` and logs them there from
+    /// now on. Returns the number of sessions restored
+    #[cfg(feature = "persistence")]
+    pub fn open_wal<P: AsRef<::std::path::Path>>(&self, path: P) -> ::error::Result<usize> {
+        let (wal, sessions) = Wal::open(path)?;
+        Ok(self.restore(wal, sessions))
+    }
+
+    /// Starts reading the write ahead log at `path` in the background. Connects
+    /// and publishes for offline sessions wait for it to finish, everything
+    /// else goes ahead. A log that fails to load is reported and the broker
+    /// carries on without one, as it would after a failed write
+    #[cfg(feature = "persistence")]
+    pub fn load_wal(&self, path: PathBuf) {
+        *self.wal_loading.borrow_mut() = Some(storage::open_in_background(path));
+    }
+
+    /// True while the write ahead log is read in the background
+    #[cfg(feature = "persistence")]
+    pub fn wal_loading(&self) -> bool {
+        self.wal_loading.borrow().as_ref().map_or(false, |loading| !loading.is_finished())
+    }
+
+    /// Restores the sessions of a write ahead log loading in the background,
+    /// waiting for it to be read if needed
+    #[cfg(feature = "persistence")]
+    pub fn finish_wal_load(&self) {
+        let loading = match self.wal_loading.borrow_mut().take() {
+            Some(loading) => loading,
+            None => return,
+        };
+
+        let start = Instant::now();
+        let waited = !loading.is_finished();
+        match loading.join() {
+            Ok(Ok((wal, sessions))) => {
+                let restored = self.restore(wal, sessions);
+                info!(self.logger, "Write ahead log loaded. Sessions = {}", restored);
+                if waited {
+                    info!(self.logger, "Waited for the write ahead log. Time = {:?}", start.elapsed());
+                }
+            }
+            Ok(Err(e)) => error!(self.logger, "Unable to load the write ahead log. Continuing without it. Error = {}", e),
+            Err(_) => error!(self.logger, "Write ahead log load panicked. Continuing without it"),
+        }
+    }
+
+    /// Builds without persistence never have a log to wait for
+    #[cfg(not(feature = "persistence"))]
+    pub fn finish_wal_load(&self) {}
+
+    #[cfg(feature = "persistence")]
+    fn restore(&self, wal: Wal, sessions: Vec<LoggedSession>) -> usize {
+        let restored = sessions.len();
+
+        {
+            let now = self.clock.now();
+            let mut offline = self.offline.borrow_mut();
+            for session in sessions {
+                offline.park(&session.id, session.subscriptions, now);
+                for (publish, qos) in session.queue {
+                    offline.queue(&session.id, publish, qos, now);
+                }
+            }
+        }
+
+        *self.wal.borrow_mut() = Some(wal);
+        restored
+    }
+
+    /// Rewrites the write ahead log with every persistent session, the
+    /// connected ones included along with their unacknowledged and held back
+    /// messages on `Wal` prefixes. Called every session checkpoint interval.
+    /// Returns the number of sessions logged
+    #[cfg(feature = "persistence")]
+    pub fn checkpoint_sessions(&self) -> usize {
+        if self.wal.borrow().is_none() {
+            return 0;
+        }
+
+        let mut sessions = self.offline.borrow().sessions();
+        for session in sessions.iter_mut() {
+            session.queue = self.durable(&session.queue);
+        }
+        let connected: Vec<Client> = self.clients
+            .borrow()
+            .values()
+            .filter(|client| !client.clean_session())
+            .cloned()
+            .collect();
+        for client in connected {
+            sessions.push(LoggedSession {
+                              id: client.id.clone(),
+                              subscriptions: self.client_subscriptions(&client.id),
+                              queue: self.durable(&client.unfinished()),
+                          });
+        }
+
+        self.log_wal(|wal| wal.rewrite(&sessions));
+        sessions.len()
+    }
+
+    /// The messages of `queue` on `Wal` prefixes
+    #[cfg(feature = "persistence")]
+    fn durable(&self, queue: &[(Box<Publish>, QoS)]) -> Vec<(Box<Publish>, QoS)> {
+        let config = self.config();
+        queue.iter()
+            .filter(|entry| storage::policy(&config.storage, &entry.0.topic_name) == StoragePolicy::Wal)
+            .cloned()
+            .collect()
+    }
+
+    /// Hands publishes on `Archive` prefixes to `connector`. They're only kept
+    /// in memory until a connector is set
+    pub fn set_connector(&self, connector: Box<Connector>) {
+        *self.connector.borrow_mut() = Some(connector);
+    }
+
+    /// POSTs publishes matching the message webhook filters through `hook`
+    #[cfg(feature = "connectors")]
+    pub fn set_message_hook(&self, hook: MessageHook) {
+        *self.message_hook.borrow_mut() = Some(hook);
+    }
+
+    /// Messages the message webhook dropped so far
+    #[cfg(feature = "connectors")]
+    pub fn message_hook_dropped(&self) -> usize {
+        self.message_hook.borrow().as_ref().map_or(0, |hook| hook.dropped())
+    }
+
+    /// Mirrors publishes matching the kafka rules into `sink`
+    #[cfg(feature = "kafka")]
+    pub fn set_kafka_sink(&self, sink: KafkaSink) {
+        *self.kafka.borrow_mut() = Some(sink);
+    }
+
+    /// Appends to the write ahead log if there's one. A failed write loses
+    /// durability but not the message, which is still queued in memory
+    #[cfg(feature = "persistence")]
+    fn log_wal<F: FnOnce(&mut Wal) -> ::error::Result<()>>(&self, record: F) {
+        if let Some(ref mut wal) = *self.wal.borrow_mut() {
+            if let Err(e) = record(wal) {
+                error!(self.logger, "Write ahead log write failed. Error = {}", e);
+            }
+        }
+    }
+
+    /// Takes `client` as the leaf's connection to its hub. Every local filter
+    /// is subscribed at the hub
+    pub fn set_uplink(&self, client: Client) {
+        self.add_client(client.clone());
+        *self.uplink.borrow_mut() = Some(Uplink::new(client));
+
+        let mut filters: Vec<String> = self.subscriptions
+            .borrow()
+            .iter()
+            .map(|(topic, _)| topic.topic_path.clone())
+            .collect();
+        filters.sort();
+        filters.dedup();
+
+        for filter in filters {
+            self.sync_upstream(&filter);
+        }
+    }
+
+    /// Filters the leaf has subscribed at its hub. Empty while the hub is unreachable
+    pub fn uplink_filters(&self) -> Vec<(String, QoS)> {
+        match *self.uplink.borrow() {
+            Some(ref uplink) => uplink.filters(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Takes `client` as the bridge's connection to the remote broker and
+    /// subscribes it to the topics going out
+    pub fn set_bridge(&self, client: Client) {
+        self.add_client(client.clone());
+
+        let topics = self.config().bridge_topics.clone();
+        for topic in topics.into_iter().filter(|t| t.direction.outbound()) {
+            let topic = SubscribeTopic {
+                topic_path: topic.filter,
+                qos: topic.qos,
+            };
+            self.add_subscription_client(topic, client.clone());
+        }
+    }
+
+    /// Updates the hub's subscription on `filter` after local subscriptions changed
+    fn sync_upstream(&self, filter: &str) {
+        if self.uplink.borrow().is_none() || leaf::is_local(filter) {
+            return;
+        }
+
+        let wanted = self.subscriptions
+            .borrow()
+            .iter()
+            .filter(|&(topic, clients)| topic.topic_path == filter && clients.iter().any(|c| c.id != UPLINK_CLIENT_ID))
+            .map(|(topic, _)| topic.qos)
+            .max_by_key(|qos| qos.to_u8());
+
+        let sync = match *self.uplink.borrow_mut() {
+            Some(ref mut uplink) => uplink.sync(filter, wanted).map(|packet| (uplink.client.clone(), packet)),
+            None => None,
+        };
+
+        if let Some((hub, packet)) = sync {
+            self.send(&hub, packet);
+        }
+    }
+
+    /// Passes a local publish up to the hub under the id of the client that sent it
+    fn forward_upstream(&self, publish: &Publish, origin: &str) {
+        let hub = match *self.uplink.borrow() {
+            Some(ref uplink) => uplink.client.clone(),
+            None => return,
+        };
+
+        if origin == UPLINK_CLIENT_ID || leaf::is_local(&publish.topic_name) {
+            return;
+        }
+
+        let delivery = Delivery {
+            qos: publish.qos,
+            dup: false,
+            retain: publish.retain,
+        };
+        self.deliver(&hub, &leaf::wrap_topic(origin, &publish.topic_name), publish.payload.clone(), delivery);
+    }
+
+    /// Hands a publish matching a message webhook filter to the webhook, within its rate
+    #[cfg(feature = "connectors")]
+    fn forward_to_webhook(&self, publish: &Publish, origin: &str, username: Option<&str>) {
+        let hook = self.message_hook.borrow();
+        let hook = match *hook {
+            Some(ref hook) => hook,
+            None => return,
+        };
+
+        let config = self.config();
+        if !config.message_webhook_filters.iter().any(|filter| acl::matches_published(filter, &publish.topic_name)) {
+            return;
+        }
+        if let Some(rate) = config.message_webhook_rate {
+            if !self.hook_limiter.borrow_mut().admit(self.clock.now(), rate) {
+                debug!(self.logger, "Message webhook rate exceeded. Dropping publish. Topic = {:?}", publish.topic_name);
+                hook.count_dropped();
+                return;
+            }
+        }
+
+        if !hook.send(publish, origin, username) {
+            warn!(self.logger, "Message webhook queue full. Dropping publish. Topic = {:?}", publish.topic_name);
+        }
+    }
+
+    /// Copies a publish to the observers subscribed to the mirror
+    fn forward_to_mirrors(&self, publish: &Publish, origin: &str) {
+        let mirrors = self.get_subscription_clients(MIRROR_FILTER);
+        if mirrors.is_empty() {
+            return;
+        }
+
+        let topic = observer::wrap_topic(origin, &publish.topic_name);
+        for (mirror, qos) in mirrors {
+            let delivery = Delivery {
+                qos: min_qos(publish.qos, qos),
+                dup: false,
+                retain: publish.retain,
+            };
+            self.deliver(&mirror, &topic, publish.payload.clone(), delivery);
+        }
+    }
+
+    /// Handles a publish the primary of this observer mirrored. It's
+    /// acknowledged and delivered to local subscribers on its original topic
+    pub fn handle_mirrored(&self, mut publish: Box<Publish>, primary: &Client) {
+        if let (QoS::AtLeastOnce, Some(pkid)) = (publish.qos, publish.pid) {
+            self.send(primary, Packet::Puback(pkid));
+        }
+
+        let topic = match observer::unwrap_topic(&publish.topic_name) {
+            Some((_, topic)) => topic.to_owned(),
+            None => return,
+        };
+        publish.topic_name = topic;
+        self.forward_to_subscribers(publish, None);
+    }
+
+    /// Adds client to a subscription. If the subscription doesn't exist,
+    /// new subscription is created and the client will be added to it
+    fn add_subscription_client(&self, topic: SubscribeTopic, client: Client) {
+        self.invalidate_single_subscriber(&topic.topic_path);
+
+        let mut subscriptions = self.subscriptions.borrow_mut();
+        let clients = subscriptions.get_or_insert_with(topic, Vec::new);
+
+        // add client to a subscription only if it doesn't already exist or
+        // else replace the existing one
+        if let Some(index) = clients.iter().position(|v| v.id == client.id) {
+            clients[index] = client;
+        } else {
+            clients.push(client);
+        }
+    }
+
+    /// Remove a client from a subscription
+    pub fn remove_subscription_client(&self, topic: SubscribeTopic, id: &str) {
+        self.invalidate_single_subscriber(&topic.topic_path);
+
+        let mut subscriptions = self.subscriptions.borrow_mut();
+
+        let empty = match subscriptions.get_mut(&topic) {
+            Some(clients) => {
+                if let Some(index) = clients.iter().position(|v| v.id == id) {
+                    clients.remove(index);
+                }
+                clients.is_empty()
+            }
+            None => false,
+        };
+
+        if empty {
+            subscriptions.remove(&topic);
+        }
+    }
+
+    /// Removes a client from every subscription on the filter `topic_path`,
+    /// whatever qos it was subscribed with
+    fn remove_subscription_filter(&self, topic_path: &str, id: &str) {
+        for qos in [QoS::AtMostOnce, QoS::AtLeastOnce, QoS::ExactlyOnce].iter() {
+            let topic = SubscribeTopic {
+                topic_path: topic_path.to_owned(),
+                qos: *qos,
+            };
+            self.remove_subscription_client(topic, id);
+        }
+    }
+
+    /// Drops the fast path entries of the topics `filter` covers
+    fn invalidate_single_subscriber(&self, filter: &str) {
+        let mut single_subscriber = self.single_subscriber.borrow_mut();
+        if trie::has_wildcards(filter) {
+            single_subscriber.retain(|topic, _| !acl::matches(filter, topic));
+        } else {
+            single_subscriber.remove(filter);
+        }
+    }
+
+    /// Subscriptions of the client `id`
+    fn client_subscriptions(&self, id: &str) -> Vec<SubscribeTopic> {
+        self.subscriptions
+            .borrow()
+            .iter()
+            .filter(|&(_, clients)| clients.iter().any(|c| c.id == id))
+            .map(|(topic, _)| topic.clone())
+            .collect()
+    }
+
+    /// Get the list of clients for a given subscription
+    #[cfg(test)]
+    fn get_subscribed_clients(&self, topic: SubscribeTopic) -> Vec<Client> {
+        let subscriptions = self.subscriptions.borrow();
+
+        if let Some(v) = subscriptions.get(&topic) {
+            v.clone()
+        } else {
+            vec![]
+        }
+    }
+
+    // Remove the client from broker (including subscriptions)
+    pub fn remove_client(&self, id: &str) {
+        if id == UPLINK_CLIENT_ID {
+            *self.uplink.borrow_mut() = None;
+        }
+
+        // filters the hub may have to drop
+        let filters = match *self.uplink.borrow() {
+            Some(_) => self.client_subscriptions(id),
+            None => Vec::new(),
+        };
+
+        if let Some(client) = self.clients.borrow_mut().remove(id) {
+            for leaked in handles::removed(&client) {
+                warn!(self.logger, "Client handles outlived the connection. ID = {:?}", leaked);
+            }
+        }
+
+        self.single_subscriber.borrow_mut().retain(|_, &mut (ref client, _)| client.id != id);
+        #[cfg(feature = "metrics")]
+        {
+            self.sys.borrow_mut().remove(&sys::keepalive_topic(id));
+            self.sys.borrow_mut().remove(&sys::stats_topic(id));
+        }
+        self.groups.borrow_mut().leave(id);
+        self.batches.borrow_mut().remove_client(id);
+        self.shared.borrow_mut().remove_client(id);
+        self.transactions.borrow_mut().remove_client(id);
+
+        {
+            // an empty subscription would stay around forever
+            self.subscriptions.borrow_mut().retain(|_, clients| {
+                if let Some(index) = clients.iter().position(|v| v.id == id) {
+                    clients.remove(index);
+                }
+                !clients.is_empty()
+            });
+        }
+
+        for topic in filters {
+            self.sync_upstream(&topic.topic_path);
+        }
+    }
+
+    pub fn handle_subscribe(&self, subscribe: Box<Subscribe>, client: &Client) {
+        let pkid = subscribe.pid;
+        let mut return_codes = Vec::new();
+        let mut retained = Vec::new();
+
+        // Add current client's id to this subscribe topic
+        for topic in subscribe.topics {
+            let shared = share::parse(&topic.topic_path).map(|(group, filter)| (group.to_owned(), filter.to_owned()));
+            if shared.is_none() && share::is_shared(&topic.topic_path) {
+                warn!(self.logger, "Refusing malformed shared subscription. ID = {:?}, Topic = {:?}", client.id, topic.topic_path);
+                return_codes.push(SubscribeReturnCodes::Failure);
+                continue;
+            }
+
+            // shared subscriptions are checked against the filter they share
+            let checked = shared.as_ref().map_or(&topic.topic_path, |&(_, ref filter)| filter).clone();
+            if !self.declared(&checked, client) {
+                warn!(self.logger, "Refusing subscription to an undeclared topic. ID = {:?}, Topic = {:?}", client.id, topic.topic_path);
+                return_codes.push(SubscribeReturnCodes::Failure);
+                continue;
+            }
+
+            if !self.authorized(&checked, client, Access::Read) {
+                warn!(self.logger, "Refusing unauthorized subscription. ID = {:?}, Topic = {:?}", client.id, topic.topic_path);
+                return_codes.push(SubscribeReturnCodes::Failure);
+                continue;
+            }
+
+            let granted = min_qos(topic.qos, self.config().max_qos);
+            let topic = SubscribeTopic {
+                topic_path: topic.topic_path,
+                qos: granted,
+            };
+
+            return_codes.push(SubscribeReturnCodes::Success(granted));
+
+            if let Some((group, filter)) = shared {
+                self.shared.borrow_mut().subscribe(&group, &filter, client.clone(), granted);
+                continue;
+            }
+
+            if let Some(topic) = self.batched_topic(&topic.topic_path) {
+                self.batches.borrow_mut().subscribe(topic, client.clone(), granted);
+                continue;
+            }
+
+            let filter = topic.topic_path.clone();
+            self.add_subscription_client(topic, client.clone());
+            self.sync_upstream(&filter);
+            if let Some(payload) = self.retained_sys(&filter) {
+                retained.push((filter, payload));
+            }
+        }
+
+        let suback = client.suback_packet(pkid, return_codes);
+        let packet = Packet::Suback(suback);
+        self.send(client, packet);
+
+        for (topic, payload) in retained {
+            let delivery = Delivery {
+                qos: QoS::AtMostOnce,
+                dup: false,
+                retain: true,
+            };
+            self.deliver(client, &topic, payload, delivery);
+        }
+    }
+
+    /// Latest `$SYS` diagnostics on `topic`, for a new subscriber
+    #[cfg(feature = "metrics")]
+    fn retained_sys(&self, topic: &str) -> Option<Arc<Vec<u8>>> {
+        self.sys.borrow().get(topic)
+    }
+
+    /// Builds without metrics have no `$SYS` topics to retain
+    #[cfg(not(feature = "metrics"))]
+    fn retained_sys(&self, _topic: &str) -> Option<Arc<Vec<u8>>> {
+        None
+    }
+
+    /// Publishes the `$SYS` diagnostics, the broker's time and the keep alive
+    /// of every connection. `unix_time` is the wall clock time
+    #[cfg(feature = "metrics")]
+    pub fn publish_diagnostics(&self, unix_time: Duration) {
+        let now = self.clock.now();
+        let mut diagnostics = vec![(sys::TIME_TOPIC.to_owned(), sys::time_payload(unix_time, now.duration_since(self.started)))];
+        for client in self.clients.borrow().values() {
+            let idle = now.duration_since(client.last_activity());
+            diagnostics.push((sys::keepalive_topic(&client.id), sys::keepalive_payload(client.keep_alive(), idle)));
+            diagnostics.push((sys::stats_topic(&client.id), client.stats().to_json(now).to_string().into_bytes()));
+        }
+
+        for (topic, payload) in diagnostics {
+            let payload = Arc::new(payload);
+            self.sys.borrow_mut().set(topic.clone(), payload.clone());
+            self.publish_sys(topic, payload, true);
+        }
+    }
+
+    /// Hands a `$SYS` payload to its subscribers as qos 0
+    #[cfg(feature = "metrics")]
+    fn publish_sys(&self, topic: String, payload: Arc<Vec<u8>>, retain: bool) {
+        let publish = Box::new(Publish {
+                                   dup: false,
+                                   qos: QoS::AtMostOnce,
+                                   retain: retain,
+                                   pid: None,
+                                   topic_name: topic,
+                                   payload: payload,
+                               });
+        self.forward_to_subscribers(publish, None);
+    }
+
+    pub fn handle_unsubscribe(&self, unsubscribe: Box<Unsubscribe>, client: &Client) {
+        for topic in unsubscribe.topics.iter() {
+            if let Some(topic) = self.batched_topic(topic) {
+                self.batches.borrow_mut().unsubscribe(topic, &client.id);
+                continue;
+            }
+
+            if let Some((group, filter)) = share::parse(topic) {
+                self.shared.borrow_mut().unsubscribe(group, filter, &client.id);
+                continue;
+            }
+
+            self.remove_subscription_filter(topic, &client.id);
+            self.sync_upstream(topic);
+        }
+
+        let packet = Packet::Unsuback(unsubscribe.pid);
+        self.send(client, packet);
+    }
+
+    /// Routes a publish of `client` locally and, on a leaf, up to the hub.
+    /// `relayed` is the id of the leaf client a hub received the publish from
+    fn route(&self, publish: Box<Publish>, client: &Client, relayed: Option<String>) {
+        let publish = match self.stage(publish, client, &relayed) {
+            Some(publish) => publish,
+            None => return,
+        };
+
+        if !self.admitted(&publish, client) {
+            return;
+        }
+
+        let except = self.record_publish(&publish, client, &relayed);
+        self.forward_to_subscribers(publish, except);
+    }
+
+    /// Checks a publish of `client` against the declared topics and the acl.
+    /// Refused publishes are logged and counted
+    fn admitted(&self, publish: &Publish, client: &Client) -> bool {
+        if !self.declared(&publish.topic_name, client) {
+            warn!(self.logger, "Dropping publish on an undeclared topic. ID = {:?}, Topic = {:?}", client.id, publish.topic_name);
+            self.sessions.borrow_mut().stats_mut(&client.id).rejected += 1;
+            return false;
+        }
+
+        if !self.authorized(&publish.topic_name, client, Access::Write) {
+            warn!(self.logger, "Dropping unauthorized publish. ID = {:?}, Topic = {:?}", client.id, publish.topic_name);
+            self.sessions.borrow_mut().stats_mut(&client.id).rejected += 1;
+            return false;
+        }
+
+        true
+    }
+
+    /// Counts an admitted publish and passes it to the uplinks, the webhook,
+    /// the archive and Kafka. Returns the connection its delivery skips
+    fn record_publish<'a>(&self, publish: &Publish, client: &'a Client, relayed: &Option<String>) -> Option<&'a Client> {
+        {
+            let origin = relayed.as_ref().unwrap_or(&client.id);
+            self.sessions.borrow_mut().stats_mut(origin).published += 1;
+            self.forward_upstream(&publish, origin);
+            self.forward_to_mirrors(&publish, origin);
+            #[cfg(feature = "connectors")]
+            self.forward_to_webhook(&publish, origin, client.username().as_ref().map(|u| u.as_str()));
+        }
+
+        if storage::policy(&self.config().storage, &publish.topic_name) == StoragePolicy::Archive {
+            if let Some(ref mut connector) = *self.connector.borrow_mut() {
+                if let Err(e) = connector.archive(&publish) {
+                    error!(self.logger, "Archiving failed. Topic = {:?}, Error = {}", publish.topic_name, e);
+                }
+            }
+        }
+
+        #[cfg(feature = "kafka")]
+        {
+            if let Some(ref sink) = *self.kafka.borrow() {
+                if sink.mirror(&publish) > 0 {
+                    warn!(self.logger, "Kafka queue full. Dropping publish. Topic = {:?}", publish.topic_name);
+                }
+            }
+        }
+
+        // the relaying leaf has delivered it to its own clients already.
+        // publishes from the bridge don't go back to the remote broker
+        if relayed.is_some() || client.id == BRIDGE_CLIENT_ID {
+            Some(client)
+        } else {
+            None
+        }
+    }
+
+    /// Delivers to every subscriber but the `except` connection
+    fn forward_to_subscribers(&self, publish: Box<Publish>, except: Option<&Client>) {
+        let publish = match self.paused.borrow_mut().hold(publish) {
+            Some(publish) => publish,
+            None => return,
+        };
+
+        // publishes relayed by a leaf went out there already and can't be held back
+        let publish = if except.is_none() {
+            match self.debouncer.borrow_mut().admit(publish, self.clock.now()) {
+                Some(publish) => publish,
+                None => return,
+            }
+        } else {
+            publish
+        };
+
+        self.deliver_live(publish, except);
+    }
+
+    /// Delivers the latest publish of every debounced topic whose interval is
+    /// over. Called at the shortest debounce interval
+    pub fn flush_debounced(&self) {
+        let due = self.debouncer.borrow_mut().due(self.clock.now());
+        for publish in due {
+            self.deliver_live(publish, None);
+        }
+    }
+
+    fn deliver_live(&self, publish: Box<Publish>, except: Option<&Client>) {
+        let ready = self.batches.borrow_mut().push(&publish);
+        self.deliver_batches(ready);
+
+        let topic = publish.topic_name.clone();
+        let payload = publish.payload.clone();
+
+        // qos 0 publishes with the same flags are identical for every subscriber.
+        // encode once and share
+        let mut qos0: Option<(Delivery, Bytes)> = None;
+
+        for (client, qos) in self.get_subscribers(&topic) {
+            if except.map_or(false, |except| except.same_connection(&client)) {
+                continue;
+            }
+
+            let delivery = self.live_delivery(&publish, qos);
+            if let Some(ref mut downgrades) = *self.downgrades.borrow_mut() {
+                downgrades.record(&topic, &client.id, publish.qos, qos);
+            }
+
+            if delivery.qos != QoS::AtMostOnce {
+                self.deliver(&client, &topic, payload.clone(), delivery);
+                continue;
+            }
+
+            let shared = match qos0 {
+                Some((d, ..)) => d == delivery,
+                None => false,
+            };
+
+            if !shared {
+                let packet = Packet::Publish(client.publish_packet(&topic, payload.clone(), delivery));
+                match codec::encode(&packet) {
+                    Ok(encoded) => qos0 = Some((delivery, encoded)),
+                    Err(e) => {
+                        error!(self.logger, "Unable to encode publish on {:?}. Skipping client. ID = {:?}, Error = {:?}", topic, client.id, e);
+                        self.sessions.borrow_mut().stats_mut(&client.id).lost += 1;
+                        client.count_dropped(1);
+                        continue;
+                    }
+                }
+            }
+
+            if let Some((_, ref encoded)) = qos0 {
+                match client.send_encoded(encoded) {
+                    Ok(()) => {
+                        self.sessions.borrow_mut().stats_mut(&client.id).delivered += 1;
+                        client.count_out(payload.len());
+                    }
+                    Err(e) => {
+                        self.sessions.borrow_mut().stats_mut(&client.id).lost += 1;
+                        client.count_dropped(1);
+                        self.handle_dead_client(&client, e);
+                    }
+                }
+            }
+        }
+
+        self.queue_offline(&publish);
+    }
+
+    /// Holds back the parts of `$txn/` groups and delivers them in order on
+    /// commit. Returns the publishes that aren't grouped
+    fn stage(&self, publish: Box<Publish>, client: &Client, relayed: &Option<String>) -> Option<Box<Publish>> {
+        if self.config().transactions.is_none() {
+            return Some(publish);
+        }
+
+        let topic = publish.topic_name.clone();
+        match txn::parse(&topic) {
+            None => Some(publish),
+            Some((group, Marker::Part(part))) if txn::parse(part).is_some() => {
+                warn!(self.logger, "Dropping nested group publish. ID = {:?}, Group = {:?}", client.id, group);
+                None
+            }
+            Some((group, Marker::Part(part))) => {
+                let mut publish = publish;
+                publish.topic_name = part.to_owned();
+                if let Err(parts) = self.transactions.borrow_mut().add(&client.id, group, publish) {
+                    warn!(self.logger, "Discarding group over the size limit. ID = {:?}, Group = {:?}, Parts = {}", client.id, group, parts);
+                }
+                None
+            }
+            Some((group, Marker::Commit)) => {
+                let parts = self.transactions.borrow_mut().commit(&client.id, group);
+                // all of the group or none of it. every part is checked before
+                // any is delivered, and parts aren't held back by pauses or
+                // debouncing on their own
+                if !parts.iter().all(|publish| self.admitted(publish, client)) {
+                    warn!(self.logger, "Dropping group with a refused part. ID = {:?}, Group = {:?}, Parts = {}", client.id, group, parts.len());
+                    return None;
+                }
+
+                for publish in parts {
+                    let except = self.record_publish(&publish, client, relayed);
+                    self.deliver_live(publish, except);
+                }
+                None
+            }
+            Some((group, Marker::Abort)) => {
+                let parts = self.transactions.borrow_mut().abort(&client.id, group);
+                debug!(self.logger, "Group aborted. ID = {:?}, Group = {:?}, Parts = {}", client.id, group, parts);
+                None
+            }
+        }
+    }
+
+    /// Queues qos 1 and 2 deliveries for the persistent sessions subscribed to
+    /// the topic while their clients are away
+    fn queue_offline(&self, publish: &Publish) {
+        self.finish_wal_load();
+        let subscribers = self.offline.borrow().subscribers(&publish.topic_name);
+        let durable = !subscribers.is_empty() && storage::policy(&self.config().storage, &publish.topic_name) == StoragePolicy::Wal;
+
+        for (id, qos) in subscribers {
+            let qos = min_qos(publish.qos, qos);
+            if qos == QoS::AtMostOnce {
+                continue;
+            }
+
+            self.expire_offline(&id);
+            let queued = self.offline
+                .borrow_mut()
+                .queue(&id, Box::new(publish.clone()), qos, self.clock.now());
+            if durable && queued != Queued::Dropped {
+                #[cfg(feature = "persistence")]
+                self.log_wal(|wal| wal.queued(&id, publish, qos));
+            }
+
+            let mut sessions = self.sessions.borrow_mut();
+            let stats = sessions.stats_mut(&id);
+            match queued {
+                Queued::Yes => stats.queued_offline += 1,
+                Queued::DroppedOldest(dropped) => {
+                    stats.queued_offline += 1;
+                    stats.missed_offline += dropped as u64;
+                }
+                Queued::Dropped => stats.missed_offline += 1,
+            }
+        }
+    }
+
+    /// Discards the persistent sessions whose clients have been away for
+    /// `session_expiry`, subscriptions and queued messages included. Returns
+    /// how many were discarded
+    pub fn expire_sessions(&self) -> usize {
+        let expired = self.offline.borrow_mut().expire_sessions(self.clock.now());
+        for id in expired.iter() {
+            debug!(self.logger, "Session expired. ID = {:?}", id);
+            #[cfg(feature = "persistence")]
+            self.log_wal(|wal| wal.resumed(id));
+        }
+        expired.len()
+    }
+
+    /// Drops the messages that sat in the offline queue of `id` for longer
+    /// than the message ttl. Queues are only looked at when they're touched
+    fn expire_offline(&self, id: &str) {
+        let ttl = match self.config().offline_message_ttl {
+            Some(ttl) => ttl,
+            None => return,
+        };
+
+        let expired = self.offline.borrow_mut().expire(id, self.clock.now(), ttl);
+        if expired > 0 {
+            debug!(self.logger, "Expired offline messages. ID = {:?}, Count = {}", id, expired);
+            self.sessions.borrow_mut().stats_mut(id).expired_offline += expired as u64;
+        }
+    }
+
+    /// Topic collected by a `$batch/` filter. `None` for ordinary filters or
+    /// when batching is off
+    fn batched_topic<'a>(&self, filter: &'a str) -> Option<&'a str> {
+        self.config().batch.and_then(|_| batch::batched_topic(filter))
+    }
+
+    /// Delivers the messages collected for every batch subscription. Called
+    /// every batch interval
+    pub fn flush_batches(&self) {
+        let ready = self.batches.borrow_mut().flush();
+        self.deliver_batches(ready);
+    }
+
+    fn deliver_batches(&self, ready: Vec<batch::Ready>) {
+        for batch in ready {
+            let delivery = Delivery {
+                qos: batch.qos,
+                dup: false,
+                retain: false,
+            };
+            self.deliver(&batch.client, &batch.topic, Arc::new(batch.payload), delivery);
+        }
+    }
+
+    /// Flags for forwarding a live publish to a subscription with `subscription_qos`
+    fn live_delivery(&self, publish: &Publish, subscription_qos: QoS) -> Delivery {
+        Delivery {
+            // never deliver at a higher qos than the message was published with
+            qos: min_qos(publish.qos, subscription_qos),
+            dup: false,
+            retain: publish.retain && self.config().retain_as_published,
+        }
+    }
+
+    /// Builds the publish for one subscriber and sends it, keeping qos 1 and 2
+    /// messages around until they're acknowledged. Qos 1 and 2 messages wait
+    /// while the client's inflight window is full
+    fn deliver(&self, client: &Client, topic: &str, payload: Arc<Vec<u8>>, delivery: Delivery) {
+        if delivery.qos != QoS::AtMostOnce && self.over_session_limits(client, payload.len()) {
+            debug!(self.logger, "Session limits reached. Dropping publish. ID = {:?}, Topic = {:?}", client.id, topic);
+            client.count_dropped(1);
+            self.sessions.borrow_mut().stats_mut(&client.id).over_limit += 1;
+            return;
+        }
+
+        if delivery.qos != QoS::AtMostOnce && self.window_full(client) {
+            let pending = Pending {
+                topic: topic.to_owned(),
+                payload: payload,
+                delivery: delivery,
+            };
+
+            if client.queue_pending(pending) > self.config().outgoing_queue_size {
+                self.handle_dead_client(client, Error::QueueFull);
+            }
+            return;
+        }
+
+        self.send_delivery(client, topic, payload, delivery);
+    }
+
+    /// True if one more qos 1 or 2 message of `size` bytes would take the
+    /// client past `max_queued_messages` or `max_queued_bytes`
+    fn over_session_limits(&self, client: &Client, size: usize) -> bool {
+        let config = self.config();
+        if config.max_queued_messages.is_none() && config.max_queued_bytes.is_none() {
+            return false;
+        }
+
+        let (messages, bytes) = client.queued_messages();
+        config.max_queued_messages.map_or(false, |max| messages >= max) || config.max_queued_bytes.map_or(false, |max| bytes + size > max)
+    }
+
+    /// True if a new qos 1 or 2 message for the client has to wait. Messages
+    /// that are already waiting go first
+    fn window_full(&self, client: &Client) -> bool {
+        match self.config().max_inflight {
+            Some(max) => client.has_pending() || client.inflight() >= max,
+            None => false,
+        }
+    }
+
+    /// Sends held back messages while the client's inflight window has room
+    fn release_pending(&self, client: &Client) {
+        let config = self.config();
+        let max = config.max_inflight.unwrap_or(usize::max_value());
+
+        while client.inflight() < max && !client.is_dead() {
+            match client.next_pending(config.backlog_drain) {
+                Some(pending) => self.send_delivery(client, &pending.topic, pending.payload, pending.delivery),
+                None => break,
+            }
+        }
+    }
+
+    fn send_delivery(&self, client: &Client, topic: &str, payload: Arc<Vec<u8>>, delivery: Delivery) {
+        let len = payload.len();
+        let publish = client.publish_packet(topic, payload, delivery);
+        let packet = Packet::Publish(publish.clone());
+
+        match delivery.qos {
+            QoS::AtLeastOnce => client.store_publish(publish),
+            QoS::ExactlyOnce => client.store_record(publish),
+            _ => (),
+        }
+
+        // a failed qos 1 or 2 message is counted as lost when the connection is cleaned up
+        match client.send(packet) {
+            Ok(()) => {
+                self.sessions.borrow_mut().stats_mut(&client.id).delivered += 1;
+                client.count_out(len);
+            }
+            Err(e) => self.handle_dead_client(client, e),
+        }
+    }
+
+    /// Clients that should receive publishes on `topic` along with the qos
+    /// they asked for
+    fn get_subscribers(&self, topic: &str) -> Vec<(Client, QoS)> {
+        let mut subscribers = self.get_subscription_clients(topic);
+        self.groups
+            .borrow()
+            .extend_deliveries(topic, &mut subscribers);
+        self.shared
+            .borrow_mut()
+            .extend_deliveries(topic, self.config().share_policy, &mut subscribers);
+        subscribers
+    }
+
+    /// Clients subscribed to `topic` along with the subscription qos
+    fn get_subscription_clients(&self, topic: &str) -> Vec<(Client, QoS)> {
+        if let Some(&(ref client, qos)) = self.single_subscriber.borrow().get(topic) {
+            return vec![(client.clone(), qos)];
+        }
+
+        let mut subscribers: Vec<(Client, QoS)> = Vec::new();
+
+        // a client with several matching filters gets one copy at the
+        // highest qos among them
+        for (filter, clients) in self.subscriptions.borrow().matches(topic) {
+            for client in clients {
+                match subscribers.iter_mut().find(|subscriber| subscriber.0.id == client.id) {
+                    Some(subscriber) => {
+                        if filter.qos.to_u8() > subscriber.1.to_u8() {
+                            subscriber.1 = filter.qos;
+                        }
+                    }
+                    None => subscribers.push((client.clone(), filter.qos)),
+                }
+            }
+        }
+
+        if subscribers.len() == 1 {
+            let mut single_subscriber = self.single_subscriber.borrow_mut();
+            if single_subscriber.len() >= SINGLE_SUBSCRIBER_CACHE_SIZE {
+                single_subscriber.clear();
+            }
+            single_subscriber.insert(topic.to_owned(), subscribers[0].clone());
+        }
+
+        subscribers
+    }
+
+    pub fn handle_publish(&self, mut publish: Box<Publish>, client: &Client) {
+        let pkid = publish.pid;
+        let qos = publish.qos;
+        client.count_in(publish.payload.len());
+
+        // passed up by a leaf. routed on the original topic under the id of the leaf's client
+        let relayed = match leaf::unwrap_topic(&publish.topic_name) {
+            Some((origin, topic)) => Some((format!("{}/{}", client.id, origin), topic.to_owned())),
+            None => None,
+        };
+        let relayed = relayed.map(|(origin, topic)| {
+                                      publish.topic_name = topic;
+                                      origin
+                                  });
+
+        // observers only deliver what their primary mirrors
+        if self.config().observer.is_some() && client.id != PRIMARY_CLIENT_ID && client.id != SELFTEST_CLIENT_ID {
+            error!(self.logger, "Publish to a read only observer. Disconnecting. ID = {:?}", client.id);
+            self.disconnect(client, DisconnectReason::ProtocolError);
+            return;
+        }
+
+        if self.config().unauthorized_publish == UnauthorizedPublish::Disconnect &&
+           !self.authorized(&publish.topic_name, client, Access::Write) {
+            error!(self.logger, "Unauthorized publish. Disconnecting. ID = {:?}, Topic = {:?}", client.id, publish.topic_name);
+            self.disconnect(client, DisconnectReason::NotAuthorized);
+            return;
+        }
+
+        if let Some(ref mut catalog) = *self.catalog.borrow_mut() {
+            catalog.record(&publish, self.clock.now());
+        }
+
+        match qos {
+            QoS::AtMostOnce => self.route(publish, client, relayed),
+            // send puback for qos1 packet immediately
+            QoS::AtLeastOnce => {
+                if let Some(pkid) = pkid {
+                    if self.is_duplicate(client, pkid) {
+                        return;
+                    }
+
+                    let packet = Packet::Puback(pkid);
+                    self.send(client, packet);
+                    // we should fwd only qos1 packets to all the subscribers (any qos) at this point
+                    self.route(publish, client, relayed);
+                } else {
+                    error!(self.logger,
+                           "Ignoring publish packet. No pkid for QoS1 packet");
+                }
+            }
+            // forward the qos2 packet once and send pubrec. the packet id is held
+            // until pubrel so that redeliveries are only acknowledged
+            QoS::ExactlyOnce => {
+                if let Some(pkid) = pkid {
+                    let new = client.store_incoming_record(pkid);
+                    if new && self.config().max_inflight.map_or(false, |max| client.incoming_inflight() > max) {
+                        error!(self.logger, "Too many unreleased QoS2 publishes. ID = {:?}", client.id);
+                        self.disconnect(client, DisconnectReason::ProtocolError);
+                        return;
+                    }
+
+                    let packet = Packet::Pubrec(pkid);
+                    self.send(client, packet);
+
+                    if new {
+                        self.route(publish, client, relayed);
+                    } else {
+                        debug!(self.logger, "Duplicate QoS2 publish. ID = {:?}, Pkid = {:?}", client.id, pkid);
+                    }
+                } else {
+                    error!(self.logger,
+                           "Ignoring record packet. No pkid for QoS2 packet");
+                }
+            }
+        }
+    }
+
+    /// Applies the duplicate packet id policy to an incoming QoS 1 publish.
+    /// Returns true if the publish shouldn't be forwarded
+    fn is_duplicate(&self, client: &Client, pkid: PacketIdentifier) -> bool {
+        let policy = match self.config().duplicate_pkid_policy {
+            Some(policy) => policy,
+            None => return false,
+        };
+
+        if !client.record_incoming_pkid(pkid, self.config().duplicate_pkid_window) {
+            return false;
+        }
+
+        match policy {
+            DuplicatePkidPolicy::Dedupe => {
+                debug!(self.logger, "Duplicate QoS1 publish. ID = {:?}, Pkid = {:?}", client.id, pkid);
+                self.send(client, Packet::Puback(pkid));
+            }
+            DuplicatePkidPolicy::Disconnect => {
+                error!(self.logger, "Packet id reused for new QoS1 publish. ID = {:?}, Pkid = {:?}", client.id, pkid);
+                self.disconnect(client, DisconnectReason::ProtocolError);
+            }
+        }
+
+        true
+    }
+
+    pub fn handle_puback(&self, pkid: PacketIdentifier, client: &Client) {
+        client.remove_publish(pkid);
+        self.release_pending(client);
+    }
+
+    pub fn handle_pubrec(&self, pkid: PacketIdentifier, client: &Client) {
+        debug!(self.logger, "PubRec <= {:?}", pkid);
+
+        // remove record packet from state queues
+        if let Some(record) = client.remove_record(pkid) {
+            // record and send pubrel packet
+            client.store_rel(record.pid.unwrap()); //TODO: Remove unwrap. Might be a problem if client behaves incorrectly
+            let packet = Packet::Pubrel(pkid);
+            self.send(client, packet);
+        }
+    }
+
+    pub fn handle_pubcomp(&self, pkid: PacketIdentifier, client: &Client) {
+        // remove release packet from state queues
+        client.remove_rel(pkid);
+        self.release_pending(client);
+    }
+
+    pub fn handle_pubrel(&self, pkid: PacketIdentifier, client: &Client) {
+        // the publish was forwarded when it arrived. forget its packet id so
+        // that it can be reused. pubcomp is sent even for unknown ids as the
+        // client may be retrying after a lost pubcomp
+        client.remove_incoming_record(pkid);
+
+        let packet = Packet::Pubcomp(pkid);
+        self.send(client, packet);
+    }
+
+    pub fn handle_pingreq(&self, client: &Client) {
+        let pingresp = Packet::Pingresp;
+        self.send(client, pingresp);
+    }
+
+    /// Sends the packet to the client. Clients that can't take any more packets
+    /// are considered disconnected
+    fn send(&self, client: &Client, packet: Packet) {
+        if let Err(e) = client.send(packet) {
+            self.handle_dead_client(client, e);
+        }
+    }
+
+    fn handle_dead_client(&self, client: &Client, e: Error) {
+        // already cleaned up, e.g. by an earlier failed send in the same fan-out
+        if !self.is_current(client) {
+            return;
+        }
+
+        error!(self.logger, "Dropping client. ID = {:?}, Error = {}", client.id, e);
+        self.disconnect(client, DisconnectReason::ConnectionLost);
+    }
+
+    /// Clean close requested by the client. The will is discarded and the
+    /// connection closes after writing what's already queued for it
+    pub fn handle_disconnect(&self, client: &Client) {
+        self.disconnect(client, DisconnectReason::ClientDisconnect);
+    }
+
+    /// Cleans up after a connection that went away without a DISCONNECT
+    pub fn handle_network_disconnect(&self, client: &Client) {
+        self.disconnect(client, DisconnectReason::ConnectionLost);
+    }
+
+    /// Announces a connection coming online, or going offline for `reason`,
+    /// when connection events are enabled
+    #[cfg(feature = "metrics")]
+    fn connection_event(&self, client: &Client, reason: Option<DisconnectReason>) {
+        if !self.config().connection_events || self.config().observer.is_some() {
+            return;
+        }
+
+        let online = reason.is_none();
+        let reason = reason.map_or("connect", |reason| reason.name());
+        let topic = sys::connection_topic(&client.id);
+        let payload = Arc::new(sys::connection_payload(&client.id, online, client.clean_session(), reason, client.addr));
+        if online {
+            self.sys.borrow_mut().set(topic.clone(), payload.clone());
+        } else {
+            self.sys.borrow_mut().remove(&topic);
+        }
+        self.publish_sys(topic, payload, online);
+    }
+
+    /// Builds without metrics have no `$SYS` topics to announce on
+    #[cfg(not(feature = "metrics"))]
+    fn connection_event(&self, _client: &Client, _reason: Option<DisconnectReason>) {}
+
+    /// Publishes the configured birth message on behalf of a newly connected client
+    fn publish_birth(&self, client: &Client) {
+        if self.config().observer.is_some() {
+            return;
+        }
+
+        let publish = match self.config().birth {
+            Some(ref birth) => birth.publish(&client.id, client.username().as_ref().map(|u| u.as_str())),
+            None => return,
+        };
+        match publish {
+            Some(publish) => self.route(publish, client, None),
+            None => debug!(self.logger, "No birth message for client. ID = {:?}", client.id),
+        }
+    }
+
+    fn publish_will(&self, client: &Client) {
+        if self.config().observer.is_some() {
+            return;
+        }
+
+        if let Some(will) = client.take_last_will() {
+            let publish = Box::new(Publish {
+                                       dup: false,
+                                       qos: will.qos,
+                                       retain: will.retain,
+                                       pid: None,
+                                       topic_name: will.topic,
+                                       payload: Arc::new(will.message.into_bytes()),
+                                   });
+
+            self.route(publish, client, None);
+        }
+    }
+}
+
+impl Debug for Broker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{:#?}\n{:#?}",
+               self.clients.borrow(),
+               self.subscriptions.borrow())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(all(feature = "metrics", feature = "connectors"))]
+    use std::net::TcpListener;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use futures::sync::mpsc::{self, Receiver};
+    use futures::{Future, Stream};
+    #[cfg(feature = "metrics")]
+    use tokio_core::reactor::Core;
+    #[cfg(all(feature = "metrics", feature = "connectors"))]
+    use tokio_core::reactor::Timeout;
+    #[cfg(all(feature = "metrics", feature = "connectors"))]
+    use alert;
+    use client::Client;
+    use clock::ManualClock;
+    use leaf::{self, UPLINK_CLIENT_ID};
+    use bridge::BRIDGE_CLIENT_ID;
+    use observer::{ObserverConfig, PRIMARY_CLIENT_ID};
+    use downgrade;
+    use super::{Broker, SINGLE_SUBSCRIBER_CACHE_SIZE};
+    use config::{BrokerConfig, DrainPolicy, DuplicatePkidPolicy};
+    use storage::{StoragePolicy, StorageRule};
+    use batch::{self, BatchConfig};
+    use debounce::DebounceRule;
+    use acl::{Access, Acl, AclSubject, Permission, UnauthorizedPublish};
+    use pause::PausePolicy;
+    use codec::Frame;
+    use disconnect::DisconnectReason;
+    use birth::BirthConfig;
+    use slow::SlowConsumerConfig;
+    use mqtt3::*;
+
+    fn mock_client(id: &str) -> (Client, Receiver<Frame>) {
+        let (tx, rx) = mpsc::channel::<Frame>(8);
+        (Client::new(id, "127.0.0.1:80".parse().unwrap(), tx), rx)
+    }
+
+    #[test]
+    fn add_and_remove_clients_to_the_broker() {
+        let (c1, ..) = mock_client("mock-client-1");
+        let (c2, ..) = mock_client("mock-client-2");
+        let (c3, ..) = mock_client("mock-client-3");
+
+        let broker = Broker::new();
+        broker.add_client(c1);
+        broker.add_client(c2);
+        broker.add_client(c3);
+
+        {
+            let clients = broker.clients.borrow();
+            assert_eq!(clients.contains_key("mock-client-1"), true);
+            assert_eq!(clients.contains_key("mock-client-2"), true);
+            assert_eq!(clients.contains_key("mock-client-3"), true);
+        }
+
+        broker.remove_client("mock-client-2");
+
+        {
+            let clients = broker.clients.borrow();
+            assert_eq!(clients.contains_key("mock-client-1"), true);
+            assert_eq!(clients.contains_key("mock-client-2"), false);
+            assert_eq!(clients.contains_key("mock-client-3"), true);
+        }
+    }
+
+    fn next_frame(rx: Receiver<Frame>) -> (Frame, Receiver<Frame>) {
+        match rx.into_future().wait() {
+            Ok((Some(frame), rx)) => (frame, rx),
+            _ => panic!("Expected a frame"),
+        }
+    }
+
+    #[test]
+    fn qos0_fan_out_shares_one_encoding() {
+        let (c1, rx1) = mock_client("mock-client-1");
+        let (c2, rx2) = mock_client("mock-client-2");
+        let (publisher, ..) = mock_client("mock-client-3");
+
+        let s = SubscribeTopic {
+            topic_path: "hello/mqtt".to_owned(),
+            qos: QoS::AtMostOnce,
+        };
+
+        let broker = Broker::new();
+        broker.add_subscription_client(s.clone(), c1.clone());
+        broker.add_subscription_client(s.clone(), c2.clone());
+
+        let publish = Box::new(Publish {
+                                   dup: false,
+                                   qos: QoS::AtMostOnce,
+                                   retain: false,
+                                   pid: None,
+                                   topic_name: "hello/mqtt".to_owned(),
+                                   payload: Arc::new(vec![1, 2, 3]),
+                               });
+        broker.handle_publish(publish, &publisher);
+
+        let (f1, _rx1) = next_frame(rx1);
+        let (f2, _rx2) = next_frame(rx2);
+        match (f1, f2) {
+            (Frame::Encoded(b1), Frame::Encoded(b2)) => {
+                assert_eq!(b1, b2);
+                assert_eq!(b1.as_ptr(), b2.as_ptr());
+            }
+            frames => panic!("Expected shared encodings. Got {:?}", frames),
+        }
+    }
+
+    #[test]
+    fn retain_flag_is_cleared_unless_configured() {
+        let mut config = BrokerConfig::default();
+        config.retain_as_published = true;
+
+        for &(ref config, retain) in [(BrokerConfig::default(), false), (config, true)].iter() {
+            let broker = Broker::with_config(config.clone());
+            let (subscriber, rx) = mock_client("mock-client-1");
+            let (publisher, ..) = mock_client("mock-client-2");
+            broker.add_subscription_client(SubscribeTopic {
+                                               topic_path: "hello/mqtt".to_owned(),
+                                               qos: QoS::AtLeastOnce,
+                                           },
+                                           subscriber.clone());
+
+            let mut publish = qos1_publish(1, 1);
+            publish.retain = true;
+            broker.handle_publish(publish, &publisher);
+
+            let (frame, _rx) = next_frame(rx);
+            match frame {
+                Frame::Packet(Packet::Publish(publish)) => {
+                    assert_eq!(publish.retain, retain);
+                    assert_eq!(publish.dup, false);
+                }
+                frame => panic!("Expected a publish. Got {:?}", frame),
+            }
+        }
+    }
+
+    #[test]
+    fn qos2_state_is_kept_per_client() {
+        let (subscriber, sub_rx) = mock_client("mock-client-1");
+        let (p1, rx1) = mock_client("mock-client-2");
+        let (p2, rx2) = mock_client("mock-client-3");
+
+        let broker = Broker::new();
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "hello/mqtt".to_owned(),
+                                           qos: QoS::AtMostOnce,
+                                       },
+                                       subscriber.clone());
+
+        // both publishers use the same packet id
+        for &(ref client, payload) in [(&p1, 1), (&p2, 2)].iter() {
+            let publish = Box::new(Publish {
+                                       dup: false,
+                                       qos: QoS::ExactlyOnce,
+                                       retain: false,
+                                       pid: Some(PacketIdentifier(7)),
+                                       topic_name: "hello/mqtt".to_owned(),
+                                       payload: Arc::new(vec![payload]),
+                                   });
+            broker.handle_publish(publish, client);
+        }
+
+        let (frame, rx1) = next_frame(rx1);
+        assert_eq!(frame, Frame::Packet(Packet::Pubrec(PacketIdentifier(7))));
+        let (frame, _rx2) = next_frame(rx2);
+        assert_eq!(frame, Frame::Packet(Packet::Pubrec(PacketIdentifier(7))));
+
+        // releasing p2's message doesn't touch p1's
+        broker.handle_pubrel(PacketIdentifier(7), &p2);
+        assert_eq!(p1.state.borrow().incoming_rec.len(), 1);
+        assert!(p2.state.borrow().incoming_rec.is_empty());
+
+        broker.handle_pubrel(PacketIdentifier(7), &p1);
+        let (frame, _rx1) = next_frame(rx1);
+        assert_eq!(frame, Frame::Packet(Packet::Pubcomp(PacketIdentifier(7))));
+
+        // each message is forwarded once
+        drop(subscriber);
+        drop(broker);
+        assert_eq!(drain(sub_rx).len(), 2);
+    }
+
+    #[test]
+    fn qos2_redeliveries_are_acked_but_not_forwarded() {
+        let (subscriber, sub_rx) = mock_client("mock-client-1");
+        let (publisher, pub_rx) = mock_client("mock-client-2");
+
+        let broker = Broker::new();
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "hello/mqtt".to_owned(),
+                                           qos: QoS::AtMostOnce,
+                                       },
+                                       subscriber.clone());
+
+        let publish = |dup| {
+            Box::new(Publish {
+                         dup: dup,
+                         qos: QoS::ExactlyOnce,
+                         retain: false,
+                         pid: Some(PacketIdentifier(3)),
+                         topic_name: "hello/mqtt".to_owned(),
+                         payload: Arc::new(vec![1]),
+                     })
+        };
+
+        broker.handle_publish(publish(false), &publisher);
+        // pubrec got lost. the client retries
+        broker.handle_publish(publish(true), &publisher);
+        broker.handle_pubrel(PacketIdentifier(3), &publisher);
+        // pubcomp got lost too
+        broker.handle_pubrel(PacketIdentifier(3), &publisher);
+        // the id is free again. this is a new message
+        broker.handle_publish(publish(false), &publisher);
+
+        drop(subscriber);
+        drop(publisher);
+        drop(broker);
+
+        assert_eq!(drain(pub_rx),
+                   vec![Frame::Packet(Packet::Pubrec(PacketIdentifier(3))),
+                        Frame::Packet(Packet::Pubrec(PacketIdentifier(3))),
+                        Frame::Packet(Packet::Pubcomp(PacketIdentifier(3))),
+                        Frame::Packet(Packet::Pubcomp(PacketIdentifier(3))),
+                        Frame::Packet(Packet::Pubrec(PacketIdentifier(3)))]);
+        assert_eq!(drain(sub_rx).len(), 2);
+    }
+
+    #[test]
+    fn unsubscribe_removes_the_filter_and_acks() {
+        let (subscriber, rx) = mock_client("mock-client-1");
+        let (other, ..) = mock_client("mock-client-2");
+
+        let broker = Broker::new();
+        for &(qos, ref client) in [(QoS::AtMostOnce, &subscriber), (QoS::AtLeastOnce, &subscriber), (QoS::AtMostOnce, &other)].iter() {
+            broker.add_subscription_client(SubscribeTopic {
+                                               topic_path: "hello/mqtt".to_owned(),
+                                               qos: qos,
+                                           },
+                                           (*client).clone());
+        }
+
+        let unsubscribe = Box::new(Unsubscribe {
+                                       pid: PacketIdentifier(9),
+                                       topics: vec!["hello/mqtt".to_owned()],
+                                   });
+        broker.handle_unsubscribe(unsubscribe, &subscriber);
+
+        let (frame, _rx) = next_frame(rx);
+        assert_eq!(frame, Frame::Packet(Packet::Unsuback(PacketIdentifier(9))));
+
+        let subscribers = broker.get_subscribers("hello/mqtt");
+        assert_eq!(subscribers.len(), 1);
+        assert_eq!(subscribers[0].0.id, "mock-client-2");
+    }
+
+    #[test]
+    fn disconnect_suppresses_the_will() {
+        let (client, rx) = mock_client("mock-client-1");
+        let (watcher, watcher_rx) = mock_client("mock-client-2");
+
+        client.set_last_will(Some(LastWill {
+                                      topic: "clients/mock-client-1/status".to_owned(),
+                                      message: "offline".to_owned(),
+                                      qos: QoS::AtMostOnce,
+                                      retain: false,
+                                  }));
+
+        let broker = Broker::new();
+        broker.add_client(client.clone());
+        broker.add_client(watcher.clone());
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "clients/mock-client-1/status".to_owned(),
+                                           qos: QoS::AtMostOnce,
+                                       },
+                                       watcher.clone());
+
+        broker.handle_pingreq(&client);
+        broker.handle_disconnect(&client);
+        assert!(broker.get_client("mock-client-1").is_none());
+
+        // the network side of the connection noticing the close is a no-op
+        broker.handle_network_disconnect(&client);
+
+        // pending packets are still written
+        assert_eq!(drain(rx), vec![Frame::Packet(Packet::Pingresp)]);
+
+        drop(watcher);
+        drop(broker);
+        assert!(drain(watcher_rx).is_empty());
+    }
+
+    #[test]
+    fn connected_clients_get_a_birth_message() {
+        let mut config = BrokerConfig::default();
+        config.birth = Some(BirthConfig::new("devices/%c/status", "online").qos(QoS::AtMostOnce));
+        let broker = Broker::with_config(config);
+
+        let (watcher, watcher_rx) = mock_client("watcher");
+        broker.add_client(watcher.clone());
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "devices/d7/status".to_owned(),
+                                           qos: QoS::AtMostOnce,
+                                       },
+                                       watcher.clone());
+
+        let (device, device_rx) = mock_client("d7");
+        broker.connect(device.clone());
+        let (connack, _device_rx) = next_frame(device_rx);
+        match connack {
+            Frame::Packet(Packet::Connack(_)) => (),
+            frame => panic!("Expected the connack first. Got {:?}", frame),
+        }
+
+        match next_frame(watcher_rx).0 {
+            Frame::Encoded(bytes) => assert!(bytes.ends_with(b"devices/d7/statusonline")),
+            frame => panic!("Expected the birth message. Got {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn disconnect_reasons_decide_the_will() {
+        let broker = Broker::new();
+        let (watcher, watcher_rx) = mock_client("watcher");
+        broker.add_client(watcher.clone());
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "clients/status".to_owned(),
+                                           qos: QoS::AtMostOnce,
+                                       },
+                                       watcher.clone());
+
+        let with_will = |id: &str| {
+            let (client, rx) = mock_client(id);
+            client.set_last_will(Some(LastWill {
+                                          topic: "clients/status".to_owned(),
+                                          message: id.to_owned(),
+                                          qos: QoS::AtMostOnce,
+                                          retain: false,
+                                      }));
+            broker.add_client(client.clone());
+            (client, rx)
+        };
+
+        // replaced by its own reconnect
+        let (_old, _old_rx) = with_will("device-1");
+        let (_new, _new_rx) = with_will("device-1");
+        let (_kicked, _kicked_rx) = with_will("device-2");
+        assert!(broker.disconnect_client("device-2", DisconnectReason::AdminKick));
+        assert!(!broker.disconnect_client("device-3", DisconnectReason::AdminKick));
+
+        assert_eq!(broker.session_stats("device-2").unwrap().last_disconnect_reason, Some(DisconnectReason::AdminKick));
+        assert_eq!(broker.disconnects(), vec![(DisconnectReason::AdminKick, 1), (DisconnectReason::Takeover, 1)]);
+
+        drop(watcher);
+        drop(broker);
+        let wills = drain(watcher_rx);
+        assert_eq!(wills.len(), 1);
+        match wills[0] {
+            Frame::Encoded(ref bytes) => assert!(bytes.ends_with(b"device-2")),
+            ref frame => panic!("Expected the will of the kicked client. Got {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn unacknowledged_packets_are_retransmitted_up_to_the_cap() {
+        let mut config = BrokerConfig::default();
+        config.retransmit_interval = Some(Duration::from_secs(10));
+        config.max_retransmits = 1;
+        let broker = Broker::with_config(config);
+
+        let (subscriber, rx) = mock_client("mock-client-1");
+        let (publisher, ..) = mock_client("mock-client-2");
+        broker.add_client(subscriber.clone());
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "hello/mqtt".to_owned(),
+                                           qos: QoS::AtLeastOnce,
+                                       },
+                                       subscriber.clone());
+        broker.handle_publish(qos1_publish(1, 1), &publisher);
+        subscriber.store_rel(PacketIdentifier(9));
+
+        // nothing is due yet
+        assert!(broker.retransmit(&subscriber));
+        let backdate = |client: &Client| {
+            let mut state = client.state.borrow_mut();
+            for inflight in state.outgoing_pub.values_mut() {
+                inflight.sent_at = Instant::now() - Duration::from_secs(11);
+            }
+            for inflight in state.outgoing_rel.values_mut() {
+                inflight.sent_at = Instant::now() - Duration::from_secs(11);
+            }
+        };
+
+        backdate(&subscriber);
+        assert!(broker.retransmit(&subscriber));
+
+        // cap is reached. the packets are dropped
+        backdate(&subscriber);
+        assert!(broker.retransmit(&subscriber));
+        assert!(subscriber.state.borrow().outgoing_pub.is_empty());
+        assert!(subscriber.state.borrow().outgoing_rel.is_empty());
+        assert_eq!(broker.session_stats("mock-client-1").unwrap().lost, 2);
+
+        drop(subscriber);
+        drop(broker);
+        let frames = drain(rx);
+        assert_eq!(frames.len(), 3);
+        match frames[1] {
+            Frame::Packet(Packet::Publish(ref publish)) => {
+                assert!(publish.dup);
+                assert_eq!(publish.pid, Some(PacketIdentifier(1)));
+            }
+            ref frame => panic!("Expected a publish. Got {:?}", frame),
+        }
+        assert_eq!(frames[2], Frame::Packet(Packet::Pubrel(PacketIdentifier(9))));
+    }
+
+    #[test]
+    fn full_inflight_window_holds_back_messages() {
+        let mut config = BrokerConfig::default();
+        config.max_inflight = Some(2);
+        let broker = Broker::with_config(config);
+
+        let (subscriber, rx) = mock_client("mock-client-1");
+        let (publisher, _prx) = mock_client("mock-client-2");
+        broker.add_client(subscriber.clone());
+        broker.add_client(publisher.clone());
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "hello/mqtt".to_owned(),
+                                           qos: QoS::AtLeastOnce,
+                                       },
+                                       subscriber.clone());
+
+        for i in 0..4 {
+            broker.handle_publish(qos1_publish(i + 1, i as u8), &publisher);
+        }
+        assert_eq!(subscriber.inflight(), 2);
+        assert_eq!(subscriber.state.borrow().pending.len(), 2);
+
+        // every ack lets one more through, in order
+        broker.handle_puback(PacketIdentifier(1), &subscriber);
+        assert_eq!(subscriber.inflight(), 2);
+        assert_eq!(subscriber.state.borrow().pending.len(), 1);
+
+        drop(subscriber);
+        drop(broker);
+        let payloads: Vec<u8> = drain(rx)
+            .into_iter()
+            .map(|frame| match frame {
+                     Frame::Packet(Packet::Publish(publish)) => publish.payload[0],
+                     frame => panic!("Expected a publish. Got {:?}", frame),
+                 })
+            .collect();
+        assert_eq!(payloads, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn too_many_unreleased_qos2_publishes_disconnect() {
+        let mut config = BrokerConfig::default();
+        config.max_inflight = Some(1);
+        let broker = Broker::with_config(config);
+
+        let (publisher, _rx) = mock_client("mock-client-1");
+        broker.add_client(publisher.clone());
+
+        let qos2_publish = |pkid| {
+            let mut publish = qos1_publish(pkid, 1);
+            publish.qos = QoS::ExactlyOnce;
+            publish
+        };
+
+        broker.handle_publish(qos2_publish(1), &publisher);
+        assert!(broker.is_current(&publisher));
+        broker.handle_publish(qos2_publish(2), &publisher);
+        assert!(!broker.is_current(&publisher));
+    }
+
+    #[test]
+    fn persistent_sessions_get_messages_sent_while_offline() {
+        let broker = Broker::new();
+        let (publisher, ..) = mock_client("mock-client-2");
+
+        let (c1, _rx1) = mock_client("mock-client-1");
+        c1.set_clean_session(false);
+        broker.connect(c1.clone());
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "hello/mqtt".to_owned(),
+                                           qos: QoS::AtLeastOnce,
+                                       },
+                                       c1.clone());
+        broker.handle_network_disconnect(&c1);
+
+        let mut qos0 = qos1_publish(1, 0);
+        qos0.qos = QoS::AtMostOnce;
+        qos0.pid = None;
+        broker.handle_publish(qos0, &publisher);
+        broker.handle_publish(qos1_publish(1, 1), &publisher);
+
+        let (c2, rx2) = mock_client("mock-client-1");
+        c2.set_clean_session(false);
+        broker.connect(c2.clone());
+
+        let (frame, rx2) = next_frame(rx2);
+        match frame {
+            Frame::Packet(Packet::Connack(connack)) => assert!(connack.session_present),
+            frame => panic!("Expected a connack. Got {:?}", frame),
+        }
+
+        // only the qos 1 message was kept
+        let (frame, _rx2) = next_frame(rx2);
+        match frame {
+            Frame::Packet(Packet::Publish(publish)) => {
+                assert_eq!(publish.qos, QoS::AtLeastOnce);
+                assert_eq!(publish.payload[0], 1);
+            }
+            frame => panic!("Expected a publish. Got {:?}", frame),
+        }
+
+        // subscriptions are back
+        assert_eq!(broker.get_subscribers("hello/mqtt").len(), 1);
+
+        let stats = broker.session_stats("mock-client-1").unwrap();
+        assert_eq!(stats.queued_offline, 1);
+        assert_eq!(stats.delivered_after_resume, 1);
+    }
+
+    #[test]
+    fn stale_offline_messages_expire() {
+        let clock = ManualClock::new();
+        let mut config = BrokerConfig::default();
+        config.offline_message_ttl = Some(Duration::from_secs(60));
+        let broker = Broker::with_clock(config, Rc::new(clock.clone()));
+        let (publisher, ..) = mock_client("mock-client-2");
+
+        let (c1, _rx1) = mock_client("mock-client-1");
+        c1.set_clean_session(false);
+        broker.connect(c1.clone());
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "hello/mqtt".to_owned(),
+                                           qos: QoS::AtLeastOnce,
+                                       },
+                                       c1.clone());
+        broker.handle_network_disconnect(&c1);
+
+        broker.handle_publish(qos1_publish(1, 1), &publisher);
+        clock.advance(Duration::from_secs(45));
+        broker.handle_publish(qos1_publish(2, 2), &publisher);
+        clock.advance(Duration::from_secs(30));
+
+        let (c2, rx2) = mock_client("mock-client-1");
+        c2.set_clean_session(false);
+        broker.connect(c2.clone());
+        let (_, rx2) = next_frame(rx2);
+        match next_frame(rx2).0 {
+            Frame::Packet(Packet::Publish(publish)) => assert_eq!(publish.payload[0], 2),
+            frame => panic!("Expected a publish. Got {:?}", frame),
+        }
+
+        assert_eq!(broker.session_stats("mock-client-1").unwrap().expired_offline, 1);
+        #[cfg(feature = "metrics")]
+        assert_eq!(broker.metrics().expired_messages, 1);
+    }
+
+    #[test]
+    fn persistent_sessions_expire_while_their_clients_are_away() {
+        let clock = ManualClock::new();
+        let mut config = BrokerConfig::default();
+        config.session_expiry = Some(Duration::from_secs(60));
+        let broker = Broker::with_clock(config, Rc::new(clock.clone()));
+
+        let (c1, _rx1) = mock_client("mock-client-1");
+        c1.set_clean_session(false);
+        broker.connect(c1.clone());
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "hello/mqtt".to_owned(),
+                                           qos: QoS::AtLeastOnce,
+                                       },
+                                       c1.clone());
+        broker.handle_network_disconnect(&c1);
+
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(broker.expire_sessions(), 0);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(broker.expire_sessions(), 1);
+
+        // comes back to a fresh session
+        let (c2, _rx2) = mock_client("mock-client-1");
+        c2.set_clean_session(false);
+        broker.connect(c2.clone());
+        assert!(broker.client_subscriptions("mock-client-1").is_empty());
+    }
+
+    #[test]
+    fn live_first_drain_keeps_the_order_per_topic() {
+        let mut config = BrokerConfig::default();
+        config.max_inflight = Some(1);
+        config.backlog_drain = DrainPolicy::LiveFirst;
+        let broker = Broker::with_config(config);
+        let (publisher, ..) = mock_client("mock-client-2");
+
+        let (c1, _rx1) = mock_client("mock-client-1");
+        c1.set_clean_session(false);
+        broker.connect(c1.clone());
+        for topic in vec!["hello/mqtt", "live"] {
+            broker.add_subscription_client(SubscribeTopic {
+                                               topic_path: topic.to_owned(),
+                                               qos: QoS::AtLeastOnce,
+                                           },
+                                           c1.clone());
+        }
+        broker.handle_network_disconnect(&c1);
+        for payload in 1..4 {
+            broker.handle_publish(qos1_publish(payload as u16, payload), &publisher);
+        }
+
+        let (c2, rx2) = mock_client("mock-client-1");
+        c2.set_clean_session(false);
+        broker.connect(c2.clone());
+        let mut live = qos1_publish(4, 8);
+        live.topic_name = "live".to_owned();
+        broker.handle_publish(live, &publisher);
+        broker.handle_publish(qos1_publish(5, 9), &publisher);
+
+        let (_connack, mut rx2) = next_frame(rx2);
+        let mut payloads = Vec::new();
+        for _ in 0..5 {
+            let (frame, rx) = next_frame(rx2);
+            rx2 = rx;
+            match frame {
+                Frame::Packet(Packet::Publish(publish)) => {
+                    payloads.push(publish.payload[0]);
+                    broker.handle_puback(publish.pid.unwrap(), &c2);
+                }
+                frame => panic!("Expected a publish. Got {:?}", frame),
+            }
+        }
+
+        // the live message on hello/mqtt waits for the backlog on its topic
+        assert_eq!(payloads, vec![1, 8, 2, 3, 9]);
+    }
+
+    #[test]
+    fn shutdown_parks_unacknowledged_messages() {
+        let broker = Broker::new();
+        let (publisher, _publisher_rx) = mock_client("mock-client-2");
+        broker.add_client(publisher.clone());
+
+        let (c1, _rx1) = mock_client("mock-client-1");
+        c1.set_clean_session(false);
+        broker.add_client(c1.clone());
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "hello/mqtt".to_owned(),
+                                           qos: QoS::AtLeastOnce,
+                                       },
+                                       c1.clone());
+        broker.handle_publish(qos1_publish(1, 1), &publisher);
+        assert_eq!(broker.open_flows(), 1);
+
+        assert_eq!(broker.shutdown(), 2);
+        assert_eq!(broker.open_flows(), 0);
+        assert!(broker.get_client("mock-client-1").is_none());
+
+        let (c2, rx2) = mock_client("mock-client-1");
+        c2.set_clean_session(false);
+        broker.connect(c2);
+        let (_connack, rx2) = next_frame(rx2);
+        let (frame, _rx2) = next_frame(rx2);
+        match frame {
+            Frame::Packet(Packet::Publish(publish)) => assert_eq!(publish.payload[0], 1),
+            frame => panic!("Expected a publish. Got {:?}", frame),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "persistence")]
+    fn wal_topics_survive_a_restart() {
+        let path = ::std::env::temp_dir().join(format!("rumqttd-broker-wal-{}.log", ::std::process::id()));
+        let _ = ::std::fs::remove_file(&path);
+
+        let mut config = BrokerConfig::default();
+        config.storage = vec![StorageRule {
+                                  prefix: "commands".to_owned(),
+                                  policy: StoragePolicy::Wal,
+                              }];
+
+        {
+            let broker = Broker::with_config(config.clone());
+            broker.open_wal(&path).unwrap();
+            let (publisher, ..) = mock_client("mock-client-2");
+
+            let (c1, _rx1) = mock_client("mock-client-1");
+            c1.set_clean_session(false);
+            broker.connect(c1.clone());
+            for topic in ["commands/7", "telemetry/7"].iter() {
+                broker.add_subscription_client(SubscribeTopic {
+                                                   topic_path: topic.to_string(),
+                                                   qos: QoS::AtLeastOnce,
+                                               },
+                                               c1.clone());
+            }
+            broker.handle_network_disconnect(&c1);
+
+            let mut command = qos1_publish(1, 1);
+            command.topic_name = "commands/7".to_owned();
+            broker.handle_publish(command, &publisher);
+            let mut telemetry = qos1_publish(2, 2);
+            telemetry.topic_name = "telemetry/7".to_owned();
+            broker.handle_publish(telemetry, &publisher);
+        }
+
+        let broker = Broker::with_config(config);
+        assert_eq!(broker.open_wal(&path).unwrap(), 1);
+
+        let (c2, rx2) = mock_client("mock-client-1");
+        c2.set_clean_session(false);
+        broker.connect(c2.clone());
+
+        let (frame, rx2) = next_frame(rx2);
+        match frame {
+            Frame::Packet(Packet::Connack(connack)) => assert!(connack.session_present),
+            frame => panic!("Expected a connack. Got {:?}", frame),
+        }
+
+        // only the command was logged. telemetry was memory only
+        let (frame, _rx2) = next_frame(rx2);
+        match frame {
+            Frame::Packet(Packet::Publish(publish)) => {
+                assert_eq!(publish.topic_name, "commands/7");
+                assert_eq!(publish.payload[0], 1);
+            }
+            frame => panic!("Expected a publish. Got {:?}", frame),
+        }
+        assert_eq!(broker.sizes().offline_sessions, 0);
+
+        // the resumed session is gone from the log as well
+        drop(broker);
+        let broker = Broker::new();
+        assert_eq!(broker.open_wal(&path).unwrap(), 0);
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "persistence")]
+    fn checkpoints_keep_connected_sessions_across_a_crash() {
+        let path = ::std::env::temp_dir().join(format!("rumqttd-broker-checkpoint-{}.log", ::std::process::id()));
+        let _ = ::std::fs::remove_file(&path);
+
+        let mut config = BrokerConfig::default();
+        config.storage = vec![StorageRule {
+                                  prefix: "commands".to_owned(),
+                                  policy: StoragePolicy::Wal,
+                              }];
+        config.session_checkpoint = Some(Duration::from_secs(1));
+
+        {
+            let broker = Broker::with_config(config.clone());
+            broker.open_wal(&path).unwrap();
+            let (publisher, ..) = mock_client("mock-client-2");
+
+            let (c1, _rx1) = mock_client("mock-client-1");
+            c1.set_clean_session(false);
+            broker.connect(c1.clone());
+            broker.add_subscription_client(SubscribeTopic {
+                                               topic_path: "commands/7".to_owned(),
+                                               qos: QoS::AtLeastOnce,
+                                           },
+                                           c1.clone());
+
+            // delivered but never acknowledged
+            let mut command = qos1_publish(1, 1);
+            command.topic_name = "commands/7".to_owned();
+            broker.handle_publish(command, &publisher);
+            assert_eq!(broker.checkpoint_sessions(), 1);
+            // dropped without a shutdown, as in a crash
+        }
+
+        let broker = Broker::with_config(config);
+        assert_eq!(broker.open_wal(&path).unwrap(), 1);
+
+        let (c2, rx2) = mock_client("mock-client-1");
+        c2.set_clean_session(false);
+        broker.connect(c2.clone());
+        let (_, rx2) = next_frame(rx2);
+        match next_frame(rx2).0 {
+            Frame::Packet(Packet::Publish(publish)) => {
+                assert_eq!(publish.topic_name, "commands/7");
+                assert_eq!(publish.payload[0], 1);
+            }
+            frame => panic!("Expected a publish. Got {:?}", frame),
+        }
+        assert_eq!(broker.client_subscriptions("mock-client-1").len(), 1);
+
+        drop(broker);
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "persistence")]
+    fn connects_wait_for_a_wal_loading_in_the_background() {
+        let path = ::std::env::temp_dir().join(format!("rumqttd-broker-wal-load-{}.log", ::std::process::id()));
+        let _ = ::std::fs::remove_file(&path);
+        {
+            let broker = Broker::new();
+            broker.open_wal(&path).unwrap();
+            let (client, _rx) = mock_client("mock-client-1");
+            client.set_clean_session(false);
+            broker.connect(client.clone());
+            broker.handle_network_disconnect(&client);
+        }
+
+        let broker = Broker::new();
+        broker.load_wal(path.clone());
+        assert_eq!(broker.sizes().offline_sessions, 0);
+
+        let (client, rx) = mock_client("mock-client-1");
+        client.set_clean_session(false);
+        broker.connect(client);
+        assert!(!broker.wal_loading());
+        match next_frame(rx).0 {
+            Frame::Packet(Packet::Connack(connack)) => assert!(connack.session_present),
+            frame => panic!("Expected a connack. Got {:?}", frame),
+        }
+
+        drop(broker);
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn clean_sessions_discard_the_stored_session() {
+        let broker = Broker::new();
+
+        let (c1, _rx1) = mock_client("mock-client-1");
+        c1.set_clean_session(false);
+        broker.connect(c1.clone());
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "hello/mqtt".to_owned(),
+                                           qos: QoS::AtLeastOnce,
+                                       },
+                                       c1.clone());
+        broker.handle_network_disconnect(&c1);
+
+        let (c2, rx2) = mock_client("mock-client-1");
+        broker.connect(c2.clone());
+
+        let (frame, _rx2) = next_frame(rx2);
+        match frame {
+            Frame::Packet(Packet::Connack(connack)) => assert!(!connack.session_present),
+            frame => panic!("Expected a connack. Got {:?}", frame),
+        }
+        assert!(broker.get_subscribers("hello/mqtt").is_empty());
+    }
+
+    #[test]
+    fn session_stats_survive_reconnects() {
+        let broker = Broker::new();
+        let (publisher, ..) = mock_client("mock-client-2");
+
+        let (c1, _rx1) = mock_client("mock-client-1");
+        broker.add_client(c1.clone());
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "hello/mqtt".to_owned(),
+                                           qos: QoS::AtLeastOnce,
+                                       },
+                                       c1.clone());
+
+        // delivered but never acknowledged
+        broker.handle_publish(qos1_publish(1, 1), &publisher);
+        broker.handle_network_disconnect(&c1);
+
+        let (c2, _rx2) = mock_client("mock-client-1");
+        broker.add_client(c2.clone());
+        let (c3, _rx3) = mock_client("mock-client-1");
+        broker.add_client(c3.clone());
+
+        let stats = broker.session_stats("mock-client-1").unwrap();
+        assert_eq!(stats.connects, 3);
+        assert_eq!(stats.takeovers, 1);
+        assert_eq!(stats.delivered, 1);
+        assert_eq!(stats.lost, 1);
+        assert_eq!(broker.sessions()[0].0, "mock-client-1");
+    }
+
+    #[test]
+    fn qos0_publishes_are_not_upgraded() {
+        let (subscriber, rx) = mock_client("mock-client-1");
+        let (publisher, ..) = mock_client("mock-client-2");
+
+        let broker = Broker::new();
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "hello/mqtt".to_owned(),
+                                           qos: QoS::ExactlyOnce,
+                                       },
+                                       subscriber.clone());
+
+        let publish = Box::new(Publish {
+                                   dup: false,
+                                   qos: QoS::AtMostOnce,
+                                   retain: false,
+                                   pid: None,
+                                   topic_name: "hello/mqtt".to_owned(),
+                                   payload: Arc::new(vec![1, 2, 3]),
+                               });
+        broker.handle_publish(publish, &publisher);
+
+        // delivered as a shared qos 0 encoding with nothing left to acknowledge
+        let (frame, _rx) = next_frame(rx);
+        match frame {
+            Frame::Encoded(..) => (),
+            frame => panic!("Expected a qos 0 publish. Got {:?}", frame),
+        }
+        assert!(subscriber.state.borrow().outgoing_rec.is_empty());
+    }
+
+    #[test]
+    fn systematic_downgrades_are_reported() {
+        let mut config = BrokerConfig::default();
+        config.downgrade_report = Some(Duration::from_secs(60));
+        let broker = Broker::with_config(config);
+
+        let (tx, _rx) = mpsc::channel::<Frame>(32);
+        let subscriber = Client::new("backend", "127.0.0.1:80".parse().unwrap(), tx);
+        let (publisher, _publisher_rx) = mock_client("sensor");
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "sensors/1".to_owned(),
+                                           qos: QoS::AtMostOnce,
+                                       },
+                                       subscriber);
+
+        for pkid in 1..(downgrade::MIN_FORWARDED as u16 + 1) {
+            let publish = Box::new(Publish {
+                                       dup: false,
+                                       qos: QoS::AtLeastOnce,
+                                       retain: false,
+                                       pid: Some(PacketIdentifier(pkid)),
+                                       topic_name: "sensors/1".to_owned(),
+                                       payload: Arc::new(vec![1, 2, 3]),
+                                   });
+            broker.handle_publish(publish, &publisher);
+        }
+
+        let (mismatches, total) = broker.downgrades();
+        assert_eq!(total, downgrade::MIN_FORWARDED);
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].topic, "sensors/1");
+        assert_eq!(mismatches[0].subscriber, "backend");
+        assert_eq!(mismatches[0].stats.published, QoS::AtLeastOnce);
+        assert_eq!(mismatches[0].stats.granted, QoS::AtMostOnce);
+
+        // not tracked by default
+        assert_eq!(Broker::new().downgrades(), (vec![], 0));
+    }
+
+    #[test]
+    fn debounced_topics_deliver_the_latest_publish_per_interval() {
+        let clock = ManualClock::new();
+        let mut config = BrokerConfig::default();
+        config.debounce = vec![DebounceRule {
+                                   prefix: "hello".to_owned(),
+                                   interval: Duration::from_secs(1),
+                               }];
+        let broker = Broker::with_clock(config, Rc::new(clock.clone()));
+
+        let (subscriber, rx) = mock_client("dashboard");
+        let (publisher, _publisher_rx) = mock_client("sensor");
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "hello/mqtt".to_owned(),
+                                           qos: QoS::AtLeastOnce,
+                                       },
+                                       subscriber.clone());
+
+        for payload in 1..4 {
+            broker.handle_publish(qos1_publish(payload as u16, payload), &publisher);
+        }
+        broker.flush_debounced();
+        clock.advance(Duration::from_secs(1));
+        broker.flush_debounced();
+
+        let (first, rx) = next_frame(rx);
+        let (second, _rx) = next_frame(rx);
+        for (frame, payload) in vec![(first, 1), (second, 3)] {
+            match frame {
+                Frame::Packet(Packet::Publish(publish)) => assert_eq!(*publish.payload, vec![payload]),
+                frame => panic!("Expected a publish. Got {:?}", frame),
+            }
+        }
+        assert_eq!(broker.conflated(), vec![("hello/mqtt".to_owned(), 1)]);
+    }
+
+    #[test]
+    fn batch_subscriptions_get_one_publish_per_flush() {
+        let mut config = BrokerConfig::default();
+        config.batch = Some(BatchConfig {
+                                interval: Duration::from_secs(1),
+                                max_messages: 10,
+                            });
+        let broker = Broker::with_config(config);
+
+        let (consumer, rx) = mock_client("analytics");
+        let (publisher, _publisher_rx) = mock_client("sensor");
+        let subscribe = Box::new(Subscribe {
+                                     pid: PacketIdentifier(1),
+                                     topics: vec![SubscribeTopic {
+                                                      topic_path: "$batch/hello/mqtt".to_owned(),
+                                                      qos: QoS::AtLeastOnce,
+                                                  }],
+                                 });
+        broker.handle_subscribe(subscribe, &consumer);
+        let (_suback, rx) = next_frame(rx);
+
+        broker.handle_publish(qos1_publish(1, 1), &publisher);
+        broker.handle_publish(qos1_publish(2, 2), &publisher);
+        assert!(broker.get_subscribers("hello/mqtt").is_empty());
+
+        broker.flush_batches();
+        let (frame, _rx) = next_frame(rx);
+        match frame {
+            Frame::Packet(Packet::Publish(publish)) => {
+                assert_eq!(publish.topic_name, "$batch/hello/mqtt");
+                assert_eq!(publish.qos, QoS::AtLeastOnce);
+                assert_eq!(batch::decode(&publish.payload), Some(vec![vec![1], vec![2]]));
+            }
+            frame => panic!("Expected a batch. Got {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn strict_mode_only_allows_declared_topics() {
+        let broker = Broker::new();
+        broker.enable_topic_registry();
+        assert!(broker.declare_topic(None, "hello/*"));
+
+        let (client, rx) = mock_client("mock-client-1");
+        let subscribe = Box::new(Subscribe {
+                                     pid: PacketIdentifier(1),
+                                     topics: vec![SubscribeTopic {
+                                                      topic_path: "hello/mqtt".to_owned(),
+                                                      qos: QoS::AtLeastOnce,
+                                                  },
+                                                  SubscribeTopic {
+                                                      topic_path: "helo/mqtt".to_owned(),
+                                                      qos: QoS::AtLeastOnce,
+                                                  }],
+                                 });
+        broker.handle_subscribe(subscribe, &client);
+
+        let (frame, _rx) = next_frame(rx);
+        match frame {
+            Frame::Packet(Packet::Suback(suback)) => {
+                assert_eq!(suback.return_codes,
+                           vec![SubscribeReturnCodes::Success(QoS::AtLeastOnce), SubscribeReturnCodes::Failure]);
+            }
+            frame => panic!("Expected a suback. Got {:?}", frame),
+        }
+
+        let (publisher, _publisher_rx) = mock_client("mock-client-2");
+        let mut typo = qos1_publish(1, 1);
+        typo.topic_name = "helo/mqtt".to_owned();
+        broker.handle_publish(typo, &publisher);
+        broker.handle_publish(qos1_publish(2, 2), &publisher);
+
+        let stats = broker.session_stats("mock-client-2").unwrap();
+        assert_eq!(stats.rejected, 1);
+        assert_eq!(stats.published, 1);
+    }
+
+    #[test]
+    fn acl_decides_subscriptions_and_publishes() {
+        let mut config = BrokerConfig::default();
+        config.acl = Some(Acl::new(Permission::Deny)
+                              .allow(AclSubject::Everyone, Access::Read, "hello/#")
+                              .allow(AclSubject::ClientId("mock-client-2".to_owned()), Access::Write, "hello/mqtt"));
+        let broker = Broker::with_config(config);
+
+        let (client, rx) = mock_client("mock-client-1");
+        let subscribe = Box::new(Subscribe {
+                                     pid: PacketIdentifier(1),
+                                     topics: vec![SubscribeTopic {
+                                                      topic_path: "hello/mqtt".to_owned(),
+                                                      qos: QoS::AtLeastOnce,
+                                                  },
+                                                  SubscribeTopic {
+                                                      topic_path: "secret/mqtt".to_owned(),
+                                                      qos: QoS::AtLeastOnce,
+                                                  }],
+                                 });
+        broker.handle_subscribe(subscribe, &client);
+
+        let (frame, _rx) = next_frame(rx);
+        match frame {
+            Frame::Packet(Packet::Suback(suback)) => {
+                assert_eq!(suback.return_codes,
+                           vec![SubscribeReturnCodes::Success(QoS::AtLeastOnce), SubscribeReturnCodes::Failure]);
+            }
+            frame => panic!("Expected a suback. Got {:?}", frame),
+        }
+
+        // dropped but still acknowledged
+        let (publisher, publisher_rx) = mock_client("mock-client-2");
+        broker.add_client(publisher.clone());
+        let mut denied = qos1_publish(1, 1);
+        denied.topic_name = "hello/world".to_owned();
+        broker.handle_publish(denied, &publisher);
+        broker.handle_publish(qos1_publish(2, 2), &publisher);
+
+        let (frame, _rx) = next_frame(publisher_rx);
+        assert_eq!(frame, Frame::Packet(Packet::Puback(PacketIdentifier(1))));
+        let stats = broker.session_stats("mock-client-2").unwrap();
+        assert_eq!(stats.rejected, 1);
+        assert_eq!(stats.published, 1);
+
+        let mut config = BrokerConfig::default();
+        config.acl = Some(Acl::new(Permission::Deny));
+        config.unauthorized_publish = UnauthorizedPublish::Disconnect;
+        let broker = Broker::with_config(config);
+        broker.add_client(publisher.clone());
+        broker.handle_publish(qos1_publish(3, 3), &publisher);
+        assert!(!broker.is_current(&publisher));
+    }
+
+    #[test]
+    fn anonymous_clients_get_the_acl_rules_of_their_role() {
+        let mut config = BrokerConfig::default();
+        config.acl = Some(Acl::new(Permission::Deny).allow(AclSubject::Username("guest".to_owned()), Access::Read, "hello/mqtt"));
+        config.anonymous_role = Some("guest".to_owned());
+        let broker = Broker::with_config(config);
+
+        let (client, rx) = mock_client("mock-client-1");
+        let subscribe = Box::new(Subscribe {
+                                     pid: PacketIdentifier(1),
+                                     topics: vec![SubscribeTopic {
+                                                      topic_path: "hello/mqtt".to_owned(),
+                                                      qos: QoS::AtLeastOnce,
+                                                  }],
+                                 });
+        broker.handle_subscribe(subscribe.clone(), &client);
+        // users with names of their own don't get the role's rules
+        let (user, user_rx) = mock_client("mock-client-2");
+        user.set_username(Some("device".to_owned()));
+        broker.handle_subscribe(subscribe, &user);
+
+        for (rx, code) in vec![(rx, SubscribeReturnCodes::Success(QoS::AtLeastOnce)), (user_rx, SubscribeReturnCodes::Failure)] {
+            match next_frame(rx).0 {
+                Frame::Packet(Packet::Suback(suback)) => assert_eq!(suback.return_codes, vec![code]),
+                frame => panic!("Expected a suback. Got {:?}", frame),
+            }
+        }
+    }
+
+    #[test]
+    fn observers_deliver_what_the_primary_mirrors() {
+        let primary = Broker::new();
+        let (mirror, mirror_rx) = mock_client("observer-1");
+        primary.add_client(mirror.clone());
+        let subscribe = Box::new(Subscribe {
+                                     pid: PacketIdentifier(1),
+                                     topics: vec![SubscribeTopic {
+                                                      topic_path: "$mirror".to_owned(),
+                                                      qos: QoS::AtLeastOnce,
+                                                  }],
+                                 });
+        primary.handle_subscribe(subscribe, &mirror);
+        let (publisher, _publisher_rx) = mock_client("mock-client-1");
+        primary.add_client(publisher.clone());
+        primary.handle_publish(qos1_publish(1, 1), &publisher);
+
+        let (_, mirror_rx) = next_frame(mirror_rx);
+        let (frame, _mirror_rx) = next_frame(mirror_rx);
+        let mirrored = match frame {
+            Frame::Packet(Packet::Publish(publish)) => publish,
+            frame => panic!("Expected a publish. Got {:?}", frame),
+        };
+        assert_eq!(mirrored.topic_name, "$mirror/mock-client-1/hello/mqtt");
+
+        let mut config = BrokerConfig::default();
+        config.observer = Some(ObserverConfig {
+                                   primary: "127.0.0.1:1883".parse().unwrap(),
+                                   node_id: "observer-1".to_owned(),
+                               });
+        let observer = Broker::with_config(config);
+        let (link, link_rx) = mock_client(PRIMARY_CLIENT_ID);
+        observer.add_client(link.clone());
+        let (subscriber, rx) = mock_client("mock-client-2");
+        observer.add_client(subscriber.clone());
+        let subscribe = Box::new(Subscribe {
+                                     pid: PacketIdentifier(1),
+                                     topics: vec![SubscribeTopic {
+                                                      topic_path: "hello/mqtt".to_owned(),
+                                                      qos: QoS::AtLeastOnce,
+                                                  }],
+                                 });
+        observer.handle_subscribe(subscribe, &subscriber);
+
+        let pkid = mirrored.pid.unwrap();
+        observer.handle_mirrored(mirrored, &link);
+        let (frame, _link_rx) = next_frame(link_rx);
+        assert_eq!(frame, Frame::Packet(Packet::Puback(pkid)));
+        let (_, rx) = next_frame(rx);
+        match next_frame(rx).0 {
+            Frame::Packet(Packet::Publish(publish)) => assert_eq!(publish.topic_name, "hello/mqtt"),
+            frame => panic!("Expected a publish. Got {:?}", frame),
+        }
+
+        // local clients are read only
+        observer.handle_publish(qos1_publish(1, 1), &subscriber);
+        assert!(!observer.is_current(&subscriber));
+    }
+
+    #[test]
+    fn topic_prefixes_confine_clients() {
+        let broker = Broker::new();
+        let (client, rx) = mock_client("mock-client-1");
+        client.set_topic_prefixes(Some(vec!["hello/".to_owned()]));
+        broker.add_client(client.clone());
+
+        let subscribe = Box::new(Subscribe {
+                                     pid: PacketIdentifier(1),
+                                     topics: vec![SubscribeTopic {
+                                                      topic_path: "hello/#".to_owned(),
+                                                      qos: QoS::AtLeastOnce,
+                                                  },
+                                                  SubscribeTopic {
+                                                      topic_path: "#".to_owned(),
+                                                      qos: QoS::AtLeastOnce,
+                                                  }],
+                                 });
+        broker.handle_subscribe(subscribe, &client);
+
+        let (frame, _rx) = next_frame(rx);
+        match frame {
+            Frame::Packet(Packet::Suback(suback)) => {
+                assert_eq!(suback.return_codes,
+                           vec![SubscribeReturnCodes::Success(QoS::AtLeastOnce), SubscribeReturnCodes::Failure]);
+            }
+            frame => panic!("Expected a suback. Got {:?}", frame),
+        }
+
+        let mut outside = qos1_publish(1, 1);
+        outside.topic_name = "world/mqtt".to_owned();
+        broker.handle_publish(outside, &client);
+        broker.handle_publish(qos1_publish(2, 2), &client);
+        let stats = broker.session_stats("mock-client-1").unwrap();
+        assert_eq!(stats.rejected, 1);
+        assert_eq!(stats.published, 1);
+    }
+
+    #[test]
+    fn grouped_publishes_are_delivered_on_commit() {
+        let mut config = BrokerConfig::default();
+        config.transactions = Some(10);
+        let broker = Broker::with_config(config);
+
+        let (subscriber, rx) = mock_client("mock-client-1");
+        for topic in ["state/a", "state/b"].iter() {
+            broker.add_subscription_client(SubscribeTopic {
+                                               topic_path: topic.to_string(),
+                                               qos: QoS::AtLeastOnce,
+                                           },
+                                           subscriber.clone());
+        }
+
+        let (publisher, _publisher_rx) = mock_client("mock-client-2");
+        broker.add_client(publisher.clone());
+        for &(topic, payload) in [("$txn/g1/state/b", 1), ("$txn/g1/state/a", 2), ("$txn/g2/state/a", 3)].iter() {
+            let mut publish = qos1_publish(u16::from(payload), payload);
+            publish.topic_name = topic.to_owned();
+            broker.handle_publish(publish, &publisher);
+        }
+
+        let mut commit = qos1_publish(4, 0);
+        commit.topic_name = "$txn/g1/$commit".to_owned();
+        broker.handle_publish(commit, &publisher);
+        // g2 is never committed
+        broker.remove_client(&publisher.id);
+        broker.remove_client(&subscriber.id);
+        drop(publisher);
+        drop(subscriber);
+
+        let delivered: Vec<(String, Vec<u8>)> = drain(rx)
+            .into_iter()
+            .map(|frame| match frame {
+                     Frame::Packet(Packet::Publish(publish)) => (publish.topic_name, publish.payload.to_vec()),
+                     frame => panic!("Expected a publish. Got {:?}", frame),
+                 })
+            .collect();
+        assert_eq!(delivered, vec![("state/b".to_owned(), vec![1]), ("state/a".to_owned(), vec![2])]);
+    }
+
+    #[test]
+    fn groups_with_a_refused_part_are_dropped_whole() {
+        let mut config = BrokerConfig::default();
+        config.transactions = Some(10);
+        config.acl = Some(Acl::new(Permission::Allow).deny(AclSubject::Everyone, Access::Write, "state/b"));
+        let broker = Broker::with_config(config);
+
+        let (subscriber, rx) = mock_client("mock-client-1");
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "state/#".to_owned(),
+                                           qos: QoS::AtLeastOnce,
+                                       },
+                                       subscriber.clone());
+
+        let (publisher, _publisher_rx) = mock_client("mock-client-2");
+        broker.add_client(publisher.clone());
+        for &(topic, payload) in [("$txn/g1/state/a", 1), ("$txn/g1/state/b", 2), ("$txn/g1/$commit", 0)].iter() {
+            let mut publish = qos1_publish(u16::from(payload) + 1, payload);
+            publish.topic_name = topic.to_owned();
+            broker.handle_publish(publish, &publisher);
+        }
+
+        let stats = broker.session_stats("mock-client-2").unwrap();
+        assert_eq!(stats.rejected, 1);
+        assert_eq!(stats.published, 0);
+
+        broker.remove_client(&publisher.id);
+        broker.remove_client(&subscriber.id);
+        drop(publisher);
+        drop(subscriber);
+        assert!(drain(rx).is_empty());
+    }
+
+    #[test]
+    fn runtime_changes_show_in_the_config_diff() {
+        let broker = Broker::new();
+        assert!(broker.config_diff().is_empty());
+        assert_eq!(broker.effective_config(), broker.config().settings());
+
+        broker.enable_catalog();
+        broker.set_acl(Some(Acl::new(Permission::Allow).deny(AclSubject::Everyone, Access::Write, "$SYS/#")));
+        broker.pause("sensors", PausePolicy::Queue(10));
+
+        let diff = broker.config_diff();
+        let names: Vec<&str> = diff.iter().map(|c| c.name).collect();
+        assert_eq!(names, vec!["catalog", "acl", "paused"]);
+        assert_eq!(diff[0].configured, Some("false".to_owned()));
+        assert_eq!(diff[0].effective, Some("true".to_owned()));
+        assert_eq!(diff[1].effective, Some("1 rules, default Allow".to_owned()));
+        assert_eq!(diff[2].configured, None);
+        assert_eq!(diff[2].effective, Some("sensors".to_owned()));
+
+        broker.set_acl(None);
+        broker.resume("sensors");
+        assert_eq!(broker.config_diff().len(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "admin")]
+    fn state_dumps_cover_clients_and_subscriptions() {
+        let dir = ::std::env::temp_dir().join(format!("rumqttd-broker-dump-{}", ::std::process::id()));
+        ::std::fs::create_dir_all(&dir).unwrap();
+        let mut config = BrokerConfig::default();
+        config.dump_dir = Some(dir.clone());
+        let broker = Broker::with_config(config);
+
+        let (client, _rx) = mock_client("mock-client-1");
+        broker.add_client(client.clone());
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "hello/mqtt".to_owned(),
+                                           qos: QoS::AtLeastOnce,
+                                       },
+                                       client.clone());
+        broker.handle_publish(qos1_publish(1, 1), &client);
+
+        let dump = broker.state_dump();
+        assert_eq!(dump["clients"][0]["id"], "mock-client-1");
+        assert_eq!(dump["clients"][0]["inflight"], 1);
+        assert_eq!(dump["subscriptions"]["hello"]["subscribers"], 1);
+        assert_eq!(dump["top_publishers"][0][1], 1);
+
+        let path = broker.dump_state().unwrap();
+        let written = ::std::fs::read(&path).unwrap();
+        assert_eq!(&written[..2], &[0x1f, 0x8b]);
+        ::std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn diagnostics_are_retained_for_new_subscribers() {
+        let clock = ManualClock::new();
+        let broker = Broker::with_clock(BrokerConfig::default(), Rc::new(clock.clone()));
+
+        let (tx, _device_rx) = mpsc::channel::<Frame>(8);
+        let device = Client::with_clock("sensor/7", "127.0.0.1:80".parse().unwrap(), tx, Rc::new(clock.clone()));
+        device.set_keep_alive(10);
+        broker.add_client(device);
+        clock.advance(Duration::from_secs(4));
+        broker.publish_diagnostics(Duration::from_secs(1_000));
+
+        let (developer, rx) = mock_client("developer");
+        let subscribe = Box::new(Subscribe {
+                                     pid: PacketIdentifier(1),
+                                     topics: vec![SubscribeTopic {
+                                                      topic_path: "$SYS/broker/clients/sensor%2F7/keepalive".to_owned(),
+                                                      qos: QoS::AtLeastOnce,
+                                                  }],
+                                 });
+        broker.handle_subscribe(subscribe, &developer);
+
+        let (_suback, rx) = next_frame(rx);
+        let (frame, _rx) = next_frame(rx);
+        match frame {
+            Frame::Packet(Packet::Publish(publish)) => {
+                assert!(publish.retain);
+                let v: ::serde_json::Value = ::serde_json::from_slice(&publish.payload).unwrap();
+                assert_eq!(v["last_packet_age_ms"], 4000);
+                assert_eq!(v["remaining_ms"], 11000);
+            }
+            frame => panic!("Expected the retained keep alive. Got {:?}", frame),
+        }
+
+        broker.remove_client("sensor/7");
+        assert_eq!(broker.sys.borrow().len(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn connects_and_disconnects_are_announced() {
+        let mut config = BrokerConfig::default();
+        config.connection_events = true;
+        let broker = Broker::with_config(config);
+
+        let (monitor, rx) = mock_client("monitor");
+        broker.add_client(monitor.clone());
+        let subscribe = Box::new(Subscribe {
+                                     pid: PacketIdentifier(1),
+                                     topics: vec![SubscribeTopic {
+                                                      topic_path: "$SYS/broker/connection/sensor%2F7/state".to_owned(),
+                                                      qos: QoS::AtMostOnce,
+                                                  }],
+                                 });
+        broker.handle_subscribe(subscribe, &monitor);
+        let (_suback, rx) = next_frame(rx);
+
+        // live events share one encoding between the subscribers
+        let state = |frame| {
+            use tokio_io::codec::Decoder;
+            let packet = match frame {
+                Frame::Encoded(bytes) => ::codec::MqttCodec::new(bytes.len()).decode(&mut ::bytes::BytesMut::from(&bytes[..])).unwrap(),
+                frame => panic!("Expected a connection event. Got {:?}", frame),
+            };
+            match packet {
+                Some(Packet::Publish(publish)) => ::serde_json::from_slice::<::serde_json::Value>(&publish.payload).unwrap(),
+                packet => panic!("Expected a connection event. Got {:?}", packet),
+            }
+        };
+
+        let (device, _device_rx) = mock_client("sensor/7");
+        broker.connect(device.clone());
+        let (frame, rx) = next_frame(rx);
+        let v = state(frame);
+        assert_eq!(v["state"], "online");
+        assert_eq!(v["client_id"], "sensor/7");
+        assert_eq!(v["clean_session"], true);
+        assert_eq!(v["address"], "127.0.0.1:80");
+        // retained for the monitors subscribing later
+        assert!(broker.sys.borrow().get("$SYS/broker/connection/sensor%2F7/state").is_some());
+
+        broker.handle_network_disconnect(&device);
+        let (frame, _rx) = next_frame(rx);
+        let v = state(frame);
+        assert_eq!(v["state"], "offline");
+        assert_eq!(v["reason"], "connection_lost");
+        assert!(broker.sys.borrow().is_empty());
+    }
+
+    #[test]
+    fn client_stats_count_the_traffic_of_a_connection() {
+        let broker = Broker::new();
+        let (subscriber, _subscriber_rx) = mock_client("dashboard");
+        let (publisher, _publisher_rx) = mock_client("sensor");
+        broker.add_client(subscriber.clone());
+        broker.add_client(publisher.clone());
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "hello/mqtt".to_owned(),
+                                           qos: QoS::AtMostOnce,
+                                       },
+                                       subscriber);
+
+        broker.handle_publish(qos1_publish(1, 1), &publisher);
+        broker.handle_publish(qos1_publish(2, 2), &publisher);
+
+        let sent = broker.client_stats("sensor").unwrap();
+        assert_eq!((sent.messages_in, sent.messages_out), (2, 0));
+        let received = broker.client_stats("dashboard").unwrap();
+        assert_eq!((received.messages_in, received.messages_out, received.dropped), (0, 2, 0));
+        assert_eq!(received.bytes_out, sent.bytes_in);
+        assert!(broker.client_stats("nobody").is_none());
+
+        #[cfg(feature = "metrics")]
+        {
+            broker.publish_diagnostics(Duration::from_secs(1_000));
+            let stats = broker.sys.borrow().get("$SYS/broker/clients/dashboard/stats").unwrap();
+            let v: ::serde_json::Value = ::serde_json::from_slice(&stats).unwrap();
+            assert_eq!(v["messages_out"], 2);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn alerts_are_published_on_the_alert_topic() {
+        let mut config = BrokerConfig::default();
+        config.alerts = vec!["crowd connections > 1".parse().unwrap()];
+        config.alert_topic = Some("alerts/broker".to_owned());
+        let broker = Broker::with_config(config);
+        let core = Core::new().unwrap();
+
+        let (operator, rx) = mock_client("operator");
+        broker.add_client(operator.clone());
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "alerts/broker".to_owned(),
+                                           qos: QoS::AtLeastOnce,
+                                       },
+                                       operator);
+        assert!(broker.check_alerts(&core.handle()).is_empty());
+
+        let (device, _device_rx) = mock_client("device");
+        broker.add_client(device);
+        assert_eq!(broker.check_alerts(&core.handle()).len(), 1);
+        assert!(broker.check_alerts(&core.handle()).is_empty());
+
+        let (frame, _rx) = next_frame(rx);
+        match frame {
+            Frame::Packet(Packet::Publish(publish)) => {
+                assert_eq!(publish.topic_name, "alerts/broker");
+                let v: ::serde_json::Value = ::serde_json::from_slice(&publish.payload).unwrap();
+                assert_eq!(v["alert"], "crowd");
+                assert_eq!(v["state"], "firing");
+                assert_eq!(v["value"], 2.0);
+            }
+            frame => panic!("Expected an alert. Got {:?}", frame),
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "metrics", feature = "connectors"))]
+    fn slow_alert_webhooks_dont_hold_up_delivery() {
+        // takes connections but never answers
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = BrokerConfig::default();
+        config.alerts = vec!["crowd connections > 0".parse().unwrap()];
+        config.alert_topic = Some("alerts/broker".to_owned());
+        config.alert_webhook = Some(format!("http://{}/alerts", silent.local_addr().unwrap()));
+        let broker = Broker::with_config(config);
+        let mut core = Core::new().unwrap();
+
+        let (operator, rx) = mock_client("operator");
+        broker.add_client(operator.clone());
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "alerts/broker".to_owned(),
+                                           qos: QoS::AtLeastOnce,
+                                       },
+                                       operator);
+
+        let started = Instant::now();
+        assert_eq!(broker.check_alerts(&core.handle()).len(), 1);
+        let (frame, _rx) = next_frame(rx);
+        match frame {
+            Frame::Packet(Packet::Publish(publish)) => assert_eq!(publish.topic_name, "alerts/broker"),
+            frame => panic!("Expected an alert. Got {:?}", frame),
+        }
+
+        // the event loop keeps turning while the request waits for its answer
+        let tick = Timeout::new(Duration::from_millis(50), &core.handle()).unwrap();
+        core.run(tick).unwrap();
+        assert!(started.elapsed() < alert::WEBHOOK_TIMEOUT);
+    }
+
+    #[test]
+    fn reloads_keep_connected_clients() {
+        let broker = Broker::new();
+        let (client, _rx) = mock_client("mock-client-1");
+        broker.add_client(client);
+
+        let mut config = BrokerConfig::default();
+        config.max_inflight = Some(5);
+        config.log_level = ::slog::Level::Debug;
+        config.acl = Some(Acl::new(Permission::Allow).deny(AclSubject::Everyone, Access::Write, "$SYS/#"));
+        config.catalog = true;
+        let reload = broker.reload(config).unwrap();
+
+        let applied: Vec<&str> = reload.applied.iter().map(|c| c.name).collect();
+        assert_eq!(applied, vec!["log_level", "max_inflight", "acl"]);
+        assert_eq!(reload.needs_restart, vec!["catalog"]);
+        assert_eq!(broker.config().max_inflight, Some(5));
+        assert_eq!(broker.log_level().level(), ::slog::Level::Debug);
+        assert!(broker.config_diff().is_empty());
+        assert!(broker.get_client("mock-client-1").is_some());
+
+        let mut config = BrokerConfig::default();
+        config.max_inflight = Some(0);
+        assert!(broker.reload(config).is_err());
+        assert_eq!(broker.config().max_inflight, Some(5));
+    }
+
+    #[test]
+    fn suback_reflects_the_granted_qos() {
+        let mut config = BrokerConfig::default();
+        config.max_qos = QoS::AtLeastOnce;
+        let broker = Broker::with_config(config);
+
+        let (client, rx) = mock_client("mock-client-1");
+        let subscribe = Box::new(Subscribe {
+                                     pid: PacketIdentifier(1),
+                                     topics: vec![SubscribeTopic {
+                                                      topic_path: "a".to_owned(),
+                                                      qos: QoS::AtMostOnce,
+                                                  },
+                                                  SubscribeTopic {
+                                                      topic_path: "b".to_owned(),
+                                                      qos: QoS::ExactlyOnce,
+                                                  }],
+                                 });
+        broker.handle_subscribe(subscribe, &client);
+
+        let (frame, _rx) = next_frame(rx);
+        match frame {
+            Frame::Packet(Packet::Suback(suback)) => {
+                assert_eq!(suback.return_codes,
+                           vec![SubscribeReturnCodes::Success(QoS::AtMostOnce), SubscribeReturnCodes::Success(QoS::AtLeastOnce)]);
+            }
+            frame => panic!("Expected a suback. Got {:?}", frame),
+        }
+
+        let subscribers = broker.get_subscribers("b");
+        assert_eq!(subscribers.len(), 1);
+        assert_eq!(subscribers[0].1, QoS::AtLeastOnce);
+    }
+
+    #[test]
+    fn shared_subscriptions_split_publishes_among_members() {
+        let broker = Broker::new();
+        let (c1, _rx1) = mock_client("mock-client-1");
+        let (c2, _rx2) = mock_client("mock-client-2");
+        let (c3, _rx3) = mock_client("mock-client-3");
+
+        let subscribe = |filter: &str| {
+            Box::new(Subscribe {
+                         pid: PacketIdentifier(1),
+                         topics: vec![SubscribeTopic {
+                                          topic_path: filter.to_owned(),
+                                          qos: QoS::AtLeastOnce,
+                                      }],
+                     })
+        };
+        broker.handle_subscribe(subscribe("$share/workers/jobs/+"), &c1);
+        broker.handle_subscribe(subscribe("$share/workers/jobs/+"), &c2);
+        broker.handle_subscribe(subscribe("jobs/#"), &c3);
+        broker.handle_subscribe(subscribe("$share/workers"), &c3);
+
+        let ids = |topic: &str| -> Vec<String> { broker.get_subscribers(topic).into_iter().map(|(c, _)| c.id).collect() };
+        assert_eq!(ids("jobs/1"), vec!["mock-client-3", "mock-client-1"]);
+        assert_eq!(ids("jobs/2"), vec!["mock-client-3", "mock-client-2"]);
+        assert_eq!(ids("jobs/3"), vec!["mock-client-3", "mock-client-1"]);
+
+        let unsubscribe = Box::new(Unsubscribe {
+                                       pid: PacketIdentifier(2),
+                                       topics: vec!["$share/workers/jobs/+".to_owned()],
+                                   });
+        broker.handle_unsubscribe(unsubscribe, &c1);
+        assert_eq!(ids("jobs/4"), vec!["mock-client-3", "mock-client-2"]);
+        broker.remove_client(&c2.id);
+        assert_eq!(ids("jobs/5"), vec!["mock-client-3"]);
+    }
+
+    #[test]
+    fn dead_subscribers_are_dropped_mid_fan_out() {
+        let (c1, rx1) = mock_client("mock-client-1");
+        let (c2, rx2) = mock_client("mock-client-2");
+        let (c3, rx3) = mock_client("mock-client-3");
+        let (watcher, rx4) = mock_client("mock-client-4");
+        let (publisher, ..) = mock_client("mock-client-5");
+
+        c2.set_last_will(Some(LastWill {
+                                  topic: "clients/mock-client-2/status".to_owned(),
+                                  message: "offline".to_owned(),
+                                  qos: QoS::AtMostOnce,
+                                  retain: false,
+                              }));
+
+        let s = SubscribeTopic {
+            topic_path: "hello/mqtt".to_owned(),
+            qos: QoS::AtLeastOnce,
+        };
+        let w = SubscribeTopic {
+            topic_path: "clients/mock-client-2/status".to_owned(),
+            qos: QoS::AtMostOnce,
+        };
+
+        let broker = Broker::new();
+        for c in [&c1, &c2, &c3, &watcher].iter() {
+            broker.add_client((*c).clone());
+        }
+        broker.add_subscription_client(s.clone(), c1.clone());
+        broker.add_subscription_client(s.clone(), c2.clone());
+        broker.add_subscription_client(s.clone(), c3.clone());
+        broker.add_subscription_client(w.clone(), watcher.clone());
+
+        // c2's connection goes away
+        drop(rx2);
+
+        let publish = Box::new(Publish {
+                                   dup: false,
+                                   qos: QoS::AtLeastOnce,
+                                   retain: false,
+                                   pid: Some(PacketIdentifier(1)),
+                                   topic_name: "hello/mqtt".to_owned(),
+                                   payload: Arc::new(vec![1, 2, 3]),
+                               });
+        broker.handle_publish(publish, &publisher);
+
+        // subscribers after the dead one still get the publish
+        assert!(match next_frame(rx1).0 {
+                    Frame::Packet(Packet::Publish(_)) => true,
+                    _ => false,
+                });
+        assert!(match next_frame(rx3).0 {
+                    Frame::Packet(Packet::Publish(_)) => true,
+                    _ => false,
+                });
+
+        // dead client is cleaned up and its will is published
+        assert!(c2.is_dead());
+        assert!(broker.get_client("mock-client-2").is_none());
+        assert_eq!(broker.get_subscribers("hello/mqtt").len(), 2);
+        match next_frame(rx4).0 {
+            Frame::Encoded(_) => (),
+            f => panic!("Expected the will. Got {:?}", f),
+        }
+    }
+
+    #[test]
+    fn quiet_clients_are_evicted_on_keep_alive_timeout() {
+        let (c1, _rx1) = mock_client("mock-client-1");
+        let (c2, _rx2) = mock_client("mock-client-2");
+
+        let broker = Broker::new();
+        broker.add_client(c1.clone());
+        broker.add_client(c2.clone());
+
+        c1.set_keep_alive(10);
+        c2.set_keep_alive(10);
+        c1.state.borrow_mut().last_activity = Instant::now() - Duration::from_secs(20);
+
+        assert!(!broker.check_keep_alive(&c1));
+        assert!(broker.get_client("mock-client-1").is_none());
+
+        assert!(broker.check_keep_alive(&c2));
+        assert!(broker.get_client("mock-client-2").is_some());
+    }
+
+    #[test]
+    fn timeouts_follow_the_broker_clock() {
+        let clock = ManualClock::new();
+        let broker = Broker::with_clock(BrokerConfig::default(), Rc::new(clock.clone()));
+
+        let (tx, _rx) = mpsc::channel::<Frame>(8);
+        let client = Client::with_clock("mock-client-1", "127.0.0.1:80".parse().unwrap(), tx, broker.clock());
+        let (publisher, ..) = mock_client("mock-client-2");
+        broker.add_client(client.clone());
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "hello/mqtt".to_owned(),
+                                           qos: QoS::AtLeastOnce,
+                                       },
+                                       client.clone());
+        client.set_keep_alive(14);
+        broker.handle_publish(qos1_publish(1, 1), &publisher);
+
+        clock.advance(Duration::from_secs(19));
+        assert!(broker.retransmit(&client));
+        assert_eq!(client.state.borrow().outgoing_pub[&PacketIdentifier(1)].retransmits, 0);
+        assert!(broker.check_keep_alive(&client));
+
+        // retransmit interval is 20s and keep alive expires after 21s
+        clock.advance(Duration::from_secs(1));
+        assert!(broker.retransmit(&client));
+        assert_eq!(client.state.borrow().outgoing_pub[&PacketIdentifier(1)].retransmits, 1);
+        assert!(broker.check_keep_alive(&client));
+
+        clock.advance(Duration::from_secs(2));
+        assert!(!broker.check_keep_alive(&client));
+    }
+
+    #[test]
+    fn unanswered_probes_disconnect_quiet_clients() {
+        let clock = ManualClock::new();
+        let broker = Broker::with_clock(BrokerConfig::default(), Rc::new(clock.clone()));
+        let interval = Duration::from_secs(5);
+
+        let (tx, rx) = mpsc::channel::<Frame>(8);
+        let client = Client::with_clock("mock-client-1", "127.0.0.1:80".parse().unwrap(), tx, broker.clock());
+        broker.add_client(client.clone());
+
+        // not quiet for long enough
+        clock.advance(Duration::from_secs(3));
+        assert!(broker.probe(&client, interval));
+        assert!(!client.probe_unanswered());
+
+        clock.advance(Duration::from_secs(2));
+        assert!(broker.probe(&client, interval));
+        let (frame, rx) = next_frame(rx);
+        assert_eq!(frame, Frame::Probe);
+
+        // answered by the pong
+        clock.advance(Duration::from_secs(1));
+        client.update_activity();
+        clock.advance(Duration::from_secs(5));
+        assert!(broker.probe(&client, interval));
+        let (frame, _rx) = next_frame(rx);
+        assert_eq!(frame, Frame::Probe);
+
+        clock.advance(Duration::from_secs(5));
+        assert!(!broker.probe(&client, interval));
+        assert!(broker.get_client("mock-client-1").is_none());
+    }
+
+    #[test]
+    fn takeovers_wait_for_the_probe_answer() {
+        let clock = ManualClock::new();
+        let broker = Broker::with_clock(BrokerConfig::default(), Rc::new(clock.clone()));
+
+        let (tx, rx) = mpsc::channel::<Frame>(8);
+        let existing = Client::with_clock("mock-client-1", "127.0.0.1:80".parse().unwrap(), tx, broker.clock());
+        broker.add_client(existing.clone());
+
+        // answered within the grace
+        clock.advance(Duration::from_secs(1));
+        assert!(broker.probe_for_takeover(&existing));
+        let (frame, rx) = next_frame(rx);
+        assert_eq!(frame, Frame::Probe);
+        clock.advance(Duration::from_secs(1));
+        existing.update_activity();
+        assert!(broker.survives_takeover(&existing));
+
+        // silent through the grace
+        clock.advance(Duration::from_secs(1));
+        assert!(broker.probe_for_takeover(&existing));
+        clock.advance(Duration::from_secs(2));
+        assert!(!broker.survives_takeover(&existing));
+
+        // gone before the probe
+        drop(rx);
+        assert!(!broker.probe_for_takeover(&existing));
+    }
+
+    #[test]
+    fn clients_behind_for_too_long_are_disconnected() {
+        let clock = ManualClock::new();
+        let mut config = BrokerConfig::default();
+        config.slow_consumer = Some(SlowConsumerConfig::new(2, Duration::from_secs(10)).disconnect(true));
+        let broker = Broker::with_clock(config, Rc::new(clock.clone()));
+
+        let (tx, rx) = mpsc::channel::<Frame>(8);
+        let client = Client::with_clock("mock-client-1", "127.0.0.1:80".parse().unwrap(), tx, broker.clock());
+        broker.add_client(client.clone());
+        for _ in 0..3 {
+            client.send(Packet::Pingresp).unwrap();
+        }
+        assert!(broker.check_slow_consumers().is_empty());
+
+        // catching up below the depth starts over
+        clock.advance(Duration::from_secs(6));
+        let (_, rx) = next_frame(rx);
+        client.frame_written().unwrap();
+        assert!(broker.check_slow_consumers().is_empty());
+        let (_, _rx) = next_frame(rx);
+        client.frame_written().unwrap();
+        assert!(broker.check_slow_consumers().is_empty());
+
+        client.send(Packet::Pingresp).unwrap();
+        clock.advance(Duration::from_secs(6));
+        assert!(broker.check_slow_consumers().is_empty());
+        assert_eq!(client.backlog().stall, Duration::from_secs(6));
+
+        clock.advance(Duration::from_secs(4));
+        assert_eq!(broker.check_slow_consumers(), vec!["mock-client-1".to_owned()]);
+        assert!(broker.get_client("mock-client-1").is_none());
+        assert_eq!(broker.disconnects(), vec![(DisconnectReason::SlowConsumer, 1)]);
+    }
+
+    fn qos1_publish(pkid: u16, payload: u8) -> Box<Publish> {
+        Box::new(Publish {
+                     dup: false,
+                     qos: QoS::AtLeastOnce,
+                     retain: false,
+                     pid: Some(PacketIdentifier(pkid)),
+                     topic_name: "hello/mqtt".to_owned(),
+                     payload: Arc::new(vec![payload]),
+                 })
+    }
+
+    fn drain(rx: Receiver<Frame>) -> Vec<Frame> {
+        rx.wait().map(|f| f.unwrap()).collect()
+    }
+
+    #[test]
+    fn duplicate_pkids_are_deduped() {
+        let mut config = BrokerConfig::default();
+        config.duplicate_pkid_policy = Some(DuplicatePkidPolicy::Dedupe);
+        let broker = Broker::with_config(config);
+
+        let (subscriber, sub_rx) = mock_client("mock-client-1");
+        let (publisher, pub_rx) = mock_client("mock-client-2");
+        broker.add_client(subscriber.clone());
+        broker.add_client(publisher.clone());
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "hello/mqtt".to_owned(),
+                                           qos: QoS::AtMostOnce,
+                                       },
+                                       subscriber.clone());
+
+        broker.handle_publish(qos1_publish(1, 1), &publisher);
+        broker.handle_publish(qos1_publish(1, 1), &publisher);
+        broker.handle_publish(qos1_publish(2, 2), &publisher);
+
+        broker.remove_client(&subscriber.id);
+        broker.remove_client(&publisher.id);
+        drop(subscriber);
+        drop(publisher);
+
+        // every publish is acked but the retransmission isn't forwarded
+        let pubacks: Vec<Frame> = drain(pub_rx);
+        assert_eq!(pubacks,
+                   vec![Frame::Packet(Packet::Puback(PacketIdentifier(1))),
+                        Frame::Packet(Packet::Puback(PacketIdentifier(1))),
+                        Frame::Packet(Packet::Puback(PacketIdentifier(2)))]);
+        assert_eq!(drain(sub_rx).len(), 2);
+    }
+
+    #[test]
+    fn duplicate_pkids_disconnect_the_client() {
+        let mut config = BrokerConfig::default();
+        config.duplicate_pkid_policy = Some(DuplicatePkidPolicy::Disconnect);
+        let broker = Broker::with_config(config);
+
+        let (publisher, _rx) = mock_client("mock-client-1");
+        broker.add_client(publisher.clone());
+
+        broker.handle_publish(qos1_publish(1, 1), &publisher);
+        assert!(broker.is_current(&publisher));
+
+        broker.handle_publish(qos1_publish(1, 1), &publisher);
+        assert!(!broker.is_current(&publisher));
+    }
+
+    #[test]
+    fn pkids_are_reusable_by_default() {
+        let broker = Broker::new();
+        let (publisher, _rx) = mock_client("mock-client-1");
+        broker.add_client(publisher.clone());
+
+        broker.handle_publish(qos1_publish(1, 1), &publisher);
+        broker.handle_publish(qos1_publish(1, 2), &publisher);
+        assert!(broker.is_current(&publisher));
+    }
+
+    #[test]
+    fn takeover_replaces_the_old_connection() {
+        let (old, ..) = mock_client("mock-client");
+        let (new, ..) = mock_client("mock-client");
+
+        let broker = Broker::new();
+        broker.add_client(old.clone());
+
+        let s = SubscribeTopic {
+            topic_path: "hello/mqtt".to_owned(),
+            qos: QoS::AtMostOnce,
+        };
+        broker.add_subscription_client(s.clone(), old.clone());
+
+        broker.add_client(new.clone());
+        assert!(!broker.is_current(&old));
+        assert!(broker.is_current(&new));
+        assert_eq!(broker.get_subscribed_clients(s.clone()).len(), 0);
+
+        // old connection going away doesn't remove the new one
+        broker.disconnect(&old, DisconnectReason::ConnectionLost);
+        assert!(broker.is_current(&new));
+
+        broker.disconnect(&new, DisconnectReason::ConnectionLost);
+        assert!(broker.get_client("mock-client").is_none());
+    }
+
+    #[test]
+    fn single_subscriber_fast_path_is_invalidated_on_subscription_changes() {
+        let (c1, ..) = mock_client("mock-client-1");
+        let (c2, ..) = mock_client("mock-client-2");
+
+        let s1 = SubscribeTopic {
+            topic_path: "hello/mqtt".to_owned(),
+            qos: QoS::AtMostOnce,
+        };
+        let s2 = SubscribeTopic {
+            topic_path: "hello/mqtt".to_owned(),
+            qos: QoS::AtLeastOnce,
+        };
+
+        let broker = Broker::new();
+        broker.add_subscription_client(s1.clone(), c1.clone());
+
+        let subscribers = broker.get_subscribers("hello/mqtt");
+        assert_eq!(subscribers.len(), 1);
+        assert!(broker.single_subscriber.borrow().contains_key("hello/mqtt"));
+
+        // a second subscriber on a different qos drops the cached route
+        broker.add_subscription_client(s2.clone(), c2.clone());
+        assert!(!broker.single_subscriber.borrow().contains_key("hello/mqtt"));
+        assert_eq!(broker.get_subscribers("hello/mqtt").len(), 2);
+        assert!(!broker.single_subscriber.borrow().contains_key("hello/mqtt"));
+
+        broker.remove_client(&c1.id);
+        let subscribers = broker.get_subscribers("hello/mqtt");
+        assert_eq!(subscribers.len(), 1);
+        assert_eq!(subscribers[0].0.id, "mock-client-2");
+        assert_eq!(subscribers[0].1, QoS::AtLeastOnce);
+
+        broker.remove_client(&c2.id);
+        assert!(!broker.single_subscriber.borrow().contains_key("hello/mqtt"));
+        assert_eq!(broker.get_subscribers("hello/mqtt").len(), 0);
+    }
+
+    #[test]
+    fn single_subscriber_cache_is_bounded() {
+        let (c1, ..) = mock_client("mock-client-1");
+        let devices = SubscribeTopic {
+            topic_path: "devices/+".to_owned(),
+            qos: QoS::AtMostOnce,
+        };
+
+        let broker = Broker::new();
+        broker.add_subscription_client(devices, c1.clone());
+        for i in 0..SINGLE_SUBSCRIBER_CACHE_SIZE + 10 {
+            assert_eq!(broker.get_subscribers(&format!("devices/{}", i)).len(), 1);
+            assert!(broker.single_subscriber.borrow().len() <= SINGLE_SUBSCRIBER_CACHE_SIZE);
+        }
+        assert!(broker.single_subscriber.borrow().contains_key(&format!("devices/{}", SINGLE_SUBSCRIBER_CACHE_SIZE + 9)));
+    }
+
+    #[test]
+    fn wildcard_subscriptions_match_published_topics() {
+        let (c1, ..) = mock_client("mock-client-1");
+        let (c2, ..) = mock_client("mock-client-2");
+
+        let exact = SubscribeTopic {
+            topic_path: "hello/mqtt".to_owned(),
+            qos: QoS::AtMostOnce,
+        };
+        let level = SubscribeTopic {
+            topic_path: "hello/+".to_owned(),
+            qos: QoS::AtLeastOnce,
+        };
+        let all = SubscribeTopic {
+            topic_path: "#".to_owned(),
+            qos: QoS::AtMostOnce,
+        };
+
+        let broker = Broker::new();
+        broker.add_subscription_client(exact.clone(), c1.clone());
+        assert_eq!(broker.get_subscribers("hello/mqtt").len(), 1);
+        assert!(broker.single_subscriber.borrow().contains_key("hello/mqtt"));
+
+        // a wildcard covering a cached topic drops the cached route
+        broker.add_subscription_client(level.clone(), c1.clone());
+        assert!(!broker.single_subscriber.borrow().contains_key("hello/mqtt"));
+
+        // one copy per client at the highest matching qos
+        let subscribers = broker.get_subscribers("hello/mqtt");
+        assert_eq!(subscribers.len(), 1);
+        assert_eq!(subscribers[0].1, QoS::AtLeastOnce);
+
+        broker.add_subscription_client(all, c2.clone());
+        assert_eq!(broker.get_subscribers("hello/world").len(), 2);
+        assert_eq!(broker.get_subscribers("world").len(), 1);
+        assert_eq!(broker.get_subscribers("$SYS/broker/time").len(), 0);
+
+        broker.remove_client(&c2.id);
+        broker.remove_subscription_client(level, &c1.id);
+        assert_eq!(broker.get_subscribers("hello/world").len(), 0);
+        assert_eq!(broker.sizes().subscriptions, 1);
+    }
+
+    #[test]
+    fn resubscribing_and_unsubscribing_leave_no_leftovers() {
+        let (c1, ..) = mock_client("mock-client-1");
+
+        let s1 = SubscribeTopic {
+            topic_path: "hello/mqtt".to_owned(),
+            qos: QoS::AtMostOnce,
+        };
+
+        let broker = Broker::new();
+        broker.add_subscription_client(s1.clone(), c1.clone());
+        broker.add_subscription_client(s1.clone(), c1.clone());
+        assert_eq!(broker.sizes().subscribers, 1);
+
+        broker.remove_subscription_client(s1.clone(), &c1.id);
+        assert_eq!(broker.sizes().subscriptions, 0);
+
+        broker.add_subscription_client(s1.clone(), c1.clone());
+        broker.remove_client(&c1.id);
+        assert_eq!(broker.sizes().subscriptions, 0);
+    }
+
+    #[test]
+    fn bridge_passes_outgoing_topics_on_without_echoing_incoming_ones() {
+        let mut config = BrokerConfig::default();
+        config.bridge_topics = vec!["hello/# both 1".parse().unwrap(), "commands/+ in".parse().unwrap()];
+        let broker = Broker::with_config(config);
+
+        let (remote, remote_rx) = mock_client(BRIDGE_CLIENT_ID);
+        let (c1, rx1) = mock_client("mock-client-1");
+        let (c2, _rx2) = mock_client("mock-client-2");
+        broker.set_bridge(remote.clone());
+        broker.add_client(c1.clone());
+        broker.add_client(c2.clone());
+        let subscribe = Box::new(Subscribe {
+                                     pid: PacketIdentifier(1),
+                                     topics: vec![SubscribeTopic {
+                                                      topic_path: "hello/mqtt".to_owned(),
+                                                      qos: QoS::AtLeastOnce,
+                                                  }],
+                                 });
+        broker.handle_subscribe(subscribe, &c1);
+        let (_, rx1) = next_frame(rx1);
+
+        // local publishes go out on their own topic
+        broker.handle_publish(qos1_publish(1, 1), &c2);
+        let (frame, remote_rx) = next_frame(remote_rx);
+        match frame {
+            Frame::Packet(Packet::Publish(p)) => assert_eq!(p.topic_name, "hello/mqtt"),
+            frame => panic!("Expected a publish. Got {:?}", frame),
+        }
+
+        // publishes from the remote broker reach local subscribers but don't go back
+        broker.handle_publish(qos1_publish(1, 2), &remote);
+        let (frame, _remote_rx) = next_frame(remote_rx);
+        assert_eq!(frame, Frame::Packet(Packet::Puback(PacketIdentifier(1))));
+        let (_, rx1) = next_frame(rx1);
+        let (frame, _rx1) = next_frame(rx1);
+        match frame {
+            Frame::Packet(Packet::Publish(p)) => assert_eq!(*p.payload, vec![2]),
+            frame => panic!("Expected a publish. Got {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn leaf_subscribes_once_at_the_hub_and_passes_publishes_up() {
+        let (hub, hub_rx) = mock_client(UPLINK_CLIENT_ID);
+        let (c1, _rx1) = mock_client("mock-client-1");
+        let (c2, _rx2) = mock_client("mock-client-2");
+
+        let broker = Broker::new();
+        broker.set_uplink(hub.clone());
+        for client in [&c1, &c2].iter() {
+            broker.add_client((*client).clone());
+            let subscribe = Box::new(Subscribe {
+                                         pid: PacketIdentifier(1),
+                                         topics: vec![SubscribeTopic {
+                                                          topic_path: "hello/mqtt".to_owned(),
+                                                          qos: QoS::AtLeastOnce,
+                                                      }],
+                                     });
+            broker.handle_subscribe(subscribe, client);
+        }
+
+        let (frame, hub_rx) = next_frame(hub_rx);
+        match frame {
+            Frame::Packet(Packet::Subscribe(s)) => assert_eq!(s.topics[0].topic_path, "hello/mqtt"),
+            frame => panic!("Expected a subscribe. Got {:?}", frame),
+        }
+        assert_eq!(broker.uplink_filters(), vec![("hello/mqtt".to_owned(), QoS::AtLeastOnce)]);
+
+        // publishes go up under the id of the local client
+        broker.handle_publish(qos1_publish(1, 1), &c1);
+        let (frame, hub_rx) = next_frame(hub_rx);
+        match frame {
+            Frame::Packet(Packet::Publish(p)) => assert_eq!(p.topic_name, "$leaf/mock-client-1/hello/mqtt"),
+            frame => panic!("Expected a publish. Got {:?}", frame),
+        }
+
+        // publishes from the hub aren't sent back
+        broker.handle_publish(qos1_publish(1, 2), &hub);
+        let (frame, hub_rx) = next_frame(hub_rx);
+        assert_eq!(frame, Frame::Packet(Packet::Puback(PacketIdentifier(1))));
+
+        // the hub subscription goes with the last local subscriber
+        broker.remove_client(&c1.id);
+        broker.remove_client(&c2.id);
+        let (frame, _hub_rx) = next_frame(hub_rx);
+        match frame {
+            Frame::Packet(Packet::Unsubscribe(u)) => assert_eq!(u.topics, vec!["hello/mqtt".to_owned()]),
+            frame => panic!("Expected an unsubscribe. Got {:?}", frame),
+        }
+        assert!(broker.uplink_filters().is_empty());
+    }
+
+    #[test]
+    fn hub_routes_leaf_publishes_under_the_leaf_client_id() {
+        let (edge, _edge_rx) = mock_client("edge-1");
+        let (backend, _backend_rx) = mock_client("backend");
+
+        let broker = Broker::new();
+        for client in [&edge, &backend].iter() {
+            broker.add_client((*client).clone());
+            broker.add_subscription_client(SubscribeTopic {
+                                               topic_path: "hello/mqtt".to_owned(),
+                                               qos: QoS::AtMostOnce,
+                                           },
+                                           (*client).clone());
+        }
+
+        let mut publish = qos1_publish(1, 1);
+        publish.topic_name = leaf::wrap_topic("sensor-7", "hello/mqtt");
+        broker.handle_publish(publish, &edge);
+
+        assert_eq!(broker.session_stats("edge-1/sensor-7").unwrap().published, 1);
+        assert_eq!(broker.session_stats("backend").unwrap().delivered, 1);
+        // the leaf delivered it to its own subscribers already
+        assert_eq!(broker.session_stats("edge-1").unwrap().delivered, 0);
+    }
+
+    #[test]
+    fn add_and_remove_subscriptions_to_the_broker() {
+        let (c1, ..) = mock_client("mock-client-1");
+        let (c2, ..) = mock_client("mock-client-2");
+
+        let s1 = SubscribeTopic {
+            topic_path: "hello/mqtt".to_owned(),
+            qos: QoS::AtMostOnce,
+        };
+        let s2 = SubscribeTopic {
+            topic_path: "hello/mqtt".to_owned(),
+            qos: QoS::AtLeastOnce,
+        };
+        let s3 = SubscribeTopic {
+            topic_path: "hello/mqtt".to_owned(),
+            qos: QoS::ExactlyOnce,
+        };
+        let s4 = SubscribeTopic {
+            topic_path: "hello/rumqttd".to_owned(),
+            qos: QoS::AtLeastOnce,
+        };
+        let s5 = SubscribeTopic {
+            topic_path: "hello/rumqttd".to_owned(),
+            qos: QoS::ExactlyOnce,
+        };
+
+        let broker = Broker::new();
+
+        // add c1 to to s1, s2, s3 & s4
+        broker.add_subscription_client(s1.clone(), c1.clone());
+        broker.add_subscription_client(s2.clone(), c1.clone());
+        broker.add_subscription_client(s3.clone(), c1.clone());
+        broker.add_subscription_client(s4.clone(), c1.clone());
+
+        // add c2 to s2 & s5
+        broker.add_subscription_client(s2.clone(), c2.clone());
+        broker.add_subscription_client(s5.clone(), c2.clone());
+
+        // verify clients in s1
+        let clients = broker.get_subscribed_clients(s1.clone());
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients.get(0).unwrap().id, "mock-client-1");
+
+        // verify clients in s2
+        let clients = broker.get_subscribed_clients(s2.clone());
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients.get(0).unwrap().id, "mock-client-1");
+        assert_eq!(clients.get(1).unwrap().id, "mock-client-2");
+
+        // verify clients in s5
+        let clients = broker.get_subscribed_clients(s5.clone());
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients.get(0).unwrap().id, "mock-client-2");
+
+        // remove c1 from s2 and verify clients
+        broker.remove_subscription_client(s2.clone(), &c1.id);
+        let clients = broker.get_subscribed_clients(s2.clone());
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients.get(0).unwrap().id, "mock-client-2");
+
+        // remove c1 & c2 from all subscriptions and verify clients
+        broker.remove_client(&c1.id);
+        broker.remove_client(&c2.id);
+
+        for s in [s1, s2, s3, s4, s5].iter() {
+            let clients = broker.get_subscribed_clients(s.clone());
+            assert_eq!(clients.len(), 0);
+        }
+
+    }
+
+}
@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use mqtt3::Publish;
+
+/// What the broker knows about a topic that has been published to
+#[derive(Debug, Clone)]
+pub struct TopicInfo {
+    pub topic: String,
+    /// When the first publish on this topic was seen
+    pub first_seen: Instant,
+    /// When the latest publish on this topic was seen
+    pub last_publish: Instant,
+    /// Number of publishes seen on this topic
+    pub messages: u64,
+    /// Retain flag of the latest publish
+    pub retained: bool,
+}
+
+impl TopicInfo {
+    /// Average publishes per second since the topic was first seen
+    pub fn rate(&self) -> f64 {
+        let elapsed = self.last_publish.duration_since(self.first_seen);
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+
+        if secs == 0.0 {
+            self.messages as f64
+        } else {
+            self.messages as f64 / secs
+        }
+    }
+}
+
+/// Catalog of active topics, kept sorted by topic name so that
+/// prefix queries are a range scan
+#[derive(Debug)]
+pub struct TopicCatalog {
+    topics: BTreeMap<String, TopicInfo>,
+}
+
+impl TopicCatalog {
+    pub fn new() -> Self {
+        TopicCatalog { topics: BTreeMap::new() }
+    }
+
+    /// Records an incoming publish against its topic, seen at `now`
+    pub fn record(&mut self, publish: &Publish, now: Instant) {
+        let info = self.topics
+            .entry(publish.topic_name.clone())
+            .or_insert(TopicInfo {
+                           topic: publish.topic_name.clone(),
+                           first_seen: now,
+                           last_publish: now,
+                           messages: 0,
+                           retained: false,
+                       });
+
+        info.last_publish = now;
+        info.messages += 1;
+        info.retained = publish.retain;
+    }
+
+    /// Returns at most `limit` topics starting with `prefix`, skipping
+    /// the first `offset` matches
+    pub fn query(&self, prefix: &str, offset: usize, limit: usize) -> Vec<TopicInfo> {
+        self.topics
+            .range(prefix.to_owned()..)
+            .take_while(|&(topic, _)| topic.starts_with(prefix))
+            .skip(offset)
+            .take(limit)
+            .map(|(_, info)| info.clone())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.topics.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Instant;
+    use mqtt3::*;
+    use super::TopicCatalog;
+
+    fn publish(topic: &str, retain: bool) -> Publish {
+        Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: retain,
+            pid: None,
+            topic_name: topic.to_owned(),
+            payload: Arc::new(vec![1, 2, 3]),
+        }
+    }
+
+    #[test]
+    fn topics_are_filtered_by_prefix_and_paginated() {
+        let mut catalog = TopicCatalog::new();
+
+        for i in 0..10 {
+            catalog.record(&publish(&format!("devices/{}/temperature", i), false), Instant::now());
+        }
+        catalog.record(&publish("dashboards/main", true), Instant::now());
+        catalog.record(&publish("devices/0/temperature", true), Instant::now());
+
+        assert_eq!(catalog.len(), 11);
+
+        let page = catalog.query("devices/", 0, 4);
+        assert_eq!(page.len(), 4);
+        assert_eq!(page[0].topic, "devices/0/temperature");
+        assert_eq!(page[0].messages, 2);
+        assert_eq!(page[0].retained, true);
+
+        let page = catalog.query("devices/", 8, 4);
+        assert_eq!(page.len(), 2);
+        assert_eq!(page[1].topic, "devices/9/temperature");
+
+        let page = catalog.query("dash", 0, 10);
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].topic, "dashboards/main");
+    }
+}
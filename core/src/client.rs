@@ -0,0 +1,1093 @@
+use std::fmt::{self, Debug};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use futures::sync::mpsc::{self, Receiver, Sender};
+use futures::sync::oneshot;
+
+use mqtt3::*;
+use serde_json::Value;
+
+use slog::{Logger, Drain};
+use slog_term;
+use slog_async;
+
+use bytes::Bytes;
+
+use clock::{self, Clock};
+use config::{DrainPolicy, OverflowPolicy};
+use codec::Frame;
+use handles;
+#[cfg(feature = "tls")]
+use tls::PeerCertificate;
+#[cfg(feature = "enrichment")]
+use enrich::ConnectMetadata;
+use profile::{tracked, Tracked};
+use error::{Error, Result};
+use slow::Backlog;
+
+/// Outgoing packet waiting for its acknowledgement
+#[derive(Debug, Clone)]
+pub struct Inflight<T> {
+    pub packet: T,
+    /// When the packet was last written
+    pub sent_at: Instant,
+    pub retransmits: u32,
+}
+
+impl<T> Inflight<T> {
+    fn new(packet: T, now: Instant) -> Self {
+        Inflight {
+            packet: packet,
+            sent_at: now,
+            retransmits: 0,
+        }
+    }
+
+    /// True when the packet has been waiting for longer than `interval`
+    fn due(&self, interval: Duration, now: Instant) -> bool {
+        now.duration_since(self.sent_at) >= interval
+    }
+}
+
+/// Traffic of a connection, for debugging misbehaving devices. Bytes are
+/// publish payload bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientStats {
+    /// Publishes received from the client
+    pub messages_in: u64,
+    /// Publishes handed to the connection
+    pub messages_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Publishes for the client that failed to send, were dropped by the
+    /// outgoing overflow policy or were given up on after the last retransmission
+    pub dropped: u64,
+    pub connected_at: Instant,
+    pub last_activity: Instant,
+}
+
+impl ClientStats {
+    /// Counters with the connection's age and idle time at `now`
+    pub fn to_json(&self, now: Instant) -> Value {
+        json!({
+            "messages_in": self.messages_in,
+            "messages_out": self.messages_out,
+            "bytes_in": self.bytes_in,
+            "bytes_out": self.bytes_out,
+            "dropped": self.dropped,
+            "connected_ms": clock::millis(now.duration_since(self.connected_at)),
+            "last_packet_age_ms": clock::millis(now.duration_since(self.last_activity)),
+        })
+    }
+}
+
+/// Outcome of a retransmission round
+#[derive(Debug, Default)]
+pub struct Retransmits {
+    /// Packets to write again
+    pub packets: Vec<Packet>,
+    /// Packets dropped after reaching the retransmission cap
+    pub given_up: usize,
+}
+
+/// Hands the packets of `queue` that are due for retransmission to `resend`.
+/// Returns the number of packets dropped for reaching `max_retransmits`
+fn retry<T, F>(queue: &mut BTreeMap<PacketIdentifier, Inflight<T>>,
+               interval: Duration,
+               max_retransmits: u32,
+               now: Instant,
+               mut resend: F)
+               -> usize
+    where F: FnMut(PacketIdentifier, &T)
+{
+    let due: Vec<PacketIdentifier> = queue
+        .iter()
+        .filter(|&(_, inflight)| inflight.due(interval, now))
+        .map(|(pkid, _)| *pkid)
+        .collect();
+
+    let mut given_up = 0;
+    for pkid in due {
+        if queue[&pkid].retransmits >= max_retransmits {
+            queue.remove(&pkid);
+            given_up += 1;
+            continue;
+        }
+
+        let inflight = queue.get_mut(&pkid).unwrap();
+        inflight.retransmits += 1;
+        inflight.sent_at = now;
+        resend(pkid, &inflight.packet);
+    }
+
+    given_up
+}
+
+/// Flags of a single delivery of a publish. Decided per subscriber by the broker
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Delivery {
+    pub qos: QoS,
+    /// Set when redelivering a message the client might have seen already
+    pub dup: bool,
+    pub retain: bool,
+}
+
+/// QoS 1 or 2 delivery waiting for a free inflight slot. The packet id is
+/// only assigned when it's sent
+#[derive(Debug, Clone)]
+pub struct Pending {
+    pub topic: String,
+    pub payload: Arc<Vec<u8>>,
+    pub delivery: Delivery,
+}
+
+#[derive(Debug)]
+pub struct ClientState {
+    pub last_pkid: PacketIdentifier,
+    /// For QoS 1. Outgoing publishes waiting for PUBACK
+    pub outgoing_pub: BTreeMap<PacketIdentifier, Inflight<Box<Publish>>>,
+    /// For QoS 2. Outgoing publishes waiting for PUBREC
+    pub outgoing_rec: BTreeMap<PacketIdentifier, Inflight<Box<Publish>>>,
+    /// For QoS 2. Outgoing releases waiting for PUBCOMP
+    pub outgoing_rel: BTreeMap<PacketIdentifier, Inflight<()>>,
+    /// Deliveries held back while the inflight window is full, oldest first
+    pub pending: VecDeque<Pending>,
+    /// Offline backlog of a resumed session that hasn't fit the inflight window yet
+    pub backlog: VecDeque<Pending>,
+    /// Backlog messages sent since the last live one
+    backlog_streak: u32,
+    /// For QoS 2. Packet ids of forwarded incoming publishes waiting for PUBREL
+    pub incoming_rec: BTreeSet<PacketIdentifier>,
+    /// For QoS 1. Recently received packet ids, newest last
+    pub incoming_pkids: VecDeque<PacketIdentifier>,
+    pub messages_in: u64,
+    pub messages_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub dropped: u64,
+    /// What happens to frames for a full outgoing queue
+    pub outgoing_overflow: OverflowPolicy,
+    /// Frames waiting for room in the outgoing queue, oldest first. Only
+    /// used by the drop policies
+    held: VecDeque<Frame>,
+    /// Frames held at most before the overflow policy drops publishes
+    held_capacity: usize,
+    /// Frames in the outgoing queue the connection hasn't written yet
+    queued: usize,
+    /// When the connection last took a frame off the outgoing queue
+    last_write: Instant,
+    /// Since when the outgoing queue has been at the slow consumer depth
+    pub behind_since: Option<Instant>,
+    /// Set once the client has been reported as a slow consumer
+    pub slow_reported: bool,
+    pub connected_at: Instant,
+    /// When the last packet was received from the client
+    pub last_activity: Instant,
+    /// When the last liveness probe was sent
+    pub probed_at: Option<Instant>,
+    /// Published by the broker if the client goes away without a DISCONNECT
+    pub last_will: Option<LastWill>,
+    /// Set once a send fails. Dead clients don't get any more packets
+    pub dead: bool,
+    /// Keep alive advertised in CONNECT. `None` if disabled
+    pub keep_alive: Option<Duration>,
+    /// False for persistent sessions, which outlive the connection
+    pub clean_session: bool,
+    /// Connected with MQTT 3.1
+    pub mqtt31: bool,
+    /// Username of the CONNECT
+    pub username: Option<String>,
+    /// Verified client certificate of a mutual TLS connection
+    #[cfg(feature = "tls")]
+    pub peer_certificate: Option<PeerCertificate>,
+    /// Prefixes the authenticator confined its topics to. `None` if it didn't
+    pub topic_prefixes: Option<Vec<String>>,
+    /// Reverse DNS and GeoIP results for the peer address, once looked up
+    #[cfg(feature = "enrichment")]
+    pub metadata: Option<ConnectMetadata>,
+    /// Fired to make the connection stop reading from the network
+    kill_switch: Option<oneshot::Sender<()>>,
+}
+
+impl ClientState {
+    pub fn new(now: Instant) -> Self {
+        ClientState {
+            last_pkid: PacketIdentifier(0),
+            outgoing_pub: BTreeMap::new(),
+            outgoing_rec: BTreeMap::new(),
+            outgoing_rel: BTreeMap::new(),
+            pending: VecDeque::new(),
+            backlog: VecDeque::new(),
+            backlog_streak: 0,
+            incoming_rec: BTreeSet::new(),
+            incoming_pkids: VecDeque::new(),
+            messages_in: 0,
+            messages_out: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            dropped: 0,
+            outgoing_overflow: OverflowPolicy::Disconnect,
+            held: VecDeque::new(),
+            held_capacity: 0,
+            queued: 0,
+            last_write: now,
+            behind_since: None,
+            slow_reported: false,
+            connected_at: now,
+            last_activity: now,
+            probed_at: None,
+            last_will: None,
+            dead: false,
+            keep_alive: None,
+            clean_session: true,
+            mqtt31: false,
+            username: None,
+            #[cfg(feature = "tls")]
+            peer_certificate: None,
+            topic_prefixes: None,
+            #[cfg(feature = "enrichment")]
+            metadata: None,
+            kill_switch: None,
+        }
+    }
+}
+
+/// True for frames an overflow policy may drop
+fn is_publish(frame: &Frame) -> bool {
+    match *frame {
+        Frame::Packet(Packet::Publish(_)) | Frame::Encoded(_) => true,
+        _ => false,
+    }
+}
+
+/// Outgoing queue of a connection with room for exactly `size` frames
+pub fn outgoing_queue(size: usize) -> (Sender<Frame>, Receiver<Frame>) {
+    // every sender also gets a guaranteed slot of its own
+    mpsc::channel(size.saturating_sub(1))
+}
+
+#[derive(Clone)]
+pub struct Client {
+    pub id: String,
+    pub addr: SocketAddr,
+    /// Outgoing packets of this connection. Shared between the clones so that
+    /// a full queue is noticed by every sender. `None` once the connection is closed
+    tx: Rc<Tracked<Option<Sender<Frame>>>>,
+
+    pub state: Rc<Tracked<ClientState>>,
+    clock: Rc<Clock>,
+    logger: Logger,
+}
+
+impl Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, " [  id = {:?}, address = {:?}\n ]", self.id, self.addr)
+    }
+}
+
+impl Client {
+    pub fn new(id: &str, addr: SocketAddr, tx: Sender<Frame>) -> Client {
+        Client::with_clock(id, addr, tx, clock::system())
+    }
+
+    /// Client whose activity and inflight timestamps are read from `clock`.
+    /// Should be the broker's clock
+    pub fn with_clock(id: &str, addr: SocketAddr, tx: Sender<Frame>, clock: Rc<Clock>) -> Client {
+        let decorator = slog_term::TermDecorator::new().build();
+        let drain = slog_term::CompactFormat::new(decorator).build().fuse();
+        let drain = slog_async::Async::new(drain).build().fuse();
+        let logger = Logger::root(Arc::new(drain), o!("version" => env!("CARGO_PKG_VERSION")));
+
+        Client::with_logger(id, addr, tx, clock, &logger)
+    }
+
+    /// Client logging through `logger`, usually the broker's, with its id as the `client-id` field
+    pub fn with_logger(id: &str, addr: SocketAddr, tx: Sender<Frame>, clock: Rc<Clock>, logger: &Logger) -> Client {
+        let state = ClientState::new(clock.now());
+
+        let client = Client {
+            addr: addr,
+            id: id.to_string(),
+            tx: Rc::new(tracked("client.tx", Some(tx))),
+            logger: logger.new(o!("client-id" => id.to_owned())),
+            state: Rc::new(tracked("client.state", state)),
+            clock: clock,
+        };
+
+        handles::register(&client);
+        client
+    }
+
+    pub fn logger(&self) -> &Logger {
+        &self.logger
+    }
+
+    /// True if both handles belong to the same network connection
+    pub fn same_connection(&self, other: &Client) -> bool {
+        Rc::ptr_eq(&self.state, &other.state)
+    }
+
+    /// Records that a packet was received from the client
+    pub fn update_activity(&self) {
+        self.state.borrow_mut().last_activity = self.clock.now();
+    }
+
+    pub fn last_activity(&self) -> Instant {
+        self.state.borrow().last_activity
+    }
+
+    /// Time since the last packet was received from the client
+    pub fn idle(&self) -> Duration {
+        self.clock.now().duration_since(self.state.borrow().last_activity)
+    }
+
+    /// Sends a liveness probe. The client answers it with any packet
+    pub fn send_probe(&self) -> Result<()> {
+        self.send_frame(Frame::Probe)?;
+        self.state.borrow_mut().probed_at = Some(self.clock.now());
+        Ok(())
+    }
+
+    /// True if nothing was received from the client since the last probe
+    pub fn probe_unanswered(&self) -> bool {
+        let state = self.state.borrow();
+        state.probed_at.map_or(false, |probed_at| state.last_activity < probed_at)
+    }
+
+    /// Counts a publish received from the client
+    pub fn count_in(&self, payload_len: usize) {
+        let mut state = self.state.borrow_mut();
+        state.messages_in += 1;
+        state.bytes_in += payload_len as u64;
+    }
+
+    /// Counts a publish handed to the connection
+    pub fn count_out(&self, payload_len: usize) {
+        let mut state = self.state.borrow_mut();
+        state.messages_out += 1;
+        state.bytes_out += payload_len as u64;
+    }
+
+    pub fn count_dropped(&self, count: u64) {
+        self.state.borrow_mut().dropped += count;
+    }
+
+    pub fn stats(&self) -> ClientStats {
+        let state = self.state.borrow();
+        ClientStats {
+            messages_in: state.messages_in,
+            messages_out: state.messages_out,
+            bytes_in: state.bytes_in,
+            bytes_out: state.bytes_out,
+            dropped: state.dropped,
+            connected_at: state.connected_at,
+            last_activity: state.last_activity,
+        }
+    }
+
+    /// Sets the keep alive from CONNECT. 0 disables it
+    pub fn set_keep_alive(&self, secs: u16) {
+        self.state.borrow_mut().keep_alive = if secs == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(secs as u64))
+        };
+    }
+
+    pub fn keep_alive(&self) -> Option<Duration> {
+        self.state.borrow().keep_alive
+    }
+
+    pub fn set_clean_session(&self, clean_session: bool) {
+        self.state.borrow_mut().clean_session = clean_session;
+    }
+
+    pub fn clean_session(&self) -> bool {
+        self.state.borrow().clean_session
+    }
+
+    pub fn set_mqtt31(&self, mqtt31: bool) {
+        self.state.borrow_mut().mqtt31 = mqtt31;
+    }
+
+    pub fn mqtt31(&self) -> bool {
+        self.state.borrow().mqtt31
+    }
+
+    pub fn set_username(&self, username: Option<String>) {
+        self.state.borrow_mut().username = username;
+    }
+
+    pub fn username(&self) -> Option<String> {
+        self.state.borrow().username.clone()
+    }
+
+    pub fn set_topic_prefixes(&self, prefixes: Option<Vec<String>>) {
+        self.state.borrow_mut().topic_prefixes = prefixes;
+    }
+
+    pub fn topic_prefixes(&self) -> Option<Vec<String>> {
+        self.state.borrow().topic_prefixes.clone()
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn set_peer_certificate(&self, peer: Option<PeerCertificate>) {
+        self.state.borrow_mut().peer_certificate = peer;
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn peer_certificate(&self) -> Option<PeerCertificate> {
+        self.state.borrow().peer_certificate.clone()
+    }
+
+    #[cfg(feature = "enrichment")]
+    pub fn set_metadata(&self, metadata: ConnectMetadata) {
+        self.state.borrow_mut().metadata = Some(metadata);
+    }
+
+    #[cfg(feature = "enrichment")]
+    pub fn metadata(&self) -> Option<ConnectMetadata> {
+        self.state.borrow().metadata.clone()
+    }
+
+    /// True if nothing was received from the client for 1.5 times its keep alive
+    pub fn keep_alive_expired(&self) -> bool {
+        let state = self.state.borrow();
+        match state.keep_alive {
+            Some(keep_alive) => self.clock.now().duration_since(state.last_activity) > keep_alive * 3 / 2,
+            None => false,
+        }
+    }
+
+    pub fn set_kill_switch(&self, kill_switch: oneshot::Sender<()>) {
+        self.state.borrow_mut().kill_switch = Some(kill_switch);
+    }
+
+    /// Makes the connection stop reading from the network and drops its
+    /// outgoing queue. The socket closes once the packets already queued are
+    /// written. Nothing can be sent after this
+    pub fn close(&self) {
+        let _ = self.flush_held();
+        if let Some(kill_switch) = self.state.borrow_mut().kill_switch.take() {
+            let _ = kill_switch.send(());
+        }
+
+        self.tx.borrow_mut().take();
+    }
+
+    pub fn next_pkid(&self) -> PacketIdentifier {
+        let mut state = self.state.borrow_mut();
+        let PacketIdentifier(mut pkid) = state.last_pkid;
+        if pkid == 65535 {
+            pkid = 0;
+        }
+        state.last_pkid = PacketIdentifier(pkid + 1);
+        state.last_pkid
+    }
+
+
+    pub fn store_publish(&self, publish: Box<Publish>) {
+        match publish.pid {
+            Some(pkid) => {
+                self.state.borrow_mut().outgoing_pub.insert(pkid, Inflight::new(publish, self.clock.now()));
+            }
+            None => error!(self.logger, "Not storing QoS1 publish without a pkid"),
+        }
+    }
+
+    /// Remembers the packet id of an incoming QoS 1 publish. Returns true if the
+    /// id is already among the last `window` ids received from this client
+    pub fn record_incoming_pkid(&self, pkid: PacketIdentifier, window: usize) -> bool {
+        let mut state = self.state.borrow_mut();
+
+        if state.incoming_pkids.contains(&pkid) {
+            return true;
+        }
+
+        state.incoming_pkids.push_back(pkid);
+        while state.incoming_pkids.len() > window {
+            state.incoming_pkids.pop_front();
+        }
+
+        false
+    }
+
+    pub fn remove_publish(&self, pkid: PacketIdentifier) -> Option<Box<Publish>> {
+        let publish = self.state.borrow_mut().outgoing_pub.remove(&pkid);
+        if publish.is_none() {
+            error!(self.logger, "Unsolicited PUBLISH packet: {:?}", pkid);
+        }
+        publish.map(|inflight| inflight.packet)
+    }
+
+    pub fn store_record(&self, publish: Box<Publish>) {
+        match publish.pid {
+            Some(pkid) => {
+                self.state.borrow_mut().outgoing_rec.insert(pkid, Inflight::new(publish, self.clock.now()));
+            }
+            None => error!(self.logger, "Not storing QoS2 publish without a pkid"),
+        }
+    }
+
+    pub fn remove_record(&self, pkid: PacketIdentifier) -> Option<Box<Publish>> {
+        let record = self.state.borrow_mut().outgoing_rec.remove(&pkid);
+        if record.is_none() {
+            error!(self.logger, "Unsolicited RECORD packet: {:?}", pkid);
+        }
+        record.map(|inflight| inflight.packet)
+    }
+
+    pub fn store_rel(&self, pkid: PacketIdentifier) {
+        self.state.borrow_mut().outgoing_rel.insert(pkid, Inflight::new((), self.clock.now()));
+    }
+
+    pub fn remove_rel(&self, pkid: PacketIdentifier) -> Option<PacketIdentifier> {
+        if self.state.borrow_mut().outgoing_rel.remove(&pkid).is_some() {
+            Some(pkid)
+        } else {
+            error!(self.logger, "Unsolicited RELEASE packet: {:?}", pkid);
+            None
+        }
+    }
+
+    /// Unacknowledged publishes and releases that have waited for `interval`.
+    /// Publishes are resent with DUP set. Packets already retransmitted
+    /// `max_retransmits` times are dropped instead
+    pub fn retransmits(&self, interval: Duration, max_retransmits: u32) -> Retransmits {
+        let now = self.clock.now();
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+
+        let mut packets = Vec::new();
+        let mut given_up = 0;
+
+        {
+            let mut resend = |_: PacketIdentifier, publish: &Box<Publish>| {
+                let mut publish = publish.clone();
+                publish.dup = true;
+                packets.push(Packet::Publish(publish));
+            };
+
+            given_up += retry(&mut state.outgoing_pub, interval, max_retransmits, now, &mut resend);
+            given_up += retry(&mut state.outgoing_rec, interval, max_retransmits, now, &mut resend);
+        }
+
+        given_up += retry(&mut state.outgoing_rel,
+                          interval,
+                          max_retransmits,
+                          now,
+                          |pkid, _| packets.push(Packet::Pubrel(pkid)));
+
+        Retransmits {
+            packets: packets,
+            given_up: given_up,
+        }
+    }
+
+    /// Outgoing QoS 1 and 2 messages whose flow isn't complete yet
+    pub fn inflight(&self) -> usize {
+        let state = self.state.borrow();
+        state.outgoing_pub.len() + state.outgoing_rec.len() + state.outgoing_rel.len()
+    }
+
+    /// QoS 1 and 2 messages the connection holds, unacknowledged or held
+    /// back, with their payload bytes
+    pub fn queued_messages(&self) -> (usize, usize) {
+        let state = self.state.borrow();
+        let sent = state.outgoing_pub.values().chain(state.outgoing_rec.values()).map(|inflight| inflight.packet.payload.len());
+        let held = state.pending.iter().chain(state.backlog.iter()).map(|pending| pending.payload.len());
+        let sizes: Vec<usize> = sent.chain(held).collect();
+        (sizes.len(), sizes.iter().sum())
+    }
+
+    /// Holds back a delivery until the inflight window has room. Returns the
+    /// number of deliveries now waiting
+    pub fn queue_pending(&self, pending: Pending) -> usize {
+        let mut state = self.state.borrow_mut();
+        state.pending.push_back(pending);
+        state.pending.len()
+    }
+
+    /// Everything sent but not acknowledged and everything held back, as
+    /// publishes to queue for the session. Oldest first, except that the held
+    /// back backlog goes before held back live messages
+    pub fn unfinished(&self) -> Vec<(Box<Publish>, QoS)> {
+        let state = self.state.borrow();
+        let mut sent: Vec<Box<Publish>> = Vec::new();
+        sent.extend(state.outgoing_pub.values().map(|inflight| inflight.packet.clone()));
+        sent.extend(state.outgoing_rec.values().map(|inflight| inflight.packet.clone()));
+        sent.sort_by_key(|publish| publish.pid.map(|PacketIdentifier(pkid)| pkid));
+
+        let mut unfinished: Vec<(Box<Publish>, QoS)> = sent.into_iter()
+            .map(|publish| {
+                     let qos = publish.qos;
+                     (publish, qos)
+                 })
+            .collect();
+        for pending in state.backlog.iter().chain(state.pending.iter()) {
+            let publish = Box::new(Publish {
+                                       dup: false,
+                                       qos: pending.delivery.qos,
+                                       retain: false,
+                                       pid: None,
+                                       topic_name: pending.topic.clone(),
+                                       payload: pending.payload.clone(),
+                                   });
+            unfinished.push((publish, pending.delivery.qos));
+        }
+        unfinished
+    }
+
+    /// Like `unfinished`, but the messages are taken off the connection
+    pub fn take_unfinished(&self) -> Vec<(Box<Publish>, QoS)> {
+        let unfinished = self.unfinished();
+        let mut state = self.state.borrow_mut();
+        state.outgoing_pub.clear();
+        state.outgoing_rec.clear();
+        state.backlog.clear();
+        state.pending.clear();
+        unfinished
+    }
+
+    /// Holds back a delivery of the offline backlog until `next_pending` picks it
+    pub fn queue_backlog(&self, pending: Pending) {
+        self.state.borrow_mut().backlog.push_back(pending);
+    }
+
+    pub fn has_pending(&self) -> bool {
+        let state = self.state.borrow();
+        !state.pending.is_empty() || !state.backlog.is_empty()
+    }
+
+    /// Next held back delivery, from the backlog or the live messages as
+    /// `policy` says. A live message never overtakes backlog on its topic
+    pub fn next_pending(&self, policy: DrainPolicy) -> Option<Pending> {
+        let mut state = self.state.borrow_mut();
+        let live_turn = !state.pending.is_empty() &&
+                        match policy {
+                            DrainPolicy::BacklogFirst => state.backlog.is_empty(),
+                            DrainPolicy::LiveFirst => true,
+                            DrainPolicy::Interleaved(n) => state.backlog.is_empty() || state.backlog_streak >= n,
+                        };
+
+        if !live_turn {
+            state.backlog_streak += 1;
+            return state.backlog.pop_front();
+        }
+
+        let older = {
+            let topic = &state.pending[0].topic;
+            state.backlog.iter().position(|backlog| backlog.topic == *topic)
+        };
+        match older {
+            Some(i) => state.backlog.remove(i),
+            None => {
+                state.backlog_streak = 0;
+                state.pending.pop_front()
+            }
+        }
+    }
+
+    /// Incoming QoS 2 publishes waiting for PUBREL
+    pub fn incoming_inflight(&self) -> usize {
+        self.state.borrow().incoming_rec.len()
+    }
+
+    /// Remembers the packet id of an incoming QoS 2 publish until the client
+    /// releases it. Returns false if the id is already held, i.e. the publish
+    /// is a redelivery
+    pub fn store_incoming_record(&self, pkid: PacketIdentifier) -> bool {
+        self.state.borrow_mut().incoming_rec.insert(pkid)
+    }
+
+    pub fn remove_incoming_record(&self, pkid: PacketIdentifier) -> bool {
+        let removed = self.state.borrow_mut().incoming_rec.remove(&pkid);
+        if !removed {
+            error!(self.logger, "Unsolicited PUBREL packet: {:?}", pkid);
+        }
+        removed
+    }
+
+    /// What to do once the outgoing queue is full. The drop policies hold up
+    /// to `capacity` more frames until the connection catches up, then drop
+    /// publishes. Other packets are always held
+    pub fn set_outgoing_overflow(&self, policy: OverflowPolicy, capacity: usize) {
+        let mut state = self.state.borrow_mut();
+        state.outgoing_overflow = policy;
+        state.held_capacity = capacity;
+    }
+
+    pub fn set_last_will(&self, last_will: Option<LastWill>) {
+        self.state.borrow_mut().last_will = last_will;
+    }
+
+    pub fn take_last_will(&self) -> Option<LastWill> {
+        self.state.borrow_mut().last_will.take()
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.state.borrow().dead
+    }
+
+    /// Queues the packet for the network. Fails if the connection is gone or
+    /// isn't keeping up, after which the client is marked dead
+    pub fn send(&self, packet: Packet) -> Result<()> {
+        self.send_frame(Frame::Packet(packet))
+    }
+
+    /// Sends a packet that is already encoded, sharing the encoding with the
+    /// other receivers of `encoded`
+    pub fn send_encoded(&self, encoded: &Bytes) -> Result<()> {
+        self.send_frame(Frame::Encoded(encoded.clone()))
+    }
+
+    fn send_frame(&self, frame: Frame) -> Result<()> {
+        if self.is_dead() {
+            return Err(Error::Disconnected);
+        }
+
+        if self.state.borrow().outgoing_overflow != OverflowPolicy::Disconnect {
+            return self.send_or_hold(frame);
+        }
+
+        let e = match self.tx.borrow_mut().as_mut().map(|tx| tx.try_send(frame)) {
+            Some(Ok(())) => {
+                self.state.borrow_mut().queued += 1;
+                return Ok(());
+            }
+            Some(Err(ref e)) if e.is_full() => Error::QueueFull,
+            _ => Error::Disconnected,
+        };
+
+        self.state.borrow_mut().dead = true;
+        Err(e)
+    }
+
+    /// Queues `frame` behind the held frames, holding it as well while the
+    /// queue is full. Past the held capacity a publish is dropped, the new
+    /// one or the oldest held one as the overflow policy says
+    fn send_or_hold(&self, frame: Frame) -> Result<()> {
+        self.flush_held()?;
+
+        let mut state = self.state.borrow_mut();
+        let frame = if state.held.is_empty() {
+            match self.tx.borrow_mut().as_mut().map(|tx| tx.try_send(frame)) {
+                Some(Ok(())) => {
+                    state.queued += 1;
+                    return Ok(());
+                }
+                Some(Err(ref e)) if !e.is_full() => {
+                    state.dead = true;
+                    return Err(Error::Disconnected);
+                }
+                Some(Err(e)) => e.into_inner(),
+                None => {
+                    state.dead = true;
+                    return Err(Error::Disconnected);
+                }
+            }
+        } else {
+            frame
+        };
+
+        state.held.push_back(frame);
+        if state.held.len() > state.held_capacity {
+            let dropped = match state.outgoing_overflow {
+                OverflowPolicy::DropNew => state.held.iter().rposition(is_publish),
+                _ => state.held.iter().position(is_publish),
+            };
+            if let Some(index) = dropped {
+                state.held.remove(index);
+                state.dropped += 1;
+                debug!(self.logger, "Outgoing queue full. Dropped a publish. ID = {:?}", self.id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Called by the connection each time it takes a frame off the outgoing
+    /// queue. Moves held frames in behind it
+    pub fn frame_written(&self) -> Result<()> {
+        {
+            let mut state = self.state.borrow_mut();
+            state.queued = state.queued.saturating_sub(1);
+            state.last_write = self.clock.now();
+        }
+        self.flush_held()
+    }
+
+    /// Frames waiting to be written and how long the connection has been
+    /// stalled on them
+    pub fn backlog(&self) -> Backlog {
+        let state = self.state.borrow();
+        let depth = state.queued + state.held.len();
+        let stall = if depth > 0 {
+            self.clock.now().duration_since(state.last_write)
+        } else {
+            Duration::from_secs(0)
+        };
+
+        Backlog {
+            depth: depth,
+            stall: stall,
+        }
+    }
+
+    /// Moves held frames to the outgoing queue while it has room
+    pub fn flush_held(&self) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        if state.held.is_empty() {
+            return Ok(());
+        }
+
+        let mut tx = self.tx.borrow_mut();
+        let tx = match tx.as_mut() {
+            Some(tx) => tx,
+            None => {
+                state.dead = true;
+                return Err(Error::Disconnected);
+            }
+        };
+        while let Some(frame) = state.held.pop_front() {
+            if let Err(e) = tx.try_send(frame) {
+                if !e.is_full() {
+                    state.dead = true;
+                    return Err(Error::Disconnected);
+                }
+                state.held.push_front(e.into_inner());
+                break;
+            }
+            state.queued += 1;
+        }
+        Ok(())
+    }
+
+    pub fn suback_packet(&self, pkid: PacketIdentifier, return_codes: Vec<SubscribeReturnCodes>) -> Box<Suback> {
+
+        Box::new(Suback {
+                     pid: pkid,
+                     return_codes: return_codes,
+                 })
+    }
+
+    pub fn publish_packet(&self, topic: &str, payload: Arc<Vec<u8>>, delivery: Delivery) -> Box<Publish> {
+
+        let pkid = if delivery.qos == QoS::AtMostOnce {
+            None
+        } else {
+            Some(self.next_pkid())
+        };
+
+        Box::new(Publish {
+                     dup: delivery.dup,
+                     qos: delivery.qos,
+                     retain: delivery.retain,
+                     pid: pkid,
+                     topic_name: topic.to_owned(),
+                     payload: payload.clone(),
+                 })
+
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use futures::sync::mpsc::{self, Receiver};
+    use futures::Stream;
+    use super::{outgoing_queue, Client};
+    use codec::Frame;
+    use config::OverflowPolicy;
+    use error::Error;
+    use mqtt3::*;
+
+    fn mock_client() -> (Client, Receiver<Frame>) {
+        let (tx, rx) = mpsc::channel::<Frame>(8);
+        (Client::new("mock-client", "127.0.0.1:80".parse().unwrap(), tx), rx)
+    }
+
+
+    #[test]
+    fn sends_fail_once_the_connection_is_gone() {
+        let (client, rx) = mock_client();
+        client.send(Packet::Pingresp).unwrap();
+
+        drop(rx);
+        match client.send(Packet::Pingresp) {
+            Err(Error::Disconnected) => (),
+            v => panic!("Expected a disconnection error. Got {:?}", v),
+        }
+        assert!(client.is_dead());
+    }
+
+    #[test]
+    fn close_flushes_queued_packets_and_ends_the_queue() {
+        let (client, rx) = mock_client();
+        let other = client.clone();
+        client.send(Packet::Pingresp).unwrap();
+
+        client.close();
+        assert!(other.send(Packet::Pingresp).is_err());
+
+        // queue ends even though clones of the client are still around
+        let received: Vec<Frame> = rx.wait().map(|p| p.unwrap()).collect();
+        assert_eq!(received, vec![Frame::Packet(Packet::Pingresp)]);
+    }
+
+    #[test]
+    fn full_queues_follow_the_overflow_policy() {
+        // the pingresp is held too, pushing out another publish
+        for &(policy, ref expected) in [(OverflowPolicy::DropOldest, vec![1, 2, 5, 0]), (OverflowPolicy::DropNew, vec![1, 2, 3, 0])].iter() {
+            let (tx, rx) = outgoing_queue(2);
+            let client = Client::new("mock-client", "127.0.0.1:80".parse().unwrap(), tx);
+            client.set_outgoing_overflow(policy, 2);
+
+            for len in 1..6 {
+                client.send(big_publish(len)).unwrap();
+            }
+            client.send(Packet::Pingresp).unwrap();
+            assert_eq!(client.stats().dropped, 2);
+
+            let mut received = Vec::new();
+            let mut rx = rx.wait();
+            while received.len() < 4 {
+                match rx.next().unwrap().unwrap() {
+                    Frame::Packet(Packet::Publish(publish)) => received.push(publish.payload.len()),
+                    Frame::Packet(Packet::Pingresp) => received.push(0),
+                    frame => panic!("Expected a publish. Got {:?}", frame),
+                }
+                client.frame_written().unwrap();
+            }
+
+            assert_eq!(&received, expected);
+            assert_eq!(client.backlog().depth, 0);
+            assert!(!client.is_dead());
+        }
+    }
+
+    #[test]
+    fn sends_fail_when_the_client_stops_reading() {
+        let (client, _rx) = mock_client();
+
+        let mut result = Ok(());
+        for _ in 0..100 {
+            result = client.send(Packet::Pingresp);
+            if result.is_err() {
+                break;
+            }
+        }
+
+        match result {
+            Err(Error::QueueFull) => (),
+            v => panic!("Expected a full queue error. Got {:?}", v),
+        }
+        assert!(client.is_dead());
+        assert!(client.send(Packet::Pingresp).is_err());
+    }
+
+    #[test]
+    fn keep_alive_expires_after_one_and_a_half_intervals() {
+        let (client, _rx) = mock_client();
+        assert!(!client.keep_alive_expired());
+
+        client.set_keep_alive(10);
+        client.state.borrow_mut().last_activity = Instant::now() - Duration::from_secs(14);
+        assert!(!client.keep_alive_expired());
+
+        client.state.borrow_mut().last_activity = Instant::now() - Duration::from_secs(16);
+        assert!(client.keep_alive_expired());
+
+        // any packet from the client resets the timer
+        client.update_activity();
+        assert!(!client.keep_alive_expired());
+
+        client.set_keep_alive(0);
+        client.state.borrow_mut().last_activity = Instant::now() - Duration::from_secs(1000);
+        assert!(!client.keep_alive_expired());
+    }
+
+    #[test]
+    fn incoming_pkids_are_remembered_within_the_window() {
+        let (client, ..) = mock_client();
+
+        for i in 0..4 {
+            assert!(!client.record_incoming_pkid(PacketIdentifier(i), 4));
+        }
+        assert!(client.record_incoming_pkid(PacketIdentifier(0), 4));
+
+        // 0 falls out of the window
+        assert!(!client.record_incoming_pkid(PacketIdentifier(4), 4));
+        assert!(!client.record_incoming_pkid(PacketIdentifier(0), 4));
+    }
+
+    #[test]
+    fn next_pkid_roll() {
+        let (client, ..) = mock_client();
+        let mut pkid = PacketIdentifier(0);
+        for _ in 0..65536 {
+            pkid = client.next_pkid();
+        }
+        assert_eq!(PacketIdentifier(1), pkid);
+    }
+
+    #[test]
+    fn add_and_remove_of_message_from_publish_queue() {
+        let (client, ..) = mock_client();
+
+        for i in 0..100 {
+            let publish = Box::new(Publish {
+                                       dup: false,
+                                       qos: QoS::AtLeastOnce,
+                                       retain: false,
+                                       pid: Some(PacketIdentifier(i)),
+                                       topic_name: "hello/world".to_owned(),
+                                       payload: Arc::new(vec![1, 2, 3]),
+                                   });
+
+            client.store_publish(publish);
+        }
+
+        // sequential remove
+        for i in 0..10 {
+            assert!(client.remove_publish(PacketIdentifier(i)).is_some());
+        }
+
+        {
+            let state = client.state.borrow();
+            for i in 0..10 {
+                assert!(!state.outgoing_pub.contains_key(&PacketIdentifier(i)));
+            }
+        }
+
+        // big sequential remove
+        for i in 10..90 {
+            client.remove_publish(PacketIdentifier(i));
+        }
+
+        {
+            let state = client.state.borrow();
+            for i in 10..90 {
+                assert!(!state.outgoing_pub.contains_key(&PacketIdentifier(i)));
+            }
+        }
+
+        // intermediate removes
+        for i in [91_u16, 93, 95, 97, 99].iter() {
+            client.remove_publish(PacketIdentifier(*i));
+        }
+
+        // removing twice isn't an error but there's nothing left to remove
+        assert!(client.remove_publish(PacketIdentifier(91)).is_none());
+
+        {
+            let state = client.state.borrow();
+            let remaining: Vec<u16> = state.outgoing_pub.keys().map(|pkid| pkid.0).collect();
+            assert_eq!(remaining, vec![90, 92, 94, 96, 98]);
+        }
+    }
+}
@@ -0,0 +1,53 @@
+//! Time source of the broker. Keep alive, retransmission, session and self
+//! test timestamps are all read from a `Clock` so tests can move time
+//! forward instead of sleeping. Timers still fire on real time and only
+//! decide when the checks run
+
+use std::cell::Cell;
+use std::fmt::Debug;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+pub trait Clock: Debug {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Clock that only moves when told to. Clones share the same time
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Rc<Cell<Instant>>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock { now: Rc::new(Cell::new(Instant::now())) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now.set(self.now.get() + by);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+}
+
+pub fn system() -> Rc<Clock> {
+    Rc::new(SystemClock)
+}
+
+/// Whole milliseconds of `duration`, as diagnostics report them
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_nanos() / 1_000_000)
+}
@@ -0,0 +1,241 @@
+use std::io::{self, ErrorKind, Cursor};
+use std::error::Error;
+use std::fmt;
+use bytes::{Bytes, BytesMut};
+use tokio_io::codec::{Encoder, Decoder};
+
+use mqtt3::{self, Packet, MqttWrite, MqttRead};
+#[cfg(feature = "websocket")]
+use ws::WsCodec;
+
+/// Largest packet MQTT can frame: 1 byte fixed header, 4 bytes of remaining
+/// length and 268435455 bytes of remaining data
+pub const MAX_PACKET_SIZE: usize = 268_435_460;
+
+pub struct MqttCodec {
+    /// Largest incoming packet the broker accepts
+    max_packet_size: usize,
+}
+
+impl MqttCodec {
+    pub fn new(max_packet_size: usize) -> Self {
+        MqttCodec { max_packet_size: max_packet_size }
+    }
+}
+
+/// Decode error for a CONNECT with a protocol level the broker doesn't speak.
+/// Lets the handshake answer with CONNACK instead of just closing
+#[derive(Debug)]
+pub struct UnsupportedProtocolVersion;
+
+impl fmt::Display for UnsupportedProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unsupported protocol version")
+    }
+}
+
+impl Error for UnsupportedProtocolVersion {
+    fn description(&self) -> &str {
+        "unsupported protocol version"
+    }
+}
+
+/// True if the decode error is an `UnsupportedProtocolVersion`
+pub fn is_unsupported_protocol_version(e: &io::Error) -> bool {
+    match e.get_ref() {
+        Some(inner) => inner.is::<UnsupportedProtocolVersion>(),
+        None => false,
+    }
+}
+
+/// What gets written to a connection
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Packet(Packet),
+    /// Packet that is already encoded. Cloning is cheap which lets fan-out of
+    /// identical packets share one encoding across all the connections
+    Encoded(Bytes),
+    /// Liveness probe. A ping on WebSocket connections, nothing on plain ones
+    Probe,
+}
+
+/// Codec of a connection. Lets plain and WebSocket connections share one
+/// connection pipeline
+pub enum Transport {
+    Tcp(MqttCodec),
+    #[cfg(feature = "websocket")]
+    WebSocket(WsCodec),
+}
+
+impl Decoder for Transport {
+    type Item = Packet;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Packet>> {
+        match *self {
+            Transport::Tcp(ref mut codec) => codec.decode(buf),
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(ref mut codec) => codec.decode(buf),
+        }
+    }
+}
+
+impl Encoder for Transport {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: Frame, buf: &mut BytesMut) -> io::Result<()> {
+        match *self {
+            Transport::Tcp(ref mut codec) => codec.encode(msg, buf),
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(ref mut codec) => codec.encode(msg, buf),
+        }
+    }
+}
+
+impl From<Packet> for Frame {
+    fn from(packet: Packet) -> Frame {
+        Frame::Packet(packet)
+    }
+}
+
+/// Encodes the packet into the bytes that go on the wire
+pub fn encode(packet: &Packet) -> io::Result<Bytes> {
+    let mut stream = Cursor::new(Vec::new());
+
+    // TODO: Implement `write_packet` for `&mut BytesMut`
+    if let Err(_) = stream.write_packet(packet) {
+        return Err(io::Error::new(io::ErrorKind::Other, "Unable to encode!"));
+    }
+
+    Ok(Bytes::from(stream.into_inner()))
+}
+
+/// Size of the packet on the wire
+pub fn encoded_len(packet: &Packet) -> io::Result<usize> {
+    encode(packet).map(|bytes| bytes.len())
+}
+
+/// Size on the wire of the packet at the start of `buf`, read from its fixed
+/// header. `None` until the whole remaining length has arrived
+fn frame_len(buf: &[u8]) -> io::Result<Option<usize>> {
+    let mut remaining = 0;
+    for (i, byte) in buf.iter().skip(1).take(4).enumerate() {
+        remaining |= ((byte & 0x7F) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some(1 + i + 1 + remaining));
+        }
+    }
+
+    // the remaining length is at most 4 bytes
+    if buf.len() > 4 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Malformed remaining length"));
+    }
+
+    Ok(None)
+}
+
+impl Decoder for MqttCodec {
+    type Item = Packet;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Packet>> {
+        // NOTE: `decode` might be called with `buf.len == 0` when prevous
+        // decode call read all the bytes in the stream. We should return
+        // Ok(None) in those cases or else the `read` call will return
+        // Ok(0) => translated to UnexpectedEOF by `byteorder` crate.
+        // `read` call Ok(0) happens when buffer specified was 0 bytes in len
+        // https://doc.rust-lang.org/std/io/trait.Read.html#tymethod.read
+        if buf.len() == 0 {
+            return Ok(None);
+        }
+
+        // refuse oversized packets from their fixed header, before the rest
+        // of them is read into `buf`
+        let len = match frame_len(buf)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+
+        if len > self.max_packet_size {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Packet exceeds maximum packet size"));
+        }
+
+        // NOTE: It's possible that `decode` got called before `buf` has full bytes
+        // necessary to frame raw bytes into a packet. In that case return Ok(None)
+        // and the next time decode` gets called, there will be more bytes in `buf`,
+        // hopefully enough to frame the packet
+        if buf.len() < len {
+            return Ok(None)
+        }
+
+        let (packet, len) = {
+            let mut buf_ref = buf.as_ref();
+            match buf_ref.read_packet_with_len() {
+                Err(e) => {
+                    if let mqtt3::Error::Io(e) = e {
+                        match e.kind() {
+                            ErrorKind::TimedOut | ErrorKind::WouldBlock => return Ok(None),
+                            _ => return Err(io::Error::new(e.kind(), e.description())),
+                        }
+                    } else if let mqtt3::Error::UnsupportedProtocolVersion = e {
+                        return Err(io::Error::new(ErrorKind::InvalidData, UnsupportedProtocolVersion));
+                    } else {
+                        return Err(io::Error::new(ErrorKind::Other, e.description()));
+                    }
+                }
+                Ok(v) => v,
+            }
+        };
+
+        // println!("{:?}, {:?}, {:?}", len, packet, buf.len());
+
+        buf.split_to(len);
+
+        Ok(Some(packet))
+    }
+}
+
+impl Encoder for MqttCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: Frame, buf: &mut BytesMut) -> io::Result<()> {
+        match msg {
+            Frame::Packet(packet) => buf.extend_from_slice(&encode(&packet)?),
+            Frame::Encoded(bytes) => buf.extend_from_slice(&bytes),
+            Frame::Probe => (),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+    use tokio_io::codec::Decoder;
+    use mqtt3::Packet;
+    use super::{encode, MqttCodec};
+
+    #[test]
+    fn oversized_packets_are_refused_from_the_fixed_header() {
+        let mut codec = MqttCodec::new(64);
+
+        // publish with a remaining length of 65535. only the fixed header has arrived
+        let mut buf = BytesMut::from(&[0x30, 0xFF, 0xFF, 0x03][..]);
+        assert!(codec.decode(&mut buf).is_err());
+
+        // remaining length cut short
+        let mut buf = BytesMut::from(&[0x30, 0xFF][..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        // more than 4 bytes of remaining length
+        let mut buf = BytesMut::from(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x01][..]);
+        assert!(codec.decode(&mut buf).is_err());
+
+        let mut buf = BytesMut::from(&encode(&Packet::Pingreq).unwrap()[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Packet::Pingreq));
+        assert!(buf.is_empty());
+    }
+}
@@ -0,0 +1,1693 @@
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+use mqtt3::QoS;
+use slog::Level;
+
+use auth::{AllowAll, Authenticator};
+use broker::Broker;
+use clock::{self, Clock};
+use codec::MAX_PACKET_SIZE;
+use error::{Error, Result};
+use group::GroupConfig;
+use birth::BirthConfig;
+use slow::SlowConsumerConfig;
+use rate::RateLimit;
+use leaf::LeafConfig;
+use bridge::{BridgeConfig, BridgeTopic};
+use observer::ObserverConfig;
+use listener::{ListenerConfig, ListenerKind};
+use logging::{LogOutput, Rotation, RotatingFile};
+use selftest::SelfTestConfig;
+use sim::SimConfig;
+use batch::BatchConfig;
+use share::SharePolicy;
+use registry::TopicRegistry;
+use acl::{Acl, UnauthorizedPublish};
+use fair::{self, WriteWeight};
+#[cfg(feature = "password-file")]
+use passwd::PasswordFile;
+#[cfg(feature = "webhook-auth")]
+use webhook::{WebhookAuth, WebhookConfig};
+#[cfg(feature = "jwt")]
+use jwt::{JwtAuth, JwtConfig};
+#[cfg(feature = "connectors")]
+use http;
+use storage::{StoragePolicy, StorageRule};
+use debounce::DebounceRule;
+#[cfg(feature = "metrics")]
+use alert::AlertRule;
+#[cfg(feature = "tls")]
+use tls::{CertIdentity, ClientAuth, TlsConfig};
+#[cfg(feature = "enrichment")]
+use enrich::EnrichConfig;
+#[cfg(feature = "kafka")]
+use kafka::KafkaConfig;
+
+/// What to do when a client reuses the packet id of one of its recent QoS 1
+/// publishes
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum DuplicatePkidPolicy {
+    /// Treat it as a retransmission. It's acknowledged again but not forwarded
+    Dedupe,
+    /// Treat it as a protocol error and disconnect the client
+    Disconnect,
+}
+
+/// Order in which a resumed persistent session gets its offline backlog and
+/// the live messages arriving meanwhile. Only matters while the inflight
+/// window is full, and messages on one topic always keep their order
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum DrainPolicy {
+    /// Live messages wait until the backlog is delivered
+    BacklogFirst,
+    /// Backlog messages only go out while no live message is waiting
+    LiveFirst,
+    /// This many backlog messages for every live one
+    Interleaved(u32),
+}
+
+/// What to do with a message for a full queue
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued message to make room
+    DropOldest,
+    /// Keep the queue as is and drop the new message
+    DropNew,
+    /// Close the connection the queue belongs to. Only for outgoing queues
+    Disconnect,
+}
+
+/// Setting whose effective value differs from the configured one
+#[derive(Debug, Clone, PartialEq)]
+pub struct SettingChange {
+    pub name: &'static str,
+    /// `None` for settings that only exist at runtime
+    pub configured: Option<String>,
+    /// `None` for settings that were dropped at runtime
+    pub effective: Option<String>,
+}
+
+/// `off` for unset options, their value otherwise
+fn optional<T: Debug>(value: Option<T>) -> String {
+    value.map_or("off".to_owned(), |v| format!("{:?}", v))
+}
+
+/// How an acl shows up in the settings
+pub fn acl_setting(acl: Option<&Acl>) -> String {
+    acl.map_or("off".to_owned(), |acl| format!("{} rules, default {:?}", acl.rules().len(), acl.default_permission()))
+}
+
+/// How a topic registry shows up in the settings
+pub fn registry_setting(registry: Option<&TopicRegistry>) -> String {
+    registry.map_or("off".to_owned(), |registry| format!("{} templates", registry.templates().len()))
+}
+
+/// Settings of `effective` that differ from `configured`, in the order of
+/// `configured` followed by the ones only `effective` has
+pub fn diff(configured: &[(&'static str, String)], effective: &[(&'static str, String)]) -> Vec<SettingChange> {
+    let value = |settings: &[(&'static str, String)], name| settings.iter().find(|s| s.0 == name).map(|s| s.1.clone());
+
+    let mut changes: Vec<SettingChange> = configured
+        .iter()
+        .filter_map(|&(name, ref before)| match value(effective, name) {
+                        Some(ref after) if after == before => None,
+                        after => {
+                            Some(SettingChange {
+                                     name: name,
+                                     configured: Some(before.clone()),
+                                     effective: after,
+                                 })
+                        }
+                    })
+        .collect();
+
+    for &(name, ref after) in effective.iter() {
+        if value(configured, name).is_none() {
+            changes.push(SettingChange {
+                             name: name,
+                             configured: None,
+                             effective: Some(after.clone()),
+                         });
+        }
+    }
+
+    changes
+}
+
+/// Broker settings
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct BrokerConfig {
+    /// Listeners served side by side. A plain TCP listener on port 1883 by default
+    pub listeners: Vec<ListenerConfig>,
+    /// Least severe level the broker logs. Info by default
+    pub log_level: Level,
+    /// Format of the log. The terminal format by default
+    pub log_output: LogOutput,
+    /// Log to this file instead of stderr. Opened by `BrokerBuilder::build`
+    pub log_file: Option<PathBuf>,
+    /// When the log file is rotated. At 10MB by default
+    pub log_rotation: Rotation,
+    /// Rotated log files kept. 5 by default
+    pub log_keep: usize,
+    /// Certificate of the TLS listeners
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+    /// Client certificates required on the TLS listeners
+    #[cfg(feature = "tls")]
+    pub client_auth: Option<ClientAuth>,
+    /// Connections served at once by all listeners together. Connections
+    /// beyond it are closed as soon as they're accepted, like those beyond a
+    /// listener's own limit. `None` only applies the listener limits
+    pub max_connections: Option<usize>,
+    /// How fast connections are accepted by all listeners together.
+    /// Connections over the rate are closed as soon as they're accepted
+    pub connect_rate: Option<RateLimit>,
+    /// How fast connections are accepted from one source ip
+    pub connect_rate_per_ip: Option<RateLimit>,
+    /// Largest incoming packet accepted
+    pub max_packet_size: usize,
+    /// Number of packets queued for a connection. A connection that falls
+    /// further behind is handled by `outgoing_overflow`
+    pub outgoing_queue_size: usize,
+    /// What happens to a connection whose outgoing queue is full. The drop
+    /// policies hold up to another `outgoing_queue_size` packets before
+    /// dropping publishes. Defaults to closing the connection
+    pub outgoing_overflow: OverflowPolicy,
+    /// Reports connections that stay behind on their outgoing queue. `None`
+    /// doesn't check the queues
+    pub slow_consumer: Option<SlowConsumerConfig>,
+    /// Track published topics in the topic catalog
+    pub catalog: bool,
+    /// How long a new connection with an already connected client id waits
+    /// for the existing connection to answer a probe before taking over. Only
+    /// WebSocket connections can be pinged, plain and TLS ones answer with
+    /// whatever they send on their own. `None` takes over immediately
+    pub takeover_grace: Option<Duration>,
+    /// Fleet wide command topics with precomputed delivery lists
+    pub groups: Vec<GroupConfig>,
+    /// Published for every client once it's connected. `None` doesn't publish one
+    pub birth: Option<BirthConfig>,
+    /// Handling of QoS 1 publishes that reuse a recently seen packet id.
+    /// `None` treats every publish as a new message
+    pub duplicate_pkid_policy: Option<DuplicatePkidPolicy>,
+    /// Number of recent QoS 1 packet ids remembered per client for the
+    /// duplicate check
+    pub duplicate_pkid_window: usize,
+    /// Highest qos granted to subscriptions. Requests above it are downgraded
+    pub max_qos: QoS,
+    /// Keep the retain flag of publishes forwarded to existing subscriptions.
+    /// Cleared by default as MQTT 3.1.1 requires
+    pub retain_as_published: bool,
+    /// Longest client id accepted from MQTT 3.1.1 clients. `None` accepts any length
+    pub max_client_id_len: Option<usize>,
+    /// Accept clients speaking MQTT 3.1, protocol name `MQIsdp` at level 3.
+    /// Refused with an unacceptable protocol version otherwise
+    pub allow_mqtt31: bool,
+    /// QoS 1 and 2 messages queued per persistent session while its client is
+    /// offline. The backlog is written in one go on reconnect so it has to fit
+    /// in the outgoing queue
+    pub offline_queue_size: usize,
+    pub offline_overflow: OverflowPolicy,
+    /// How long a message may sit in an offline queue before it's dropped
+    /// instead of delivered. Messages restored from the write ahead log count
+    /// from when they were restored. `None` keeps them until they're delivered
+    pub offline_message_ttl: Option<Duration>,
+    /// How long a persistent session is kept after its client disconnects.
+    /// Sessions restored from the write ahead log count from when they were
+    /// restored. `None` keeps them until their clients come back
+    pub session_expiry: Option<Duration>,
+    /// QoS 1 and 2 messages held per session, counting unacknowledged and
+    /// held back ones for connected clients and the offline queue otherwise.
+    /// Messages past it are dropped. `None` leaves it to the queue sizes
+    pub max_queued_messages: Option<usize>,
+    /// Payload bytes held per session, counted like `max_queued_messages`
+    pub max_queued_bytes: Option<usize>,
+    /// How long an outgoing QoS 1 or 2 packet waits for its acknowledgement
+    /// before it's resent. `None` disables retransmission
+    pub retransmit_interval: Option<Duration>,
+    /// Retransmissions of a packet before it's given up on
+    pub max_retransmits: u32,
+    /// Outgoing QoS 1 and 2 messages per client waiting for their
+    /// acknowledgements. Further messages are held back until slots free up.
+    /// Clients with more unreleased QoS 2 publishes of their own are
+    /// disconnected. `None` doesn't limit either direction
+    pub max_inflight: Option<usize>,
+    /// Frames a connection writes per turn before the other connections get
+    /// theirs. `None` lets a connection write all it has queued
+    pub write_quantum: Option<usize>,
+    /// Connections getting more than one quantum per turn
+    pub write_weights: Vec<WriteWeight>,
+    /// Periodic round trip probe through an internal subscriber. `None` disables it
+    pub selftest: Option<SelfTestConfig>,
+    /// Virtual clients run inside the broker for demos and reproductions
+    pub simulation: Option<SimConfig>,
+    /// Hub this broker is a leaf node of. `None` runs standalone
+    pub leaf: Option<LeafConfig>,
+    /// UDP address of the MQTT-SN gateway. `None` runs without one
+    pub sn_gateway: Option<SocketAddr>,
+    /// Primary this broker is a read only observer of. `None` takes publishes
+    pub observer: Option<ObserverConfig>,
+    /// Remote broker topics are bridged with. `None` runs without a bridge
+    pub bridge: Option<BridgeConfig>,
+    /// Topics passed between this broker and the remote broker of the bridge
+    pub bridge_topics: Vec<BridgeTopic>,
+    /// How often topic/subscriber pairs that are systematically forwarded
+    /// below their publish qos are reported. `None` doesn't track downgrades
+    pub downgrade_report: Option<Duration>,
+    /// Batched deliveries to `$batch/` subscriptions. `None` treats `$batch/`
+    /// filters like any other
+    pub batch: Option<BatchConfig>,
+    /// Which member of a `$share/` group gets a publish
+    pub share_policy: SharePolicy,
+    /// Messages a `$txn/` group may hold. `None` treats `$txn/` topics like any other
+    pub transactions: Option<usize>,
+    /// Strict mode. Only topics declared in the registry can be published or
+    /// subscribed to. `None` allows any topic
+    pub topic_registry: Option<TopicRegistry>,
+    /// Mosquitto style `username:bcrypt hash` file every CONNECT is checked
+    /// against. Loaded by `BrokerBuilder::build`
+    pub password_file: Option<PathBuf>,
+    /// HTTP endpoint of an identity service every CONNECT is checked against
+    #[cfg(feature = "webhook-auth")]
+    pub webhook: Option<WebhookConfig>,
+    /// Keys and required claims of the JWTs clients send as passwords
+    #[cfg(feature = "jwt")]
+    pub jwt: Option<JwtConfig>,
+    /// Read and write rules of clients on topics. `None` allows everything
+    pub acl: Option<Acl>,
+    /// What happens to publishes the acl denies
+    pub unauthorized_publish: UnauthorizedPublish,
+    /// False refuses CONNECTs without a username with not authorized
+    pub allow_anonymous: bool,
+    /// Acl user whose rules apply to anonymous clients. `None` leaves them
+    /// to the rules for anonymous clients
+    pub anonymous_role: Option<String>,
+    /// Storage policies per topic prefix. Topics without one are kept in memory only
+    pub storage: Vec<StorageRule>,
+    /// Write ahead log for the `Wal` prefixes. Opened by `BrokerBuilder::build`
+    pub wal_path: Option<PathBuf>,
+    /// Read the write ahead log in the background instead of before `build` returns
+    pub wal_background_load: bool,
+    /// How often the sessions of connected persistent clients are written to
+    /// the write ahead log, with their unacknowledged and held back messages
+    /// on `Wal` prefixes. `None` only logs sessions once they're offline
+    pub session_checkpoint: Option<Duration>,
+    /// Where state dumps go. The system's temporary directory by default
+    pub dump_dir: Option<PathBuf>,
+    /// How long a shutdown waits for open qos 1 and 2 flows before closing the connections
+    pub shutdown_timeout: Duration,
+    /// Order of the offline backlog and live messages after a session resumes
+    pub backlog_drain: DrainPolicy,
+    /// Shortest time between deliveries per topic prefix. Publishes in between
+    /// are conflated into the latest
+    pub debounce: Vec<DebounceRule>,
+    /// How often the `$SYS` time and keep alive diagnostics are published. `None` disables them
+    pub sys_interval: Option<Duration>,
+    /// Publish connects and disconnects on `$SYS/broker/connection/<id>/state`
+    pub connection_events: bool,
+    /// Alerting rules over broker metrics
+    #[cfg(feature = "metrics")]
+    pub alerts: Vec<AlertRule>,
+    /// How often the alerting rules are evaluated
+    pub alert_interval: Duration,
+    /// Where alerts are published
+    pub alert_topic: Option<String>,
+    /// `http://` endpoint alerts are POSTed to
+    pub alert_webhook: Option<String>,
+    /// `http://` endpoint publishes matching `message_webhook_filters` are POSTed to
+    pub message_webhook: Option<String>,
+    pub message_webhook_filters: Vec<String>,
+    /// Messages POSTed per second. `None` POSTs every matching publish
+    pub message_webhook_rate: Option<RateLimit>,
+    /// Attempts after a failed POST
+    pub message_webhook_retries: usize,
+    /// Reverse DNS and GeoIP lookups of client addresses. `None` disables them
+    #[cfg(feature = "enrichment")]
+    pub enrichment: Option<EnrichConfig>,
+    /// Kafka topics publishes are mirrored into. `None` mirrors nothing
+    #[cfg(feature = "kafka")]
+    pub kafka: Option<KafkaConfig>,
+}
+
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        BrokerConfig {
+            listeners: vec![ListenerConfig::new("0.0.0.0:1883".parse().unwrap(), ListenerKind::Tcp)],
+            log_level: Level::Info,
+            log_output: LogOutput::Terminal,
+            log_file: None,
+            log_rotation: Rotation::Size(10 << 20),
+            log_keep: 5,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "tls")]
+            client_auth: None,
+            max_connections: None,
+            connect_rate: None,
+            connect_rate_per_ip: None,
+            max_packet_size: MAX_PACKET_SIZE,
+            outgoing_queue_size: 100,
+            outgoing_overflow: OverflowPolicy::Disconnect,
+            slow_consumer: None,
+            catalog: false,
+            takeover_grace: None,
+            groups: Vec::new(),
+            birth: None,
+            duplicate_pkid_policy: None,
+            duplicate_pkid_window: 32,
+            max_qos: QoS::ExactlyOnce,
+            retain_as_published: false,
+            max_client_id_len: None,
+            allow_mqtt31: true,
+            offline_queue_size: 50,
+            offline_overflow: OverflowPolicy::DropOldest,
+            offline_message_ttl: None,
+            session_expiry: None,
+            max_queued_messages: None,
+            max_queued_bytes: None,
+            retransmit_interval: Some(Duration::from_secs(20)),
+            max_retransmits: 3,
+            max_inflight: Some(20),
+            write_quantum: Some(32),
+            write_weights: Vec::new(),
+            selftest: None,
+            simulation: None,
+            leaf: None,
+            sn_gateway: None,
+            observer: None,
+            bridge: None,
+            bridge_topics: Vec::new(),
+            downgrade_report: None,
+            batch: None,
+            share_policy: SharePolicy::RoundRobin,
+            transactions: None,
+            topic_registry: None,
+            password_file: None,
+            #[cfg(feature = "webhook-auth")]
+            webhook: None,
+            #[cfg(feature = "jwt")]
+            jwt: None,
+            acl: None,
+            unauthorized_publish: UnauthorizedPublish::Drop,
+            allow_anonymous: true,
+            anonymous_role: None,
+            storage: Vec::new(),
+            wal_path: None,
+            wal_background_load: false,
+            session_checkpoint: None,
+            dump_dir: None,
+            shutdown_timeout: Duration::from_secs(10),
+            backlog_drain: DrainPolicy::BacklogFirst,
+            debounce: Vec::new(),
+            sys_interval: None,
+            connection_events: false,
+            #[cfg(feature = "metrics")]
+            alerts: Vec::new(),
+            alert_interval: Duration::from_secs(10),
+            alert_topic: None,
+            alert_webhook: None,
+            message_webhook: None,
+            message_webhook_filters: Vec::new(),
+            message_webhook_rate: None,
+            message_webhook_retries: 3,
+            #[cfg(feature = "enrichment")]
+            enrichment: None,
+            #[cfg(feature = "kafka")]
+            kafka: None,
+        }
+    }
+}
+
+impl BrokerConfig {
+    /// Numeric limits that are enforced on clients. Every limit listed here
+    /// gets boundary cases in the limit test matrix
+    pub fn limits(&self) -> Vec<(&'static str, usize)> {
+        let mut limits = vec![("max_packet_size", self.max_packet_size),
+                              ("outgoing_queue_size", self.outgoing_queue_size),
+                              ("duplicate_pkid_window", self.duplicate_pkid_window),
+                              ("offline_queue_size", self.offline_queue_size)];
+
+        if let Some(len) = self.max_client_id_len {
+            limits.push(("max_client_id_len", len));
+        }
+
+        if let Some(max) = self.max_inflight {
+            limits.push(("max_inflight", max));
+        }
+
+        if let Some(max) = self.max_connections {
+            limits.push(("max_connections", max));
+        }
+
+        if let Some(max) = self.max_queued_messages {
+            limits.push(("max_queued_messages", max));
+        }
+
+        if let Some(max) = self.max_queued_bytes {
+            limits.push(("max_queued_bytes", max));
+        }
+
+        limits
+    }
+
+    /// Credential check of the configured password file, webhook or JWT
+    /// keys. Everyone is let in without one
+    #[cfg_attr(not(feature = "webhook-auth"), allow(unused_variables))]
+    pub fn authenticator(&self, clock: Rc<Clock>) -> Result<Box<Authenticator>> {
+        #[cfg(feature = "password-file")]
+        {
+            if let Some(ref path) = self.password_file {
+                return Ok(Box::new(PasswordFile::load(path)?));
+            }
+        }
+        #[cfg(feature = "webhook-auth")]
+        {
+            if let Some(ref webhook) = self.webhook {
+                return Ok(Box::new(WebhookAuth::new(webhook.clone(), clock)?));
+            }
+        }
+        #[cfg(feature = "jwt")]
+        {
+            if let Some(ref jwt) = self.jwt {
+                return Ok(Box::new(JwtAuth::new(jwt.clone())?));
+            }
+        }
+        Ok(Box::new(AllowAll))
+    }
+
+    /// Frames the connection of `client_id` writes per turn
+    pub fn write_budget(&self, client_id: &str) -> Option<usize> {
+        self.write_quantum
+            .map(|quantum| fair::budget(quantum, &self.write_weights, client_id))
+    }
+
+    /// Every setting with its value in a readable form, defaults included
+    pub fn settings(&self) -> Vec<(&'static str, String)> {
+        let listeners: Vec<String> = self.listeners
+            .iter()
+            .map(|l| {
+                     let mut listener = format!("{} {:?}", l.address, l.kind);
+                     if let Some(max) = l.max_connections {
+                         listener += &format!(" max_connections={}", max);
+                     }
+                     if l.auth_required {
+                         listener += " auth_required";
+                     }
+                     if let Some(probe) = l.probe {
+                         listener += &format!(" probe={:?}", probe);
+                     }
+                     listener
+                 })
+            .collect();
+        let groups: Vec<String> = self.groups.iter().map(|g| format!("{} {}", g.name, g.topic)).collect();
+        let weights: Vec<String> = self.write_weights
+            .iter()
+            .map(|w| format!("{} {}", w.client_id_prefix, w.weight))
+            .collect();
+        let storage: Vec<String> = self.storage.iter().map(|r| format!("{} {:?}", r.prefix, r.policy)).collect();
+        let bridge_topics: Vec<String> = self.bridge_topics
+            .iter()
+            .map(|t| format!("{} {:?} {}", t.filter, t.direction, t.qos.to_u8()))
+            .collect();
+        let debounce: Vec<String> = self.debounce.iter().map(|r| format!("{} {:?}", r.prefix, r.interval)).collect();
+
+        let settings = vec![("listeners", listeners.join(", ")),
+                            ("log_level", self.log_level.as_str().to_lowercase()),
+                            ("log_output", format!("{:?}", self.log_output)),
+                            ("log_file", optional(self.log_file.as_ref().map(|p| p.display()))),
+                            ("log_rotation", format!("{:?}", self.log_rotation)),
+                            ("log_keep", self.log_keep.to_string()),
+                            ("max_connections", optional(self.max_connections)),
+                            ("connect_rate", optional(self.connect_rate.map(|r| format!("{}/s {}", r.per_second, r.burst)))),
+                            ("connect_rate_per_ip", optional(self.connect_rate_per_ip.map(|r| format!("{}/s {}", r.per_second, r.burst)))),
+                            ("max_packet_size", self.max_packet_size.to_string()),
+                            ("outgoing_queue_size", self.outgoing_queue_size.to_string()),
+                            ("outgoing_overflow", format!("{:?}", self.outgoing_overflow)),
+                            ("slow_consumer", optional(self.slow_consumer.map(|s| format!("{} frames for {:?}, disconnect {}", s.depth, s.duration, s.disconnect)))),
+                            ("catalog", self.catalog.to_string()),
+                            ("takeover_grace", optional(self.takeover_grace)),
+                            ("groups", groups.join(", ")),
+                            ("birth", optional(self.birth.as_ref().map(|b| format!("{} {:?}", b.topic, b.payload)))),
+                            ("duplicate_pkid_policy", optional(self.duplicate_pkid_policy)),
+                            ("duplicate_pkid_window", self.duplicate_pkid_window.to_string()),
+                            ("max_qos", self.max_qos.to_u8().to_string()),
+                            ("retain_as_published", self.retain_as_published.to_string()),
+                            ("max_client_id_len", optional(self.max_client_id_len)),
+                            ("allow_mqtt31", self.allow_mqtt31.to_string()),
+                            ("offline_queue_size", self.offline_queue_size.to_string()),
+                            ("offline_overflow", format!("{:?}", self.offline_overflow)),
+                            ("offline_message_ttl", optional(self.offline_message_ttl)),
+                            ("session_expiry", optional(self.session_expiry)),
+                            ("max_queued_messages", optional(self.max_queued_messages)),
+                            ("max_queued_bytes", optional(self.max_queued_bytes)),
+                            ("retransmit_interval", optional(self.retransmit_interval)),
+                            ("max_retransmits", self.max_retransmits.to_string()),
+                            ("max_inflight", optional(self.max_inflight)),
+                            ("write_quantum", optional(self.write_quantum)),
+                            ("write_weights", weights.join(", ")),
+                            ("selftest", optional(self.selftest.map(|s| s.interval))),
+                            ("simulation", optional(self.simulation.as_ref().map(|s| (s.clients(), s.tick)))),
+                            ("leaf", optional(self.leaf.as_ref().map(|l| l.hub))),
+                            ("sn_gateway", optional(self.sn_gateway)),
+                            ("observer", optional(self.observer.as_ref().map(|o| o.primary))),
+                            ("bridge", optional(self.bridge.as_ref().map(|b| b.remote))),
+                            ("bridge_topics", bridge_topics.join(", ")),
+                            ("downgrade_report", optional(self.downgrade_report)),
+                            ("batch", optional(self.batch.map(|b| (b.interval, b.max_messages)))),
+                            ("share_policy", format!("{:?}", self.share_policy)),
+                            ("transactions", optional(self.transactions)),
+                            ("topic_registry", registry_setting(self.topic_registry.as_ref())),
+                            ("password_file", optional(self.password_file.as_ref().map(|p| p.display()))),
+                            ("acl", acl_setting(self.acl.as_ref())),
+                            ("unauthorized_publish", format!("{:?}", self.unauthorized_publish)),
+                            ("allow_anonymous", self.allow_anonymous.to_string()),
+                            ("anonymous_role", optional(self.anonymous_role.as_ref())),
+                            ("storage", storage.join(", ")),
+                            ("wal_path", optional(self.wal_path.as_ref().map(|p| p.display()))),
+                            ("wal_background_load", self.wal_background_load.to_string()),
+                            ("session_checkpoint", optional(self.session_checkpoint)),
+                            ("dump_dir", optional(self.dump_dir.as_ref().map(|p| p.display()))),
+                            ("shutdown_timeout", format!("{:?}", self.shutdown_timeout)),
+                            ("backlog_drain", format!("{:?}", self.backlog_drain)),
+                            ("debounce", debounce.join(", ")),
+                            ("sys_interval", optional(self.sys_interval)),
+                            ("connection_events", self.connection_events.to_string()),
+                            ("alert_interval", format!("{:?}", self.alert_interval)),
+                            ("alert_topic", optional(self.alert_topic.as_ref())),
+                            ("alert_webhook", optional(self.alert_webhook.as_ref())),
+                            ("message_webhook", optional(self.message_webhook.as_ref())),
+                            ("message_webhook_filters", self.message_webhook_filters.join(", ")),
+                            ("message_webhook_rate", optional(self.message_webhook_rate.map(|r| format!("{}/s {}", r.per_second, r.burst)))),
+                            ("message_webhook_retries", self.message_webhook_retries.to_string())];
+
+        #[cfg(feature = "tls")]
+        let settings: Vec<_> = settings
+            .into_iter()
+            .chain(vec![("tls", optional(self.tls.as_ref().map(|t| t.cert_path.display()))),
+                        ("client_auth", optional(self.client_auth.as_ref().map(|c| c.identity)))])
+            .collect();
+
+        #[cfg(feature = "webhook-auth")]
+        let settings: Vec<_> = settings
+            .into_iter()
+            .chain(Some(("webhook", optional(self.webhook.as_ref().map(|w| &w.url)))))
+            .collect();
+
+        #[cfg(feature = "jwt")]
+        let settings: Vec<_> = settings
+            .into_iter()
+            .chain(Some(("jwt", optional(self.jwt.as_ref()))))
+            .collect();
+
+        #[cfg(feature = "metrics")]
+        let settings: Vec<_> = {
+            let alerts: Vec<String> = self.alerts
+                .iter()
+                .map(|r| format!("{} {} {:?} {}", r.name, r.watched(), r.comparison, r.threshold))
+                .collect();
+            settings
+                .into_iter()
+                .chain(Some(("alerts", alerts.join(", "))))
+                .collect()
+        };
+
+        #[cfg(feature = "enrichment")]
+        let settings: Vec<_> = settings
+            .into_iter()
+            .chain(Some(("enrichment", optional(self.enrichment.as_ref()))))
+            .collect();
+
+        #[cfg(feature = "kafka")]
+        let settings: Vec<_> = settings
+            .into_iter()
+            .chain(Some(("kafka", optional(self.kafka.as_ref().map(|k| &k.broker)))))
+            .collect();
+
+        settings
+    }
+
+    /// Checks settings that can't be expressed in the types
+    pub fn validate(&self) -> Result<()> {
+        if self.max_packet_size == 0 || self.max_packet_size > MAX_PACKET_SIZE {
+            return Err(Error::Config(format!("max_packet_size must be between 1 and {}", MAX_PACKET_SIZE)));
+        }
+        if self.max_connections == Some(0) {
+            return Err(Error::Config("max_connections can't be 0".to_owned()));
+        }
+        for rate in self.connect_rate.iter().chain(self.connect_rate_per_ip.iter()) {
+            if rate.per_second == 0 || rate.burst == 0 {
+                return Err(Error::Config("connect rates and bursts can't be 0".to_owned()));
+            }
+        }
+
+        if self.outgoing_queue_size == 0 {
+            return Err(Error::Config("outgoing_queue_size can't be 0".to_owned()));
+        }
+
+        // room has to be left for the connack
+        if self.offline_queue_size >= self.outgoing_queue_size {
+            return Err(Error::Config("offline_queue_size must be smaller than outgoing_queue_size".to_owned()));
+        }
+
+        if self.offline_overflow == OverflowPolicy::Disconnect {
+            return Err(Error::Config("offline sessions have no connection to close on overflow".to_owned()));
+        }
+        if self.max_queued_messages == Some(0) || self.max_queued_bytes == Some(0) {
+            return Err(Error::Config("max_queued_messages and max_queued_bytes can't be 0".to_owned()));
+        }
+        if self.offline_message_ttl == Some(Duration::from_secs(0)) {
+            return Err(Error::Config("offline_message_ttl can't be 0".to_owned()));
+        }
+        if self.session_expiry == Some(Duration::from_secs(0)) {
+            return Err(Error::Config("session_expiry can't be 0".to_owned()));
+        }
+
+        if self.duplicate_pkid_policy.is_some() && self.duplicate_pkid_window == 0 {
+            return Err(Error::Config("duplicate_pkid_window can't be 0 with a duplicate pkid policy".to_owned()));
+        }
+
+        if self.retransmit_interval == Some(Duration::from_secs(0)) {
+            return Err(Error::Config("retransmit_interval can't be 0".to_owned()));
+        }
+
+        if self.listeners.is_empty() {
+            return Err(Error::Config("at least one listener is needed".to_owned()));
+        }
+
+        let mut addresses = HashSet::new();
+        for listener in self.listeners.iter() {
+            if !addresses.insert(listener.address) {
+                return Err(Error::Config(format!("more than one listener on {}", listener.address)));
+            }
+
+            if listener.max_connections == Some(0) {
+                return Err(Error::Config(format!("max_connections of listener {} can't be 0", listener.address)));
+            }
+
+            if listener.probe == Some(Duration::from_secs(0)) {
+                return Err(Error::Config(format!("probe of listener {} can't be 0", listener.address)));
+            }
+        }
+
+        // settings of subsystems left out of the build are refused, not ignored
+        #[cfg(not(feature = "websocket"))]
+        {
+            if let Some(listener) = self.listeners.iter().find(|listener| listener.kind == ListenerKind::WebSocket) {
+                return Err(Error::Config(format!("listener {} needs the websocket feature", listener.address)));
+            }
+        }
+        #[cfg(not(feature = "persistence"))]
+        {
+            if self.wal_path.is_some() {
+                return Err(Error::Config("wal_path needs the persistence feature".to_owned()));
+            }
+        }
+        #[cfg(not(feature = "admin"))]
+        {
+            if self.dump_dir.is_some() {
+                return Err(Error::Config("dump_dir needs the admin feature".to_owned()));
+            }
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            if self.sys_interval.is_some() || self.connection_events {
+                return Err(Error::Config("sys_interval and connection_events need the metrics feature".to_owned()));
+            }
+        }
+        #[cfg(not(feature = "tls"))]
+        {
+            if let Some(listener) = self.listeners.iter().find(|listener| listener.kind == ListenerKind::Tls) {
+                return Err(Error::Config(format!("listener {} needs the tls feature", listener.address)));
+            }
+        }
+        #[cfg(not(feature = "password-file"))]
+        {
+            if self.password_file.is_some() {
+                return Err(Error::Config("password_file needs the password-file feature".to_owned()));
+            }
+        }
+        #[cfg(not(feature = "connectors"))]
+        {
+            if self.message_webhook.is_some() || self.alert_webhook.is_some() {
+                return Err(Error::Config("message_webhook and alert_webhook need the connectors feature".to_owned()));
+            }
+        }
+
+        #[cfg(feature = "tls")]
+        {
+            let tls_listener = self.listeners.iter().any(|listener| listener.kind == ListenerKind::Tls);
+            if tls_listener && self.tls.is_none() {
+                return Err(Error::Config("TLS listeners need a certificate".to_owned()));
+            }
+
+            if self.client_auth.is_some() && !tls_listener {
+                return Err(Error::Config("client certificates need a TLS listener".to_owned()));
+            }
+        }
+
+        if let Some(ref simulation) = self.simulation {
+            simulation.validate().map_err(Error::Config)?;
+        }
+
+        if let Some(ref selftest) = self.selftest {
+            if selftest.interval == Duration::from_secs(0) {
+                return Err(Error::Config("selftest interval can't be 0".to_owned()));
+            }
+        }
+
+        let mut prefixes = HashSet::new();
+        for rule in self.storage.iter() {
+            if rule.prefix.is_empty() {
+                return Err(Error::Config("storage prefix can't be empty".to_owned()));
+            }
+            if !prefixes.insert(rule.prefix.as_str()) {
+                return Err(Error::Config(format!("duplicate storage prefix {:?}", rule.prefix)));
+            }
+        }
+
+        if self.wal_path.is_none() && self.storage.iter().any(|rule| rule.policy == StoragePolicy::Wal) {
+            return Err(Error::Config("wal storage needs a write ahead log path".to_owned()));
+        }
+        if self.wal_background_load && self.wal_path.is_none() {
+            return Err(Error::Config("wal_background_load needs a write ahead log path".to_owned()));
+        }
+        if let Some(interval) = self.session_checkpoint {
+            if self.wal_path.is_none() {
+                return Err(Error::Config("session_checkpoint needs a write ahead log path".to_owned()));
+            }
+            if interval == Duration::from_secs(0) {
+                return Err(Error::Config("session_checkpoint can't be 0".to_owned()));
+            }
+        }
+
+        if let Some(ref path) = self.log_file {
+            let dir = match path.parent() {
+                Some(dir) if dir != Path::new("") => dir,
+                _ => Path::new("."),
+            };
+            if !dir.is_dir() {
+                return Err(Error::Config(format!("directory of log_file {} doesn't exist", path.display())));
+            }
+        }
+
+        if self.log_rotation == Rotation::Size(0) || self.log_rotation == Rotation::Interval(Duration::from_secs(0)) {
+            return Err(Error::Config("log_rotation can't be 0".to_owned()));
+        }
+
+        if self.backlog_drain == DrainPolicy::Interleaved(0) {
+            return Err(Error::Config("interleaved backlog drain needs at least one backlog message per live one".to_owned()));
+        }
+
+        let mut prefixes = HashSet::new();
+        for rule in self.debounce.iter() {
+            if rule.interval == Duration::from_secs(0) {
+                return Err(Error::Config(format!("debounce interval of {:?} can't be 0", rule.prefix)));
+            }
+            if !prefixes.insert(rule.prefix.as_str()) {
+                return Err(Error::Config(format!("duplicate debounce prefix {:?}", rule.prefix)));
+            }
+        }
+
+        #[cfg(feature = "metrics")]
+        {
+            if !self.alerts.is_empty() {
+                if self.alert_topic.is_none() && self.alert_webhook.is_none() {
+                    return Err(Error::Config("alerts need an alert topic or an alert webhook".to_owned()));
+                }
+                if self.alert_interval == Duration::from_secs(0) {
+                    return Err(Error::Config("alert interval can't be 0".to_owned()));
+                }
+                let mut names = HashSet::new();
+                if let Some(rule) = self.alerts.iter().find(|rule| !names.insert(rule.name.as_str())) {
+                    return Err(Error::Config(format!("duplicate alert {:?}", rule.name)));
+                }
+            }
+        }
+        #[cfg(feature = "connectors")]
+        {
+            if let Some(ref url) = self.alert_webhook {
+                http::check_url(url)?;
+            }
+            if let Some(ref url) = self.message_webhook {
+                http::check_url(url)?;
+            }
+        }
+
+        match self.message_webhook {
+            Some(_) => {
+                if self.message_webhook_filters.is_empty() {
+                    return Err(Error::Config("the message webhook needs at least one filter".to_owned()));
+                }
+            }
+            None if !self.message_webhook_filters.is_empty() => {
+                return Err(Error::Config("message webhook filters need a message webhook".to_owned()))
+            }
+            None => (),
+        }
+        if let Some(rate) = self.message_webhook_rate {
+            if rate.per_second == 0 || rate.burst == 0 {
+                return Err(Error::Config("message webhook rates and bursts can't be 0".to_owned()));
+            }
+        }
+
+        if self.sys_interval == Some(Duration::from_secs(0)) {
+            return Err(Error::Config("sys interval can't be 0".to_owned()));
+        }
+
+        if let Some(batch) = self.batch {
+            if batch.interval == Duration::from_secs(0) || batch.max_messages == 0 {
+                return Err(Error::Config("batch interval and size can't be 0".to_owned()));
+            }
+        }
+
+        if self.transactions == Some(0) {
+            return Err(Error::Config("transactions need room for at least one message".to_owned()));
+        }
+
+        if self.downgrade_report == Some(Duration::from_secs(0)) {
+            return Err(Error::Config("downgrade report interval can't be 0".to_owned()));
+        }
+
+        #[cfg(feature = "enrichment")]
+        {
+            if let Some(ref enrichment) = self.enrichment {
+                if enrichment.cache_size == 0 {
+                    return Err(Error::Config("enrichment cache size can't be 0".to_owned()));
+                }
+                if !enrichment.reverse_dns && enrichment.geoip.is_none() {
+                    return Err(Error::Config("enrichment needs reverse dns or a geoip table".to_owned()));
+                }
+            }
+        }
+
+        #[cfg(feature = "kafka")]
+        {
+            if let Some(ref kafka) = self.kafka {
+                if kafka.rules.is_empty() {
+                    return Err(Error::Config("kafka needs at least one rule".to_owned()));
+                }
+                if kafka.batch_size == 0 || kafka.queue_size == 0 {
+                    return Err(Error::Config("kafka batch size and queue size can't be 0".to_owned()));
+                }
+            }
+        }
+
+        if let Some(ref leaf) = self.leaf {
+            if leaf.node_id.is_empty() {
+                return Err(Error::Config("leaf node id can't be empty".to_owned()));
+            }
+        }
+
+        if let Some(ref observer) = self.observer {
+            if observer.node_id.is_empty() {
+                return Err(Error::Config("observer node id can't be empty".to_owned()));
+            }
+            if self.leaf.is_some() || self.simulation.is_some() || self.sn_gateway.is_some() {
+                return Err(Error::Config("an observer can't be a leaf node, run simulated clients or an MQTT-SN gateway".to_owned()));
+            }
+        }
+
+        match self.bridge {
+            Some(ref bridge) => {
+                if bridge.client_id.is_empty() {
+                    return Err(Error::Config("bridge client id can't be empty".to_owned()));
+                }
+                if self.bridge_topics.is_empty() {
+                    return Err(Error::Config("a bridge needs at least one topic".to_owned()));
+                }
+                if self.observer.is_some() {
+                    return Err(Error::Config("an observer can't run a bridge".to_owned()));
+                }
+            }
+            None if !self.bridge_topics.is_empty() => return Err(Error::Config("bridge topics need a bridge".to_owned())),
+            None => (),
+        }
+
+        if self.max_inflight == Some(0) {
+            return Err(Error::Config("max_inflight can't be 0".to_owned()));
+        }
+
+        #[cfg(feature = "webhook-auth")]
+        {
+            if let Some(ref webhook) = self.webhook {
+                if self.password_file.is_some() {
+                    return Err(Error::Config("a password file and a webhook can't be used together".to_owned()));
+                }
+                if webhook.timeout == Duration::from_secs(0) || webhook.cache_size == 0 {
+                    return Err(Error::Config("webhook timeout and cache size can't be 0".to_owned()));
+                }
+            }
+        }
+
+        if self.anonymous_role.is_some() && !self.allow_anonymous {
+            return Err(Error::Config("anonymous_role needs allow_anonymous".to_owned()));
+        }
+
+        #[cfg(feature = "jwt")]
+        {
+            if let Some(ref jwt) = self.jwt {
+                #[cfg(feature = "webhook-auth")]
+                let webhook = self.webhook.is_some();
+                #[cfg(not(feature = "webhook-auth"))]
+                let webhook = false;
+                if self.password_file.is_some() || webhook {
+                    return Err(Error::Config("jwt can't be used together with a password file or a webhook".to_owned()));
+                }
+                if jwt.hmac_secret.is_none() && jwt.rsa_public_key.is_none() {
+                    return Err(Error::Config("jwt needs an hmac secret or an rsa public key".to_owned()));
+                }
+            }
+        }
+
+        if self.write_quantum == Some(0) {
+            return Err(Error::Config("write_quantum can't be 0".to_owned()));
+        }
+
+        if self.write_weights.iter().any(|w| w.weight == 0) {
+            return Err(Error::Config("write weights can't be 0".to_owned()));
+        }
+
+        if self.max_client_id_len == Some(0) {
+            return Err(Error::Config("max_client_id_len can't be 0".to_owned()));
+        }
+
+        let mut names = HashSet::new();
+        for group in self.groups.iter() {
+            if !names.insert(&group.name) {
+                return Err(Error::Config(format!("duplicate group {:?}", group.name)));
+            }
+        }
+
+        if let Some(ref birth) = self.birth {
+            if birth.topic.is_empty() || birth.topic.contains(|c| c == '+' || c == '#') {
+                return Err(Error::Config(format!("birth topic {:?} needs to be a topic without wildcards", birth.topic)));
+            }
+        }
+
+        if let Some(slow) = self.slow_consumer {
+            if slow.depth == 0 || slow.duration == Duration::from_secs(0) {
+                return Err(Error::Config("slow_consumer depth and duration need to be greater than 0".to_owned()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Builds a `Broker` from validated settings. Anything not set keeps its
+/// `BrokerConfig::default()` value
+#[derive(Debug, Clone)]
+pub struct BrokerBuilder {
+    config: BrokerConfig,
+}
+
+impl BrokerBuilder {
+    pub fn new() -> Self {
+        BrokerBuilder { config: BrokerConfig::default() }
+    }
+
+    /// Moves the default plain TCP listener to `addr`
+    pub fn listener(mut self, addr: SocketAddr) -> Self {
+        match self.config.listeners.iter_mut().find(|listener| listener.kind == ListenerKind::Tcp) {
+            Some(listener) => listener.address = addr,
+            None => self.config.listeners.push(ListenerConfig::new(addr, ListenerKind::Tcp)),
+        }
+        self
+    }
+
+    /// Serves another listener alongside the configured ones
+    pub fn add_listener(mut self, listener: ListenerConfig) -> Self {
+        self.config.listeners.push(listener);
+        self
+    }
+
+    /// Serves TLS on `listener` with the PEM certificate chain and key at the given paths
+    #[cfg(feature = "tls")]
+    pub fn tls<P: Into<PathBuf>>(mut self, listener: SocketAddr, cert_path: P, key_path: P) -> Self {
+        self.config.tls = Some(TlsConfig {
+                                   cert_path: cert_path.into(),
+                                   key_path: key_path.into(),
+                               });
+        self.add_listener(ListenerConfig::new(listener, ListenerKind::Tls))
+    }
+
+    /// Requires TLS clients to present a certificate signed by a CA in `ca_path`
+    #[cfg(feature = "tls")]
+    pub fn client_auth<P: Into<PathBuf>>(mut self, ca_path: P, identity: CertIdentity) -> Self {
+        self.config.client_auth = Some(ClientAuth {
+                                           ca_path: ca_path.into(),
+                                           identity: identity,
+                                       });
+        self
+    }
+
+    /// Serves MQTT over WebSocket on `listener`
+    pub fn websocket(self, listener: SocketAddr) -> Self {
+        self.add_listener(ListenerConfig::new(listener, ListenerKind::WebSocket))
+    }
+
+    pub fn log_level(mut self, level: Level) -> Self {
+        self.config.log_level = level;
+        self
+    }
+
+    pub fn log_output(mut self, output: LogOutput) -> Self {
+        self.config.log_output = output;
+        self
+    }
+
+    pub fn log_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.config.log_file = Some(path.into());
+        self
+    }
+
+    pub fn log_rotation(mut self, rotation: Rotation) -> Self {
+        self.config.log_rotation = rotation;
+        self
+    }
+
+    pub fn log_keep(mut self, keep: usize) -> Self {
+        self.config.log_keep = keep;
+        self
+    }
+
+    pub fn max_packet_size(mut self, size: usize) -> Self {
+        self.config.max_packet_size = size;
+        self
+    }
+
+    /// Limits the connections of all listeners together
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.config.max_connections = Some(max);
+        self
+    }
+
+    pub fn connect_rate(mut self, rate: RateLimit) -> Self {
+        self.config.connect_rate = Some(rate);
+        self
+    }
+
+    pub fn connect_rate_per_ip(mut self, rate: RateLimit) -> Self {
+        self.config.connect_rate_per_ip = Some(rate);
+        self
+    }
+
+    pub fn outgoing_queue_size(mut self, size: usize) -> Self {
+        self.config.outgoing_queue_size = size;
+        self
+    }
+
+    pub fn outgoing_overflow(mut self, overflow: OverflowPolicy) -> Self {
+        self.config.outgoing_overflow = overflow;
+        self
+    }
+
+    pub fn slow_consumer(mut self, slow: SlowConsumerConfig) -> Self {
+        self.config.slow_consumer = Some(slow);
+        self
+    }
+
+    pub fn max_client_id_len(mut self, len: usize) -> Self {
+        self.config.max_client_id_len = Some(len);
+        self
+    }
+
+    /// Lets MQTT 3.1 (`MQIsdp`) clients connect. On by default
+    pub fn allow_mqtt31(mut self, allow: bool) -> Self {
+        self.config.allow_mqtt31 = allow;
+        self
+    }
+
+    pub fn max_qos(mut self, qos: QoS) -> Self {
+        self.config.max_qos = qos;
+        self
+    }
+
+    pub fn catalog(mut self, enabled: bool) -> Self {
+        self.config.catalog = enabled;
+        self
+    }
+
+    pub fn takeover_grace(mut self, grace: Duration) -> Self {
+        self.config.takeover_grace = Some(grace);
+        self
+    }
+
+    pub fn group(mut self, group: GroupConfig) -> Self {
+        self.config.groups.push(group);
+        self
+    }
+
+    pub fn birth(mut self, birth: BirthConfig) -> Self {
+        self.config.birth = Some(birth);
+        self
+    }
+
+    pub fn duplicate_pkid_policy(mut self, policy: DuplicatePkidPolicy, window: usize) -> Self {
+        self.config.duplicate_pkid_policy = Some(policy);
+        self.config.duplicate_pkid_window = window;
+        self
+    }
+
+    pub fn offline_queue(mut self, size: usize, overflow: OverflowPolicy) -> Self {
+        self.config.offline_queue_size = size;
+        self.config.offline_overflow = overflow;
+        self
+    }
+
+    /// Limits the messages and payload bytes held per session
+    pub fn max_queued(mut self, messages: usize, bytes: usize) -> Self {
+        self.config.max_queued_messages = Some(messages);
+        self.config.max_queued_bytes = Some(bytes);
+        self
+    }
+
+    /// Drops offline messages that weren't delivered within `ttl`
+    pub fn offline_message_ttl(mut self, ttl: Duration) -> Self {
+        self.config.offline_message_ttl = Some(ttl);
+        self
+    }
+
+    /// Discards persistent sessions whose clients stay away for `expiry`
+    pub fn session_expiry(mut self, expiry: Duration) -> Self {
+        self.config.session_expiry = Some(expiry);
+        self
+    }
+
+    /// Resends unacknowledged packets every `interval`, at most `max_retransmits`
+    /// times. `None` disables retransmission
+    pub fn retransmit(mut self, interval: Option<Duration>, max_retransmits: u32) -> Self {
+        self.config.retransmit_interval = interval;
+        self.config.max_retransmits = max_retransmits;
+        self
+    }
+
+    pub fn max_inflight(mut self, max: Option<usize>) -> Self {
+        self.config.max_inflight = max;
+        self
+    }
+
+    /// Writes at most `quantum` frames per connection and turn. `None` turns
+    /// fair scheduling off
+    pub fn write_quantum(mut self, quantum: Option<usize>) -> Self {
+        self.config.write_quantum = quantum;
+        self
+    }
+
+    /// Gives connections of clients whose id starts with `prefix` `weight` quanta per turn
+    pub fn write_weight(mut self, prefix: &str, weight: usize) -> Self {
+        self.config.write_weights.push(WriteWeight {
+                                           client_id_prefix: prefix.to_owned(),
+                                           weight: weight,
+                                       });
+        self
+    }
+
+    /// Runs the simulated clients of `config` inside the broker
+    pub fn simulation(mut self, config: SimConfig) -> Self {
+        self.config.simulation = Some(config);
+        self
+    }
+
+    /// Probes the broker every `interval` and alerts on round trips slower than `slo`
+    pub fn selftest(mut self, interval: Duration, slo: Duration) -> Self {
+        self.config.selftest = Some(SelfTestConfig {
+                                        interval: interval,
+                                        slo: slo,
+                                    });
+        self
+    }
+
+    /// Runs as a leaf node of the broker at `hub`, connecting with the client id `node_id`
+    pub fn leaf(mut self, hub: SocketAddr, node_id: &str) -> Self {
+        self.config.leaf = Some(LeafConfig {
+                                    hub: hub,
+                                    node_id: node_id.to_owned(),
+                                });
+        self
+    }
+
+    /// Lets MQTT-SN sensors publish over UDP to a gateway on `addr`
+    pub fn sn_gateway(mut self, addr: SocketAddr) -> Self {
+        self.config.sn_gateway = Some(addr);
+        self
+    }
+
+    /// Runs as a read only observer of the broker at `primary`, connecting with the client id `node_id`
+    pub fn observer(mut self, primary: SocketAddr, node_id: &str) -> Self {
+        self.config.observer = Some(ObserverConfig {
+                                        primary: primary,
+                                        node_id: node_id.to_owned(),
+                                    });
+        self
+    }
+
+    /// Bridges topics with the broker at `remote`, connecting with the client id `client_id`
+    pub fn bridge(mut self, remote: SocketAddr, client_id: &str) -> Self {
+        self.config.bridge = Some(BridgeConfig {
+                                      remote: remote,
+                                      client_id: client_id.to_owned(),
+                                  });
+        self
+    }
+
+    /// Passes publishes on a topic to or from the bridged broker
+    pub fn bridge_topic(mut self, topic: BridgeTopic) -> Self {
+        self.config.bridge_topics.push(topic);
+        self
+    }
+
+    /// Tracks qos downgrades and reports systematic ones every `interval`
+    pub fn downgrade_report(mut self, interval: Duration) -> Self {
+        self.config.downgrade_report = Some(interval);
+        self
+    }
+
+    /// Delivers `$batch/` subscriptions every `interval` or once `max_messages` are collected
+    pub fn batch(mut self, interval: Duration, max_messages: usize) -> Self {
+        self.config.batch = Some(BatchConfig {
+                                     interval: interval,
+                                     max_messages: max_messages,
+                                 });
+        self
+    }
+
+    /// How publishes are split among the members of `$share/` groups
+    pub fn share_policy(mut self, policy: SharePolicy) -> Self {
+        self.config.share_policy = policy;
+        self
+    }
+
+    /// Holds `$txn/` publishes back until their group is committed. Groups
+    /// with more than `max_messages` parts are discarded
+    pub fn transactions(mut self, max_messages: usize) -> Self {
+        self.config.transactions = Some(max_messages);
+        self
+    }
+
+    /// Turns on strict mode with the topics declared in `registry`
+    pub fn topic_registry(mut self, registry: TopicRegistry) -> Self {
+        self.config.topic_registry = Some(registry);
+        self
+    }
+
+    pub fn acl(mut self, acl: Acl) -> Self {
+        self.config.acl = Some(acl);
+        self
+    }
+
+    pub fn allow_anonymous(mut self, allow: bool) -> Self {
+        self.config.allow_anonymous = allow;
+        self
+    }
+
+    /// Checks anonymous clients against the acl rules of `username`
+    pub fn anonymous_role(mut self, username: &str) -> Self {
+        self.config.anonymous_role = Some(username.to_owned());
+        self
+    }
+
+    pub fn unauthorized_publish(mut self, policy: UnauthorizedPublish) -> Self {
+        self.config.unauthorized_publish = policy;
+        self
+    }
+
+    /// Checks every CONNECT with the identity service at `config.url`
+    #[cfg(feature = "webhook-auth")]
+    pub fn webhook(mut self, config: WebhookConfig) -> Self {
+        self.config.webhook = Some(config);
+        self
+    }
+
+    /// Takes CONNECT passwords as JWTs signed with the keys of `config`
+    #[cfg(feature = "jwt")]
+    pub fn jwt(mut self, config: JwtConfig) -> Self {
+        self.config.jwt = Some(config);
+        self
+    }
+
+    pub fn password_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.config.password_file = Some(path.into());
+        self
+    }
+
+    /// Stores messages on `prefix` and its subtree with `policy`
+    pub fn storage(mut self, prefix: &str, policy: StoragePolicy) -> Self {
+        self.config.storage.push(StorageRule {
+                                     prefix: prefix.to_owned(),
+                                     policy: policy,
+                                 });
+        self
+    }
+
+    pub fn dump_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.config.dump_dir = Some(dir.into());
+        self
+    }
+
+    /// Waits up to `timeout` on shutdown for qos 1 and 2 flows to complete
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.config.shutdown_timeout = timeout;
+        self
+    }
+
+    pub fn backlog_drain(mut self, policy: DrainPolicy) -> Self {
+        self.config.backlog_drain = policy;
+        self
+    }
+
+    /// Delivers each topic on `prefix` and its subtree at most once every `interval`
+    pub fn debounce(mut self, prefix: &str, interval: Duration) -> Self {
+        self.config.debounce.push(DebounceRule {
+                                      prefix: prefix.to_owned(),
+                                      interval: interval,
+                                  });
+        self
+    }
+
+    /// Publishes the `$SYS` diagnostics every `interval`
+    pub fn sys_interval(mut self, interval: Duration) -> Self {
+        self.config.sys_interval = Some(interval);
+        self
+    }
+
+    pub fn connection_events(mut self, enabled: bool) -> Self {
+        self.config.connection_events = enabled;
+        self
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn alert(mut self, rule: AlertRule) -> Self {
+        self.config.alerts.push(rule);
+        self
+    }
+
+    /// Evaluates the alerting rules every `interval`
+    pub fn alert_interval(mut self, interval: Duration) -> Self {
+        self.config.alert_interval = interval;
+        self
+    }
+
+    pub fn alert_topic(mut self, topic: &str) -> Self {
+        self.config.alert_topic = Some(topic.to_owned());
+        self
+    }
+
+    pub fn alert_webhook(mut self, url: &str) -> Self {
+        self.config.alert_webhook = Some(url.to_owned());
+        self
+    }
+
+    /// POSTs publishes matching the message webhook filters to `url`
+    pub fn message_webhook(mut self, url: &str) -> Self {
+        self.config.message_webhook = Some(url.to_owned());
+        self
+    }
+
+    pub fn message_webhook_filter(mut self, filter: &str) -> Self {
+        self.config.message_webhook_filters.push(filter.to_owned());
+        self
+    }
+
+    pub fn message_webhook_rate(mut self, rate: RateLimit) -> Self {
+        self.config.message_webhook_rate = Some(rate);
+        self
+    }
+
+    pub fn message_webhook_retries(mut self, retries: usize) -> Self {
+        self.config.message_webhook_retries = retries;
+        self
+    }
+
+    pub fn wal<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.config.wal_path = Some(path.into());
+        self
+    }
+
+    /// Reads the write ahead log while the listeners start
+    pub fn wal_background_load(mut self, enabled: bool) -> Self {
+        self.config.wal_background_load = enabled;
+        self
+    }
+
+    /// Logs connected persistent sessions every `interval`
+    pub fn session_checkpoint(mut self, interval: Duration) -> Self {
+        self.config.session_checkpoint = Some(interval);
+        self
+    }
+
+    /// Looks up where clients connect from
+    #[cfg(feature = "enrichment")]
+    pub fn enrichment(mut self, enrichment: EnrichConfig) -> Self {
+        self.config.enrichment = Some(enrichment);
+        self
+    }
+
+    /// Mirrors publishes into Kafka
+    #[cfg(feature = "kafka")]
+    pub fn kafka(mut self, kafka: KafkaConfig) -> Self {
+        self.config.kafka = Some(kafka);
+        self
+    }
+
+    pub fn retain_as_published(mut self, enabled: bool) -> Self {
+        self.config.retain_as_published = enabled;
+        self
+    }
+
+    pub fn build(self) -> Result<Broker> {
+        self.config.validate()?;
+
+        let file = match self.config.log_file {
+            Some(ref path) => {
+                let file = RotatingFile::open(path, self.config.log_rotation, self.config.log_keep)
+                    .map_err(|e| Error::Config(format!("unable to open log_file {}: {}", path.display(), e)))?;
+                Some(file)
+            }
+            None => None,
+        };
+
+        let broker = Broker::with_log_file(self.config, clock::system(), file);
+        #[cfg(feature = "persistence")]
+        match broker.config().wal_path.clone() {
+            Some(path) if broker.config().wal_background_load => broker.load_wal(path),
+            Some(path) => {
+                broker.open_wal(path)?;
+            }
+            None => (),
+        }
+        let authenticator = broker.config().authenticator(broker.clock())?;
+        broker.set_authenticator(authenticator);
+        Ok(broker)
+    }
+
+    /// The configuration without a broker, for `Broker::reload`
+    pub fn into_config(self) -> BrokerConfig {
+        self.config
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::time::Duration;
+    use mqtt3::QoS;
+    use group::GroupConfig;
+    use listener::{ListenerConfig, ListenerKind};
+    use storage::StoragePolicy;
+    #[cfg(feature = "tls")]
+    use tls::CertIdentity;
+    #[cfg(feature = "webhook-auth")]
+    use webhook::WebhookConfig;
+    #[cfg(feature = "jwt")]
+    use jwt::JwtConfig;
+    use sim::SimConfig;
+    use super::{BrokerBuilder, DrainPolicy, DuplicatePkidPolicy, OverflowPolicy};
+
+    fn group(name: &str) -> GroupConfig {
+        GroupConfig {
+            name: name.to_owned(),
+            topic: "commands".to_owned(),
+            client_id_prefix: "device-".to_owned(),
+            qos: QoS::AtMostOnce,
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "websocket")]
+    fn builder_applies_settings() {
+        let broker = BrokerBuilder::new()
+            .listener("127.0.0.1:1884".parse().unwrap())
+            .websocket("127.0.0.1:8083".parse().unwrap())
+            .max_packet_size(1024)
+            .max_qos(QoS::AtLeastOnce)
+            .catalog(true)
+            .build()
+            .unwrap();
+
+        let config = broker.config();
+        assert_eq!(config.listeners[0].address, "127.0.0.1:1884".parse().unwrap());
+        assert_eq!(config.listeners[1].kind, ListenerKind::WebSocket);
+        assert_eq!(config.max_packet_size, 1024);
+        assert_eq!(config.max_qos, QoS::AtLeastOnce);
+        assert!(config.catalog);
+    }
+
+    #[test]
+    #[cfg(not(all(feature = "websocket",
+                  feature = "persistence",
+                  feature = "admin",
+                  feature = "metrics",
+                  feature = "tls",
+                  feature = "password-file",
+                  feature = "connectors")))]
+    fn settings_of_left_out_subsystems_are_refused() {
+        #[cfg(not(feature = "websocket"))]
+        assert!(BrokerBuilder::new().websocket("127.0.0.1:8083".parse().unwrap()).build().is_err());
+        #[cfg(not(feature = "persistence"))]
+        assert!(BrokerBuilder::new().wal("/var/lib/rumqttd/wal").build().is_err());
+        #[cfg(not(feature = "admin"))]
+        assert!(BrokerBuilder::new().dump_dir("/tmp").build().is_err());
+        #[cfg(not(feature = "metrics"))]
+        assert!(BrokerBuilder::new().connection_events(true).build().is_err());
+        #[cfg(not(feature = "tls"))]
+        assert!(BrokerBuilder::new()
+                    .add_listener(ListenerConfig::new("0.0.0.0:8883".parse().unwrap(), ListenerKind::Tls))
+                    .build()
+                    .is_err());
+        #[cfg(not(feature = "password-file"))]
+        assert!(BrokerBuilder::new().password_file("/etc/rumqttd/passwd").build().is_err());
+        #[cfg(not(feature = "connectors"))]
+        assert!(BrokerBuilder::new()
+                    .message_webhook("http://hooks/messages")
+                    .message_webhook_filter("sensors/#")
+                    .build()
+                    .is_err());
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        assert!(BrokerBuilder::new().build().is_ok());
+        assert!(BrokerBuilder::new().max_packet_size(0).build().is_err());
+        assert!(BrokerBuilder::new().outgoing_queue_size(0).build().is_err());
+        assert!(BrokerBuilder::new()
+                    .outgoing_queue_size(10)
+                    .offline_queue(10, OverflowPolicy::DropNew)
+                    .build()
+                    .is_err());
+        assert!(BrokerBuilder::new().offline_queue(10, OverflowPolicy::Disconnect).build().is_err());
+        assert!(BrokerBuilder::new().max_client_id_len(0).build().is_err());
+        assert!(BrokerBuilder::new().max_inflight(Some(0)).build().is_err());
+        assert!(BrokerBuilder::new().write_quantum(Some(0)).build().is_err());
+        assert!(BrokerBuilder::new().log_file(env::temp_dir()).build().is_err());
+        #[cfg(feature = "webhook-auth")]
+        assert!(BrokerBuilder::new().webhook(WebhookConfig::new("ftp://auth/")).build().is_err());
+        #[cfg(feature = "webhook-auth")]
+        assert!(BrokerBuilder::new()
+                    .webhook(WebhookConfig::new("http://auth/"))
+                    .password_file("/etc/rumqttd/passwd")
+                    .build()
+                    .is_err());
+        #[cfg(feature = "jwt")]
+        assert!(BrokerBuilder::new().jwt(JwtConfig::new()).build().is_err());
+        assert!(BrokerBuilder::new().allow_anonymous(false).anonymous_role("guest").build().is_err());
+        assert!(BrokerBuilder::new().simulation(SimConfig::new(Duration::from_secs(0))).build().is_err());
+        #[cfg(all(feature = "jwt", feature = "webhook-auth"))]
+        assert!(BrokerBuilder::new()
+                    .jwt(JwtConfig { hmac_secret: Some("/etc/rumqttd/jwt.key".into()), ..JwtConfig::new() })
+                    .webhook(WebhookConfig::new("http://auth/"))
+                    .build()
+                    .is_err());
+        assert!(BrokerBuilder::new().write_weight("backend-", 0).build().is_err());
+        #[cfg(feature = "tls")]
+        assert!(BrokerBuilder::new()
+                    .tls("0.0.0.0:1883".parse().unwrap(), "cert.pem", "key.pem")
+                    .build()
+                    .is_err());
+        assert!(BrokerBuilder::new()
+                    .websocket("0.0.0.0:1883".parse().unwrap())
+                    .build()
+                    .is_err());
+        assert!(BrokerBuilder::new()
+                    .add_listener(ListenerConfig::new("0.0.0.0:8883".parse().unwrap(), ListenerKind::Tls))
+                    .build()
+                    .is_err());
+        assert!(BrokerBuilder::new()
+                    .add_listener(ListenerConfig::new("0.0.0.0:1884".parse().unwrap(), ListenerKind::Tcp).max_connections(0))
+                    .build()
+                    .is_err());
+        #[cfg(feature = "tls")]
+        assert!(BrokerBuilder::new()
+                    .client_auth("ca.pem", CertIdentity::ClientId)
+                    .build()
+                    .is_err());
+        assert!(BrokerBuilder::new()
+                    .selftest(Duration::from_secs(0), Duration::from_secs(1))
+                    .build()
+                    .is_err());
+        assert!(BrokerBuilder::new()
+                    .leaf("10.0.0.1:1883".parse().unwrap(), "")
+                    .build()
+                    .is_err());
+        assert!(BrokerBuilder::new()
+                    .leaf("10.0.0.1:1883".parse().unwrap(), "leaf-1")
+                    .observer("10.0.0.1:1883".parse().unwrap(), "observer-1")
+                    .build()
+                    .is_err());
+        assert!(BrokerBuilder::new()
+                    .bridge("10.0.0.1:1883".parse().unwrap(), "edge-1")
+                    .build()
+                    .is_err());
+        assert!(BrokerBuilder::new()
+                    .bridge_topic("telemetry/# out".parse().unwrap())
+                    .build()
+                    .is_err());
+        assert!(BrokerBuilder::new()
+                    .message_webhook_filter("sensors/#")
+                    .build()
+                    .is_err());
+        assert!(BrokerBuilder::new()
+                    .downgrade_report(Duration::from_secs(0))
+                    .build()
+                    .is_err());
+        assert!(BrokerBuilder::new()
+                    .batch(Duration::from_secs(1), 0)
+                    .build()
+                    .is_err());
+        assert!(BrokerBuilder::new()
+                    .storage("commands", StoragePolicy::Wal)
+                    .build()
+                    .is_err());
+        assert!(BrokerBuilder::new()
+                    .storage("telemetry", StoragePolicy::Memory)
+                    .storage("telemetry", StoragePolicy::Archive)
+                    .build()
+                    .is_err());
+        assert!(BrokerBuilder::new()
+                    .backlog_drain(DrainPolicy::Interleaved(0))
+                    .build()
+                    .is_err());
+        assert!(BrokerBuilder::new()
+                    .debounce("sensors", Duration::from_secs(0))
+                    .build()
+                    .is_err());
+        assert!(BrokerBuilder::new()
+                    .duplicate_pkid_policy(DuplicatePkidPolicy::Dedupe, 0)
+                    .build()
+                    .is_err());
+        assert!(BrokerBuilder::new()
+                    .group(group("devices"))
+                    .group(group("devices"))
+                    .build()
+                    .is_err());
+    }
+}
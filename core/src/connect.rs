@@ -0,0 +1,170 @@
+use mqtt3::{Connect, ConnectReturnCode, Protocol};
+
+use config::BrokerConfig;
+#[cfg(feature = "tls")]
+use tls::{CertIdentity, PeerCertificate};
+
+/// Longest client id MQTT 3.1 allows. 3.1.1 servers must accept at least this
+pub const MQISDP_MAX_CLIENT_ID_LEN: usize = 23;
+
+/// Checks a CONNECT before the client is let in. Returns the code to send
+/// back in CONNACK
+pub fn validate(connect: &Connect, config: &BrokerConfig) -> ConnectReturnCode {
+    let max_client_id_len = match connect.protocol {
+        Protocol::MQIsdp(3) if config.allow_mqtt31 => Some(MQISDP_MAX_CLIENT_ID_LEN),
+        Protocol::MQTT(4) => config.max_client_id_len,
+        _ => return ConnectReturnCode::RefusedProtocolVersion,
+    };
+
+    // the broker doesn't assign ids so empty ones are refused even with a
+    // clean session, which 3.1.1 allows
+    if connect.client_id.is_empty() {
+        return ConnectReturnCode::RefusedIdentifierRejected;
+    }
+
+    if let Some(max) = max_client_id_len {
+        if connect.client_id.len() > max {
+            return ConnectReturnCode::RefusedIdentifierRejected;
+        }
+    }
+
+    // password flag can't be set without the username flag
+    if connect.password.is_some() && connect.username.is_none() {
+        return ConnectReturnCode::BadUsernamePassword;
+    }
+
+    ConnectReturnCode::Accepted
+}
+
+/// True for MQTT 3.1 clients. Their CONNACK has no session present flag
+pub fn is_mqtt31(connect: &Connect) -> bool {
+    match connect.protocol {
+        Protocol::MQIsdp(_) => true,
+        Protocol::MQTT(_) => false,
+    }
+}
+
+/// Checks the CONNECT against the listener's authentication requirement
+pub fn authorize(connect: &Connect, auth_required: bool) -> ConnectReturnCode {
+    if auth_required && connect.username.is_none() {
+        return ConnectReturnCode::NotAuthorized;
+    }
+
+    ConnectReturnCode::Accepted
+}
+
+/// Checks the CONNECT against the client certificate of a mutual TLS
+/// connection. Returns the client id the connection goes by
+#[cfg(feature = "tls")]
+pub fn authenticate_peer(connect: &Connect, peer: &PeerCertificate, identity: CertIdentity) -> Result<String, ConnectReturnCode> {
+    match identity {
+        CertIdentity::ClientId => peer.common_name.clone().ok_or(ConnectReturnCode::NotAuthorized),
+        CertIdentity::MatchClientId if peer.names(&connect.client_id) => Ok(connect.client_id.clone()),
+        CertIdentity::MatchClientId => Err(ConnectReturnCode::NotAuthorized),
+        CertIdentity::MatchUsername => {
+            match connect.username {
+                Some(ref username) if peer.names(username) => Ok(connect.client_id.clone()),
+                Some(_) => Err(ConnectReturnCode::BadUsernamePassword),
+                None => Err(ConnectReturnCode::NotAuthorized),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mqtt3::*;
+    use config::BrokerConfig;
+    #[cfg(feature = "tls")]
+    use tls::{CertIdentity, PeerCertificate};
+    use super::{authorize, validate};
+    #[cfg(feature = "tls")]
+    use super::authenticate_peer;
+
+    fn connect(protocol: Protocol, client_id: &str) -> Connect {
+        Connect {
+            protocol: protocol,
+            keep_alive: 10,
+            client_id: client_id.to_owned(),
+            clean_session: true,
+            last_will: None,
+            username: None,
+            password: None,
+        }
+    }
+
+    #[test]
+    fn client_ids_are_checked_per_protocol() {
+        let config = BrokerConfig::default();
+        let long = "a-client-id-longer-than-23";
+
+        assert_eq!(validate(&connect(Protocol::MQTT(4), "client"), &config), ConnectReturnCode::Accepted);
+        assert_eq!(validate(&connect(Protocol::MQTT(4), ""), &config), ConnectReturnCode::RefusedIdentifierRejected);
+        assert_eq!(validate(&connect(Protocol::MQTT(4), long), &config), ConnectReturnCode::Accepted);
+        assert_eq!(validate(&connect(Protocol::MQIsdp(3), long), &config), ConnectReturnCode::RefusedIdentifierRejected);
+
+        let mut config = BrokerConfig::default();
+        config.max_client_id_len = Some(6);
+        assert_eq!(validate(&connect(Protocol::MQTT(4), "client"), &config), ConnectReturnCode::Accepted);
+        assert_eq!(validate(&connect(Protocol::MQTT(4), "client1"), &config), ConnectReturnCode::RefusedIdentifierRejected);
+    }
+
+    #[test]
+    fn mqtt31_clients_can_be_refused() {
+        let mut config = BrokerConfig::default();
+        assert_eq!(validate(&connect(Protocol::MQIsdp(3), "client"), &config), ConnectReturnCode::Accepted);
+        assert_eq!(validate(&connect(Protocol::MQIsdp(4), "client"), &config), ConnectReturnCode::RefusedProtocolVersion);
+        assert_eq!(validate(&connect(Protocol::MQTT(3), "client"), &config), ConnectReturnCode::RefusedProtocolVersion);
+
+        config.allow_mqtt31 = false;
+        assert_eq!(validate(&connect(Protocol::MQIsdp(3), "client"), &config), ConnectReturnCode::RefusedProtocolVersion);
+        assert_eq!(validate(&connect(Protocol::MQTT(4), "client"), &config), ConnectReturnCode::Accepted);
+    }
+
+    #[test]
+    fn password_needs_a_username() {
+        let config = BrokerConfig::default();
+
+        let mut c = connect(Protocol::MQTT(4), "client");
+        c.password = Some("secret".to_owned());
+        assert_eq!(validate(&c, &config), ConnectReturnCode::BadUsernamePassword);
+
+        c.username = Some("user".to_owned());
+        assert_eq!(validate(&c, &config), ConnectReturnCode::Accepted);
+    }
+
+    #[test]
+    fn listeners_can_require_a_username() {
+        let mut c = connect(Protocol::MQTT(4), "client");
+        assert_eq!(authorize(&c, false), ConnectReturnCode::Accepted);
+        assert_eq!(authorize(&c, true), ConnectReturnCode::NotAuthorized);
+
+        c.username = Some("user".to_owned());
+        assert_eq!(authorize(&c, true), ConnectReturnCode::Accepted);
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn client_certificates_are_matched_against_the_connect() {
+        let peer = PeerCertificate {
+            common_name: Some("sensor-1".to_owned()),
+            san: vec!["sensor-1.example.com".to_owned()],
+        };
+
+        let mut c = connect(Protocol::MQTT(4), "anything");
+        assert_eq!(authenticate_peer(&c, &peer, CertIdentity::ClientId), Ok("sensor-1".to_owned()));
+        assert_eq!(authenticate_peer(&c, &peer, CertIdentity::MatchClientId), Err(ConnectReturnCode::NotAuthorized));
+        assert_eq!(authenticate_peer(&c, &peer, CertIdentity::MatchUsername), Err(ConnectReturnCode::NotAuthorized));
+
+        c.client_id = "sensor-1.example.com".to_owned();
+        assert_eq!(authenticate_peer(&c, &peer, CertIdentity::MatchClientId), Ok("sensor-1.example.com".to_owned()));
+
+        c.username = Some("sensor-2".to_owned());
+        assert_eq!(authenticate_peer(&c, &peer, CertIdentity::MatchUsername), Err(ConnectReturnCode::BadUsernamePassword));
+        c.username = Some("sensor-1".to_owned());
+        assert!(authenticate_peer(&c, &peer, CertIdentity::MatchUsername).is_ok());
+
+        let anonymous = PeerCertificate::default();
+        assert!(authenticate_peer(&c, &anonymous, CertIdentity::ClientId).is_err());
+    }
+}
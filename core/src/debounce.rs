@@ -0,0 +1,178 @@
+//! Debounced topics. Under a debounce rule a topic is delivered at most once
+//! per interval: the first publish goes out right away and the ones arriving
+//! before the interval is over are conflated into the latest, which goes out
+//! when it ends. Subscribers of high rate sensor topics get the freshest
+//! reading without every intermediate one. Publishers are acknowledged as
+//! usual, conflated qos 1 and 2 publishes are simply superseded
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use mqtt3::Publish;
+
+use pause::in_subtree;
+
+/// How often the conflation counts are logged
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DebounceRule {
+    /// Topic subtree the rule applies to
+    pub prefix: String,
+    /// Shortest time between deliveries of one topic
+    pub interval: Duration,
+}
+
+/// Interval of the most specific rule covering `topic`. `None` delivers every publish
+pub fn interval(rules: &[DebounceRule], topic: &str) -> Option<Duration> {
+    rules
+        .iter()
+        .filter(|rule| in_subtree(topic, &rule.prefix))
+        .max_by_key(|rule| rule.prefix.len())
+        .map(|rule| rule.interval)
+}
+
+#[derive(Debug)]
+struct Slot {
+    interval: Duration,
+    delivered_at: Instant,
+    /// Latest publish since the last delivery
+    held: Option<Box<Publish>>,
+    conflated: u64,
+}
+
+/// Delivery times and held publishes of debounced topics
+#[derive(Debug)]
+pub struct Debouncer {
+    rules: Vec<DebounceRule>,
+    topics: HashMap<String, Slot>,
+}
+
+impl Debouncer {
+    pub fn new(rules: Vec<DebounceRule>) -> Self {
+        Debouncer {
+            rules: rules,
+            topics: HashMap::new(),
+        }
+    }
+
+    /// The publish if it can be delivered now. Otherwise it's held in place
+    /// of the one held so far
+    pub fn admit(&mut self, publish: Box<Publish>, now: Instant) -> Option<Box<Publish>> {
+        if self.rules.is_empty() {
+            return Some(publish);
+        }
+        let interval = match interval(&self.rules, &publish.topic_name) {
+            Some(interval) => interval,
+            None => return Some(publish),
+        };
+
+        if let Some(slot) = self.topics.get_mut(&publish.topic_name) {
+            if now.duration_since(slot.delivered_at) < slot.interval {
+                if slot.held.is_some() {
+                    slot.conflated += 1;
+                }
+                slot.held = Some(publish);
+                return None;
+            }
+            // a held publish the timer didn't get to yet is superseded
+            if slot.held.take().is_some() {
+                slot.conflated += 1;
+            }
+            slot.delivered_at = now;
+            return Some(publish);
+        }
+
+        self.topics.insert(publish.topic_name.clone(),
+                           Slot {
+                               interval: interval,
+                               delivered_at: now,
+                               held: None,
+                               conflated: 0,
+                           });
+        Some(publish)
+    }
+
+    /// Held publishes whose interval is over. Topics that stayed quiet for an
+    /// interval are forgotten, conflation counts aside
+    pub fn due(&mut self, now: Instant) -> Vec<Box<Publish>> {
+        let mut due = Vec::new();
+        for slot in self.topics.values_mut() {
+            if now.duration_since(slot.delivered_at) < slot.interval {
+                continue;
+            }
+            if let Some(publish) = slot.held.take() {
+                slot.delivered_at = now;
+                due.push(publish);
+            }
+        }
+
+        self.topics.retain(|_, slot| slot.held.is_some() || slot.conflated > 0 || now.duration_since(slot.delivered_at) < slot.interval);
+        due
+    }
+
+    /// Publishes superseded on every debounced topic that has had any
+    pub fn conflated(&self) -> Vec<(String, u64)> {
+        let mut conflated: Vec<(String, u64)> = self.topics
+            .iter()
+            .filter(|&(_, slot)| slot.conflated > 0)
+            .map(|(topic, slot)| (topic.clone(), slot.conflated))
+            .collect();
+        conflated.sort();
+        conflated
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use mqtt3::*;
+    use super::{interval, DebounceRule, Debouncer};
+
+    fn publish(topic: &str, value: u8) -> Box<Publish> {
+        Box::new(Publish {
+                     dup: false,
+                     qos: QoS::AtLeastOnce,
+                     retain: false,
+                     pid: Some(PacketIdentifier(1)),
+                     topic_name: topic.to_owned(),
+                     payload: Arc::new(vec![value]),
+                 })
+    }
+
+    #[test]
+    fn intermediate_publishes_are_conflated_into_the_latest() {
+        let rules = vec![DebounceRule {
+                             prefix: "sensors".to_owned(),
+                             interval: Duration::from_secs(1),
+                         },
+                         DebounceRule {
+                             prefix: "sensors/fast".to_owned(),
+                             interval: Duration::from_millis(100),
+                         }];
+        assert_eq!(interval(&rules, "sensors/fast/1"), Some(Duration::from_millis(100)));
+        assert_eq!(interval(&rules, "sensors/slow/1"), Some(Duration::from_secs(1)));
+        assert_eq!(interval(&rules, "commands/1"), None);
+
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(rules);
+        assert!(debouncer.admit(publish("sensors/temp", 1), start).is_some());
+        assert!(debouncer.admit(publish("commands/reboot", 1), start).is_some());
+        for value in 2..5 {
+            assert!(debouncer.admit(publish("sensors/temp", value), start + Duration::from_millis(100)).is_none());
+        }
+        assert!(debouncer.due(start + Duration::from_millis(500)).is_empty());
+
+        let due = debouncer.due(start + Duration::from_secs(1));
+        assert_eq!(due.len(), 1);
+        assert_eq!(*due[0].payload, vec![4]);
+        assert_eq!(debouncer.conflated(), vec![("sensors/temp".to_owned(), 2)]);
+
+        // an interval after the held publish went out the next one is delivered right away
+        assert!(debouncer.admit(publish("sensors/temp", 5), start + Duration::from_millis(1500)).is_none());
+        assert!(debouncer.admit(publish("sensors/temp", 6), start + Duration::from_millis(1900)).is_none());
+        assert_eq!(*debouncer.due(start + Duration::from_secs(2))[0].payload, vec![6]);
+        assert!(debouncer.admit(publish("sensors/temp", 7), start + Duration::from_secs(3)).is_some());
+    }
+}
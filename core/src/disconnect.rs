@@ -0,0 +1,69 @@
+//! Why connections end. Every path that closes a connection goes through
+//! `Broker::disconnect` with one of these, which decides whether the will is
+//! published and carries the reason to the log, the session statistics and
+//! the `$SYS` connection events
+
+/// Why the broker let go of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DisconnectReason {
+    /// DISCONNECT from the client
+    ClientDisconnect,
+    /// Nothing received within the keep alive or an answer to a liveness probe
+    KeepAliveTimeout,
+    /// The client broke the protocol or the broker's limits
+    ProtocolError,
+    /// Publish the client isn't allowed to make
+    NotAuthorized,
+    /// A newer connection took over the client id
+    Takeover,
+    /// Closed on an operator's request
+    AdminKick,
+    /// The broker is shutting down
+    Shutdown,
+    /// The client's credentials are no longer valid
+    AuthRevoked,
+    /// The network connection failed or stopped keeping up
+    ConnectionLost,
+    /// The client stayed behind on its outgoing queue for too long
+    SlowConsumer,
+}
+
+impl DisconnectReason {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DisconnectReason::ClientDisconnect => "disconnect",
+            DisconnectReason::KeepAliveTimeout => "keep_alive_timeout",
+            DisconnectReason::ProtocolError => "protocol_error",
+            DisconnectReason::NotAuthorized => "not_authorized",
+            DisconnectReason::Takeover => "takeover",
+            DisconnectReason::AdminKick => "admin_kick",
+            DisconnectReason::Shutdown => "shutdown",
+            DisconnectReason::AuthRevoked => "auth_revoked",
+            DisconnectReason::ConnectionLost => "connection_lost",
+            DisconnectReason::SlowConsumer => "slow_consumer",
+        }
+    }
+
+    /// A client that said goodbye, was replaced by its own reconnect or is
+    /// parked by a shutdown didn't go away unexpectedly. Its will is discarded
+    pub fn publishes_will(&self) -> bool {
+        match *self {
+            DisconnectReason::ClientDisconnect | DisconnectReason::Takeover | DisconnectReason::Shutdown => false,
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DisconnectReason;
+
+    #[test]
+    fn only_unexpected_disconnects_publish_the_will() {
+        assert!(!DisconnectReason::ClientDisconnect.publishes_will());
+        assert!(!DisconnectReason::Takeover.publishes_will());
+        assert!(DisconnectReason::KeepAliveTimeout.publishes_will());
+        assert!(DisconnectReason::AdminKick.publishes_will());
+        assert_eq!(DisconnectReason::AuthRevoked.name(), "auth_revoked");
+    }
+}
@@ -0,0 +1,148 @@
+//! QoS downgrade statistics. A publish is downgraded when it's forwarded at
+//! a lower qos than it was published with because the subscription was
+//! granted less. Topic/subscriber pairs that are downgraded nearly every
+//! time point at misconfigured devices, e.g. a sensor publishing at QoS 2
+//! to a backend that only ever subscribes at QoS 0
+
+use std::collections::HashMap;
+
+use mqtt3::QoS;
+
+/// Forwards a pair needs before it can show up in the report
+pub const MIN_FORWARDED: u64 = 10;
+
+/// Share of a pair's forwards that have to be downgraded for the mismatch to
+/// count as systematic
+pub const SYSTEMATIC_RATIO: f64 = 0.9;
+
+/// Forwards of one topic to one subscriber since its first downgrade
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PairStats {
+    pub forwarded: u64,
+    pub downgraded: u64,
+    /// Highest qos the topic was published with
+    pub published: QoS,
+    /// Qos of the latest forward's subscription
+    pub granted: QoS,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub topic: String,
+    pub subscriber: String,
+    pub stats: PairStats,
+}
+
+/// Downgrade counts per topic and subscriber. Pairs are only tracked once
+/// they've been downgraded so matching subscriptions cost a lookup but no memory
+#[derive(Debug, Default)]
+pub struct DowngradeStats {
+    pairs: HashMap<String, HashMap<String, PairStats>>,
+    /// Downgraded forwards over all pairs
+    total: u64,
+}
+
+impl DowngradeStats {
+    pub fn new() -> Self {
+        DowngradeStats::default()
+    }
+
+    /// Records a forward of a publish on `topic` to `subscriber`
+    pub fn record(&mut self, topic: &str, subscriber: &str, published: QoS, granted: QoS) {
+        let downgraded = granted.to_u8() < published.to_u8();
+        if downgraded {
+            self.total += 1;
+        }
+
+        let tracked = self.pairs
+            .get_mut(topic)
+            .and_then(|subscribers| subscribers.get_mut(subscriber));
+
+        let stats = match tracked {
+            Some(stats) => stats,
+            None if downgraded => {
+                self.pairs
+                    .entry(topic.to_owned())
+                    .or_insert_with(HashMap::new)
+                    .entry(subscriber.to_owned())
+                    .or_insert(PairStats {
+                                   forwarded: 0,
+                                   downgraded: 0,
+                                   published: published,
+                                   granted: granted,
+                               })
+            }
+            None => return,
+        };
+
+        stats.forwarded += 1;
+        if downgraded {
+            stats.downgraded += 1;
+        }
+        if published.to_u8() > stats.published.to_u8() {
+            stats.published = published;
+        }
+        stats.granted = granted;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Pairs with systematic mismatches, most downgrades first
+    pub fn report(&self) -> Vec<Mismatch> {
+        let mut mismatches: Vec<Mismatch> = self.pairs
+            .iter()
+            .flat_map(|(topic, subscribers)| {
+                subscribers
+                    .iter()
+                    .filter(|&(_, stats)| {
+                                stats.forwarded >= MIN_FORWARDED &&
+                                stats.downgraded as f64 >= stats.forwarded as f64 * SYSTEMATIC_RATIO
+                            })
+                    .map(move |(subscriber, stats)| {
+                             Mismatch {
+                                 topic: topic.clone(),
+                                 subscriber: subscriber.clone(),
+                                 stats: *stats,
+                             }
+                         })
+            })
+            .collect();
+
+        mismatches.sort_by(|a, b| b.stats.downgraded.cmp(&a.stats.downgraded).then(a.topic.cmp(&b.topic)));
+        mismatches
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mqtt3::QoS;
+    use super::{DowngradeStats, MIN_FORWARDED};
+
+    #[test]
+    fn only_systematic_downgrades_are_reported() {
+        let mut stats = DowngradeStats::new();
+
+        for i in 0..MIN_FORWARDED {
+            // always downgraded
+            stats.record("sensors/1", "backend", QoS::ExactlyOnce, QoS::AtMostOnce);
+            // downgraded now and then
+            let qos = if i % 2 == 0 { QoS::AtMostOnce } else { QoS::AtLeastOnce };
+            stats.record("sensors/2", "backend", QoS::AtLeastOnce, qos);
+            // never downgraded. not tracked
+            stats.record("sensors/3", "backend", QoS::AtMostOnce, QoS::AtLeastOnce);
+        }
+
+        assert_eq!(stats.total(), MIN_FORWARDED + MIN_FORWARDED / 2);
+        assert!(stats.pairs.get("sensors/3").is_none());
+
+        let report = stats.report();
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].topic, "sensors/1");
+        assert_eq!(report[0].subscriber, "backend");
+        assert_eq!(report[0].stats.published, QoS::ExactlyOnce);
+        assert_eq!(report[0].stats.granted, QoS::AtMostOnce);
+        assert_eq!(report[0].stats.downgraded, MIN_FORWARDED);
+    }
+}
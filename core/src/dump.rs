@@ -0,0 +1,88 @@
+//! State dumps for post-mortem debugging. A snapshot of the broker's
+//! internals, the client table, subscription counts per prefix, queue
+//! depths and the busiest clients, written as gzipped JSON so support can
+//! look at an incident after the fact without a debugger attached
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use serde_json::{self, Value};
+
+use error::Result;
+
+/// Clients listed per top talker ranking
+pub const TOP_TALKERS: usize = 10;
+
+/// First level of a topic filter, which subscriptions are counted under
+pub fn prefix(filter: &str) -> &str {
+    filter.split('/').next().unwrap_or(filter)
+}
+
+/// Filters and subscribers per prefix
+pub fn subscription_counts<'a, I: Iterator<Item = (&'a String, usize)>>(subscriptions: I) -> Value {
+    let mut counts: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+    for (filter, subscribers) in subscriptions {
+        let count = counts.entry(prefix(filter)).or_insert((0, 0));
+        count.0 += 1;
+        count.1 += subscribers;
+    }
+
+    let counts: serde_json::Map<String, Value> = counts
+        .into_iter()
+        .map(|(prefix, (filters, subscribers))| (prefix.to_owned(), json!({"filters": filters, "subscribers": subscribers})))
+        .collect();
+    Value::Object(counts)
+}
+
+/// The `n` entries with the highest `key`, highest first
+pub fn top<T, F: Fn(&T) -> u64>(mut items: Vec<T>, n: usize, key: F) -> Vec<T> {
+    items.sort_by(|a, b| key(b).cmp(&key(a)));
+    items.truncate(n);
+    items
+}
+
+/// Writes `snapshot` to `dir` as `rumqttd-<timestamp>.json.gz`. Returns the file's path
+pub fn write(dir: &Path, snapshot: &Value, timestamp: u64) -> Result<PathBuf> {
+    let path = dir.join(format!("rumqttd-{}.json.gz", timestamp));
+    let json = serde_json::to_vec(snapshot).expect("json values serialize");
+    let mut gz = GzEncoder::new(File::create(&path)?, Compression::default());
+    gz.write_all(&json)?;
+    gz.finish()?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs::{self, File};
+    use flate2::read::GzDecoder;
+    use serde_json::{self, Value};
+    use super::{subscription_counts, top, write};
+
+    #[test]
+    fn subscriptions_are_counted_per_prefix() {
+        let subscriptions = vec![("sensors/1".to_owned(), 2), ("sensors/2".to_owned(), 1), ("commands".to_owned(), 4)];
+        let counts = subscription_counts(subscriptions.iter().map(|&(ref filter, n)| (filter, n)));
+        assert_eq!(counts["sensors"]["filters"], 2);
+        assert_eq!(counts["sensors"]["subscribers"], 3);
+        assert_eq!(counts["commands"]["subscribers"], 4);
+
+        assert_eq!(top(vec![3, 9, 1, 7], 2, |&n| n), vec![9, 7]);
+    }
+
+    #[test]
+    fn dumps_are_gzipped_json() {
+        let dir = env::temp_dir();
+        let snapshot = json!({"clients": [{"id": "d7", "queued": 3}], "subscriptions": {"sensors": {"filters": 2}}});
+        let path = write(&dir, &snapshot, 1_700_000_000).unwrap();
+        assert_eq!(path, dir.join("rumqttd-1700000000.json.gz"));
+
+        let read: Value = serde_json::from_reader(GzDecoder::new(File::open(&path).unwrap())).unwrap();
+        assert_eq!(read, snapshot);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,327 @@
+//! Connection metadata enrichment. With the `enrichment` feature the peer
+//! address of every accepted connection is looked up in reverse DNS and in a
+//! GeoIP table so operators can tell where their clients connect from. The
+//! lookups block, so they run on a worker thread and the connection is served
+//! in the meantime. Results are cached per address on the event loop side
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use futures::sync::oneshot;
+use libc;
+
+use client::Client;
+use clock::Clock;
+use error::{Error, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnrichConfig {
+    /// Look up the host name of peer addresses
+    pub reverse_dns: bool,
+    /// CSV of `<network>/<prefix>,<country>` lines, IPv4 or IPv6. `None`
+    /// leaves the country out
+    pub geoip: Option<PathBuf>,
+    /// How long a lookup result is reused for the same address
+    pub cache_ttl: Duration,
+    /// Addresses kept in the cache
+    pub cache_size: usize,
+}
+
+impl Default for EnrichConfig {
+    fn default() -> Self {
+        EnrichConfig {
+            reverse_dns: true,
+            geoip: None,
+            cache_ttl: Duration::from_secs(3600),
+            cache_size: 10_000,
+        }
+    }
+}
+
+/// What's known about where a connection comes from
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConnectMetadata {
+    pub host: Option<String>,
+    /// Country code from the GeoIP table
+    pub country: Option<String>,
+}
+
+/// Network ranges mapped to country codes. Addresses are kept as IPv6 with
+/// IPv4 mapped into it so both families share one sorted list
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GeoIpTable {
+    /// First and last address of each network with its country, sorted by the first address
+    ranges: Vec<(u128, u128, String)>,
+}
+
+fn key(ip: IpAddr) -> u128 {
+    let v6 = match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    };
+    u128::from(v6)
+}
+
+impl GeoIpTable {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut csv = String::new();
+        File::open(path.as_ref())?.read_to_string(&mut csv)?;
+        GeoIpTable::from_csv(&csv).map_err(|e| Error::Config(format!("{}: {}", path.as_ref().display(), e)))
+    }
+
+    /// Parses `<network>/<prefix>,<country>` lines. Empty lines and `#` comments are skipped
+    pub fn from_csv(csv: &str) -> ::std::result::Result<Self, String> {
+        let mut ranges = Vec::new();
+
+        for (n, line) in csv.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || format!("invalid entry on line {}", n + 1);
+            let mut fields = line.split(',');
+            let network = fields.next().ok_or_else(&invalid)?;
+            let country = fields.next().map(|c| c.trim()).filter(|c| !c.is_empty()).ok_or_else(&invalid)?;
+
+            let mut parts = network.trim().split('/');
+            let ip: IpAddr = parts.next().and_then(|ip| ip.parse().ok()).ok_or_else(&invalid)?;
+            let prefix: u32 = parts.next().and_then(|p| p.parse().ok()).ok_or_else(&invalid)?;
+
+            // ipv4 prefixes count from the start of the mapped range
+            let prefix = match ip {
+                IpAddr::V4(_) if prefix <= 32 => prefix + 96,
+                IpAddr::V6(_) if prefix <= 128 => prefix,
+                _ => return Err(invalid()),
+            };
+
+            let host_bits = 128 - prefix;
+            let mask = if host_bits == 128 { !0 } else { (1u128 << host_bits) - 1 };
+            let start = key(ip) & !mask;
+            ranges.push((start, start | mask, country.to_owned()));
+        }
+
+        // wider networks first so nested ones are found before them
+        ranges.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+        Ok(GeoIpTable { ranges: ranges })
+    }
+
+    /// Country of the most specific network containing `ip`
+    pub fn country(&self, ip: IpAddr) -> Option<&str> {
+        let ip = key(ip);
+        // networks starting at or before the address. nested ones come later
+        let candidates = match self.ranges.binary_search_by(|range| range.0.cmp(&ip)) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        };
+
+        self.ranges[..candidates]
+            .iter()
+            .rev()
+            .find(|&&(_, end, _)| ip <= end)
+            .map(|&(_, _, ref country)| country.as_str())
+    }
+}
+
+/// Host name of `ip`. `None` when it has no PTR record
+pub fn reverse_dns(ip: IpAddr) -> Option<String> {
+    let mut host = [0 as libc::c_char; 1025];
+
+    let code = unsafe {
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        let len = match SocketAddr::new(ip, 0) {
+            SocketAddr::V4(addr) => {
+                let sin = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in);
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6);
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_addr.s6_addr = addr.ip().octets();
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+
+        libc::getnameinfo(&storage as *const _ as *const libc::sockaddr,
+                          len as libc::socklen_t,
+                          host.as_mut_ptr(),
+                          host.len() as libc::socklen_t,
+                          ::std::ptr::null_mut(),
+                          0,
+                          libc::NI_NAMEREQD)
+    };
+
+    if code != 0 {
+        return None;
+    }
+
+    let host = unsafe { CStr::from_ptr(host.as_ptr()) };
+    host.to_str().ok().map(|host| host.to_owned())
+}
+
+/// Lookup results by address. Full caches make room by dropping the oldest entry
+#[derive(Debug)]
+pub struct MetadataCache {
+    entries: HashMap<IpAddr, (ConnectMetadata, Instant)>,
+    ttl: Duration,
+    size: usize,
+}
+
+impl MetadataCache {
+    pub fn new(ttl: Duration, size: usize) -> Self {
+        MetadataCache {
+            entries: HashMap::new(),
+            ttl: ttl,
+            size: size,
+        }
+    }
+
+    pub fn get(&self, ip: IpAddr, now: Instant) -> Option<ConnectMetadata> {
+        match self.entries.get(&ip) {
+            Some(&(ref metadata, at)) if now.duration_since(at) < self.ttl => Some(metadata.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, ip: IpAddr, metadata: ConnectMetadata, now: Instant) {
+        if self.entries.len() >= self.size && !self.entries.contains_key(&ip) {
+            let ttl = self.ttl;
+            self.entries.retain(|_, &mut (_, at)| now.duration_since(at) < ttl);
+        }
+
+        if self.entries.len() >= self.size && !self.entries.contains_key(&ip) {
+            let oldest = self.entries.iter().min_by_key(|&(_, &(_, at))| at).map(|(ip, _)| *ip);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.entries.insert(ip, (metadata, now));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+type Lookup = (IpAddr, oneshot::Sender<ConnectMetadata>);
+
+/// Hands addresses to the lookup thread and keeps the results
+pub struct Enricher {
+    lookups: std_mpsc::Sender<Lookup>,
+    cache: Rc<RefCell<MetadataCache>>,
+    clock: Rc<Clock>,
+}
+
+impl Enricher {
+    /// Loads the GeoIP table and starts the lookup thread
+    pub fn start(config: &EnrichConfig, clock: Rc<Clock>) -> Result<Self> {
+        let geoip = match config.geoip {
+            Some(ref path) => Some(GeoIpTable::load(path)?),
+            None => None,
+        };
+        let reverse = config.reverse_dns;
+
+        let (tx, rx) = std_mpsc::channel::<Lookup>();
+        thread::Builder::new()
+            .name("enrichment".to_owned())
+            .spawn(move || for (ip, reply) in rx {
+                       let metadata = ConnectMetadata {
+                           host: if reverse { reverse_dns(ip) } else { None },
+                           country: geoip.as_ref().and_then(|geoip| geoip.country(ip)).map(|c| c.to_owned()),
+                       };
+                       // the connection may be gone by now
+                       let _ = reply.send(metadata);
+                   })?;
+
+        Ok(Enricher {
+               lookups: tx,
+               cache: Rc::new(RefCell::new(MetadataCache::new(config.cache_ttl, config.cache_size))),
+               clock: clock,
+           })
+    }
+
+    /// Looks up the client's peer address and attaches the result to the client
+    pub fn enrich(&self, client: Client) -> Box<Future<Item = ConnectMetadata, Error = ()>> {
+        let ip = client.addr.ip();
+
+        if let Some(metadata) = self.cache.borrow().get(ip, self.clock.now()) {
+            client.set_metadata(metadata.clone());
+            return Box::new(future::ok(metadata));
+        }
+
+        let (reply, result) = oneshot::channel();
+        if self.lookups.send((ip, reply)).is_err() {
+            return Box::new(future::err(()));
+        }
+
+        let cache = self.cache.clone();
+        let clock = self.clock.clone();
+        let enriched = result.map_err(|_| ()).map(move |metadata| {
+            cache.borrow_mut().insert(ip, metadata.clone(), clock.now());
+            client.set_metadata(metadata.clone());
+            metadata
+        });
+
+        Box::new(enriched)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+    use super::{ConnectMetadata, GeoIpTable, MetadataCache};
+
+    #[test]
+    fn most_specific_network_wins() {
+        let table = GeoIpTable::from_csv("# network,country\n\
+                                          81.0.0.0/8,GB\n\
+                                          81.2.69.0/24,IE\n\
+                                          2001:db8::/32,DE\n")
+                .unwrap();
+
+        assert_eq!(table.country("81.2.69.160".parse().unwrap()), Some("IE"));
+        assert_eq!(table.country("81.2.70.1".parse().unwrap()), Some("GB"));
+        assert_eq!(table.country("82.0.0.1".parse().unwrap()), None);
+        assert_eq!(table.country("2001:db8::1".parse().unwrap()), Some("DE"));
+
+        assert!(GeoIpTable::from_csv("81.0.0.0/33,GB").is_err());
+        assert!(GeoIpTable::from_csv("81.0.0.0/8").is_err());
+    }
+
+    #[test]
+    fn cache_entries_expire_and_make_room() {
+        let now = Instant::now();
+        let mut cache = MetadataCache::new(Duration::from_secs(60), 2);
+        let metadata = ConnectMetadata {
+            host: Some("a.example".to_owned()),
+            country: None,
+        };
+
+        cache.insert("10.0.0.1".parse().unwrap(), metadata.clone(), now);
+        assert_eq!(cache.get("10.0.0.1".parse().unwrap(), now + Duration::from_secs(59)), Some(metadata.clone()));
+        assert_eq!(cache.get("10.0.0.1".parse().unwrap(), now + Duration::from_secs(60)), None);
+
+        cache.insert("10.0.0.2".parse().unwrap(), metadata.clone(), now + Duration::from_secs(1));
+        cache.insert("10.0.0.3".parse().unwrap(), metadata.clone(), now + Duration::from_secs(2));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("10.0.0.1".parse().unwrap(), now + Duration::from_secs(2)), None);
+    }
+}
@@ -8,6 +8,7 @@ pub type Result<T> = result::Result<T, Error>;
 
 quick_error! {
     #[derive(Debug)]
+    #[non_exhaustive]
     pub enum Error {
         Io(err: io::Error) {
             from()
@@ -27,6 +28,21 @@ quick_error! {
             cause(err)
             display("timer error: {}", err)
         }
+        /// Client's connection is gone
+        Disconnected {
+            description("client disconnected")
+            display("client disconnected")
+        }
+        /// Client isn't reading its packets fast enough
+        QueueFull {
+            description("client queue full")
+            display("client queue full")
+        }
+        /// Broker settings that don't make sense
+        Config(reason: String) {
+            description("invalid config")
+            display("invalid config: {}", reason)
+        }
         Other
     }
 }
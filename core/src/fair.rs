@@ -0,0 +1,151 @@
+//! Fair scheduling of the outgoing queues. Every connection writes from its
+//! own task on the shared event loop, and a subscriber with a deep queue
+//! would otherwise hand all of it to its socket before the other connections
+//! get a turn. Each writer gets a quantum of frames per turn instead and
+//! then goes to the back of the run queue. Weights give chosen clients, like
+//! backends behind one big wildcard subscription, several quanta per turn
+
+use futures::{task, Async, Poll, Stream};
+use futures::sync::mpsc::Receiver;
+
+use client::Client;
+use codec::Frame;
+
+/// Quanta per turn of the clients whose id starts with `client_id_prefix`
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteWeight {
+    pub client_id_prefix: String,
+    pub weight: usize,
+}
+
+/// Frames a client may write per turn. The longest matching prefix decides
+/// its weight, other clients have a weight of 1
+pub fn budget(quantum: usize, weights: &[WriteWeight], client_id: &str) -> usize {
+    let weight = weights
+        .iter()
+        .filter(|w| client_id.starts_with(w.client_id_prefix.as_str()))
+        .max_by_key(|w| w.client_id_prefix.len())
+        .map_or(1, |w| w.weight);
+
+    quantum.saturating_mul(weight)
+}
+
+/// Stream that yields to the other tasks after `budget` items. `None` never
+/// yields
+#[derive(Debug)]
+pub struct Budgeted<S> {
+    stream: S,
+    budget: Option<usize>,
+    remaining: usize,
+}
+
+impl<S> Budgeted<S> {
+    pub fn new(stream: S, budget: Option<usize>) -> Self {
+        Budgeted {
+            stream: stream,
+            budget: budget,
+            remaining: budget.unwrap_or(0),
+        }
+    }
+}
+
+impl<S: Stream> Stream for Budgeted<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        let budget = match self.budget {
+            Some(budget) => budget,
+            None => return self.stream.poll(),
+        };
+
+        // turn's over. polled again once the tasks queued before are done
+        if self.remaining == 0 {
+            self.remaining = budget;
+            task::current().notify();
+            return Ok(Async::NotReady);
+        }
+
+        match self.stream.poll()? {
+            Async::Ready(Some(item)) => {
+                self.remaining -= 1;
+                Ok(Async::Ready(Some(item)))
+            }
+            // an idle writer starts its next turn with a full budget
+            done => {
+                self.remaining = budget;
+                Ok(done)
+            }
+        }
+    }
+}
+
+/// Frames of the outgoing queue of `client` in the order its connection
+/// writes them, `budget` frames per turn. Every frame taken off the queue
+/// makes room for one that's held back
+pub fn writer(rx: Receiver<Frame>, client: Client, budget: Option<usize>) -> Box<Stream<Item = Frame, Error = ()>> {
+    let frames = Budgeted::new(rx, budget).inspect(move |_| {
+                                                      let _ = client.frame_written();
+                                                  });
+    Box::new(frames)
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+    use futures::{future, stream, Async, Future, Stream};
+    use tokio_io::codec::{Decoder, Encoder};
+    use mqtt3::*;
+    use broker::Broker;
+    use client::{outgoing_queue, Client};
+    use codec::{MqttCodec, Transport, MAX_PACKET_SIZE};
+    use super::{budget, writer, Budgeted, WriteWeight};
+
+    #[test]
+    fn writers_yield_after_their_budget() {
+        let weights = vec![WriteWeight {
+                               client_id_prefix: "backend-".to_owned(),
+                               weight: 4,
+                           }];
+        assert_eq!(budget(8, &weights, "backend-1"), 32);
+        assert_eq!(budget(8, &weights, "device-1"), 8);
+
+        let mut frames = Budgeted::new(stream::iter_ok::<_, ()>(0..5), Some(2));
+        let polled = future::lazy(move || Ok::<_, ()>((0..8).map(|_| frames.poll().unwrap()).collect::<Vec<_>>()))
+            .wait()
+            .unwrap();
+
+        assert_eq!(polled,
+                   vec![Async::Ready(Some(0)),
+                        Async::Ready(Some(1)),
+                        Async::NotReady,
+                        Async::Ready(Some(2)),
+                        Async::Ready(Some(3)),
+                        Async::NotReady,
+                        Async::Ready(Some(4)),
+                        Async::Ready(None)]);
+    }
+
+    #[test]
+    fn unsubacks_are_written_to_the_connection() {
+        let (tx, rx) = outgoing_queue(8);
+        let client = Client::new("mock-client-1", "127.0.0.1:80".parse().unwrap(), tx);
+        let broker = Broker::new();
+        broker.add_client(client.clone());
+
+        let unsubscribe = Box::new(Unsubscribe {
+                                       pid: PacketIdentifier(7),
+                                       topics: vec!["hello/mqtt".to_owned()],
+                                   });
+        broker.handle_unsubscribe(unsubscribe, &client);
+
+        let frame = match writer(rx, client, Some(2)).into_future().wait() {
+            Ok((Some(frame), _)) => frame,
+            _ => panic!("Expected a frame"),
+        };
+        let mut transport = Transport::Tcp(MqttCodec::new(MAX_PACKET_SIZE));
+        let mut buf = BytesMut::new();
+        transport.encode(frame, &mut buf).unwrap();
+        assert_eq!(transport.decode(&mut buf).unwrap(), Some(Packet::Unsuback(PacketIdentifier(7))));
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::{HashMap, HashSet};
+
+use mqtt3::QoS;
+
+use client::Client;
+
+/// A fleet of clients auto-subscribed to a command topic
+#[derive(Debug, Clone)]
+pub struct GroupConfig {
+    pub name: String,
+    /// Topic whose publishes are broadcast to the group
+    pub topic: String,
+    /// Clients whose id starts with this prefix are members
+    pub client_id_prefix: String,
+    pub qos: QoS,
+}
+
+/// Precomputed delivery list of a group. Membership is updated as clients
+/// connect and disconnect so broadcasts don't need any matching
+#[derive(Debug)]
+struct ClientGroup {
+    config: GroupConfig,
+    members: HashMap<String, Client>,
+}
+
+#[derive(Debug)]
+pub struct ClientGroups {
+    groups: Vec<ClientGroup>,
+}
+
+impl ClientGroups {
+    pub fn new(configs: Vec<GroupConfig>) -> Self {
+        let groups = configs
+            .into_iter()
+            .map(|config| {
+                     ClientGroup {
+                         config: config,
+                         members: HashMap::new(),
+                     }
+                 })
+            .collect();
+
+        ClientGroups { groups: groups }
+    }
+
+    /// Adds a newly connected client to every group it belongs to
+    pub fn join(&mut self, client: &Client) {
+        for group in self.groups.iter_mut() {
+            if client.id.starts_with(&group.config.client_id_prefix) {
+                group.members.insert(client.id.clone(), client.clone());
+            }
+        }
+    }
+
+    /// Removes a disconnected client from all the groups
+    pub fn leave(&mut self, id: &str) {
+        for group in self.groups.iter_mut() {
+            group.members.remove(id);
+        }
+    }
+
+    /// Appends members of groups broadcasting on `topic` to `deliveries`,
+    /// skipping clients that are already in there through a subscription
+    pub fn extend_deliveries(&self, topic: &str, deliveries: &mut Vec<(Client, QoS)>) {
+        let mut groups = self.groups.iter().filter(|g| g.config.topic == topic).peekable();
+
+        if groups.peek().is_none() {
+            return;
+        }
+
+        let mut seen: HashSet<String> = deliveries.iter().map(|&(ref c, _)| c.id.clone()).collect();
+
+        for group in groups {
+            for (id, client) in group.members.iter() {
+                if seen.insert(id.clone()) {
+                    deliveries.push((client.clone(), group.config.qos));
+                }
+            }
+        }
+    }
+
+    /// Group names with their member counts
+    pub fn list(&self) -> Vec<(String, usize)> {
+        self.groups
+            .iter()
+            .map(|g| (g.config.name.clone(), g.members.len()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::sync::mpsc;
+    use mqtt3::QoS;
+    use client::Client;
+    use codec::Frame;
+    use super::{ClientGroups, GroupConfig};
+
+    fn mock_client(id: &str) -> Client {
+        let (tx, _) = mpsc::channel::<Frame>(8);
+        Client::new(id, "127.0.0.1:80".parse().unwrap(), tx)
+    }
+
+    #[test]
+    fn membership_follows_connects_and_disconnects() {
+        let mut groups = ClientGroups::new(vec![GroupConfig {
+                                                    name: "sensors".to_owned(),
+                                                    topic: "commands/sensors".to_owned(),
+                                                    client_id_prefix: "sensor-".to_owned(),
+                                                    qos: QoS::AtLeastOnce,
+                                                }]);
+
+        groups.join(&mock_client("sensor-1"));
+        groups.join(&mock_client("sensor-2"));
+        groups.join(&mock_client("dashboard"));
+        assert_eq!(groups.list(), vec![("sensors".to_owned(), 2)]);
+
+        // sensor-1 is also subscribed. it shouldn't get the broadcast twice
+        let mut deliveries = vec![(mock_client("sensor-1"), QoS::AtMostOnce)];
+        groups.extend_deliveries("commands/sensors", &mut deliveries);
+        assert_eq!(deliveries.len(), 2);
+        assert_eq!(deliveries[1].0.id, "sensor-2");
+        assert_eq!(deliveries[1].1, QoS::AtLeastOnce);
+
+        groups.leave("sensor-2");
+        let mut deliveries = vec![];
+        groups.extend_deliveries("commands/sensors", &mut deliveries);
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].0.id, "sensor-1");
+
+        let mut deliveries = vec![];
+        groups.extend_deliveries("commands/other", &mut deliveries);
+        assert_eq!(deliveries.len(), 0);
+    }
+}
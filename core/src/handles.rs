@@ -0,0 +1,190 @@
+//! Leak tracking for `Client` handles. Clients are cloned into subscription
+//! lists, groups and queues, so a clone that isn't cleaned up keeps the whole
+//! connection state alive. With the `handle-tracker` feature the live handles
+//! of every connection are counted and the ones still alive a while after
+//! the client was removed from the broker are reported. Without it the
+//! tracking calls do nothing
+
+#[cfg(not(feature = "handle-tracker"))]
+pub use self::disabled::*;
+#[cfg(feature = "handle-tracker")]
+pub use self::enabled::*;
+
+#[cfg(not(feature = "handle-tracker"))]
+mod disabled {
+    use client::Client;
+
+    pub fn register(_client: &Client) {}
+
+    pub fn removed(_client: &Client) -> Vec<String> {
+        vec![]
+    }
+}
+
+#[cfg(feature = "handle-tracker")]
+mod enabled {
+    use std::cell::RefCell;
+    use std::net::SocketAddr;
+    use std::rc::{Rc, Weak};
+    use std::time::{Duration, Instant};
+
+    use client::{Client, ClientState};
+    use profile::Tracked;
+
+    /// How long handles may outlive `remove_client` before they count as
+    /// leaked. Covers the connection future and in flight callers letting go
+    pub const LEAK_GRACE: Duration = Duration::from_secs(5);
+
+    /// Handles of a removed client that are still alive
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct LeakedHandle {
+        pub id: String,
+        pub addr: SocketAddr,
+        pub handles: usize,
+        pub removed_for: Duration,
+    }
+
+    struct Connection {
+        id: String,
+        addr: SocketAddr,
+        /// Every `Client` clone holds one strong reference to the state
+        state: Weak<Tracked<ClientState>>,
+        removed: Option<Instant>,
+        warned: bool,
+    }
+
+    impl Connection {
+        fn leak(&self, grace: Duration) -> Option<LeakedHandle> {
+            let removed = self.removed?;
+            let handles = self.state.upgrade().map(|state| Rc::strong_count(&state) - 1)?;
+
+            if handles == 0 || removed.elapsed() < grace {
+                return None;
+            }
+
+            Some(LeakedHandle {
+                     id: self.id.clone(),
+                     addr: self.addr,
+                     handles: handles,
+                     removed_for: removed.elapsed(),
+                 })
+        }
+    }
+
+    thread_local! {
+        static CONNECTIONS: RefCell<Vec<Connection>> = RefCell::new(Vec::new());
+    }
+
+    /// Starts tracking the handles of a new connection
+    pub fn register(client: &Client) {
+        CONNECTIONS.with(|connections| {
+            let mut connections = connections.borrow_mut();
+            connections.retain(|c| c.state.upgrade().is_some());
+            connections.push(Connection {
+                                 id: client.id.clone(),
+                                 addr: client.addr,
+                                 state: Rc::downgrade(&client.state),
+                                 removed: None,
+                                 warned: false,
+                             });
+        })
+    }
+
+    /// Marks the connection as removed from the broker. Returns the ids of
+    /// connections that just went past the grace period with handles alive,
+    /// each reported only once
+    pub fn removed(client: &Client) -> Vec<String> {
+        CONNECTIONS.with(|connections| {
+            let mut connections = connections.borrow_mut();
+
+            for c in connections.iter_mut() {
+                let same = c.state.upgrade().map_or(false, |state| Rc::ptr_eq(&state, &client.state));
+                if same && c.removed.is_none() {
+                    c.removed = Some(Instant::now());
+                }
+            }
+
+            let mut leaked = vec![];
+            for c in connections.iter_mut().filter(|c| !c.warned) {
+                if c.leak(LEAK_GRACE).is_some() {
+                    c.warned = true;
+                    leaked.push(c.id.clone());
+                }
+            }
+
+            leaked
+        })
+    }
+
+    /// Live handles of all the connections of client `id`
+    pub fn live_handles(id: &str) -> usize {
+        CONNECTIONS.with(|connections| {
+            connections
+                .borrow()
+                .iter()
+                .filter(|c| c.id == id)
+                .filter_map(|c| c.state.upgrade())
+                .map(|state| Rc::strong_count(&state) - 1)
+                .sum()
+        })
+    }
+
+    /// Removed connections whose handles outlived `grace`
+    pub fn leaked(grace: Duration) -> Vec<LeakedHandle> {
+        CONNECTIONS.with(|connections| connections.borrow().iter().filter_map(|c| c.leak(grace)).collect())
+    }
+
+    #[cfg(test)]
+    mod test {
+        use std::time::Duration;
+        use futures::sync::mpsc;
+        use mqtt3::*;
+        use broker::Broker;
+        use client::Client;
+        use codec::Frame;
+        use super::{leaked, live_handles, removed};
+
+        fn mock_client(id: &str) -> Client {
+            let (tx, _) = mpsc::channel::<Frame>(8);
+            Client::new(id, "127.0.0.1:80".parse().unwrap(), tx)
+        }
+
+        #[test]
+        fn handles_alive_after_removal_are_reported() {
+            let client = mock_client("handles-1");
+            let stray = client.clone();
+            assert_eq!(live_handles("handles-1"), 2);
+
+            removed(&client);
+            drop(client);
+            let leaks = leaked(Duration::from_secs(0));
+            assert_eq!(leaks.len(), 1);
+            assert_eq!(leaks[0].id, "handles-1");
+            assert_eq!(leaks[0].handles, 1);
+
+            drop(stray);
+            assert_eq!(live_handles("handles-1"), 0);
+            assert!(leaked(Duration::from_secs(0)).is_empty());
+        }
+
+        #[test]
+        fn broker_lets_go_of_removed_clients() {
+            let (tx, _rx) = mpsc::channel::<Frame>(8);
+            let client = Client::new("handles-2", "127.0.0.1:80".parse().unwrap(), tx);
+            let broker = Broker::new();
+            broker.add_client(client.clone());
+            let subscribe = Box::new(Subscribe {
+                                         pid: PacketIdentifier(1),
+                                         topics: vec![SubscribeTopic {
+                                                          topic_path: "hello/mqtt".to_owned(),
+                                                          qos: QoS::AtMostOnce,
+                                                      }],
+                                     });
+            broker.handle_subscribe(subscribe, &client);
+            assert!(live_handles("handles-2") > 1);
+
+            broker.remove_client("handles-2");
+            assert_eq!(live_handles("handles-2"), 1);
+        }
+    }
+}
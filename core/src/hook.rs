@@ -0,0 +1,236 @@
+//! Message webhook. Publishes matching the configured filters are POSTed to
+//! an HTTP endpoint as
+//!
+//! ```text
+//! {"topic":"sensors/7","payload":"MjEuNQ==","qos":1,"retain":false,"client_id":"d7","username":"device","timestamp":1700000000000}
+//! ```
+//!
+//! with the payload in base64 and the timestamp in milliseconds since the
+//! unix epoch. The requests are made on a thread of their own. A request
+//! that fails or gets a `5xx` or `429` answer is retried with a doubling
+//! wait, other answers are final. Messages over the rate limit, or arriving
+//! while the queue of the thread is full, are dropped and counted
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self as std_mpsc, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::{Client, Uri};
+use hyper::client::HttpConnector;
+use mqtt3::Publish;
+use slog::Logger;
+use tokio_core::reactor::Core;
+
+use base64;
+use error::Result;
+use http;
+
+/// Longest wait for the endpoint's answer
+pub const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Messages waiting for the request thread before new ones are dropped
+const QUEUE_SIZE: usize = 1000;
+
+/// Wait before the first retry
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// JSON document describing a publish of `client_id`
+pub fn request_body(publish: &Publish, client_id: &str, username: Option<&str>, timestamp: Duration) -> String {
+    let body = json!({
+        "topic": publish.topic_name,
+        "payload": base64::encode(&publish.payload),
+        "qos": publish.qos.to_u8(),
+        "retain": publish.retain,
+        "client_id": client_id,
+        "username": username,
+        "timestamp": timestamp.as_millis() as u64,
+    });
+    body.to_string()
+}
+
+/// True for answers worth asking again
+fn retryable(status: u16) -> bool {
+    status == 429 || status >= 500
+}
+
+/// POSTs `body` until the endpoint answers or `retries` more attempts failed.
+/// Returns the last error
+fn deliver(core: &mut Core, client: &Client<HttpConnector>, uri: &Uri, body: &str, retries: usize) -> ::std::result::Result<(), String> {
+    let handle = core.handle();
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        let error = match core.run(http::send(client, uri.clone(), body.to_owned(), TIMEOUT, &handle)) {
+            Ok(200..=299) => return Ok(()),
+            Ok(status) if !retryable(status) => return Err(format!("status {}", status)),
+            Ok(status) => format!("status {}", status),
+            Err(e) => e.to_string(),
+        };
+        if attempt == retries {
+            return Err(error);
+        }
+
+        attempt += 1;
+        thread::sleep(delay);
+        delay *= 2;
+    }
+}
+
+/// Queue of the request thread. Dropping it stops the thread once the
+/// queued messages are sent
+pub struct MessageHook {
+    bodies: SyncSender<String>,
+    /// Messages dropped on a full queue or after their last attempt
+    dropped: Arc<AtomicUsize>,
+}
+
+impl MessageHook {
+    /// Starts the request thread for the endpoint at `url`
+    pub fn start(url: &str, retries: usize, logger: Logger) -> Result<Self> {
+        let uri = http::uri(url)?;
+
+        let (tx, rx) = std_mpsc::sync_channel::<String>(QUEUE_SIZE);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let failed = dropped.clone();
+        thread::Builder::new()
+            .name("message-webhook".to_owned())
+            .spawn(move || {
+                // the thread's own event loop, for the requests only
+                let mut core = match Core::new() {
+                    Ok(core) => core,
+                    Err(e) => {
+                        error!(logger, "Unable to start the message webhook. Error = {}", e);
+                        return;
+                    }
+                };
+                let client = Client::new(&core.handle());
+                for body in rx {
+                    if let Err(e) = deliver(&mut core, &client, &uri, &body, retries) {
+                        warn!(logger, "Message webhook failed. Dropping message. Error = {}", e);
+                        failed.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            })?;
+
+        Ok(MessageHook {
+               bodies: tx,
+               dropped: dropped,
+           })
+    }
+
+    /// Queues `publish` for the endpoint. False if it was dropped
+    pub fn send(&self, publish: &Publish, client_id: &str, username: Option<&str>) -> bool {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        match self.bodies.try_send(request_body(publish, client_id, username, timestamp)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.count_dropped();
+                false
+            }
+        }
+    }
+
+    /// Counts a message that never made it to the queue, e.g. over the rate limit
+    pub fn count_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Messages dropped so far
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use mqtt3::{PacketIdentifier, Publish, QoS};
+    use serde_json::{self, Value};
+    use slog::{Discard, Logger};
+    use super::{request_body, MessageHook};
+
+    fn publish() -> Publish {
+        Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            pid: Some(PacketIdentifier(1)),
+            topic_name: "sensors/7".to_owned(),
+            payload: Arc::new(b"21.5".to_vec()),
+        }
+    }
+
+    /// Head and body of the request on `stream`
+    fn read_request(stream: &mut TcpStream) -> String {
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            let text = String::from_utf8_lossy(&request).into_owned();
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text.lines()
+                    .find(|line| line.to_lowercase().starts_with("content-length:"))
+                    .and_then(|line| line[15..].trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                if request.len() >= end + 4 + length {
+                    return text;
+                }
+            }
+            match stream.read(&mut buf).unwrap() {
+                0 => return text,
+                n => request.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    #[test]
+    fn bodies_carry_the_metadata() {
+        let body = request_body(&publish(), "d7", None, Duration::from_secs(1_700_000_000));
+        let v: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(v["topic"], "sensors/7");
+        assert_eq!(v["payload"], "MjEuNQ==");
+        assert_eq!(v["qos"], 1);
+        assert_eq!(v["client_id"], "d7");
+        assert_eq!(v["username"], Value::Null);
+        assert_eq!(v["timestamp"], 1_700_000_000_000u64);
+    }
+
+    #[test]
+    fn unavailable_endpoints_are_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/mqtt/messages", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        {
+            let requests = requests.clone();
+            thread::spawn(move || for stream in listener.incoming() {
+                              let mut stream = stream.unwrap();
+                              let request = read_request(&mut stream);
+                              let mut requests = requests.lock().unwrap();
+                              requests.push(request);
+                              let status = if requests.len() == 1 { "503 Service Unavailable" } else { "200 OK" };
+                              write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+                          });
+        }
+
+        let hook = MessageHook::start(&url, 2, Logger::root(Discard, o!())).unwrap();
+        assert!(hook.send(&publish(), "d7", Some("device")));
+        for _ in 0..200 {
+            if requests.lock().unwrap().len() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].starts_with("POST /mqtt/messages HTTP/1.1\r\n"));
+        assert!(requests[1].contains("\"topic\":\"sensors/7\""));
+        assert_eq!(hook.dropped(), 0);
+        assert!(MessageHook::start("https://hooks/messages", 2, Logger::root(Discard, o!())).is_err());
+    }
+}
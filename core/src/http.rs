@@ -0,0 +1,56 @@
+//! HTTP requests of the webhooks. Bodies are JSON documents POSTed over
+//! plain HTTP on an event loop, with one connection per request
+
+use std::io;
+use std::time::Duration;
+
+use futures::Future;
+use futures::future::{self, Either};
+use hyper::{Client, Method, Request, Uri};
+use hyper::client::HttpConnector;
+use hyper::header::{Connection, ContentLength, ContentType};
+use tokio_core::reactor::{Handle, Timeout};
+
+use error::{Error, Result};
+
+/// Uri of an `http://host[:port][/path]` endpoint
+pub fn uri(url: &str) -> Result<Uri> {
+    let uri: Uri = url.parse().map_err(|e| Error::Config(format!("{:?}: {}", url, e)))?;
+    if uri.scheme() != Some("http") {
+        return Err(Error::Config(format!("{:?} isn't an http url", url)));
+    }
+    if uri.host().map_or(true, str::is_empty) {
+        return Err(Error::Config(format!("{:?} has no host", url)));
+    }
+    Ok(uri)
+}
+
+/// Checks that requests can be made to `url`
+pub fn check_url(url: &str) -> Result<()> {
+    uri(url).map(|_| ())
+}
+
+/// POSTs the JSON `body` to `uri` on the event loop of `handle`. The
+/// timeout bounds the whole request, name lookup included. Resolves to the
+/// status of the answer
+pub fn send(client: &Client<HttpConnector>, uri: Uri, body: String, timeout: Duration, handle: &Handle) -> Box<Future<Item = u16, Error = Error>> {
+    let deadline = match Timeout::new(timeout, handle) {
+        Ok(deadline) => deadline,
+        Err(e) => return Box::new(future::err(Error::Io(e))),
+    };
+
+    let mut request = Request::new(Method::Post, uri);
+    request.headers_mut().set(ContentType::json());
+    request.headers_mut().set(ContentLength(body.len() as u64));
+    request.headers_mut().set(Connection::close());
+    request.set_body(body);
+
+    let status = client.request(request).map(|response| response.status().as_u16());
+    let answer = status.select2(deadline).then(|answer| match answer {
+        Ok(Either::A((status, _))) => Ok(status),
+        Ok(Either::B(_)) => Err(Error::Io(io::Error::new(io::ErrorKind::TimedOut, "no answer within the timeout"))),
+        Err(Either::A((e, _))) => Err(Error::Io(io::Error::new(io::ErrorKind::Other, e.to_string()))),
+        Err(Either::B((e, _))) => Err(Error::Io(e)),
+    });
+    Box::new(answer)
+}
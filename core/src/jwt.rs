@@ -0,0 +1,305 @@
+//! JWT authentication. Clients send a signed token as the CONNECT password
+//! (MQTT wants a username along with it, which can be anything). HS256
+//! tokens are checked against a shared secret and RS256 tokens against an
+//! RSA public key. `exp` is required, and `nbf`, `iss`, `aud` and `sub`
+//! (which has to be the client id) are checked when present or configured.
+//! With a `topics_claim` the client may only publish and subscribe under
+//! the topic prefixes listed in that claim
+
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use jsonwebtoken::{self, Algorithm, DecodingKey, Validation};
+use jsonwebtoken::errors::ErrorKind;
+use mqtt3::ConnectReturnCode;
+use serde_json::Value;
+use tokio_core::reactor::Handle;
+
+use auth::{Authenticator, Verdict, Verification};
+use error::{Error, Result};
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct JwtConfig {
+    /// File with the HS256 secret. A trailing line break isn't part of it
+    pub hmac_secret: Option<PathBuf>,
+    /// PEM file with the RS256 public key
+    pub rsa_public_key: Option<PathBuf>,
+    /// Required `iss` of tokens
+    pub issuer: Option<String>,
+    /// Required `aud` of tokens
+    pub audience: Option<String>,
+    /// Clock skew allowed on `exp` and `nbf`
+    pub leeway: Duration,
+    /// Claim with the topic prefixes the client is confined to
+    pub topics_claim: Option<String>,
+}
+
+impl JwtConfig {
+    pub fn new() -> Self {
+        JwtConfig::default()
+    }
+}
+
+/// Verification keys
+#[derive(Clone, Default)]
+pub struct Keys {
+    pub hmac: Option<DecodingKey>,
+    pub rsa: Option<DecodingKey>,
+}
+
+/// What a valid token says about the client
+#[derive(Debug, Clone, PartialEq)]
+pub struct Claims {
+    /// Prefixes from the topics claim. `None` without one configured
+    pub topics: Option<Vec<String>>,
+}
+
+/// Checks the signature of `token` and returns its claims. Time and
+/// audience are left to `validate`, which knows the time
+fn verify(token: &str, keys: &Keys) -> ::std::result::Result<Value, String> {
+    let header = jsonwebtoken::decode_header(token).map_err(|e| e.to_string())?;
+    let key = match (header.alg, keys) {
+        (Algorithm::HS256, &Keys { hmac: Some(ref secret), .. }) => secret,
+        (Algorithm::RS256, &Keys { rsa: Some(ref key), .. }) => key,
+        (alg, _) => return Err(format!("unsupported algorithm {:?}", alg)),
+    };
+
+    let mut validation = Validation::new(header.alg);
+    validation.validate_exp = false;
+    validation.required_spec_claims.clear();
+    match jsonwebtoken::decode::<Value>(token, key, &validation) {
+        Ok(data) => {
+            match data.claims {
+                claims @ Value::Object(_) => Ok(claims),
+                _ => Err("not a json object".to_owned()),
+            }
+        }
+        Err(e) => {
+            match *e.kind() {
+                ErrorKind::InvalidSignature => Err("bad signature".to_owned()),
+                _ => Err(e.to_string()),
+            }
+        }
+    }
+}
+
+/// Checks signature and claims of `token` for `client_id` at `now` seconds
+/// since the epoch
+pub fn validate(token: &str, client_id: &str, keys: &Keys, config: &JwtConfig, now: u64) -> ::std::result::Result<Claims, String> {
+    let claims = verify(token, keys)?;
+    let leeway = config.leeway.as_secs();
+    match claims["exp"].as_u64() {
+        Some(exp) if now <= exp.saturating_add(leeway) => (),
+        Some(_) => return Err("expired".to_owned()),
+        None => return Err("no exp claim".to_owned()),
+    }
+    if let Some(nbf) = claims.get("nbf") {
+        match nbf.as_u64() {
+            Some(nbf) if now.saturating_add(leeway) >= nbf => (),
+            _ => return Err("not valid yet".to_owned()),
+        }
+    }
+
+    if let Some(ref issuer) = config.issuer {
+        if claims["iss"].as_str() != Some(issuer.as_str()) {
+            return Err("wrong issuer".to_owned());
+        }
+    }
+    if let Some(ref audience) = config.audience {
+        let matches = match claims["aud"] {
+            Value::String(ref aud) => aud == audience,
+            Value::Array(ref auds) => auds.iter().any(|aud| aud.as_str() == Some(audience.as_str())),
+            _ => false,
+        };
+        if !matches {
+            return Err("wrong audience".to_owned());
+        }
+    }
+    if let Some(sub) = claims.get("sub") {
+        if sub.as_str() != Some(client_id) {
+            return Err("subject isn't the client id".to_owned());
+        }
+    }
+
+    let topics = match config.topics_claim {
+        Some(ref name) => {
+            let topics = match claims[name.as_str()] {
+                Value::String(ref prefix) => Some(vec![prefix.clone()]),
+                Value::Array(ref prefixes) => prefixes.iter().map(|p| p.as_str().map(|p| p.to_owned())).collect(),
+                _ => None,
+            };
+            Some(topics.ok_or_else(|| format!("no list of topics in {:?}", name))?)
+        }
+        None => None,
+    };
+
+    Ok(Claims { topics: topics })
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+pub struct JwtAuth {
+    keys: Keys,
+    config: JwtConfig,
+}
+
+impl JwtAuth {
+    /// Loads the keys of `config`
+    pub fn new(config: JwtConfig) -> Result<Self> {
+        let mut keys = Keys::default();
+        if let Some(ref path) = config.hmac_secret {
+            let mut secret = read(path)?;
+            while secret.last().map_or(false, |&b| b == b'\n' || b == b'\r') {
+                secret.pop();
+            }
+            if secret.is_empty() {
+                return Err(Error::Config(format!("{}: empty secret", path.display())));
+            }
+            keys.hmac = Some(DecodingKey::from_secret(&secret));
+        }
+        if let Some(ref path) = config.rsa_public_key {
+            let key = DecodingKey::from_rsa_pem(&read(path)?).map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+            keys.rsa = Some(key);
+        }
+
+        Ok(JwtAuth {
+               keys: keys,
+               config: config,
+           })
+    }
+
+    fn validate(&self, client_id: &str, password: &str) -> ::std::result::Result<Claims, String> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        validate(password, client_id, &self.keys, &self.config, now)
+    }
+}
+
+impl Authenticator for JwtAuth {
+    fn authenticate(&self, client_id: &str, _: Option<&str>, password: Option<&str>, _: SocketAddr, _: &Handle) -> Verification {
+        let verdict = match password.map(|token| self.validate(client_id, token)) {
+            Some(Ok(claims)) => {
+                Verdict {
+                    code: ConnectReturnCode::Accepted,
+                    topic_prefixes: claims.topics,
+                }
+            }
+            Some(Err(_)) => ConnectReturnCode::BadUsernamePassword.into(),
+            None => ConnectReturnCode::NotAuthorized.into(),
+        };
+        verdict.ready()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use std::time::Duration;
+    use mqtt3::ConnectReturnCode;
+    use futures::Future;
+    use jsonwebtoken::DecodingKey;
+    use tokio_core::reactor::Core;
+    use auth::Authenticator;
+    use super::{validate, JwtAuth, JwtConfig, Keys};
+
+    /// `{"sub":"d7","iss":"auth.example","aud":["mqtt"],"exp":4102444800,"nbf":1700000000,"topics":["devices/d7/","fleet/"]}`
+    const HS256: &str = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.\
+                         eyJzdWIiOiJkNyIsImlzcyI6ImF1dGguZXhhbXBsZSIsImF1ZCI6WyJtcXR0Il0sImV4cCI6NDEwMjQ0NDgwMCwibmJmIjoxNzAwMDAwMDAwLCJ0b3BpY3MiOlsiZGV2aWNlcy9kNy8iLCJmbGVldC8iXX0.\
+                         mlm0LjiJei2urRmRK4FtuH8DVRgdqB4jhPpui9uJicw";
+
+    /// Same claims signed with the private half of `PEM`
+    const RS256: &str = "eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9.\
+                         eyJzdWIiOiJkNyIsImlzcyI6ImF1dGguZXhhbXBsZSIsImF1ZCI6WyJtcXR0Il0sImV4cCI6NDEwMjQ0NDgwMCwibmJmIjoxNzAwMDAwMDAwLCJ0b3BpY3MiOlsiZGV2aWNlcy9kNy8iLCJmbGVldC8iXX0.\
+                         MgUf0nduYzENdKWjs6iUhSBV9D_9bV1-Jv-5g0VrJqVG_HLWj4bx2AI2E9lgfN1ER1E0F1BPUcqzCxKZFNOIYCcWP-bNNJPPBP0XHCI82Lt_Wm0BdMJE_Ql1FVLcTR9laqSF4DgvxVe2sHXa6q4Qz5H9tS5KVsbPdN8kWmsUaHw";
+
+    const PEM: &str = "-----BEGIN PUBLIC KEY-----
+MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDbHLQICDyvbA7dm3om2+JWzf4Q
+c97Mkf42XvNvOtGE26Rv8K3agBwR0c6y12qevuYpXknYN2CjoYs+ZeNC2AmYzTOQ
+AYNcOuOeAF6zwHDqwa9y3afki0BM0EfbwFyHLGvCp5xgfKlODOCZOSDX6X9jxzpw
+Lg9QphXf7PnYhZVOzwIDAQAB
+-----END PUBLIC KEY-----";
+
+    const NOW: u64 = 1_800_000_000;
+    const EXP: u64 = 4_102_444_800;
+
+    #[test]
+    fn signature_expiry_and_claims_are_checked() {
+        let keys = Keys {
+            hmac: Some(DecodingKey::from_secret(b"secret-key")),
+            rsa: Some(DecodingKey::from_rsa_pem(PEM.as_bytes()).unwrap()),
+        };
+        let mut config = JwtConfig::new();
+        config.issuer = Some("auth.example".to_owned());
+        config.audience = Some("mqtt".to_owned());
+
+        assert_eq!(validate(HS256, "d7", &keys, &config, NOW).unwrap().topics, None);
+        assert!(validate(RS256, "d7", &keys, &config, NOW).is_ok());
+        assert_eq!(validate(HS256, "d8", &keys, &config, NOW), Err("subject isn't the client id".to_owned()));
+        assert_eq!(validate(HS256, "d7", &keys, &config, EXP + 1), Err("expired".to_owned()));
+        assert_eq!(validate(HS256, "d7", &keys, &config, 1_600_000_000), Err("not valid yet".to_owned()));
+
+        let tampered = RS256.replace(".eyJzdWIiOiJkNyIs", ".eyJzdWIiOiJkOCIs");
+        assert_eq!(validate(&tampered, "d8", &keys, &config, NOW), Err("bad signature".to_owned()));
+        let wrong_secret = Keys { hmac: Some(DecodingKey::from_secret(b"guess")), rsa: None };
+        assert!(validate(HS256, "d7", &wrong_secret, &config, NOW).is_err());
+        // no rsa key configured
+        assert!(validate(RS256, "d7", &wrong_secret, &config, NOW).is_err());
+        let unsigned = "eyJhbGciOiJub25lIn0.eyJleHAiOjQxMDI0NDQ4MDB9.";
+        assert!(validate(unsigned, "d7", &keys, &config, NOW).is_err());
+
+        config.leeway = Duration::from_secs(60);
+        assert!(validate(HS256, "d7", &keys, &config, EXP + 60).is_ok());
+        config.audience = Some("http".to_owned());
+        assert_eq!(validate(HS256, "d7", &keys, &config, NOW), Err("wrong audience".to_owned()));
+        config.audience = None;
+        config.topics_claim = Some("topics".to_owned());
+        assert_eq!(validate(HS256, "d7", &keys, &config, NOW).unwrap().topics,
+                   Some(vec!["devices/d7/".to_owned(), "fleet/".to_owned()]));
+        config.topics_claim = Some("scope".to_owned());
+        assert!(validate(HS256, "d7", &keys, &config, NOW).is_err());
+    }
+
+    #[test]
+    fn keys_are_loaded_from_files() {
+        let dir = env::temp_dir();
+        let secret = dir.join(format!("rumqttd-jwt-secret-{}", ::std::process::id()));
+        let public = dir.join(format!("rumqttd-jwt-public-{}", ::std::process::id()));
+        writeln!(File::create(&secret).unwrap(), "secret-key").unwrap();
+        write!(File::create(&public).unwrap(), "{}", PEM).unwrap();
+
+        let mut config = JwtConfig::new();
+        config.hmac_secret = Some(secret.clone());
+        config.rsa_public_key = Some(public.clone());
+        config.topics_claim = Some("topics".to_owned());
+        let auth = JwtAuth::new(config).unwrap();
+
+        let core = Core::new().unwrap();
+        let handle = core.handle();
+        let addr = "10.0.0.7:40000".parse().unwrap();
+        assert_eq!(auth.authenticate("d7", Some("jwt"), Some(HS256), addr, &handle).wait().unwrap().code, ConnectReturnCode::Accepted);
+        assert_eq!(auth.authenticate("d7", Some("jwt"), Some("secret-key"), addr, &handle).wait().unwrap().code, ConnectReturnCode::BadUsernamePassword);
+        assert_eq!(auth.authenticate("d7", None, None, addr, &handle).wait().unwrap().code, ConnectReturnCode::NotAuthorized);
+
+        let verdict = auth.authenticate("d7", Some("jwt"), Some(RS256), addr, &handle).wait().unwrap();
+        assert_eq!(verdict.code, ConnectReturnCode::Accepted);
+        assert_eq!(verdict.topic_prefixes.unwrap().len(), 2);
+        assert_eq!(auth.authenticate("d7", Some("jwt"), Some("secret-key"), addr, &handle).wait().unwrap().topic_prefixes, None);
+
+        write!(File::create(&public).unwrap(), "-----BEGIN PUBLIC KEY-----\nAAAA\n-----END PUBLIC KEY-----").unwrap();
+        let mut config = JwtConfig::new();
+        config.rsa_public_key = Some(public.clone());
+        assert!(JwtAuth::new(config).is_err());
+        assert!(JwtAuth::new(JwtConfig { hmac_secret: Some(dir.join("rumqttd-jwt-missing")), ..JwtConfig::new() }).is_err());
+
+        let _ = ::std::fs::remove_file(secret);
+        let _ = ::std::fs::remove_file(public);
+    }
+}
@@ -0,0 +1,249 @@
+//! Kafka sink. With the `kafka` feature publishes matching the configured
+//! filters are mirrored into Kafka topics, keyed by their MQTT topic so the
+//! messages of one topic stay in order on one partition. Records are handed
+//! to an rdkafka producer, which batches them per partition for up to
+//! `batch_size` records or `linger`, whichever comes first, and retries
+//! failed batches on its own threads. While Kafka is slow or down its queue
+//! fills up and further records are dropped and counted instead of holding
+//! up the event loop
+
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use mqtt3::Publish;
+use rdkafka::ClientContext;
+use rdkafka::config::ClientConfig;
+use rdkafka::producer::{BaseRecord, DeliveryResult, Producer, ProducerContext, ThreadedProducer};
+use slog::Logger;
+
+use acl;
+use error::{Error, Result};
+
+/// Attempts at sending a batch before its records are dropped
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Wait before the next attempt after a failed one
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KafkaConfig {
+    /// `host:port` of the Kafka brokers the producer starts from, comma separated
+    pub broker: String,
+    /// Client id sent with every request
+    pub client_id: String,
+    pub rules: Vec<KafkaRule>,
+    /// Most records in one batch
+    pub batch_size: usize,
+    /// Longest wait for a batch to fill up
+    pub linger: Duration,
+    /// Records waiting for the producer before new ones are dropped
+    pub queue_size: usize,
+    /// Longest wait for Kafka's answer to one attempt
+    pub timeout: Duration,
+}
+
+impl KafkaConfig {
+    pub fn new(broker: &str) -> Self {
+        KafkaConfig {
+            broker: broker.to_owned(),
+            client_id: "rumqttd".to_owned(),
+            rules: Vec::new(),
+            batch_size: 100,
+            linger: Duration::from_millis(50),
+            queue_size: 10_000,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Mirrors publishes matching `filter` into the Kafka topic `topic`
+    pub fn rule(mut self, filter: &str, topic: &str) -> Self {
+        self.rules.push(KafkaRule {
+                            filter: filter.to_owned(),
+                            topic: topic.to_owned(),
+                        });
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KafkaRule {
+    /// MQTT filter of the publishes to mirror
+    pub filter: String,
+    /// Kafka topic they go to
+    pub topic: String,
+}
+
+/// Parses `<filter> <kafka topic>`, e.g. `sensors/+/temperature temperatures`
+impl FromStr for KafkaRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        if parts.len() != 2 {
+            return Err(Error::Config(format!("invalid kafka rule {:?}. Expected <filter> <kafka topic>", s)));
+        }
+
+        Ok(KafkaRule {
+               filter: parts[0].to_owned(),
+               topic: parts[1].to_owned(),
+           })
+    }
+}
+
+/// Counts the records rdkafka gave up on
+struct Deliveries {
+    dropped: Arc<AtomicUsize>,
+    logger: Logger,
+}
+
+impl ClientContext for Deliveries {}
+
+impl ProducerContext for Deliveries {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, result: &DeliveryResult, _: ()) {
+        if let Err((ref e, _)) = *result {
+            error!(self.logger, "Kafka produce failed. Dropping record. Error = {}", e);
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Settings of the rdkafka producer for `config`
+fn client_config(config: &KafkaConfig) -> ClientConfig {
+    let millis = |d: Duration| d.as_millis().to_string();
+    // every attempt may take the whole timeout before the record is given up on
+    let delivery_timeout = (config.timeout + RETRY_DELAY) * MAX_ATTEMPTS;
+
+    let mut client = ClientConfig::new();
+    client
+        .set("bootstrap.servers", config.broker.as_str())
+        .set("client.id", config.client_id.as_str())
+        // same key, same partition as with the java clients
+        .set("partitioner", "murmur2_random")
+        .set("acks", "1")
+        .set("batch.num.messages", config.batch_size.to_string())
+        .set("linger.ms", millis(config.linger))
+        .set("queue.buffering.max.messages", config.queue_size.to_string())
+        .set("request.timeout.ms", millis(config.timeout))
+        .set("message.send.max.retries", (MAX_ATTEMPTS - 1).to_string())
+        .set("retry.backoff.ms", millis(RETRY_DELAY))
+        .set("message.timeout.ms", millis(delivery_timeout));
+    client
+}
+
+/// Producer of the sink. Dropping it waits up to the timeout for the queued
+/// records to be sent
+pub struct KafkaSink {
+    rules: Vec<KafkaRule>,
+    producer: ThreadedProducer<Deliveries>,
+    timeout: Duration,
+    /// Records dropped on a full queue or after failed attempts
+    dropped: Arc<AtomicUsize>,
+}
+
+impl KafkaSink {
+    /// Starts the producer. Kafka is connected to in the background
+    pub fn start(config: &KafkaConfig, logger: Logger) -> Result<Self> {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let context = Deliveries {
+            dropped: dropped.clone(),
+            logger: logger,
+        };
+        let producer = client_config(config)
+            .create_with_context(context)
+            .map_err(|e| Error::Config(format!("invalid kafka settings: {}", e)))?;
+
+        Ok(KafkaSink {
+               rules: config.rules.clone(),
+               producer: producer,
+               timeout: config.timeout,
+               dropped: dropped,
+           })
+    }
+
+    /// Queues a record of `publish` for every rule whose filter matches.
+    /// Returns how many were dropped on a full queue
+    pub fn mirror(&self, publish: &Publish) -> usize {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or(0);
+
+        let mut dropped = 0;
+        for rule in self.rules.iter().filter(|rule| acl::matches_published(&rule.filter, &publish.topic_name)) {
+            let record = BaseRecord::to(&rule.topic)
+                .key(&publish.topic_name)
+                .payload(&publish.payload[..])
+                .timestamp(timestamp);
+            if self.producer.send(record).is_err() {
+                dropped += 1;
+            }
+        }
+
+        self.dropped.fetch_add(dropped, Ordering::Relaxed);
+        dropped
+    }
+
+    /// Records dropped so far
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for KafkaSink {
+    fn drop(&mut self) {
+        let _ = self.producer.flush(self.timeout);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use mqtt3::{Publish, QoS};
+    use slog::{Discard, Logger};
+    use super::{client_config, KafkaConfig, KafkaRule, KafkaSink};
+
+    fn publish(topic: &str) -> Publish {
+        Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            pid: None,
+            topic_name: topic.to_owned(),
+            payload: Arc::new(b"21.5".to_vec()),
+        }
+    }
+
+    #[test]
+    fn settings_follow_the_config() {
+        let rule: KafkaRule = "sensors/+/temperature temperatures".parse().unwrap();
+        assert_eq!(rule.topic, "temperatures");
+        assert!("sensors/#".parse::<KafkaRule>().is_err());
+
+        let client = client_config(&KafkaConfig::new("kafka:9092"));
+        assert_eq!(client.get("bootstrap.servers"), Some("kafka:9092"));
+        assert_eq!(client.get("batch.num.messages"), Some("100"));
+        assert_eq!(client.get("linger.ms"), Some("50"));
+        assert_eq!(client.get("message.timeout.ms"), Some("30000"));
+    }
+
+    #[test]
+    fn records_are_dropped_once_the_queue_is_full() {
+        // nothing listens there, so records stay queued
+        let mut config = KafkaConfig::new("127.0.0.1:1").rule("sensors/#", "sensors");
+        config.queue_size = 1;
+        config.timeout = Duration::from_millis(100);
+
+        let sink = KafkaSink::start(&config, Logger::root(Discard, o!())).unwrap();
+        assert_eq!(sink.mirror(&publish("sensors/7/temperature")), 0);
+        assert_eq!(sink.mirror(&publish("sensors/8/temperature")), 1);
+        assert_eq!(sink.mirror(&publish("lights/7")), 0);
+        // wildcard filters leave $ topics alone
+        assert_eq!(sink.mirror(&publish("$SYS/broker/uptime")), 0);
+        assert_eq!(sink.dropped(), 1);
+    }
+}
@@ -0,0 +1,176 @@
+//! Leaf node mode. A leaf broker keeps one MQTT connection to a hub broker
+//! and stands in for its local clients there: the filters local clients
+//! subscribe to are subscribed at the hub once, however many local clients
+//! share them, and local publishes are passed up. Unlike a plain bridge the
+//! publishes keep the id of the local client that sent them. They travel on
+//! `$leaf/<client id>/<topic>` and the hub routes them on `<topic>` under
+//! the id `<leaf id>/<client id>`
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use mqtt3::*;
+
+use client::Client;
+
+/// Id of the hub connection among the leaf's clients
+pub const UPLINK_CLIENT_ID: &str = "$uplink";
+
+/// Topic prefix of publishes passed up by a leaf
+pub const LEAF_TOPIC_PREFIX: &str = "$leaf/";
+
+/// Keep alive of the connection to the hub
+pub const LEAF_KEEP_ALIVE: u16 = 30;
+
+/// Wait before connecting to the hub again after losing the connection
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeafConfig {
+    /// Hub broker to connect to
+    pub hub: SocketAddr,
+    /// Client id of the leaf at the hub
+    pub node_id: String,
+}
+
+impl LeafConfig {
+    /// CONNECT sent to the hub. Subscriptions are made again on every
+    /// connection so the session doesn't have to persist
+    pub fn connect_packet(&self) -> Packet {
+        Packet::Connect(Box::new(Connect {
+                                     protocol: Protocol::MQTT(4),
+                                     keep_alive: LEAF_KEEP_ALIVE,
+                                     client_id: self.node_id.clone(),
+                                     clean_session: true,
+                                     last_will: None,
+                                     username: None,
+                                     password: None,
+                                 }))
+    }
+}
+
+/// Topic a publish of the local client `origin` is passed up on. Slashes in
+/// the id are escaped so the topic can be split again
+pub fn wrap_topic(origin: &str, topic: &str) -> String {
+    wrap(LEAF_TOPIC_PREFIX, origin, topic)
+}
+
+/// Client id and topic of a publish passed up by a leaf
+pub fn unwrap_topic(topic: &str) -> Option<(String, &str)> {
+    unwrap(LEAF_TOPIC_PREFIX, topic)
+}
+
+/// `topic` under `prefix` and the escaped client id `origin`
+pub fn wrap(prefix: &str, origin: &str, topic: &str) -> String {
+    let origin = origin.replace('%', "%25").replace('/', "%2F");
+    format!("{}{}/{}", prefix, origin, topic)
+}
+
+/// Client id and topic of a topic made by `wrap` with `prefix`
+pub fn unwrap<'a>(prefix: &str, topic: &'a str) -> Option<(String, &'a str)> {
+    if !topic.starts_with(prefix) {
+        return None;
+    }
+
+    let rest = &topic[prefix.len()..];
+    match rest.find('/') {
+        Some(i) if i > 0 && i + 1 < rest.len() => Some((rest[..i].replace("%2F", "/").replace("%25", "%"), &rest[i + 1..])),
+        _ => None,
+    }
+}
+
+/// True for filters that stay local. `$` topics belong to this broker
+pub fn is_local(filter: &str) -> bool {
+    filter.starts_with('$')
+}
+
+/// Connection of a leaf to its hub and the filters subscribed there
+pub struct Uplink {
+    pub client: Client,
+    /// Filters subscribed at the hub with the highest qos a local client asked for
+    filters: HashMap<String, QoS>,
+}
+
+impl Uplink {
+    pub fn new(client: Client) -> Self {
+        Uplink {
+            client: client,
+            filters: HashMap::new(),
+        }
+    }
+
+    /// Filters subscribed at the hub
+    pub fn filters(&self) -> Vec<(String, QoS)> {
+        self.filters.iter().map(|(filter, &qos)| (filter.clone(), qos)).collect()
+    }
+
+    /// Packet that brings the hub's subscription on `filter` in line with
+    /// `wanted`, the highest qos of the local subscriptions. `None` while
+    /// the hub is up to date
+    pub fn sync(&mut self, filter: &str, wanted: Option<QoS>) -> Option<Packet> {
+        match (self.filters.get(filter).cloned(), wanted) {
+            (current, Some(qos)) if current != Some(qos) => {
+                self.filters.insert(filter.to_owned(), qos);
+                Some(Packet::Subscribe(Box::new(Subscribe {
+                                                    pid: self.client.next_pkid(),
+                                                    topics: vec![SubscribeTopic {
+                                                                     topic_path: filter.to_owned(),
+                                                                     qos: qos,
+                                                                 }],
+                                                })))
+            }
+            (Some(_), None) => {
+                self.filters.remove(filter);
+                Some(Packet::Unsubscribe(Box::new(Unsubscribe {
+                                                      pid: self.client.next_pkid(),
+                                                      topics: vec![filter.to_owned()],
+                                                  })))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::sync::mpsc;
+    use mqtt3::*;
+    use client::Client;
+    use super::{unwrap_topic, wrap_topic, Uplink, UPLINK_CLIENT_ID};
+
+    #[test]
+    fn origin_survives_the_topic_round_trip() {
+        let topic = wrap_topic("sensor/7%", "hello/mqtt");
+        assert_eq!(topic, "$leaf/sensor%2F7%25/hello/mqtt");
+        assert_eq!(unwrap_topic(&topic), Some(("sensor/7%".to_owned(), "hello/mqtt")));
+
+        assert_eq!(unwrap_topic("hello/mqtt"), None);
+        assert_eq!(unwrap_topic("$leaf/sensor"), None);
+        assert_eq!(unwrap_topic("$leaf//hello"), None);
+    }
+
+    #[test]
+    fn hub_subscription_follows_the_highest_local_qos() {
+        let (tx, _rx) = mpsc::channel(8);
+        let mut uplink = Uplink::new(Client::new(UPLINK_CLIENT_ID, "127.0.0.1:1883".parse().unwrap(), tx));
+
+        match uplink.sync("hello/mqtt", Some(QoS::AtMostOnce)) {
+            Some(Packet::Subscribe(s)) => assert_eq!(s.topics[0].qos, QoS::AtMostOnce),
+            p => panic!("Expected a subscribe. Got {:?}", p),
+        }
+        assert_eq!(uplink.sync("hello/mqtt", Some(QoS::AtMostOnce)), None);
+
+        match uplink.sync("hello/mqtt", Some(QoS::AtLeastOnce)) {
+            Some(Packet::Subscribe(s)) => assert_eq!(s.topics[0].qos, QoS::AtLeastOnce),
+            p => panic!("Expected a subscribe. Got {:?}", p),
+        }
+
+        match uplink.sync("hello/mqtt", None) {
+            Some(Packet::Unsubscribe(u)) => assert_eq!(u.topics, vec!["hello/mqtt".to_owned()]),
+            p => panic!("Expected an unsubscribe. Got {:?}", p),
+        }
+        assert!(uplink.filters().is_empty());
+        assert_eq!(uplink.sync("hello/mqtt", None), None);
+    }
+}
@@ -0,0 +1,219 @@
+//! Core of the rumqttd MQTT broker: the router, client sessions, offline
+//! storage and the broker configuration. Listeners and other front-ends are
+//! built on top of it by the `rumqttd` binary and by embedders.
+//!
+//! # Stability
+//!
+//! The items re-exported at the crate root are the public API and follow
+//! semver. `BrokerConfig`, `Error` and the policy enums are
+//! `#[non_exhaustive]` so that settings and error cases can be added in minor
+//! releases. Settings are changed through `BrokerBuilder` or by assigning
+//! fields of a `BrokerConfig::default()`.
+//!
+//! The modules themselves are public for the rumqttd front-ends but hidden
+//! from the docs. Anything only reachable through a module path may change
+//! in any release.
+
+extern crate mqtt3;
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_timer;
+extern crate bytes;
+#[macro_use]
+extern crate slog;
+extern crate slog_term;
+extern crate slog_async;
+extern crate slog_json;
+#[macro_use]
+extern crate quick_error;
+#[macro_use]
+extern crate serde_json;
+extern crate tokio_core;
+#[cfg(feature = "connectors")]
+extern crate base64;
+#[cfg(feature = "password-file")]
+extern crate bcrypt;
+#[cfg(feature = "password-file")]
+extern crate futures_cpupool;
+#[cfg(feature = "hyper")]
+extern crate hyper;
+#[cfg(feature = "jwt")]
+extern crate jsonwebtoken;
+#[cfg(feature = "webhook-auth")]
+extern crate rand;
+#[cfg(feature = "webhook-auth")]
+extern crate sha2;
+#[cfg(feature = "tls")]
+extern crate tokio_rustls;
+#[cfg(feature = "tls")]
+extern crate x509_parser;
+#[cfg(feature = "enrichment")]
+extern crate libc;
+#[cfg(feature = "kafka")]
+extern crate rdkafka;
+#[cfg(feature = "admin")]
+extern crate flate2;
+
+#[doc(hidden)]
+pub mod error;
+#[doc(hidden)]
+pub mod codec;
+#[doc(hidden)]
+pub mod broker;
+#[doc(hidden)]
+pub mod client;
+#[doc(hidden)]
+pub mod catalog;
+#[doc(hidden)]
+pub mod pause;
+#[doc(hidden)]
+pub mod config;
+#[doc(hidden)]
+pub mod group;
+#[doc(hidden)]
+pub mod profile;
+#[doc(hidden)]
+pub mod handles;
+#[doc(hidden)]
+pub mod clock;
+#[doc(hidden)]
+pub mod connect;
+#[doc(hidden)]
+pub mod session;
+#[doc(hidden)]
+pub mod offline;
+#[doc(hidden)]
+pub mod selftest;
+#[cfg(feature = "tls")]
+#[doc(hidden)]
+pub mod tls;
+#[doc(hidden)]
+pub mod listener;
+#[doc(hidden)]
+pub mod leaf;
+#[doc(hidden)]
+pub mod bridge;
+#[cfg(feature = "websocket")]
+#[doc(hidden)]
+pub mod ws;
+#[doc(hidden)]
+pub mod downgrade;
+#[doc(hidden)]
+pub mod storage;
+#[doc(hidden)]
+pub mod auth;
+#[doc(hidden)]
+pub mod batch;
+#[doc(hidden)]
+pub mod registry;
+#[cfg(feature = "password-file")]
+#[doc(hidden)]
+pub mod passwd;
+#[doc(hidden)]
+pub mod acl;
+#[doc(hidden)]
+pub mod fair;
+#[cfg(feature = "hyper")]
+#[doc(hidden)]
+pub mod http;
+#[cfg(feature = "webhook-auth")]
+#[doc(hidden)]
+pub mod webhook;
+#[cfg(feature = "connectors")]
+#[doc(hidden)]
+pub mod hook;
+#[doc(hidden)]
+pub mod txn;
+#[cfg(feature = "jwt")]
+#[doc(hidden)]
+pub mod jwt;
+#[doc(hidden)]
+pub mod sim;
+#[doc(hidden)]
+pub mod observer;
+#[doc(hidden)]
+pub mod debounce;
+#[doc(hidden)]
+pub mod reload;
+#[cfg(feature = "admin")]
+#[doc(hidden)]
+pub mod dump;
+#[cfg(feature = "metrics")]
+#[doc(hidden)]
+pub mod sys;
+#[cfg(feature = "metrics")]
+#[doc(hidden)]
+pub mod alert;
+#[doc(hidden)]
+pub mod logging;
+#[doc(hidden)]
+pub mod disconnect;
+#[doc(hidden)]
+pub mod birth;
+#[doc(hidden)]
+pub mod trie;
+#[doc(hidden)]
+pub mod slow;
+#[doc(hidden)]
+pub mod rate;
+#[doc(hidden)]
+pub mod share;
+#[doc(hidden)]
+pub mod sn;
+#[cfg(feature = "enrichment")]
+#[doc(hidden)]
+pub mod enrich;
+#[cfg(feature = "kafka")]
+#[doc(hidden)]
+pub mod kafka;
+#[cfg(test)]
+mod limits;
+#[cfg(test)]
+mod soak;
+#[cfg(test)]
+mod bench;
+
+pub use acl::{Access, Acl, AclRule, AclSubject, Permission, UnauthorizedPublish};
+#[cfg(feature = "metrics")]
+pub use alert::{Alert, AlertRule, Comparison, Metric};
+pub use auth::{AllowAll, Authenticator, Verdict, Verification};
+pub use batch::BatchConfig;
+pub use birth::BirthConfig;
+pub use bridge::{BridgeConfig, BridgeTopic, Direction};
+pub use broker::{Broker, BrokerSizes};
+pub use catalog::TopicInfo;
+pub use client::{Client, ClientStats};
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use config::{BrokerBuilder, BrokerConfig, DrainPolicy, DuplicatePkidPolicy, OverflowPolicy, SettingChange};
+pub use debounce::DebounceRule;
+pub use disconnect::DisconnectReason;
+pub use downgrade::{Mismatch, PairStats};
+#[cfg(feature = "enrichment")]
+pub use enrich::{ConnectMetadata, EnrichConfig};
+pub use error::{Error, Result};
+pub use fair::WriteWeight;
+pub use group::GroupConfig;
+#[cfg(feature = "jwt")]
+pub use jwt::{JwtAuth, JwtConfig};
+#[cfg(feature = "kafka")]
+pub use kafka::{KafkaConfig, KafkaRule};
+pub use leaf::LeafConfig;
+pub use listener::{ListenerConfig, ListenerKind};
+pub use logging::{LogOutput, Rotation};
+pub use observer::ObserverConfig;
+#[cfg(feature = "password-file")]
+pub use passwd::PasswordFile;
+pub use pause::PausePolicy;
+pub use rate::RateLimit;
+pub use registry::{TopicRegistry, TopicTemplate};
+pub use reload::{LevelSwitch, Reload};
+pub use selftest::{SelfTestConfig, SelfTestStats};
+pub use session::SessionStats;
+pub use share::SharePolicy;
+pub use slow::SlowConsumerConfig;
+pub use sim::{Behavior, PublishPattern, SimConfig, SimGroup, SimStats, Simulation};
+pub use storage::{Connector, StoragePolicy, StorageRule};
+#[cfg(feature = "tls")]
+pub use tls::{CertIdentity, ClientAuth, TlsConfig};
+#[cfg(feature = "webhook-auth")]
+pub use webhook::{WebhookAuth, WebhookConfig};
@@ -0,0 +1,326 @@
+//! Boundary test matrix for the limits listed by `BrokerConfig::limits`.
+//! Every limit is probed one below, at and one above its value. A limit
+//! without a case here fails the matrix
+
+use std::iter;
+use std::sync::Arc;
+use std::time::Instant;
+
+use bytes::BytesMut;
+use mqtt3::*;
+use tokio_io::codec::Decoder;
+
+use broker::Broker;
+use client::{self, Client};
+use codec::{self, MqttCodec};
+use config::{BrokerConfig, DuplicatePkidPolicy};
+use connect;
+use listener::{ListenerConfig, ListenerKind, ListenerSlots};
+use offline::{OfflineSessions, Queued};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    Accepted,
+    /// Refused without affecting the rest of the connection
+    Rejected,
+    /// The client is dropped
+    Disconnected,
+    /// Held back until there's room
+    HeldBack,
+}
+
+struct LimitCase {
+    name: &'static str,
+    /// Outcome for values up to and including the limit
+    within: Outcome,
+    /// Outcome for values above the limit
+    over: Outcome,
+    /// Exercises the broker with `n` against the limit in the config
+    probe: fn(&BrokerConfig, usize) -> Outcome,
+}
+
+const CASES: &[LimitCase] = &[LimitCase {
+                                  name: "max_packet_size",
+                                  within: Outcome::Accepted,
+                                  over: Outcome::Rejected,
+                                  probe: packet_of_size,
+                              },
+                              LimitCase {
+                                  name: "outgoing_queue_size",
+                                  within: Outcome::Accepted,
+                                  over: Outcome::Disconnected,
+                                  probe: queued_packets,
+                              },
+                              LimitCase {
+                                  name: "max_client_id_len",
+                                  within: Outcome::Accepted,
+                                  over: Outcome::Rejected,
+                                  probe: client_id_of_len,
+                              },
+                              LimitCase {
+                                  name: "offline_queue_size",
+                                  within: Outcome::Accepted,
+                                  over: Outcome::Rejected,
+                                  probe: queued_offline,
+                              },
+                              LimitCase {
+                                  name: "max_inflight",
+                                  within: Outcome::Accepted,
+                                  over: Outcome::HeldBack,
+                                  probe: inflight_messages,
+                              },
+                              LimitCase {
+                                  name: "max_connections",
+                                  within: Outcome::Accepted,
+                                  over: Outcome::Disconnected,
+                                  probe: open_connections,
+                              },
+                              LimitCase {
+                                  name: "max_queued_messages",
+                                  within: Outcome::Accepted,
+                                  over: Outcome::Rejected,
+                                  probe: unacknowledged_messages,
+                              },
+                              LimitCase {
+                                  name: "max_queued_bytes",
+                                  within: Outcome::Accepted,
+                                  over: Outcome::Rejected,
+                                  probe: unacknowledged_bytes,
+                              },
+                              // a reused pkid is caught while it's within the window
+                              LimitCase {
+                                  name: "duplicate_pkid_window",
+                                  within: Outcome::Disconnected,
+                                  over: Outcome::Accepted,
+                                  probe: pkid_reused_after,
+                              }];
+
+/// Small limits to keep the probes cheap
+fn config() -> BrokerConfig {
+    let mut config = BrokerConfig::default();
+    config.max_packet_size = 64;
+    config.outgoing_queue_size = 4;
+    config.max_client_id_len = Some(8);
+    config.duplicate_pkid_policy = Some(DuplicatePkidPolicy::Disconnect);
+    config.duplicate_pkid_window = 4;
+    config.offline_queue_size = 3;
+    config.max_inflight = Some(2);
+    config.max_connections = Some(3);
+    config.max_queued_messages = Some(3);
+    config.max_queued_bytes = Some(16);
+    config
+}
+
+fn publish(qos: QoS, pkid: Option<u16>, payload: usize) -> Box<Publish> {
+    Box::new(Publish {
+                 dup: false,
+                 qos: qos,
+                 retain: false,
+                 pid: pkid.map(PacketIdentifier),
+                 topic_name: "a".to_owned(),
+                 payload: Arc::new(vec![0; payload]),
+             })
+}
+
+/// Decodes an incoming publish that is `n` bytes on the wire
+fn packet_of_size(config: &BrokerConfig, n: usize) -> Outcome {
+    let mut payload = 0;
+    let packet = loop {
+        let packet = Packet::Publish(publish(QoS::AtMostOnce, None, payload));
+        if codec::encoded_len(&packet).unwrap() >= n {
+            break packet;
+        }
+        payload += 1;
+    };
+
+    let encoded = codec::encode(&packet).unwrap();
+    assert_eq!(encoded.len(), n, "no publish encodes to {} bytes", n);
+
+    let mut buf = BytesMut::from(&encoded[..]);
+    match MqttCodec::new(config.max_packet_size).decode(&mut buf) {
+        Ok(Some(_)) => Outcome::Accepted,
+        Ok(None) => panic!("Incomplete packet of {} bytes", n),
+        Err(_) => Outcome::Rejected,
+    }
+}
+
+/// Queues `n` packets for a client that isn't reading
+fn queued_packets(config: &BrokerConfig, n: usize) -> Outcome {
+    let (tx, _rx) = client::outgoing_queue(config.outgoing_queue_size);
+    let client = Client::new("client", "127.0.0.1:80".parse().unwrap(), tx);
+
+    for _ in 0..n {
+        if client.send(Packet::Pingresp).is_err() {
+            return Outcome::Disconnected;
+        }
+    }
+
+    Outcome::Accepted
+}
+
+/// Queues `n` messages for an offline persistent session
+fn queued_offline(config: &BrokerConfig, n: usize) -> Outcome {
+    let mut offline = OfflineSessions::new(config.offline_queue_size, config.offline_overflow);
+    offline.park("client",
+                 vec![SubscribeTopic {
+                          topic_path: "a".to_owned(),
+                          qos: QoS::AtLeastOnce,
+                      }],
+                 Instant::now());
+
+    for _ in 0..n {
+        if offline.queue("client", publish(QoS::AtLeastOnce, Some(1), 1), QoS::AtLeastOnce, Instant::now()) != Queued::Yes {
+            return Outcome::Rejected;
+        }
+    }
+
+    Outcome::Accepted
+}
+
+/// Connects with a client id of `n` characters
+fn client_id_of_len(config: &BrokerConfig, n: usize) -> Outcome {
+    let connect = Connect {
+        protocol: Protocol::MQTT(4),
+        keep_alive: 10,
+        client_id: iter::repeat('c').take(n).collect(),
+        clean_session: true,
+        last_will: None,
+        username: None,
+        password: None,
+    };
+
+    match connect::validate(&connect, config) {
+        ConnectReturnCode::Accepted => Outcome::Accepted,
+        _ => Outcome::Rejected,
+    }
+}
+
+/// Sends `n` QoS 1 messages to a subscriber that doesn't acknowledge them
+fn inflight_messages(config: &BrokerConfig, n: usize) -> Outcome {
+    let broker = Broker::with_config(config.clone());
+    let (tx, _rx) = client::outgoing_queue(config.outgoing_queue_size);
+    let subscriber = Client::new("subscriber", "127.0.0.1:80".parse().unwrap(), tx);
+    let (tx, _rx) = client::outgoing_queue(n + 1);
+    let publisher = Client::new("publisher", "127.0.0.1:80".parse().unwrap(), tx);
+    broker.add_client(subscriber.clone());
+    broker.add_client(publisher.clone());
+
+    let subscribe = Box::new(Subscribe {
+                                 pid: PacketIdentifier(1),
+                                 topics: vec![SubscribeTopic {
+                                                  topic_path: "a".to_owned(),
+                                                  qos: QoS::AtLeastOnce,
+                                              }],
+                             });
+    broker.handle_subscribe(subscribe, &subscriber);
+
+    for pkid in 0..n {
+        broker.handle_publish(publish(QoS::AtLeastOnce, Some(pkid as u16 + 1), 1), &publisher);
+    }
+
+    assert!(broker.is_current(&subscriber));
+    if subscriber.has_pending() {
+        Outcome::HeldBack
+    } else {
+        Outcome::Accepted
+    }
+}
+
+/// Accepts `n` connections spread over two listeners
+fn open_connections(config: &BrokerConfig, n: usize) -> Outcome {
+    let broker = Broker::with_config(config.clone());
+    let listeners = [ListenerSlots::with_total(ListenerConfig::new("0.0.0.0:1883".parse().unwrap(), ListenerKind::Tcp), broker.total_slots()),
+                     ListenerSlots::with_total(ListenerConfig::new("0.0.0.0:8083".parse().unwrap(), ListenerKind::WebSocket), broker.total_slots())];
+
+    let mut slots = Vec::new();
+    for i in 0..n {
+        match listeners[i % 2].admit() {
+            Some(slot) => slots.push(slot),
+            None => return Outcome::Disconnected,
+        }
+    }
+
+    assert_eq!(broker.total_slots().live(), n);
+    Outcome::Accepted
+}
+
+/// Sends `n` QoS 1 messages to a subscriber that doesn't acknowledge them
+fn unacknowledged_messages(config: &BrokerConfig, n: usize) -> Outcome {
+    session_limits(config, n, 1)
+}
+
+/// Sends a QoS 1 message with `n` payload bytes to a subscriber that doesn't
+/// acknowledge it
+fn unacknowledged_bytes(config: &BrokerConfig, n: usize) -> Outcome {
+    session_limits(config, 1, n)
+}
+
+fn session_limits(config: &BrokerConfig, messages: usize, payload: usize) -> Outcome {
+    let broker = Broker::with_config(config.clone());
+    let (tx, _rx) = client::outgoing_queue(config.outgoing_queue_size);
+    let subscriber = Client::new("subscriber", "127.0.0.1:80".parse().unwrap(), tx);
+    let (tx, _rx) = client::outgoing_queue(messages + 1);
+    let publisher = Client::new("publisher", "127.0.0.1:80".parse().unwrap(), tx);
+    broker.add_client(subscriber.clone());
+    broker.add_client(publisher.clone());
+
+    let subscribe = Box::new(Subscribe {
+                                 pid: PacketIdentifier(1),
+                                 topics: vec![SubscribeTopic {
+                                                  topic_path: "a".to_owned(),
+                                                  qos: QoS::AtLeastOnce,
+                                              }],
+                             });
+    broker.handle_subscribe(subscribe, &subscriber);
+
+    for pkid in 0..messages {
+        broker.handle_publish(publish(QoS::AtLeastOnce, Some(pkid as u16 + 1), payload), &publisher);
+    }
+
+    assert!(broker.is_current(&subscriber));
+    if broker.session_stats("subscriber").unwrap().over_limit > 0 {
+        Outcome::Rejected
+    } else {
+        Outcome::Accepted
+    }
+}
+
+/// Reuses the first pkid after `n` distinct QoS 1 publishes
+fn pkid_reused_after(config: &BrokerConfig, n: usize) -> Outcome {
+    let broker = Broker::with_config(config.clone());
+    let (tx, _rx) = client::outgoing_queue(n + 1);
+    let client = Client::new("client", "127.0.0.1:80".parse().unwrap(), tx);
+    broker.add_client(client.clone());
+
+    for pkid in 0..n {
+        broker.handle_publish(publish(QoS::AtLeastOnce, Some(pkid as u16 + 1), 1), &client);
+    }
+    broker.handle_publish(publish(QoS::AtLeastOnce, Some(1), 1), &client);
+
+    if broker.is_current(&client) {
+        Outcome::Accepted
+    } else {
+        Outcome::Disconnected
+    }
+}
+
+#[test]
+fn every_limit_has_boundary_cases() {
+    for (name, _) in config().limits() {
+        assert!(CASES.iter().any(|case| case.name == name), "No boundary cases for limit {:?}", name);
+    }
+}
+
+#[test]
+fn limits_hold_at_their_boundaries() {
+    let config = config();
+
+    for (name, limit) in config.limits() {
+        let case = CASES.iter().find(|case| case.name == name).unwrap();
+
+        for &n in [limit - 1, limit, limit + 1].iter() {
+            let expected = if n <= limit { case.within } else { case.over };
+            assert_eq!((case.probe)(&config, n), expected, "{} = {} probed with {}", name, limit, n);
+        }
+    }
+}
@@ -0,0 +1,279 @@
+//! Listener settings. A broker serves any number of listeners from the same
+//! event loop, each with its own transport, connection limit,
+//! authentication requirement and liveness probe. The broker's
+//! `max_connections` limits the connections of all listeners together
+
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::Poll;
+use tokio_core::net::TcpStream;
+use tokio_io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::Session;
+#[cfg(feature = "tls")]
+use tokio_rustls::server::TlsStream;
+
+#[cfg(feature = "tls")]
+use tls::PeerCertificate;
+
+/// How clients talk to a listener
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum ListenerKind {
+    /// MQTT over plain TCP
+    Tcp,
+    /// MQTT over TLS with the broker's `TlsConfig`
+    Tls,
+    /// MQTT over WebSocket with the `mqtt` subprotocol
+    WebSocket,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct ListenerConfig {
+    pub address: SocketAddr,
+    pub kind: ListenerKind,
+    /// Connections served at once. Connections beyond it are closed as soon
+    /// as they're accepted. `None` doesn't limit them
+    pub max_connections: Option<usize>,
+    /// Refuse CONNECTs without a username
+    pub auth_required: bool,
+    /// Probe connections quiet for this long instead of waiting for their
+    /// keep alive to run out. TCP keep alive on every connection and a
+    /// WebSocket ping on WebSocket connections, which are closed when the
+    /// pong doesn't come back within another interval. `None` doesn't probe
+    pub probe: Option<Duration>,
+}
+
+impl ListenerConfig {
+    pub fn new(address: SocketAddr, kind: ListenerKind) -> Self {
+        ListenerConfig {
+            address: address,
+            kind: kind,
+            max_connections: None,
+            auth_required: false,
+            probe: None,
+        }
+    }
+
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    pub fn auth_required(mut self, required: bool) -> Self {
+        self.auth_required = required;
+        self
+    }
+
+    pub fn probe(mut self, interval: Duration) -> Self {
+        self.probe = Some(interval);
+        self
+    }
+}
+
+/// Connections currently served by all listeners
+#[derive(Debug, Clone)]
+pub struct TotalSlots {
+    max: Option<usize>,
+    live: Rc<Cell<usize>>,
+}
+
+impl TotalSlots {
+    /// `None` doesn't limit the total
+    pub fn new(max: Option<usize>) -> Self {
+        TotalSlots {
+            max: max,
+            live: Rc::new(Cell::new(0)),
+        }
+    }
+
+    pub fn live(&self) -> usize {
+        self.live.get()
+    }
+
+    pub fn full(&self) -> bool {
+        self.max.map_or(false, |max| self.live.get() >= max)
+    }
+}
+
+/// Connections currently served by a listener
+#[derive(Debug, Clone)]
+pub struct ListenerSlots {
+    config: ListenerConfig,
+    live: Rc<Cell<usize>>,
+    total: TotalSlots,
+}
+
+impl ListenerSlots {
+    pub fn new(config: ListenerConfig) -> Self {
+        ListenerSlots::with_total(config, TotalSlots::new(None))
+    }
+
+    /// Slots of a listener that also count towards `total`
+    pub fn with_total(config: ListenerConfig, total: TotalSlots) -> Self {
+        ListenerSlots {
+            config: config,
+            live: Rc::new(Cell::new(0)),
+            total: total,
+        }
+    }
+
+    pub fn config(&self) -> &ListenerConfig {
+        &self.config
+    }
+
+    pub fn live(&self) -> usize {
+        self.live.get()
+    }
+
+    /// True if the listeners together are at the broker's limit
+    pub fn total_full(&self) -> bool {
+        self.total.full()
+    }
+
+    /// Takes a slot for a new connection. `None` while the listener or the
+    /// broker is full
+    pub fn admit(&self) -> Option<Slot> {
+        if self.config.max_connections.map_or(false, |max| self.live.get() >= max) || self.total.full() {
+            return None;
+        }
+
+        self.live.set(self.live.get() + 1);
+        self.total.live.set(self.total.live.get() + 1);
+        Some(Slot {
+                 live: self.live.clone(),
+                 total: self.total.live.clone(),
+                 auth_required: self.config.auth_required,
+                 probe: match self.config.kind {
+                     ListenerKind::WebSocket => self.config.probe,
+                     _ => None,
+                 },
+             })
+    }
+}
+
+/// A connection's place on its listener. Given back when dropped
+#[derive(Debug)]
+pub struct Slot {
+    live: Rc<Cell<usize>>,
+    total: Rc<Cell<usize>>,
+    pub auth_required: bool,
+    /// Interval of the WebSocket ping. Plain connections only have TCP keep alive
+    pub probe: Option<Duration>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.live.set(self.live.get() - 1);
+        self.total.set(self.total.get() - 1);
+    }
+}
+
+/// Socket of a connection, TLS terminated or not
+pub enum Socket {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(TlsStream<TcpStream>),
+}
+
+impl Socket {
+    /// Names of the certificate the client presented. `None` on plain
+    /// connections. A certificate that can't be parsed has no names
+    #[cfg(feature = "tls")]
+    pub fn peer_certificate(&self) -> Option<PeerCertificate> {
+        match *self {
+            Socket::Plain(_) => None,
+            Socket::Tls(ref socket) => {
+                let (_, session) = socket.get_ref();
+                let certs = session.get_peer_certificates()?;
+                let cert = certs.first()?;
+                Some(PeerCertificate::from_der(&cert.0).unwrap_or_default())
+            }
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Socket::Plain(ref mut socket) => socket.read(buf),
+            #[cfg(feature = "tls")]
+            Socket::Tls(ref mut socket) => socket.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Socket::Plain(ref mut socket) => socket.write(buf),
+            #[cfg(feature = "tls")]
+            Socket::Tls(ref mut socket) => socket.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Socket::Plain(ref mut socket) => socket.flush(),
+            #[cfg(feature = "tls")]
+            Socket::Tls(ref mut socket) => socket.flush(),
+        }
+    }
+}
+
+impl AsyncRead for Socket {}
+
+impl AsyncWrite for Socket {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            Socket::Plain(ref mut socket) => AsyncWrite::shutdown(socket),
+            #[cfg(feature = "tls")]
+            Socket::Tls(ref mut socket) => AsyncWrite::shutdown(socket),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ListenerConfig, ListenerKind, ListenerSlots, TotalSlots};
+
+    #[test]
+    fn slots_are_given_back_by_closed_connections() {
+        let config = ListenerConfig::new("0.0.0.0:1883".parse().unwrap(), ListenerKind::Tcp).max_connections(2);
+        let slots = ListenerSlots::new(config);
+
+        let first = slots.admit().unwrap();
+        let second = slots.admit().unwrap();
+        assert!(slots.admit().is_none());
+        assert_eq!(slots.live(), 2);
+
+        drop(first);
+        assert_eq!(slots.live(), 1);
+        assert!(slots.admit().is_some());
+
+        drop(second);
+        assert_eq!(slots.live(), 0);
+    }
+
+    #[test]
+    fn listeners_share_the_total() {
+        let total = TotalSlots::new(Some(2));
+        let tcp = ListenerSlots::with_total(ListenerConfig::new("0.0.0.0:1883".parse().unwrap(), ListenerKind::Tcp), total.clone());
+        let ws = ListenerSlots::with_total(ListenerConfig::new("0.0.0.0:8083".parse().unwrap(), ListenerKind::WebSocket), total.clone());
+
+        let first = tcp.admit().unwrap();
+        let _second = ws.admit().unwrap();
+        assert!(tcp.admit().is_none());
+        assert!(tcp.total_full());
+        assert_eq!(total.live(), 2);
+
+        drop(first);
+        assert!(ws.admit().is_some());
+        assert_eq!(total.live(), 1);
+    }
+}
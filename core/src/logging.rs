@@ -0,0 +1,268 @@
+//! Log output. The terminal format is for people reading the log, the JSON
+//! format for shipping it to ELK or Loki. Every record becomes one line
+//!
+//! ```text
+//! {"ts":"2023-11-14T22:13:20.000000000+00:00","level":"TRCE","msg":"Packet received","client-id":"d7","packet":"publish","topic":"sensors/7"}
+//! ```
+//!
+//! written by slog-json, with the key values of the record and its logger as
+//! fields. Either goes to stderr or to a log file that's rotated once it's
+//! big or old enough, keeping the most recent rotated files next to it as
+//! `<file>.1`, `<file>.2`...
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use mqtt3::Packet;
+use slog::{Drain, Fuse};
+use slog_async::Async;
+use slog_json::Json;
+use slog_term;
+
+use error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogOutput {
+    Terminal,
+    Json,
+}
+
+/// When a log file is rotated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotation {
+    /// Once it holds this many bytes
+    Size(u64),
+    /// Once it was written to for this long
+    Interval(Duration),
+}
+
+/// Parses a size, `<n>B`, `<n>KB`, `<n>MB` or `<n>GB`, or an interval,
+/// `<n>s`, `<n>m`, `<n>h` or `<n>d`
+impl FromStr for Rotation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::Config(format!("invalid log rotation {:?}. Expected a size like 10MB or an interval like 24h", s));
+        let split = s.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+        let n: u64 = s[..split].parse().map_err(|_| invalid())?;
+
+        let rotation = match &s[split..] {
+            "B" => Rotation::Size(n),
+            "KB" => Rotation::Size(n << 10),
+            "MB" => Rotation::Size(n << 20),
+            "GB" => Rotation::Size(n << 30),
+            "s" => Rotation::Interval(Duration::from_secs(n)),
+            "m" => Rotation::Interval(Duration::from_secs(n * 60)),
+            "h" => Rotation::Interval(Duration::from_secs(n * 60 * 60)),
+            "d" => Rotation::Interval(Duration::from_secs(n * 24 * 60 * 60)),
+            _ => return Err(invalid()),
+        };
+        Ok(rotation)
+    }
+}
+
+/// Asynchronous drain writing in `output` format to `file`, or to stderr
+/// without one
+pub fn drain(output: LogOutput, file: Option<RotatingFile>) -> Fuse<Async> {
+    match (output, file) {
+        (LogOutput::Terminal, Some(file)) => {
+            let drain = slog_term::CompactFormat::new(slog_term::PlainDecorator::new(file)).build().fuse();
+            Async::new(drain).build().fuse()
+        }
+        (LogOutput::Terminal, None) => {
+            let decorator = slog_term::TermDecorator::new().build();
+            let drain = slog_term::CompactFormat::new(decorator).build().fuse();
+            Async::new(drain).build().fuse()
+        }
+        (LogOutput::Json, Some(file)) => Async::new(json(file).fuse()).build().fuse(),
+        (LogOutput::Json, None) => Async::new(json(io::stderr()).fuse()).build().fuse(),
+    }
+}
+
+/// Drain writing every record as a line of JSON, flushed after the line
+pub fn json<W: Write>(out: W) -> Json<W> {
+    Json::new(out)
+        .add_default_keys()
+        .set_newlines(true)
+        .set_flush(true)
+        .build()
+}
+
+/// Log file that's rotated between records. The current file is renamed to
+/// `<path>.1`, older ones move up a number and the ones beyond `keep` are deleted
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    keep: usize,
+    file: File,
+    written: u64,
+    opened: Instant,
+}
+
+impl RotatingFile {
+    /// Appends to the file at `path`
+    pub fn open(path: &Path, rotation: Rotation, keep: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(RotatingFile {
+               path: path.to_owned(),
+               rotation: rotation,
+               keep: keep,
+               written: file.metadata()?.len(),
+               file: file,
+               opened: Instant::now(),
+           })
+    }
+
+    fn due(&self) -> bool {
+        match self.rotation {
+            Rotation::Size(size) => self.written >= size,
+            Rotation::Interval(interval) => self.opened.elapsed() >= interval,
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let _ = fs::remove_file(rotated(&self.path, self.keep));
+        for n in (1..self.keep).rev() {
+            let _ = fs::rename(rotated(&self.path, n), rotated(&self.path, n + 1));
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, rotated(&self.path, 1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+
+        self.file = File::create(&self.path)?;
+        self.written = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+/// Name of the `n`th most recent rotated file
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    /// Called by the drains after every record, which keeps records whole
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.due() {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+}
+
+/// Field value of the packet type of `packet`
+pub fn packet_type(packet: &Packet) -> &'static str {
+    match *packet {
+        Packet::Connect(_) => "connect",
+        Packet::Connack(_) => "connack",
+        Packet::Publish(_) => "publish",
+        Packet::Puback(_) => "puback",
+        Packet::Pubrec(_) => "pubrec",
+        Packet::Pubrel(_) => "pubrel",
+        Packet::Pubcomp(_) => "pubcomp",
+        Packet::Subscribe(_) => "subscribe",
+        Packet::Suback(_) => "suback",
+        Packet::Unsubscribe(_) => "unsubscribe",
+        Packet::Unsuback(_) => "unsuback",
+        Packet::Pingreq => "pingreq",
+        Packet::Pingresp => "pingresp",
+        Packet::Disconnect => "disconnect",
+    }
+}
+
+/// Topic of a publish, first filter of a subscribe or an unsubscribe
+pub fn topic(packet: &Packet) -> Option<&str> {
+    match *packet {
+        Packet::Publish(ref publish) => Some(&publish.topic_name),
+        Packet::Subscribe(ref subscribe) => subscribe.topics.first().map(|topic| topic.topic_path.as_str()),
+        Packet::Unsubscribe(ref unsubscribe) => unsubscribe.topics.first().map(|topic| topic.as_str()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use serde_json::{self, Value};
+    use slog::{Drain, Logger};
+    use super::{json, Rotation, RotatingFile};
+
+    #[derive(Clone)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_lines_carry_the_key_values() {
+        let out = Shared(Arc::new(Mutex::new(Vec::new())));
+        let logger = Logger::root(Mutex::new(json(out.clone())).fuse(), o!("client-id" => "d7"));
+        info!(logger, "Packet received"; "packet" => "publish", "topic" => Some("sensors/7"), "len" => 3u32);
+        warn!(logger.new(o!("listener" => "tcp")), "Gone"; "topic" => None::<&str>);
+
+        let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<Value> = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines[0]["msg"], "Packet received");
+        assert_eq!(lines[0]["level"], "INFO");
+        assert_eq!(lines[0]["client-id"], "d7");
+        assert_eq!(lines[0]["topic"], "sensors/7");
+        assert_eq!(lines[0]["len"], 3);
+        assert_eq!(lines[1]["level"], "WARN");
+        assert_eq!(lines[1]["client-id"], "d7");
+        assert_eq!(lines[1]["listener"], "tcp");
+        assert_eq!(lines[1]["topic"], Value::Null);
+    }
+
+    #[test]
+    fn log_files_are_rotated_and_pruned() {
+        assert_eq!("10MB".parse::<Rotation>().unwrap(), Rotation::Size(10 << 20));
+        assert_eq!("24h".parse::<Rotation>().unwrap(), Rotation::Interval(Duration::from_secs(86400)));
+        assert!("10".parse::<Rotation>().is_err());
+        assert!("MB".parse::<Rotation>().is_err());
+
+        let dir = env::temp_dir().join("rumqttd-log-rotation");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rumqttd.log");
+
+        let mut file = RotatingFile::open(&path, Rotation::Size(10), 2).unwrap();
+        for record in ["first record\n", "second record\n", "third record\n", "fourth\n"].iter() {
+            file.write_all(record.as_bytes()).unwrap();
+            file.flush().unwrap();
+        }
+
+        // the first record fell off and the last one is too small for a rotation
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(dir.join("rumqttd.log.1")).unwrap(), "third record\n");
+        assert_eq!(fs::read_to_string(dir.join("rumqttd.log.2")).unwrap(), "second record\n");
+        assert!(!dir.join("rumqttd.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
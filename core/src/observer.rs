@@ -0,0 +1,90 @@
+//! Read only observer replicas. A broker subscribed to `$mirror` gets every
+//! publish it routes, on `$mirror/<client id>/<topic>`, which costs the
+//! primary one extra delivery per publish. An observer keeps one such
+//! connection to its primary and delivers the mirrored publishes to its own
+//! subscribers on the original topics, for analytics, debugging or as a
+//! warm standby. Its clients can subscribe but not publish
+
+use std::net::SocketAddr;
+
+use mqtt3::*;
+
+use leaf;
+
+/// Filter that subscribes to everything a broker routes
+pub const MIRROR_FILTER: &str = "$mirror";
+
+/// Topic prefix of mirrored publishes
+pub const MIRROR_TOPIC_PREFIX: &str = "$mirror/";
+
+/// Id of the primary connection among the observer's clients
+pub const PRIMARY_CLIENT_ID: &str = "$primary";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObserverConfig {
+    /// Broker to mirror
+    pub primary: SocketAddr,
+    /// Client id of the observer at the primary
+    pub node_id: String,
+}
+
+impl ObserverConfig {
+    /// CONNECT sent to the primary. The mirror is subscribed again on every
+    /// connection so the session doesn't have to persist
+    pub fn connect_packet(&self) -> Packet {
+        Packet::Connect(Box::new(Connect {
+                                     protocol: Protocol::MQTT(4),
+                                     keep_alive: leaf::LEAF_KEEP_ALIVE,
+                                     client_id: self.node_id.clone(),
+                                     clean_session: true,
+                                     last_will: None,
+                                     username: None,
+                                     password: None,
+                                 }))
+    }
+
+    /// Subscription to everything the primary routes. Qos 1 so a full
+    /// queue at the observer doesn't lose messages silently
+    pub fn subscribe_packet(&self) -> Packet {
+        Packet::Subscribe(Box::new(Subscribe {
+                                       pid: PacketIdentifier(1),
+                                       topics: vec![SubscribeTopic {
+                                                        topic_path: MIRROR_FILTER.to_owned(),
+                                                        qos: QoS::AtLeastOnce,
+                                                    }],
+                                   }))
+    }
+}
+
+/// Topic a publish of `origin` is mirrored on
+pub fn wrap_topic(origin: &str, topic: &str) -> String {
+    leaf::wrap(MIRROR_TOPIC_PREFIX, origin, topic)
+}
+
+/// Client id and topic of a mirrored publish
+pub fn unwrap_topic(topic: &str) -> Option<(String, &str)> {
+    leaf::unwrap(MIRROR_TOPIC_PREFIX, topic)
+}
+
+#[cfg(test)]
+mod test {
+    use mqtt3::*;
+    use super::{unwrap_topic, wrap_topic, ObserverConfig, MIRROR_FILTER};
+
+    #[test]
+    fn mirrored_topics_keep_their_origin() {
+        let topic = wrap_topic("sensor/7", "hello/mqtt");
+        assert_eq!(topic, "$mirror/sensor%2F7/hello/mqtt");
+        assert_eq!(unwrap_topic(&topic), Some(("sensor/7".to_owned(), "hello/mqtt")));
+        assert_eq!(unwrap_topic("$leaf/sensor/hello/mqtt"), None);
+
+        let config = ObserverConfig {
+            primary: "10.0.0.1:1883".parse().unwrap(),
+            node_id: "observer-1".to_owned(),
+        };
+        match config.subscribe_packet() {
+            Packet::Subscribe(s) => assert_eq!(s.topics[0].topic_path, MIRROR_FILTER),
+            p => panic!("Expected a subscribe. Got {:?}", p),
+        }
+    }
+}
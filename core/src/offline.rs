@@ -0,0 +1,371 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use mqtt3::{Publish, QoS, SubscribeTopic};
+
+use config::OverflowPolicy;
+use storage::LoggedSession;
+
+/// Whether a publish queued for an offline session was kept
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Queued {
+    Yes,
+    /// Queued in place of this many of the oldest messages, which were dropped
+    DroppedOldest(usize),
+    /// Queue was full and the message was dropped
+    Dropped,
+}
+
+/// Subscriptions and pending messages of a persistent session whose client
+/// is disconnected
+#[derive(Debug)]
+struct OfflineSession {
+    subscriptions: Vec<SubscribeTopic>,
+    /// Publishes with the qos they're to be delivered at and when they were queued
+    queue: VecDeque<(Box<Publish>, QoS, Instant)>,
+    /// Payload bytes in the queue
+    bytes: usize,
+    parked_at: Instant,
+}
+
+/// Persistent sessions waiting for their clients to come back
+#[derive(Debug)]
+pub struct OfflineSessions {
+    sessions: HashMap<String, OfflineSession>,
+    /// Topic -> offline client ids subscribed to it with the subscription qos
+    subscribers: HashMap<String, Vec<(String, QoS)>>,
+    queue_size: usize,
+    /// Payload bytes queued at most per session. `None` only limits the count
+    max_bytes: Option<usize>,
+    overflow: OverflowPolicy,
+    /// How long a session is kept. `None` keeps sessions until their clients return
+    expiry: Option<Duration>,
+    /// Parked sessions oldest first, with when they were parked. Entries of
+    /// sessions that were resumed or parked again since are skipped on expiry
+    parked: VecDeque<(Instant, String)>,
+}
+
+impl OfflineSessions {
+    pub fn new(queue_size: usize, overflow: OverflowPolicy) -> Self {
+        OfflineSessions {
+            sessions: HashMap::new(),
+            subscribers: HashMap::new(),
+            queue_size: queue_size,
+            max_bytes: None,
+            overflow: overflow,
+            expiry: None,
+            parked: VecDeque::new(),
+        }
+    }
+
+    /// Also limits the payload bytes queued per session
+    pub fn max_bytes(mut self, max_bytes: Option<usize>) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Drops sessions `expiry` after they were parked
+    pub fn expiry(mut self, expiry: Option<Duration>) -> Self {
+        self.expiry = expiry;
+        self
+    }
+
+    /// Keeps the session of a disconnected client, which happened at `now`
+    pub fn park(&mut self, id: &str, subscriptions: Vec<SubscribeTopic>, now: Instant) {
+        self.discard(id);
+        if self.expiry.is_some() {
+            self.parked.push_back((now, id.to_owned()));
+        }
+
+        for s in subscriptions.iter() {
+            self.subscribers
+                .entry(s.topic_path.clone())
+                .or_insert_with(Vec::new)
+                .push((id.to_owned(), s.qos));
+        }
+
+        self.sessions.insert(id.to_owned(),
+                             OfflineSession {
+                                 subscriptions: subscriptions,
+                                 queue: VecDeque::new(),
+                                 bytes: 0,
+                                 parked_at: now,
+                             });
+    }
+
+    /// Takes the session back for a reconnecting client. Returns its
+    /// subscriptions and the messages queued while it was away
+    pub fn resume(&mut self, id: &str) -> Option<(Vec<SubscribeTopic>, Vec<(Box<Publish>, QoS)>)> {
+        self.unindex(id);
+        self.sessions
+            .remove(id)
+            .map(|session| {
+                     let queue = session.queue.into_iter().map(|(publish, qos, _)| (publish, qos)).collect();
+                     (session.subscriptions, queue)
+                 })
+    }
+
+    /// Forgets the session, e.g. when the client reconnects with a clean session
+    pub fn discard(&mut self, id: &str) -> bool {
+        self.unindex(id);
+        self.sessions.remove(id).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// Messages queued per offline session
+    pub fn queue_depths(&self) -> Vec<(String, usize)> {
+        self.sessions
+            .iter()
+            .map(|(id, session)| (id.clone(), session.queue.len()))
+            .collect()
+    }
+
+    /// Every session with a copy of its queue, for a checkpoint
+    pub fn sessions(&self) -> Vec<LoggedSession> {
+        self.sessions
+            .iter()
+            .map(|(id, session)| {
+                     LoggedSession {
+                         id: id.clone(),
+                         subscriptions: session.subscriptions.clone(),
+                         queue: session.queue.iter().map(|&(ref publish, qos, _)| (publish.clone(), qos)).collect(),
+                     }
+                 })
+            .collect()
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.sessions.contains_key(id)
+    }
+
+    /// Offline client ids subscribed to `topic` with their subscription qos
+    pub fn subscribers(&self, topic: &str) -> Vec<(String, QoS)> {
+        self.subscribers.get(topic).cloned().unwrap_or_default()
+    }
+
+    /// Drops the messages queued for `id` at least `ttl` before `now`.
+    /// Returns how many were dropped
+    pub fn expire(&mut self, id: &str, now: Instant, ttl: Duration) -> usize {
+        let session = match self.sessions.get_mut(id) {
+            Some(session) => session,
+            None => return 0,
+        };
+
+        let mut expired = 0;
+        while let Some(queued_at) = session.queue.front().map(|entry| entry.2) {
+            if now.duration_since(queued_at) < ttl {
+                break;
+            }
+            let (publish, ..) = session.queue.pop_front().unwrap();
+            session.bytes -= publish.payload.len();
+            expired += 1;
+        }
+        expired
+    }
+
+    /// Discards the sessions parked at least `expiry` before `now`. Returns
+    /// their ids
+    pub fn expire_sessions(&mut self, now: Instant) -> Vec<String> {
+        let expiry = match self.expiry {
+            Some(expiry) => expiry,
+            None => return Vec::new(),
+        };
+
+        let mut expired = Vec::new();
+        while let Some(parked_at) = self.parked.front().map(|entry| entry.0) {
+            if now.duration_since(parked_at) < expiry {
+                break;
+            }
+            let (_, id) = self.parked.pop_front().unwrap();
+            if self.sessions.get(&id).map_or(false, |session| session.parked_at == parked_at) {
+                self.discard(&id);
+                expired.push(id);
+            }
+        }
+        expired
+    }
+
+    /// Queues a publish for the offline client `id`. A full queue is handled
+    /// by the overflow policy, where the queue is full at `queue_size`
+    /// messages or `max_bytes` payload bytes
+    pub fn queue(&mut self, id: &str, publish: Box<Publish>, qos: QoS, now: Instant) -> Queued {
+        let queue_size = self.queue_size;
+        let max_bytes = self.max_bytes.unwrap_or(::std::usize::MAX);
+        let session = match self.sessions.get_mut(id) {
+            Some(session) => session,
+            None => return Queued::Dropped,
+        };
+
+        let size = publish.payload.len();
+        let fits = |session: &OfflineSession| session.queue.len() < queue_size && session.bytes + size <= max_bytes;
+        if fits(&*session) {
+            session.queue.push_back((publish, qos, now));
+            session.bytes += size;
+            return Queued::Yes;
+        }
+
+        match self.overflow {
+            // there's no connection to close. refused by the config anyway
+            OverflowPolicy::DropNew | OverflowPolicy::Disconnect => Queued::Dropped,
+            // dropping the others wouldn't make room
+            OverflowPolicy::DropOldest if queue_size == 0 || size > max_bytes => Queued::Dropped,
+            OverflowPolicy::DropOldest => {
+                let mut dropped = 0;
+                while !fits(&*session) {
+                    match session.queue.pop_front() {
+                        Some((oldest, ..)) => session.bytes -= oldest.payload.len(),
+                        None => return Queued::Dropped,
+                    }
+                    dropped += 1;
+                }
+                session.queue.push_back((publish, qos, now));
+                session.bytes += size;
+                Queued::DroppedOldest(dropped)
+            }
+        }
+    }
+
+    fn unindex(&mut self, id: &str) {
+        let topics = match self.sessions.get(id) {
+            Some(session) => session.subscriptions.iter().map(|s| s.topic_path.clone()).collect(),
+            None => vec![],
+        };
+
+        for topic in topics {
+            let empty = match self.subscribers.get_mut(&topic) {
+                Some(ids) => {
+                    ids.retain(|&(ref i, _)| i != id);
+                    ids.is_empty()
+                }
+                None => false,
+            };
+
+            if empty {
+                self.subscribers.remove(&topic);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use mqtt3::*;
+    use config::OverflowPolicy;
+    use super::{OfflineSessions, Queued};
+
+    fn publish(payload: u8) -> Box<Publish> {
+        Box::new(Publish {
+                     dup: false,
+                     qos: QoS::AtLeastOnce,
+                     retain: false,
+                     pid: Some(PacketIdentifier(1)),
+                     topic_name: "hello/mqtt".to_owned(),
+                     payload: Arc::new(vec![payload]),
+                 })
+    }
+
+    fn subscriptions() -> Vec<SubscribeTopic> {
+        vec![SubscribeTopic {
+                 topic_path: "hello/mqtt".to_owned(),
+                 qos: QoS::AtLeastOnce,
+             }]
+    }
+
+    #[test]
+    fn queue_overflow_follows_the_policy() {
+        for &(policy, ref expected) in [(OverflowPolicy::DropNew, vec![0, 1]), (OverflowPolicy::DropOldest, vec![1, 2])].iter() {
+            let now = Instant::now();
+            let mut offline = OfflineSessions::new(2, policy);
+            offline.park("device-1", subscriptions(), now);
+
+            assert_eq!(offline.queue("device-1", publish(0), QoS::AtLeastOnce, now), Queued::Yes);
+            assert_eq!(offline.queue("device-1", publish(1), QoS::AtLeastOnce, now), Queued::Yes);
+            assert!(offline.queue("device-1", publish(2), QoS::AtLeastOnce, now) != Queued::Yes);
+
+            let (subscriptions, queued) = offline.resume("device-1").unwrap();
+            assert_eq!(subscriptions.len(), 1);
+            let payloads: Vec<u8> = queued.iter().map(|&(ref p, _)| p.payload[0]).collect();
+            assert_eq!(&payloads, expected);
+        }
+    }
+
+    #[test]
+    fn stale_messages_expire() {
+        let start = Instant::now();
+        let mut offline = OfflineSessions::new(3, OverflowPolicy::DropNew);
+        offline.park("device-1", subscriptions(), start);
+        offline.queue("device-1", publish(0), QoS::AtLeastOnce, start);
+        offline.queue("device-1", publish(1), QoS::AtLeastOnce, start + Duration::from_secs(30));
+
+        let ttl = Duration::from_secs(60);
+        assert_eq!(offline.expire("device-1", start + Duration::from_secs(59), ttl), 0);
+        assert_eq!(offline.expire("device-1", start + Duration::from_secs(60), ttl), 1);
+        assert_eq!(offline.expire("device-2", start, ttl), 0);
+
+        let (_, queued) = offline.resume("device-1").unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].0.payload[0], 1);
+    }
+
+    #[test]
+    fn sessions_expire_after_they_are_parked() {
+        let start = Instant::now();
+        let mut offline = OfflineSessions::new(3, OverflowPolicy::DropNew).expiry(Some(Duration::from_secs(60)));
+        offline.park("device-1", subscriptions(), start);
+        offline.park("device-2", subscriptions(), start);
+        offline.park("device-3", subscriptions(), start + Duration::from_secs(10));
+        // came back and left again, the first deadline no longer counts
+        offline.resume("device-2");
+        offline.park("device-2", subscriptions(), start + Duration::from_secs(30));
+
+        assert!(offline.expire_sessions(start + Duration::from_secs(59)).is_empty());
+        assert_eq!(offline.expire_sessions(start + Duration::from_secs(70)), vec!["device-1", "device-3"]);
+        assert_eq!(offline.expire_sessions(start + Duration::from_secs(90)), vec!["device-2"]);
+        assert!(offline.is_empty());
+        assert!(offline.subscribers("hello/mqtt").is_empty());
+    }
+
+    #[test]
+    fn queues_are_limited_in_bytes() {
+        let now = Instant::now();
+        let mut offline = OfflineSessions::new(10, OverflowPolicy::DropOldest).max_bytes(Some(4));
+        offline.park("device-1", subscriptions(), now);
+        let sized = |len: usize| {
+            let mut publish = publish(len as u8);
+            publish.payload = Arc::new(vec![len as u8; len]);
+            publish
+        };
+
+        assert_eq!(offline.queue("device-1", sized(1), QoS::AtLeastOnce, now), Queued::Yes);
+        assert_eq!(offline.queue("device-1", sized(2), QoS::AtLeastOnce, now), Queued::Yes);
+        assert_eq!(offline.queue("device-1", sized(3), QoS::AtLeastOnce, now), Queued::DroppedOldest(2));
+        assert_eq!(offline.queue("device-1", sized(5), QoS::AtLeastOnce, now), Queued::Dropped);
+
+        let (_, queued) = offline.resume("device-1").unwrap();
+        let lens: Vec<usize> = queued.iter().map(|&(ref p, _)| p.payload.len()).collect();
+        assert_eq!(lens, vec![3]);
+    }
+
+    #[test]
+    fn subscribers_are_indexed_while_offline() {
+        let now = Instant::now();
+        let mut offline = OfflineSessions::new(2, OverflowPolicy::DropNew);
+        offline.park("device-1", subscriptions(), now);
+        offline.park("device-2", subscriptions(), now);
+        assert_eq!(offline.subscribers("hello/mqtt").len(), 2);
+
+        offline.resume("device-1");
+        assert!(offline.discard("device-2"));
+        assert!(offline.subscribers("hello/mqtt").is_empty());
+        assert!(!offline.contains("device-2"));
+    }
+}
@@ -0,0 +1,182 @@
+//! Password file authentication. The file has one `username:hash` entry per
+//! line like mosquitto's, with bcrypt hashes. It's reloaded when it changes
+//! on disk. Connected clients stay connected; only new CONNECTs see the new
+//! credentials. Hashes are checked on a thread pool, bcrypt is slow on
+//! purpose and would hold up the event loop
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+
+use bcrypt;
+use futures_cpupool::{Builder, CpuPool};
+use mqtt3::ConnectReturnCode;
+use tokio_core::reactor::Handle;
+
+use auth::{Authenticator, Verdict, Verification};
+use error::{Error, Result};
+
+/// How often the file is checked for changes
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+struct Entries {
+    hashes: HashMap<String, String>,
+    /// Modification time and size of the loaded file
+    version: (Option<SystemTime>, u64),
+}
+
+/// Credentials from a password file. Clones share the entries and the
+/// threads checking the hashes
+#[derive(Debug, Clone)]
+pub struct PasswordFile {
+    path: PathBuf,
+    entries: Rc<RefCell<Entries>>,
+    pool: CpuPool,
+}
+
+/// Parses `username:hash` lines. Empty lines and `#` comments are skipped
+pub fn parse(text: &str) -> ::std::result::Result<HashMap<String, String>, String> {
+    let mut hashes = HashMap::new();
+
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match line.find(':') {
+            Some(i) if i > 0 && i + 1 < line.len() => {
+                hashes.insert(line[..i].to_owned(), line[i + 1..].to_owned());
+            }
+            _ => return Err(format!("invalid entry on line {}", n + 1)),
+        }
+    }
+
+    Ok(hashes)
+}
+
+/// Changes whenever the file is written. Sizes tell apart writes within the
+/// timestamp granularity
+fn version(path: &Path) -> Result<(Option<SystemTime>, u64)> {
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.modified().ok(), metadata.len()))
+}
+
+fn read(path: &Path) -> Result<Entries> {
+    let version = version(path)?;
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+
+    let hashes = parse(&text).map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+    Ok(Entries {
+           hashes: hashes,
+           version: version,
+       })
+}
+
+impl PasswordFile {
+    pub fn load<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        let entries = read(&path)?;
+
+        Ok(PasswordFile {
+               path: path,
+               entries: Rc::new(RefCell::new(entries)),
+               pool: Builder::new().name_prefix("bcrypt-").create(),
+           })
+    }
+
+    /// Reads the file again if it changed since it was loaded. A file that
+    /// doesn't parse leaves the current credentials in place
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        if version(&self.path)? == self.entries.borrow().version {
+            return Ok(false);
+        }
+
+        let entries = read(&self.path)?;
+        *self.entries.borrow_mut() = entries;
+        Ok(true)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.borrow().hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.borrow().hashes.is_empty()
+    }
+}
+
+impl Authenticator for PasswordFile {
+    fn authenticate(&self, _: &str, username: Option<&str>, password: Option<&str>, _: SocketAddr, _: &Handle) -> Verification {
+        let username = match username {
+            Some(username) => username,
+            None => return Verdict::from(ConnectReturnCode::NotAuthorized).ready(),
+        };
+
+        let (hash, password) = match (self.entries.borrow().hashes.get(username), password) {
+            (Some(hash), Some(password)) => (hash.clone(), password.to_owned()),
+            _ => return Verdict::from(ConnectReturnCode::BadUsernamePassword).ready(),
+        };
+        let verification = self.pool.spawn_fn(move || {
+            let code = match bcrypt::verify(&password, &hash) {
+                Ok(true) => ConnectReturnCode::Accepted,
+                // a hash that doesn't parse matches no password
+                Ok(false) | Err(_) => ConnectReturnCode::BadUsernamePassword,
+            };
+            Ok(Verdict::from(code))
+        });
+        Box::new(verification)
+    }
+
+    fn reload(&self) -> Result<bool> {
+        self.reload_if_changed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use futures::Future;
+    use mqtt3::ConnectReturnCode;
+    use tokio_core::reactor::Core;
+    use auth::Authenticator;
+    use super::PasswordFile;
+
+    const SECRET: &str = "$2b$04$abcdefghijklmnopqrstuu2r9OfJnfCsdneAXAGHnS4UpFFP8WIrW";
+
+    #[test]
+    fn credentials_follow_the_file() {
+        let path = env::temp_dir().join(format!("rumqttd-passwd-{}", ::std::process::id()));
+        fs::write(&path, format!("# users\ndevice:{}\n", SECRET)).unwrap();
+
+        let file = PasswordFile::load(&path).unwrap();
+        let core = Core::new().unwrap();
+        let handle = core.handle();
+        let addr = "127.0.0.1:40000".parse().unwrap();
+        assert_eq!(file.authenticate("d7", Some("device"), Some("secret"), addr, &handle).wait().unwrap().code, ConnectReturnCode::Accepted);
+        assert_eq!(file.authenticate("d7", Some("device"), Some("guess"), addr, &handle).wait().unwrap().code, ConnectReturnCode::BadUsernamePassword);
+        assert_eq!(file.authenticate("d7", Some("robot"), Some("secret"), addr, &handle).wait().unwrap().code, ConnectReturnCode::BadUsernamePassword);
+        assert_eq!(file.authenticate("d7", None, None, addr, &handle).wait().unwrap().code, ConnectReturnCode::NotAuthorized);
+        assert_eq!(file.reload().unwrap(), false);
+
+        // a broken file keeps the loaded credentials
+        fs::write(&path, "robot\n").unwrap();
+        assert!(file.reload().is_err());
+        assert_eq!(file.authenticate("d7", Some("device"), Some("secret"), addr, &handle).wait().unwrap().code, ConnectReturnCode::Accepted);
+
+        fs::write(&path, format!("robot:{}\n", SECRET)).unwrap();
+        assert_eq!(file.reload().unwrap(), true);
+        assert_eq!(file.authenticate("d7", Some("robot"), Some("secret"), addr, &handle).wait().unwrap().code, ConnectReturnCode::Accepted);
+        assert_eq!(file.authenticate("d7", Some("device"), Some("secret"), addr, &handle).wait().unwrap().code, ConnectReturnCode::BadUsernamePassword);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
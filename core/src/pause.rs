@@ -0,0 +1,151 @@
+use std::collections::VecDeque;
+
+use mqtt3::Publish;
+
+/// What happens to publishes on a paused subtree
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PausePolicy {
+    /// Hold up to this many messages and deliver them on resume. Oldest
+    /// messages are dropped once the limit is hit
+    Queue(usize),
+    /// Discard messages while paused
+    Drop,
+}
+
+#[derive(Debug)]
+struct Pause {
+    prefix: String,
+    policy: PausePolicy,
+    queue: VecDeque<Box<Publish>>,
+    dropped: u64,
+}
+
+/// Topic subtrees whose delivery is on hold
+#[derive(Debug)]
+pub struct PausedTopics {
+    pauses: Vec<Pause>,
+}
+
+/// True if `topic` is `prefix` itself or lives underneath it
+pub fn in_subtree(topic: &str, prefix: &str) -> bool {
+    topic == prefix || (topic.starts_with(prefix) && topic[prefix.len()..].starts_with('/'))
+}
+
+impl PausedTopics {
+    pub fn new() -> Self {
+        PausedTopics { pauses: Vec::new() }
+    }
+
+    /// Pauses delivery for `prefix` and everything below it. Pausing an already
+    /// paused subtree only updates its policy
+    pub fn pause(&mut self, prefix: &str, policy: PausePolicy) {
+        if let Some(pause) = self.pauses.iter_mut().find(|p| p.prefix == prefix) {
+            pause.policy = policy;
+            return;
+        }
+
+        self.pauses.push(Pause {
+                             prefix: prefix.to_owned(),
+                             policy: policy,
+                             queue: VecDeque::new(),
+                             dropped: 0,
+                         });
+    }
+
+    /// Resumes delivery for `prefix` and returns the messages held while it was paused
+    pub fn resume(&mut self, prefix: &str) -> Vec<Box<Publish>> {
+        match self.pauses.iter().position(|p| p.prefix == prefix) {
+            Some(index) => self.pauses.remove(index).queue.into_iter().collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn is_paused(&self, topic: &str) -> bool {
+        self.pauses.iter().any(|p| in_subtree(topic, &p.prefix))
+    }
+
+    /// Holds back the publish if its topic is paused. Returns the publish
+    /// back when it should be delivered right away
+    pub fn hold(&mut self, publish: Box<Publish>) -> Option<Box<Publish>> {
+        let pause = match self.pauses.iter_mut().find(|p| in_subtree(&publish.topic_name, &p.prefix)) {
+            Some(pause) => pause,
+            None => return Some(publish),
+        };
+
+        match pause.policy {
+            PausePolicy::Queue(limit) => {
+                if pause.queue.len() >= limit {
+                    pause.queue.pop_front();
+                    pause.dropped += 1;
+                }
+
+                if limit > 0 {
+                    pause.queue.push_back(publish);
+                } else {
+                    pause.dropped += 1;
+                }
+            }
+            PausePolicy::Drop => pause.dropped += 1,
+        }
+
+        None
+    }
+
+    /// Paused prefixes with the number of queued and dropped messages
+    pub fn list(&self) -> Vec<(String, usize, u64)> {
+        self.pauses
+            .iter()
+            .map(|p| (p.prefix.clone(), p.queue.len(), p.dropped))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use mqtt3::*;
+    use super::{PausedTopics, PausePolicy};
+
+    fn publish(topic: &str, payload: u8) -> Box<Publish> {
+        Box::new(Publish {
+                     dup: false,
+                     qos: QoS::AtMostOnce,
+                     retain: false,
+                     pid: None,
+                     topic_name: topic.to_owned(),
+                     payload: Arc::new(vec![payload]),
+                 })
+    }
+
+    #[test]
+    fn only_the_paused_subtree_is_held() {
+        let mut paused = PausedTopics::new();
+        paused.pause("factory/line1", PausePolicy::Drop);
+
+        assert!(paused.hold(publish("factory/line1", 1)).is_none());
+        assert!(paused.hold(publish("factory/line1/temp", 1)).is_none());
+        assert!(paused.hold(publish("factory/line10", 1)).is_some());
+        assert!(paused.hold(publish("factory/line2/temp", 1)).is_some());
+        assert_eq!(paused.list(), vec![("factory/line1".to_owned(), 0, 2)]);
+
+        assert!(paused.resume("factory/line1").is_empty());
+        assert!(!paused.is_paused("factory/line1/temp"));
+    }
+
+    #[test]
+    fn queued_messages_are_released_in_order_on_resume() {
+        let mut paused = PausedTopics::new();
+        paused.pause("factory", PausePolicy::Queue(2));
+
+        for i in 0..3 {
+            assert!(paused.hold(publish("factory/line1", i)).is_none());
+        }
+
+        // oldest message is dropped to make room
+        assert_eq!(paused.list(), vec![("factory".to_owned(), 2, 1)]);
+
+        let released: Vec<u8> = paused.resume("factory").iter().map(|p| p.payload[0]).collect();
+        assert_eq!(released, vec![1, 2]);
+        assert!(paused.hold(publish("factory/line1", 4)).is_some());
+    }
+}
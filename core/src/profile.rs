@@ -0,0 +1,199 @@
+//! Borrow profiling for the broker's shared `RefCell`s. With the
+//! `contention-profiler` feature every borrow is counted and timed per named
+//! cell. Without it `Tracked` is a plain `RefCell`
+
+#[cfg(not(feature = "contention-profiler"))]
+pub use self::disabled::*;
+#[cfg(feature = "contention-profiler")]
+pub use self::enabled::*;
+
+#[cfg(not(feature = "contention-profiler"))]
+mod disabled {
+    use std::cell::RefCell;
+
+    pub type Tracked<T> = RefCell<T>;
+
+    pub fn tracked<T>(_name: &'static str, value: T) -> Tracked<T> {
+        RefCell::new(value)
+    }
+}
+
+#[cfg(feature = "contention-profiler")]
+mod enabled {
+    use std::cell::{Ref, RefCell, RefMut};
+    use std::collections::HashMap;
+    use std::fmt::{self, Debug};
+    use std::ops::{Deref, DerefMut};
+    use std::time::{Duration, Instant};
+
+    /// Borrow statistics of all the cells sharing a name
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct CellStats {
+        pub borrows: u64,
+        pub borrow_muts: u64,
+        /// Borrows attempted while the cell was already borrowed incompatibly.
+        /// With threads these would have been lock waits
+        pub conflicts: u64,
+        pub total_hold: Duration,
+        pub max_hold: Duration,
+    }
+
+    thread_local! {
+        static STATS: RefCell<HashMap<&'static str, CellStats>> = RefCell::new(HashMap::new());
+    }
+
+    fn record<F: FnOnce(&mut CellStats)>(name: &'static str, f: F) {
+        STATS.with(|stats| f(stats.borrow_mut().entry(name).or_insert_with(CellStats::default)));
+    }
+
+    fn record_hold(name: &'static str, start: Instant) {
+        let held = start.elapsed();
+        record(name, |s| {
+            s.total_hold += held;
+            if held > s.max_hold {
+                s.max_hold = held;
+            }
+        });
+    }
+
+    /// Snapshot of borrow statistics, sorted by name
+    pub fn report() -> Vec<(&'static str, CellStats)> {
+        let mut report: Vec<_> = STATS.with(|stats| stats.borrow().iter().map(|(k, v)| (*k, v.clone())).collect());
+        report.sort_by(|a, b| a.0.cmp(b.0));
+        report
+    }
+
+    pub fn reset() {
+        STATS.with(|stats| stats.borrow_mut().clear());
+    }
+
+    #[derive(Debug)]
+    pub struct Tracked<T> {
+        name: &'static str,
+        cell: RefCell<T>,
+    }
+
+    pub fn tracked<T>(name: &'static str, value: T) -> Tracked<T> {
+        Tracked {
+            name: name,
+            cell: RefCell::new(value),
+        }
+    }
+
+    impl<T> Tracked<T> {
+        pub fn borrow<'a>(&'a self) -> TrackedRef<'a, T> {
+            let name = self.name;
+            let inner = match self.cell.try_borrow() {
+                Ok(inner) => inner,
+                Err(e) => {
+                    record(name, |s| s.conflicts += 1);
+                    panic!("{} already mutably borrowed: {:?}", name, e);
+                }
+            };
+
+            record(name, |s| s.borrows += 1);
+            TrackedRef {
+                name: name,
+                start: Instant::now(),
+                inner: inner,
+            }
+        }
+
+        pub fn borrow_mut<'a>(&'a self) -> TrackedRefMut<'a, T> {
+            let name = self.name;
+            let inner = match self.cell.try_borrow_mut() {
+                Ok(inner) => inner,
+                Err(e) => {
+                    record(name, |s| s.conflicts += 1);
+                    panic!("{} already borrowed: {:?}", name, e);
+                }
+            };
+
+            record(name, |s| s.borrow_muts += 1);
+            TrackedRefMut {
+                name: name,
+                start: Instant::now(),
+                inner: inner,
+            }
+        }
+    }
+
+    pub struct TrackedRef<'a, T: 'a> {
+        name: &'static str,
+        start: Instant,
+        inner: Ref<'a, T>,
+    }
+
+    impl<'a, T> Deref for TrackedRef<'a, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            &self.inner
+        }
+    }
+
+    impl<'a, T: Debug> Debug for TrackedRef<'a, T> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            self.inner.fmt(f)
+        }
+    }
+
+    impl<'a, T> Drop for TrackedRef<'a, T> {
+        fn drop(&mut self) {
+            record_hold(self.name, self.start);
+        }
+    }
+
+    pub struct TrackedRefMut<'a, T: 'a> {
+        name: &'static str,
+        start: Instant,
+        inner: RefMut<'a, T>,
+    }
+
+    impl<'a, T> Deref for TrackedRefMut<'a, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            &self.inner
+        }
+    }
+
+    impl<'a, T> DerefMut for TrackedRefMut<'a, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            &mut self.inner
+        }
+    }
+
+    impl<'a, T> Drop for TrackedRefMut<'a, T> {
+        fn drop(&mut self) {
+            record_hold(self.name, self.start);
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::{tracked, report, reset};
+
+        #[test]
+        fn borrows_are_counted_per_cell_name() {
+            reset();
+
+            let a = tracked("test.a", 0);
+            let b = tracked("test.b", vec![1]);
+
+            *a.borrow_mut() += 1;
+            *a.borrow_mut() += 1;
+            assert_eq!(*a.borrow(), 2);
+            assert_eq!(b.borrow().len(), 1);
+
+            let report = report();
+            assert_eq!(report.len(), 2);
+            assert_eq!(report[0].0, "test.a");
+            assert_eq!(report[0].1.borrows, 1);
+            assert_eq!(report[0].1.borrow_muts, 2);
+            assert_eq!(report[1].0, "test.b");
+            assert_eq!(report[1].1.borrows, 1);
+            assert_eq!(report[1].1.conflicts, 0);
+        }
+    }
+}
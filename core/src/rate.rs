@@ -0,0 +1,212 @@
+//! Token buckets limiting how fast connections are accepted, overall and per
+//! source ip. After a network blip a large fleet reconnects at once. The
+//! buckets spread the storm out, and the clients closed right after accept
+//! retry on their own backoff. Rates come from the config on every check so
+//! they follow reloads. A single bucket also limits the message webhook
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use clock::millis;
+use error::{Error, Result};
+
+/// Per ip buckets kept before idle ones are dropped
+const PRUNE_AT: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// Connections accepted per second on average
+    pub per_second: u32,
+    /// Connections accepted in one go after a quiet period
+    pub burst: u32,
+}
+
+impl RateLimit {
+    pub fn new(per_second: u32, burst: u32) -> Self {
+        RateLimit {
+            per_second: per_second,
+            burst: burst,
+        }
+    }
+
+    /// How long an empty bucket takes to fill up again
+    fn refill(&self) -> Duration {
+        Duration::from_millis(u64::from(self.burst) * 1000 / u64::from(self.per_second))
+    }
+}
+
+/// Parses `<per second>/s <burst>`, e.g. `100/s 500`
+impl FromStr for RateLimit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::Config(format!("invalid rate {:?}. Expected <per second>/s <burst> like 100/s 500", s));
+        let mut parts = s.split_whitespace();
+        let per_second = match parts.next() {
+            Some(rate) if rate.ends_with("/s") => rate[..rate.len() - 2].parse().map_err(|_| invalid())?,
+            _ => return Err(invalid()),
+        };
+        let burst = parts.next().ok_or_else(invalid)?.parse().map_err(|_| invalid())?;
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(RateLimit::new(per_second, burst))
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(limit: RateLimit, now: Instant) -> Self {
+        Bucket {
+            tokens: f64::from(limit.burst),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: RateLimit, now: Instant) {
+        let elapsed = millis(now.duration_since(self.updated)) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * f64::from(limit.per_second)).min(f64::from(limit.burst));
+        self.updated = now;
+    }
+
+    fn has_token(&self) -> bool {
+        self.tokens >= 1.0
+    }
+}
+
+/// Buckets of the connections accepted so far
+#[derive(Debug, Default)]
+pub struct AcceptLimiter {
+    total: Option<Bucket>,
+    per_ip: HashMap<IpAddr, Bucket>,
+    /// Connections refused by either limit
+    pub refused: u64,
+}
+
+impl AcceptLimiter {
+    pub fn new() -> Self {
+        AcceptLimiter::default()
+    }
+
+    /// True if a connection from `ip` may be accepted at `now`. Takes a token
+    /// from both buckets only when both have one, so refused attempts from one
+    /// ip don't use up the total
+    pub fn admit(&mut self, ip: IpAddr, now: Instant, total: Option<RateLimit>, per_ip: Option<RateLimit>) -> bool {
+        if let Some(limit) = total {
+            let bucket = self.total.get_or_insert_with(|| Bucket::full(limit, now));
+            bucket.refill(limit, now);
+        }
+        if let Some(limit) = per_ip {
+            if self.per_ip.len() >= PRUNE_AT {
+                let idle = limit.refill();
+                self.per_ip.retain(|_, bucket| now.duration_since(bucket.updated) < idle);
+            }
+            let bucket = self.per_ip.entry(ip).or_insert_with(|| Bucket::full(limit, now));
+            bucket.refill(limit, now);
+        }
+
+        let total_ok = total.map_or(true, |_| self.total.as_ref().map_or(true, Bucket::has_token));
+        let ip_ok = per_ip.map_or(true, |_| self.per_ip.get(&ip).map_or(true, Bucket::has_token));
+        if !(total_ok && ip_ok) {
+            self.refused += 1;
+            return false;
+        }
+
+        if total.is_some() {
+            if let Some(ref mut bucket) = self.total {
+                bucket.tokens -= 1.0;
+            }
+        }
+        if per_ip.is_some() {
+            if let Some(bucket) = self.per_ip.get_mut(&ip) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        true
+    }
+}
+
+/// One bucket on its own
+#[derive(Debug, Default)]
+pub struct Limiter {
+    bucket: Option<Bucket>,
+    /// Events refused so far
+    pub refused: u64,
+}
+
+impl Limiter {
+    pub fn new() -> Self {
+        Limiter::default()
+    }
+
+    /// True if an event may go ahead at `now`, taking a token if so
+    pub fn admit(&mut self, now: Instant, limit: RateLimit) -> bool {
+        let bucket = self.bucket.get_or_insert_with(|| Bucket::full(limit, now));
+        bucket.refill(limit, now);
+        if !bucket.has_token() {
+            self.refused += 1;
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+    use super::{AcceptLimiter, Limiter, RateLimit};
+
+    #[test]
+    fn bursts_are_spread_out() {
+        let mut limiter = AcceptLimiter::new();
+        let total = Some(RateLimit::new(10, 3));
+        let per_ip = Some(RateLimit::new(1, 2));
+        let (a, b) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let start = Instant::now();
+
+        assert!(limiter.admit(a, start, total, per_ip));
+        assert!(limiter.admit(a, start, total, per_ip));
+        // a is out of tokens, the total isn't
+        assert!(!limiter.admit(a, start, total, per_ip));
+        assert!(limiter.admit(b, start, total, per_ip));
+        // the total is out of tokens
+        assert!(!limiter.admit(b, start, total, per_ip));
+        assert_eq!(limiter.refused, 2);
+
+        // 100ms is a token for the total, a needs a whole second
+        let later = start + Duration::from_millis(100);
+        assert!(!limiter.admit(a, later, total, per_ip));
+        assert!(limiter.admit(b, later, total, per_ip));
+
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.admit(a, later, total, per_ip));
+        assert!(limiter.admit("10.0.0.3".parse().unwrap(), later, None, None));
+    }
+
+    #[test]
+    fn single_bucket_refills() {
+        let mut limiter = Limiter::new();
+        let limit = RateLimit::new(2, 1);
+        let start = Instant::now();
+
+        assert!(limiter.admit(start, limit));
+        assert!(!limiter.admit(start, limit));
+        assert!(limiter.admit(start + Duration::from_millis(500), limit));
+        assert_eq!(limiter.refused, 1);
+    }
+
+    #[test]
+    fn rates_are_parsed() {
+        assert_eq!("100/s 500".parse::<RateLimit>().unwrap(), RateLimit::new(100, 500));
+        assert!("100 500".parse::<RateLimit>().is_err());
+        assert!("100/s".parse::<RateLimit>().is_err());
+    }
+}
@@ -0,0 +1,163 @@
+//! Topic registry for strict mode. Only topics matching a declared template
+//! can be published or subscribed to, which keeps the topic space from
+//! sprawling and catches typos. Templates are globs over topic levels: `*`
+//! stands for one level and `**` for any number of trailing levels.
+//! `{client_id}` and `{username}` are replaced with the client's own. A
+//! template is either for everyone or for one role, and usernames are
+//! assigned roles in the registry
+//!
+//! Registry files have one entry per line:
+//!
+//! ```text
+//! # everyone
+//! topic * devices/{client_id}/telemetry/**
+//! # backends subscribe to all the telemetry
+//! topic backend devices/*/telemetry/**
+//! role analytics-1 backend
+//! ```
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use error::{Error, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopicTemplate {
+    /// Role the template is for. `None` for everyone
+    pub role: Option<String>,
+    pub pattern: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TopicRegistry {
+    templates: Vec<TopicTemplate>,
+    /// Username -> role
+    roles: HashMap<String, String>,
+}
+
+/// True if the levels of `topic` match the template levels in `pattern`
+fn glob(pattern: &[&str], topic: &[&str]) -> bool {
+    match (pattern.first(), topic.first()) {
+        (Some(&"**"), _) => true,
+        (Some(&"*"), Some(_)) => glob(&pattern[1..], &topic[1..]),
+        (Some(p), Some(t)) if p == t => glob(&pattern[1..], &topic[1..]),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+impl TopicRegistry {
+    pub fn new() -> Self {
+        TopicRegistry::default()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut text = String::new();
+        File::open(path.as_ref())?.read_to_string(&mut text)?;
+        TopicRegistry::parse(&text).map_err(|e| Error::Config(format!("{}: {}", path.as_ref().display(), e)))
+    }
+
+    /// Parses `topic <role|*> <template>` and `role <username> <role>` lines.
+    /// Empty lines and `#` comments are skipped
+    pub fn parse(text: &str) -> ::std::result::Result<Self, String> {
+        let mut registry = TopicRegistry::new();
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["topic", "*", pattern] => registry.declare(None, pattern),
+                ["topic", role, pattern] => registry.declare(Some(role), pattern),
+                ["role", username, role] => registry.assign(username, role),
+                _ => return Err(format!("invalid entry on line {}", n + 1)),
+            }
+        }
+
+        Ok(registry)
+    }
+
+    /// Declares the topics matching `pattern` for `role`, or for everyone
+    pub fn declare(&mut self, role: Option<&str>, pattern: &str) {
+        let template = TopicTemplate {
+            role: role.map(|r| r.to_owned()),
+            pattern: pattern.to_owned(),
+        };
+
+        if !self.templates.contains(&template) {
+            self.templates.push(template);
+        }
+    }
+
+    /// Removes a declared template. Returns false if it wasn't declared
+    pub fn retract(&mut self, role: Option<&str>, pattern: &str) -> bool {
+        let before = self.templates.len();
+        self.templates
+            .retain(|t| !(t.role.as_ref().map(|r| r.as_str()) == role && t.pattern == pattern));
+        self.templates.len() != before
+    }
+
+    pub fn assign(&mut self, username: &str, role: &str) {
+        self.roles.insert(username.to_owned(), role.to_owned());
+    }
+
+    pub fn templates(&self) -> &[TopicTemplate] {
+        &self.templates
+    }
+
+    /// True if `topic` is declared for the client
+    pub fn allows(&self, topic: &str, client_id: &str, username: Option<&str>) -> bool {
+        let role = username.and_then(|u| self.roles.get(u));
+        let levels: Vec<&str> = topic.split('/').collect();
+
+        self.templates
+            .iter()
+            .filter(|t| t.role.is_none() || t.role.as_ref() == role)
+            .any(|t| {
+                let pattern = t.pattern.replace("{client_id}", client_id);
+                let pattern = match username {
+                    Some(username) => pattern.replace("{username}", username),
+                    // nothing to match templates of the username with
+                    None if pattern.contains("{username}") => return false,
+                    None => pattern,
+                };
+                let pattern: Vec<&str> = pattern.split('/').collect();
+                glob(&pattern, &levels)
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TopicRegistry;
+
+    #[test]
+    fn templates_match_per_role() {
+        let registry = TopicRegistry::parse("# devices\n\
+                                             topic * devices/{client_id}/telemetry/**\n\
+                                             topic * users/{username}/inbox\n\
+                                             topic backend devices/*/telemetry/**\n\
+                                             role analytics-1 backend\n")
+                .unwrap();
+
+        assert!(registry.allows("devices/d7/telemetry/cpu", "d7", None));
+        assert!(registry.allows("devices/d7/telemetry", "d7", None));
+        assert!(!registry.allows("devices/d8/telemetry/cpu", "d7", None));
+        // typo
+        assert!(!registry.allows("devices/d7/telemtry/cpu", "d7", None));
+
+        assert!(registry.allows("users/alice/inbox", "d7", Some("alice")));
+        assert!(!registry.allows("users/alice/inbox", "d7", Some("bob")));
+        assert!(!registry.allows("users/{username}/inbox", "d7", None));
+
+        assert!(registry.allows("devices/d8/telemetry/cpu", "a1", Some("analytics-1")));
+        assert!(!registry.allows("devices/d8/telemetry/cpu", "a2", Some("analytics-2")));
+
+        assert!(TopicRegistry::parse("topic devices/**").is_err());
+    }
+}
@@ -0,0 +1,171 @@
+//! Configuration reloads. Log level, limits, credentials and access rules
+//! follow a reloaded configuration without restarting the broker or
+//! dropping connected clients. Limits of connections apply to the ones
+//! accepted after the reload. Listeners, storage and the other settings
+//! that shape the broker itself keep their value until the next restart
+
+use std::result;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use slog::{Drain, Level, OwnedKVList, Record};
+
+use config::{self, BrokerConfig, SettingChange};
+
+/// Settings a reload applies
+pub const RELOADABLE: &[&str] = &["log_level",
+                                  "max_packet_size",
+                                  "outgoing_queue_size",
+                                  "outgoing_overflow",
+                                  "offline_message_ttl",
+                                  "connect_rate",
+                                  "connect_rate_per_ip",
+                                  "max_client_id_len",
+                                  "allow_mqtt31",
+                                  "max_inflight",
+                                  "max_retransmits",
+                                  "max_qos",
+                                  "backlog_drain",
+                                  "shutdown_timeout",
+                                  "dump_dir",
+                                  "retain_as_published",
+                                  "share_policy",
+                                  "password_file",
+                                  "webhook",
+                                  "jwt",
+                                  "acl",
+                                  "unauthorized_publish",
+                                  "allow_anonymous",
+                                  "anonymous_role"];
+
+/// Outcome of a reload
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reload {
+    /// Settings that took their new value
+    pub applied: Vec<SettingChange>,
+    /// Changed settings that only a restart applies
+    pub needs_restart: Vec<&'static str>,
+}
+
+/// `current` with the reloadable settings of `new`
+pub fn merge(current: &BrokerConfig, new: BrokerConfig) -> (BrokerConfig, Reload) {
+    let needs_restart = config::diff(&current.settings(), &new.settings())
+        .into_iter()
+        .map(|change| change.name)
+        .filter(|name| !RELOADABLE.contains(name))
+        .collect();
+
+    let mut merged = current.clone();
+    merged.log_level = new.log_level;
+    merged.max_packet_size = new.max_packet_size;
+    merged.outgoing_queue_size = new.outgoing_queue_size;
+    merged.outgoing_overflow = new.outgoing_overflow;
+    merged.offline_message_ttl = new.offline_message_ttl;
+    merged.connect_rate = new.connect_rate;
+    merged.connect_rate_per_ip = new.connect_rate_per_ip;
+    merged.max_client_id_len = new.max_client_id_len;
+    merged.allow_mqtt31 = new.allow_mqtt31;
+    merged.max_inflight = new.max_inflight;
+    merged.max_retransmits = new.max_retransmits;
+    merged.max_qos = new.max_qos;
+    merged.backlog_drain = new.backlog_drain;
+    merged.shutdown_timeout = new.shutdown_timeout;
+    merged.dump_dir = new.dump_dir;
+    merged.retain_as_published = new.retain_as_published;
+    merged.share_policy = new.share_policy;
+    merged.password_file = new.password_file;
+    #[cfg(feature = "webhook-auth")]
+    {
+        merged.webhook = new.webhook;
+    }
+    #[cfg(feature = "jwt")]
+    {
+        merged.jwt = new.jwt;
+    }
+    merged.acl = new.acl;
+    merged.unauthorized_publish = new.unauthorized_publish;
+    merged.allow_anonymous = new.allow_anonymous;
+    merged.anonymous_role = new.anonymous_role;
+
+    let reload = Reload {
+        applied: config::diff(&current.settings(), &merged.settings()),
+        needs_restart: needs_restart,
+    };
+    (merged, reload)
+}
+
+/// Log level that can be changed while loggers use it
+#[derive(Debug, Clone)]
+pub struct LevelSwitch(Arc<AtomicUsize>);
+
+impl LevelSwitch {
+    pub fn new(level: Level) -> Self {
+        LevelSwitch(Arc::new(AtomicUsize::new(level.as_usize())))
+    }
+
+    pub fn set(&self, level: Level) {
+        self.0.store(level.as_usize(), Ordering::Relaxed);
+    }
+
+    pub fn level(&self) -> Level {
+        Level::from_usize(self.0.load(Ordering::Relaxed)).unwrap_or(Level::Info)
+    }
+
+    /// `drain` without the records below the switch's level
+    pub fn filter<D: Drain>(&self, drain: D) -> SwitchedLevel<D> {
+        SwitchedLevel {
+            drain: drain,
+            switch: self.clone(),
+        }
+    }
+}
+
+/// Drain filtered by a `LevelSwitch`
+#[derive(Debug)]
+pub struct SwitchedLevel<D> {
+    drain: D,
+    switch: LevelSwitch,
+}
+
+impl<D: Drain> Drain for SwitchedLevel<D> {
+    type Ok = Option<D::Ok>;
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> result::Result<Self::Ok, Self::Err> {
+        if record.level().is_at_least(self.switch.level()) {
+            self.drain.log(record, values).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use slog::Level;
+    use listener::{ListenerConfig, ListenerKind};
+    use config::BrokerConfig;
+    use super::{merge, LevelSwitch};
+
+    #[test]
+    fn reloads_apply_the_reloadable_settings_only() {
+        let current = BrokerConfig::default();
+        let mut new = BrokerConfig::default();
+        new.log_level = Level::Debug;
+        new.max_inflight = Some(5);
+        new.listeners = vec![ListenerConfig::new("0.0.0.0:1884".parse().unwrap(), ListenerKind::Tcp)];
+
+        let (merged, reload) = merge(&current, new);
+        assert_eq!(merged.log_level, Level::Debug);
+        assert_eq!(merged.max_inflight, Some(5));
+        assert_eq!(merged.listeners[0].address, current.listeners[0].address);
+
+        let applied: Vec<&str> = reload.applied.iter().map(|change| change.name).collect();
+        assert_eq!(applied, vec!["log_level", "max_inflight"]);
+        assert_eq!(reload.needs_restart, vec!["listeners"]);
+
+        let switch = LevelSwitch::new(Level::Info);
+        switch.clone().set(Level::Warning);
+        assert_eq!(switch.level(), Level::Warning);
+    }
+}
@@ -0,0 +1,213 @@
+//! End to end canary. Every interval a probe is published to
+//! `$SYS/selftest` and received back by an internal subscriber going through
+//! the same routing as every other client. Round trips slower than the SLO
+//! and probes that never come back raise an alert
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use futures::sync::mpsc::Receiver;
+use mqtt3::*;
+use slog::Logger;
+use tokio_io::codec::Decoder;
+
+use broker::Broker;
+use client::{self, Client};
+use codec::{Frame, MqttCodec};
+
+pub const SELFTEST_TOPIC: &str = "$SYS/selftest";
+pub const SELFTEST_CLIENT_ID: &str = "$selftest";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SelfTestConfig {
+    /// Time between probes. A probe that isn't back by the next one is lost
+    pub interval: Duration,
+    /// Slowest acceptable round trip
+    pub slo: Duration,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SelfTestStats {
+    pub probes: u64,
+    /// Probes that came back slower than the SLO or not at all
+    pub breaches: u64,
+    pub lost: u64,
+    pub last_round_trip: Option<Duration>,
+    pub max_round_trip: Option<Duration>,
+}
+
+pub struct SelfTest {
+    broker: Broker,
+    client: Client,
+    config: SelfTestConfig,
+    /// Sequence number and send time of the probe on its way
+    outstanding: Option<(u64, Instant)>,
+    next_seq: u64,
+    stats: SelfTestStats,
+    logger: Logger,
+}
+
+fn encode_seq(seq: u64) -> Vec<u8> {
+    (0..8).map(|i| (seq >> (56 - 8 * i)) as u8).collect()
+}
+
+fn decode_seq(payload: &[u8]) -> Option<u64> {
+    if payload.len() != 8 {
+        return None;
+    }
+
+    Some(payload.iter().fold(0, |seq, &b| seq << 8 | b as u64))
+}
+
+impl SelfTest {
+    /// Connects the internal subscriber. Frames from the returned receiver
+    /// have to be passed to `receive`
+    pub fn new(broker: Broker, config: SelfTestConfig) -> (SelfTest, Receiver<Frame>) {
+        let logger = broker.logger();
+        let (tx, rx) = client::outgoing_queue(broker.config().outgoing_queue_size);
+        let client = Client::with_clock(SELFTEST_CLIENT_ID, SocketAddr::from(([127, 0, 0, 1], 0)), tx, broker.clock());
+        broker.connect(client.clone());
+
+        let subscribe = Box::new(Subscribe {
+                                     pid: PacketIdentifier(1),
+                                     topics: vec![SubscribeTopic {
+                                                      topic_path: SELFTEST_TOPIC.to_owned(),
+                                                      qos: QoS::AtMostOnce,
+                                                  }],
+                                 });
+        broker.handle_subscribe(subscribe, &client);
+
+        let selftest = SelfTest {
+            broker: broker,
+            client: client,
+            config: config,
+            outstanding: None,
+            next_seq: 0,
+            stats: SelfTestStats::default(),
+            logger: logger,
+        };
+
+        (selftest, rx)
+    }
+
+    /// Publishes the next probe. The previous one counts as lost if it isn't back yet
+    pub fn probe(&mut self) {
+        if let Some((seq, _)) = self.outstanding.take() {
+            warn!(self.logger, "Self test probe lost. Seq = {}", seq);
+            self.stats.lost += 1;
+            self.stats.breaches += 1;
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.stats.probes += 1;
+        self.outstanding = Some((seq, self.broker.clock().now()));
+
+        let publish = Box::new(Publish {
+                                   dup: false,
+                                   qos: QoS::AtMostOnce,
+                                   retain: false,
+                                   pid: None,
+                                   topic_name: SELFTEST_TOPIC.to_owned(),
+                                   payload: Arc::new(encode_seq(seq)),
+                               });
+        self.broker.handle_publish(publish, &self.client);
+    }
+
+    /// Handles a frame delivered to the internal subscriber. Returns the round
+    /// trip when it's the outstanding probe
+    pub fn receive(&mut self, frame: Frame) -> Option<Duration> {
+        let packet = match frame {
+            Frame::Packet(packet) => packet,
+            Frame::Encoded(bytes) => {
+                let mut buf = BytesMut::from(&bytes[..]);
+                match MqttCodec::new(bytes.len()).decode(&mut buf) {
+                    Ok(Some(packet)) => packet,
+                    _ => return None,
+                }
+            }
+            Frame::Probe => return None,
+        };
+
+        let seq = match packet {
+            Packet::Publish(ref publish) if publish.topic_name == SELFTEST_TOPIC => decode_seq(&publish.payload),
+            _ => None,
+        };
+
+        let sent_at = match (seq, self.outstanding) {
+            (Some(seq), Some((outstanding, sent_at))) if seq == outstanding => sent_at,
+            _ => return None,
+        };
+
+        self.outstanding = None;
+        let round_trip = self.broker.clock().now().duration_since(sent_at);
+        self.stats.last_round_trip = Some(round_trip);
+        if self.stats.max_round_trip.map_or(true, |max| round_trip > max) {
+            self.stats.max_round_trip = Some(round_trip);
+        }
+
+        if round_trip > self.config.slo {
+            warn!(self.logger, "Self test round trip over SLO. Round trip = {:?}, SLO = {:?}", round_trip, self.config.slo);
+            self.stats.breaches += 1;
+        }
+
+        Some(round_trip)
+    }
+
+    pub fn stats(&self) -> SelfTestStats {
+        self.stats.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+    use std::time::Duration;
+    use futures::Stream;
+    use broker::Broker;
+    use clock::ManualClock;
+    use config::BrokerConfig;
+    use super::{decode_seq, encode_seq, SelfTest, SelfTestConfig};
+
+    #[test]
+    fn probes_make_the_round_trip() {
+        assert_eq!(decode_seq(&encode_seq(0x0102_0304_0506_0708)), Some(0x0102_0304_0506_0708));
+
+        let config = SelfTestConfig {
+            interval: Duration::from_secs(1),
+            slo: Duration::from_secs(10),
+        };
+        let clock = ManualClock::new();
+        let broker = Broker::with_clock(BrokerConfig::default(), Rc::new(clock.clone()));
+        let (mut selftest, rx) = SelfTest::new(broker, config);
+        let mut rx = rx.wait();
+
+        selftest.probe();
+        // connack and suback come first
+        let round_trips: Vec<_> = rx.by_ref()
+            .take(3)
+            .filter_map(|f| selftest.receive(f.unwrap()))
+            .collect();
+        assert_eq!(round_trips.len(), 1);
+
+        let stats = selftest.stats();
+        assert_eq!(stats.probes, 1);
+        assert_eq!(stats.breaches, 0);
+        assert!(stats.last_round_trip.is_some());
+
+        // nothing came back for this one
+        selftest.probe();
+        selftest.probe();
+        let stats = selftest.stats();
+        assert_eq!(stats.lost, 1);
+        assert_eq!(stats.breaches, 1);
+
+        // skip the probe that was counted as lost. the next one comes back over the SLO
+        rx.next();
+        clock.advance(Duration::from_secs(11));
+        assert_eq!(selftest.receive(rx.next().unwrap().unwrap()), Some(Duration::from_secs(11)));
+        assert_eq!(selftest.stats().breaches, 2);
+    }
+}
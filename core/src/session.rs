@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Instant;
+
+use clock::Clock;
+use disconnect::DisconnectReason;
+
+/// Cumulative statistics of a client id. Kept across reconnects so devices
+/// that regularly lose data can be found
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionStats {
+    pub connects: u64,
+    /// Connections that replaced a still registered connection with the same id
+    pub takeovers: u64,
+    /// Publishes received from the client. Clients of a leaf node are counted
+    /// under `<leaf id>/<client id>` at the hub
+    pub published: u64,
+    /// Publishes dropped because the topic isn't declared in the topic registry
+    pub rejected: u64,
+    /// Publishes handed to the client's connection
+    pub delivered: u64,
+    /// Publishes lost because the connection failed or went away before
+    /// acknowledging them
+    pub lost: u64,
+    /// Publishes for the session that arrived while it was offline and
+    /// didn't fit in its queue
+    pub missed_offline: u64,
+    /// Publishes queued for the session while it was offline
+    pub queued_offline: u64,
+    /// Queued publishes dropped for sitting in the offline queue past the
+    /// message ttl
+    pub expired_offline: u64,
+    /// Publishes dropped because the session already held
+    /// `max_queued_messages` messages or `max_queued_bytes` bytes
+    pub over_limit: u64,
+    /// Queued publishes delivered after the client came back
+    pub delivered_after_resume: u64,
+    pub last_connect: Instant,
+    pub last_disconnect: Option<Instant>,
+    pub last_disconnect_reason: Option<DisconnectReason>,
+}
+
+impl SessionStats {
+    fn new(now: Instant) -> Self {
+        SessionStats {
+            connects: 0,
+            takeovers: 0,
+            published: 0,
+            rejected: 0,
+            delivered: 0,
+            lost: 0,
+            missed_offline: 0,
+            queued_offline: 0,
+            expired_offline: 0,
+            over_limit: 0,
+            delivered_after_resume: 0,
+            last_connect: now,
+            last_disconnect: None,
+            last_disconnect_reason: None,
+        }
+    }
+}
+
+/// Session statistics of every client id seen since the broker started
+#[derive(Debug)]
+pub struct SessionTable {
+    sessions: HashMap<String, SessionStats>,
+    /// Connections ended per reason
+    disconnects: HashMap<DisconnectReason, u64>,
+    clock: Rc<Clock>,
+}
+
+impl SessionTable {
+    pub fn new(clock: Rc<Clock>) -> Self {
+        SessionTable {
+            sessions: HashMap::new(),
+            disconnects: HashMap::new(),
+            clock: clock,
+        }
+    }
+
+    /// Statistics of `id`, created on first use
+    pub fn stats_mut(&mut self, id: &str) -> &mut SessionStats {
+        if !self.sessions.contains_key(id) {
+            let stats = SessionStats::new(self.clock.now());
+            self.sessions.insert(id.to_owned(), stats);
+        }
+
+        self.sessions.get_mut(id).unwrap()
+    }
+
+    pub fn connected(&mut self, id: &str, takeover: bool) {
+        let now = self.clock.now();
+        let stats = self.stats_mut(id);
+        stats.connects += 1;
+        stats.last_connect = now;
+        if takeover {
+            stats.takeovers += 1;
+        }
+    }
+
+    /// Records the end of a connection for `reason` with `lost` publishes
+    /// still unacknowledged
+    pub fn disconnected(&mut self, id: &str, lost: u64, reason: DisconnectReason) {
+        let now = self.clock.now();
+        *self.disconnects.entry(reason).or_insert(0) += 1;
+        let stats = self.stats_mut(id);
+        stats.lost += lost;
+        stats.last_disconnect = Some(now);
+        stats.last_disconnect_reason = Some(reason);
+    }
+
+    /// Connections ended per reason, by reason name
+    pub fn disconnects(&self) -> Vec<(DisconnectReason, u64)> {
+        let mut disconnects: Vec<(DisconnectReason, u64)> = self.disconnects.iter().map(|(&reason, &n)| (reason, n)).collect();
+        disconnects.sort_by_key(|&(reason, _)| reason.name());
+        disconnects
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    pub fn get(&self, id: &str) -> Option<SessionStats> {
+        self.sessions.get(id).cloned()
+    }
+
+    /// Sessions sorted by the number of lost and missed publishes, worst first
+    pub fn list(&self) -> Vec<(String, SessionStats)> {
+        let mut sessions: Vec<_> = self.sessions.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        sessions.sort_by(|a, b| (b.1.lost + b.1.missed_offline).cmp(&(a.1.lost + a.1.missed_offline)).then(a.0.cmp(&b.0)));
+        sessions
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+    use std::time::Duration;
+    use clock::ManualClock;
+    use disconnect::DisconnectReason;
+    use super::SessionTable;
+
+    #[test]
+    fn stats_accumulate_across_connections() {
+        let clock = ManualClock::new();
+        let mut sessions = SessionTable::new(Rc::new(clock.clone()));
+
+        sessions.connected("device-1", false);
+        sessions.stats_mut("device-1").delivered += 3;
+        sessions.disconnected("device-1", 2, DisconnectReason::KeepAliveTimeout);
+        clock.advance(Duration::from_secs(5));
+        sessions.connected("device-1", false);
+        sessions.connected("device-1", true);
+        sessions.connected("device-2", false);
+
+        let stats = sessions.get("device-1").unwrap();
+        assert_eq!(stats.connects, 3);
+        assert_eq!(stats.takeovers, 1);
+        assert_eq!(stats.delivered, 3);
+        assert_eq!(stats.lost, 2);
+        assert_eq!(stats.last_connect - stats.last_disconnect.unwrap(), Duration::from_secs(5));
+        assert_eq!(stats.last_disconnect_reason, Some(DisconnectReason::KeepAliveTimeout));
+        assert_eq!(sessions.disconnects(), vec![(DisconnectReason::KeepAliveTimeout, 1)]);
+
+        let ids: Vec<String> = sessions.list().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec!["device-1".to_owned(), "device-2".to_owned()]);
+    }
+}
@@ -0,0 +1,186 @@
+//! Shared subscriptions. Clients subscribing to `$share/<group>/<filter>`
+//! split the publishes on `<filter>` among themselves: each publish goes to
+//! one member of the group instead of to all of them. Groups are separate
+//! per filter, so `$share/g/a` and `$share/g/b` are two groups. Members are
+//! picked round robin or by the fewest frames waiting on their connection.
+//! Shared subscriptions end with the connection
+
+use mqtt3::QoS;
+
+use acl;
+use client::Client;
+
+/// Filter prefix of shared subscriptions
+pub const SHARE_PREFIX: &str = "$share/";
+
+/// How the member that gets a publish is picked
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SharePolicy {
+    /// Members take turns
+    RoundRobin,
+    /// The member with the fewest frames waiting to be written
+    LeastLoaded,
+}
+
+/// True if `filter` asks for a shared subscription, valid or not
+pub fn is_shared(filter: &str) -> bool {
+    filter.starts_with(SHARE_PREFIX)
+}
+
+/// Group and filter of a shared subscription. `None` for ordinary filters
+/// and for a missing group or filter or a group name with wildcards
+pub fn parse(filter: &str) -> Option<(&str, &str)> {
+    if !is_shared(filter) {
+        return None;
+    }
+
+    let rest = &filter[SHARE_PREFIX.len()..];
+    let split = rest.find('/')?;
+    let (group, filter) = (&rest[..split], &rest[split + 1..]);
+    if group.is_empty() || group.contains(|c| c == '+' || c == '#') || filter.is_empty() {
+        return None;
+    }
+    Some((group, filter))
+}
+
+#[derive(Debug)]
+struct ShareGroup {
+    name: String,
+    filter: String,
+    members: Vec<(Client, QoS)>,
+    /// Member whose turn is next
+    next: usize,
+}
+
+impl ShareGroup {
+    /// Member that gets the next publish. Ties go to the member whose turn is next
+    fn pick(&mut self, policy: SharePolicy) -> Option<(Client, QoS)> {
+        let len = self.members.len();
+        let mut live = (0..len).map(|i| (self.next + i) % len).filter(|&i| !self.members[i].0.is_dead());
+        let picked = match policy {
+            SharePolicy::RoundRobin => live.next(),
+            SharePolicy::LeastLoaded => live.min_by_key(|&i| self.members[i].0.backlog().depth),
+        }?;
+
+        self.next = (picked + 1) % len;
+        Some(self.members[picked].clone())
+    }
+}
+
+/// Shared subscription groups with their members
+#[derive(Debug)]
+pub struct SharedSubscriptions {
+    groups: Vec<ShareGroup>,
+}
+
+impl SharedSubscriptions {
+    pub fn new() -> Self {
+        SharedSubscriptions { groups: Vec::new() }
+    }
+
+    /// Number of members over all the groups
+    pub fn len(&self) -> usize {
+        self.groups.iter().map(|g| g.members.len()).sum()
+    }
+
+    /// Adds `client` to the group `name` on `filter`. Subscribing again only
+    /// updates the qos
+    pub fn subscribe(&mut self, name: &str, filter: &str, client: Client, qos: QoS) {
+        let index = match self.groups.iter().position(|g| g.name == name && g.filter == filter) {
+            Some(index) => index,
+            None => {
+                self.groups.push(ShareGroup {
+                                     name: name.to_owned(),
+                                     filter: filter.to_owned(),
+                                     members: Vec::new(),
+                                     next: 0,
+                                 });
+                self.groups.len() - 1
+            }
+        };
+
+        let members = &mut self.groups[index].members;
+        match members.iter().position(|m| m.0.id == client.id) {
+            Some(index) => members[index] = (client, qos),
+            None => members.push((client, qos)),
+        }
+    }
+
+    pub fn unsubscribe(&mut self, name: &str, filter: &str, id: &str) {
+        for group in self.groups.iter_mut().filter(|g| g.name == name && g.filter == filter) {
+            group.members.retain(|m| m.0.id != id);
+        }
+        self.prune();
+    }
+
+    /// Takes a disconnected client out of all its groups
+    pub fn remove_client(&mut self, id: &str) {
+        for group in self.groups.iter_mut() {
+            group.members.retain(|m| m.0.id != id);
+        }
+        self.prune();
+    }
+
+    fn prune(&mut self) {
+        self.groups.retain(|g| !g.members.is_empty());
+        for group in self.groups.iter_mut() {
+            group.next %= group.members.len();
+        }
+    }
+
+    /// Appends one member of every group matching `topic` to `deliveries`
+    pub fn extend_deliveries(&mut self, topic: &str, policy: SharePolicy, deliveries: &mut Vec<(Client, QoS)>) {
+        for group in self.groups.iter_mut().filter(|g| acl::matches_published(&g.filter, topic)) {
+            if let Some(member) = group.pick(policy) {
+                deliveries.push(member);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::sync::mpsc;
+    use mqtt3::QoS;
+    use client::Client;
+    use codec::Frame;
+    use super::{parse, SharedSubscriptions, SharePolicy};
+
+    fn picked(shared: &mut SharedSubscriptions, topic: &str, policy: SharePolicy) -> Vec<String> {
+        let mut deliveries = Vec::new();
+        shared.extend_deliveries(topic, policy, &mut deliveries);
+        deliveries.into_iter().map(|(client, _)| client.id).collect()
+    }
+
+    #[test]
+    fn filters_are_parsed() {
+        assert_eq!(parse("$share/workers/jobs/+"), Some(("workers", "jobs/+")));
+        assert_eq!(parse("$share/workers"), None);
+        assert_eq!(parse("$share//jobs"), None);
+        assert_eq!(parse("$share/+/jobs"), None);
+        assert_eq!(parse("jobs/+"), None);
+    }
+
+    #[test]
+    fn members_take_turns() {
+        let mut shared = SharedSubscriptions::new();
+        let mut receivers = Vec::new();
+        for id in &["a", "b", "c"] {
+            let (tx, rx) = mpsc::channel::<Frame>(8);
+            receivers.push(rx);
+            shared.subscribe("workers", "jobs/+", Client::new(id, "127.0.0.1:80".parse().unwrap(), tx), QoS::AtLeastOnce);
+        }
+        let (tx, rx) = mpsc::channel::<Frame>(8);
+        receivers.push(rx);
+        shared.subscribe("audit", "jobs/#", Client::new("d", "127.0.0.1:80".parse().unwrap(), tx), QoS::AtMostOnce);
+
+        assert_eq!(picked(&mut shared, "jobs/1", SharePolicy::RoundRobin), vec!["a", "d"]);
+        assert_eq!(picked(&mut shared, "jobs/2", SharePolicy::RoundRobin), vec!["b", "d"]);
+        shared.remove_client("c");
+        assert_eq!(picked(&mut shared, "jobs/3", SharePolicy::RoundRobin), vec!["a", "d"]);
+        assert_eq!(picked(&mut shared, "other", SharePolicy::RoundRobin), Vec::<String>::new());
+
+        shared.unsubscribe("audit", "jobs/#", "d");
+        assert_eq!(shared.len(), 2);
+    }
+}
@@ -0,0 +1,388 @@
+//! Virtual clients inside the broker process. Groups of simulated clients
+//! subscribe, publish on a schedule and can misbehave in the ways real
+//! devices do, which makes demos, tests of new features and user reported
+//! scenarios reproducible without external tools. Like every other client
+//! they go through routing, acks and queue limits. Each `tick` reads the
+//! clients' queues, answers what needs answering and publishes what's due
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use futures::executor::{self, Notify, Spawn};
+use futures::sync::mpsc::Receiver;
+use futures::Async;
+use mqtt3::*;
+use tokio_io::codec::Decoder;
+
+use broker::Broker;
+use client::{self, Client};
+use codec::{Frame, MqttCodec};
+
+/// How a simulated client deviates from a well behaved one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Behavior {
+    /// Reads everything and acknowledges every delivery
+    Polite,
+    /// Never acknowledges deliveries, so they stay inflight
+    NeverAcks,
+    /// Reads its queue only every `n`th tick
+    SlowReader(u32),
+    /// Drops its connection without a DISCONNECT and reconnects every `n`th tick
+    Flapping(u32),
+    /// Sends every publish with packet id 1, whether the last one is acknowledged or not
+    ReusesPkids,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublishPattern {
+    /// Topic of the publishes. `{id}` is replaced with the client id and
+    /// `{seq}` with the number of the publish
+    pub topic: String,
+    pub qos: QoS,
+    pub payload_size: usize,
+    /// Publishing every `every`th tick
+    pub every: u32,
+    /// Publishes each time
+    pub burst: usize,
+}
+
+impl PublishPattern {
+    /// One 64 byte publish every tick
+    pub fn new(topic: &str, qos: QoS) -> Self {
+        PublishPattern {
+            topic: topic.to_owned(),
+            qos: qos,
+            payload_size: 64,
+            every: 1,
+            burst: 1,
+        }
+    }
+}
+
+/// `count` clients with the same subscriptions, publishes and behavior
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimGroup {
+    /// Client ids are the prefix followed by the number of the client
+    pub id_prefix: String,
+    pub count: usize,
+    pub subscriptions: Vec<(String, QoS)>,
+    pub publish: Option<PublishPattern>,
+    pub behavior: Behavior,
+}
+
+impl SimGroup {
+    pub fn new(id_prefix: &str, count: usize) -> Self {
+        SimGroup {
+            id_prefix: id_prefix.to_owned(),
+            count: count,
+            subscriptions: Vec::new(),
+            publish: None,
+            behavior: Behavior::Polite,
+        }
+    }
+
+    pub fn subscribe(mut self, filter: &str, qos: QoS) -> Self {
+        self.subscriptions.push((filter.to_owned(), qos));
+        self
+    }
+
+    pub fn publish(mut self, pattern: PublishPattern) -> Self {
+        self.publish = Some(pattern);
+        self
+    }
+
+    pub fn behavior(mut self, behavior: Behavior) -> Self {
+        self.behavior = behavior;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimConfig {
+    /// Time between ticks when the broker drives the simulation
+    pub tick: Duration,
+    pub groups: Vec<SimGroup>,
+}
+
+impl SimConfig {
+    pub fn new(tick: Duration) -> Self {
+        SimConfig {
+            tick: tick,
+            groups: Vec::new(),
+        }
+    }
+
+    pub fn group(mut self, group: SimGroup) -> Self {
+        self.groups.push(group);
+        self
+    }
+
+    pub fn clients(&self) -> usize {
+        self.groups.iter().map(|g| g.count).sum()
+    }
+
+    /// Reason the simulation can't run, if any
+    pub fn validate(&self) -> ::std::result::Result<(), String> {
+        if self.tick == Duration::from_secs(0) {
+            return Err("simulation tick can't be 0".to_owned());
+        }
+
+        for group in self.groups.iter() {
+            if group.id_prefix.is_empty() || group.count == 0 {
+                return Err("simulated groups need an id prefix and at least one client".to_owned());
+            }
+            if group.publish.as_ref().map_or(false, |p| p.every == 0 || p.burst == 0) {
+                return Err(format!("publish interval and burst of {:?} can't be 0", group.id_prefix));
+            }
+            match group.behavior {
+                Behavior::SlowReader(0) | Behavior::Flapping(0) => return Err(format!("behavior interval of {:?} can't be 0", group.id_prefix)),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SimStats {
+    pub ticks: u64,
+    /// Publishes sent by simulated clients
+    pub published: u64,
+    /// Publishes delivered to simulated clients
+    pub received: u64,
+    /// Deliveries acknowledged by simulated clients
+    pub acknowledged: u64,
+    pub reconnects: u64,
+}
+
+/// Lets queues be polled without a task. Ticks poll again anyway
+struct Noop;
+
+impl Notify for Noop {
+    fn notify(&self, _: usize) {}
+}
+
+struct SimClient {
+    group: usize,
+    id: String,
+    client: Client,
+    rx: Spawn<Receiver<Frame>>,
+    last_pkid: u16,
+    seq: u64,
+}
+
+fn packet(frame: Frame) -> Option<Packet> {
+    match frame {
+        Frame::Packet(packet) => Some(packet),
+        Frame::Encoded(bytes) => {
+            let mut buf = BytesMut::from(&bytes[..]);
+            MqttCodec::new(bytes.len()).decode(&mut buf).ok().and_then(|packet| packet)
+        }
+        Frame::Probe => None,
+    }
+}
+
+pub struct Simulation {
+    broker: Broker,
+    config: SimConfig,
+    clients: Vec<SimClient>,
+    notify: Arc<Noop>,
+    stats: SimStats,
+}
+
+impl Simulation {
+    /// Connects every simulated client and makes its subscriptions
+    pub fn new(broker: Broker, config: SimConfig) -> Simulation {
+        let mut simulation = Simulation {
+            broker: broker,
+            config: config,
+            clients: Vec::new(),
+            notify: Arc::new(Noop),
+            stats: SimStats::default(),
+        };
+
+        let ids: Vec<(usize, String)> = simulation
+            .config
+            .groups
+            .iter()
+            .enumerate()
+            .flat_map(|(group, g)| (0..g.count).map(move |n| (group, format!("{}{}", g.id_prefix, n))))
+            .collect();
+        for (group, id) in ids {
+            let client = simulation.connect(group, id);
+            simulation.clients.push(client);
+        }
+
+        simulation
+    }
+
+    fn connect(&self, group: usize, id: String) -> SimClient {
+        let (tx, rx) = client::outgoing_queue(self.broker.config().outgoing_queue_size);
+        let client = Client::with_clock(&id, SocketAddr::from(([127, 0, 0, 1], 0)), tx, self.broker.clock());
+        self.broker.connect(client.clone());
+
+        let subscriptions = &self.config.groups[group].subscriptions;
+        if !subscriptions.is_empty() {
+            let topics = subscriptions
+                .iter()
+                .map(|&(ref filter, qos)| {
+                         SubscribeTopic {
+                             topic_path: filter.clone(),
+                             qos: qos,
+                         }
+                     })
+                .collect();
+            let subscribe = Box::new(Subscribe {
+                                         pid: PacketIdentifier(1),
+                                         topics: topics,
+                                     });
+            self.broker.handle_subscribe(subscribe, &client);
+        }
+
+        SimClient {
+            group: group,
+            id: id,
+            client: client,
+            rx: executor::spawn(rx),
+            last_pkid: 0,
+            seq: 0,
+        }
+    }
+
+    /// Reads queues, answers deliveries and sends the publishes that are due
+    pub fn tick(&mut self) {
+        self.stats.ticks += 1;
+        let ticks = self.stats.ticks;
+
+        for i in 0..self.clients.len() {
+            let group = self.config.groups[self.clients[i].group].clone();
+
+            if let Behavior::Flapping(every) = group.behavior {
+                if ticks % u64::from(every) == 0 {
+                    self.broker.handle_network_disconnect(&self.clients[i].client);
+                    let id = self.clients[i].id.clone();
+                    let reconnected = self.connect(self.clients[i].group, id);
+                    self.clients[i] = reconnected;
+                    self.stats.reconnects += 1;
+                }
+            }
+
+            let reads = match group.behavior {
+                Behavior::SlowReader(every) => ticks % u64::from(every) == 0,
+                _ => true,
+            };
+            if reads {
+                self.read(i, group.behavior != Behavior::NeverAcks);
+            }
+
+            if let Some(ref pattern) = group.publish {
+                if ticks % u64::from(pattern.every) == 0 {
+                    for _ in 0..pattern.burst {
+                        self.publish(i, pattern, group.behavior == Behavior::ReusesPkids);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Takes everything off the client's queue
+    fn read(&mut self, i: usize, acks: bool) {
+        loop {
+            let frame = match self.clients[i].rx.poll_stream_notify(&self.notify, 0) {
+                Ok(Async::Ready(Some(frame))) => frame,
+                _ => return,
+            };
+
+            let client = &self.clients[i].client;
+            match packet(frame) {
+                Some(Packet::Publish(publish)) => {
+                    self.stats.received += 1;
+                    match (publish.qos, publish.pid) {
+                        (QoS::AtLeastOnce, Some(pkid)) if acks => self.broker.handle_puback(pkid, client),
+                        (QoS::ExactlyOnce, Some(pkid)) if acks => self.broker.handle_pubrec(pkid, client),
+                        _ => continue,
+                    }
+                    self.stats.acknowledged += 1;
+                }
+                Some(Packet::Pubrel(pkid)) if acks => self.broker.handle_pubcomp(pkid, client),
+                // the broker's answers to our own qos 2 publishes
+                Some(Packet::Pubrec(pkid)) => self.broker.handle_pubrel(pkid, client),
+                _ => (),
+            }
+        }
+    }
+
+    fn publish(&mut self, i: usize, pattern: &PublishPattern, reuse_pkids: bool) {
+        let sim = &mut self.clients[i];
+        sim.seq += 1;
+        let pid = match pattern.qos {
+            QoS::AtMostOnce => None,
+            _ if reuse_pkids => Some(PacketIdentifier(1)),
+            _ => {
+                sim.last_pkid = sim.last_pkid % 65_535 + 1;
+                Some(PacketIdentifier(sim.last_pkid))
+            }
+        };
+
+        let publish = Box::new(Publish {
+                                   dup: false,
+                                   qos: pattern.qos,
+                                   retain: false,
+                                   pid: pid,
+                                   topic_name: pattern.topic.replace("{id}", &sim.id).replace("{seq}", &sim.seq.to_string()),
+                                   payload: Arc::new(vec![0; pattern.payload_size]),
+                               });
+        self.broker.handle_publish(publish, &sim.client);
+        self.stats.published += 1;
+    }
+
+    pub fn stats(&self) -> SimStats {
+        self.stats.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use mqtt3::QoS;
+    use broker::Broker;
+    use super::{Behavior, PublishPattern, SimConfig, SimGroup, Simulation};
+
+    #[test]
+    fn simulated_clients_publish_subscribe_and_misbehave() {
+        let config = SimConfig::new(Duration::from_millis(100))
+            .group(SimGroup::new("sensor-", 2).publish(PublishPattern::new("sim/{id}", QoS::AtLeastOnce)))
+            .group(SimGroup::new("dashboard-", 3)
+                       .subscribe("sim/sensor-0", QoS::AtLeastOnce)
+                       .subscribe("sim/sensor-1", QoS::AtLeastOnce))
+            .group(SimGroup::new("stuck-", 1)
+                       .subscribe("sim/sensor-0", QoS::AtLeastOnce)
+                       .behavior(Behavior::NeverAcks))
+            .group(SimGroup::new("flaky-", 1).behavior(Behavior::Flapping(2)));
+        assert!(config.validate().is_ok());
+        assert_eq!(config.clients(), 7);
+        assert!(SimConfig::new(Duration::from_secs(0)).validate().is_err());
+        assert!(SimConfig::new(Duration::from_secs(1))
+                    .group(SimGroup::new("slow-", 1).behavior(Behavior::SlowReader(0)))
+                    .validate()
+                    .is_err());
+
+        let broker = Broker::new();
+        let mut simulation = Simulation::new(broker.clone(), config);
+        for _ in 0..6 {
+            simulation.tick();
+        }
+
+        let stats = simulation.stats();
+        assert_eq!(stats.ticks, 6);
+        assert_eq!(stats.published, 12);
+        assert_eq!(stats.reconnects, 3);
+        // every publish to every dashboard and the ones of sensor-0 to the stuck client
+        assert_eq!(stats.received, 3 * 12 + 6);
+        assert_eq!(stats.acknowledged, 3 * 12);
+        assert_eq!(broker.session_stats("sensor-1").unwrap().published, 6);
+        assert!(broker.get_client("flaky-0").is_some());
+    }
+}
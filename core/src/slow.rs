@@ -0,0 +1,62 @@
+//! Slow consumer detection. A connection whose outgoing queue stays at or
+//! above `depth` frames for `duration` is reported once: logged, announced on
+//! `$SYS/broker/clients/<id>/slow` with its queue depth and write stall, and
+//! closed if `disconnect` is set. The write stall is how long the connection
+//! hasn't taken anything off its queue while there was something on it
+
+use std::cmp;
+use std::time::Duration;
+
+/// Shortest interval between two checks of the outgoing queues
+pub const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SlowConsumerConfig {
+    /// Queued frames from which a connection counts as behind
+    pub depth: usize,
+    /// How long a connection may stay behind before it's reported
+    pub duration: Duration,
+    /// Close slow connections instead of only reporting them
+    pub disconnect: bool,
+}
+
+impl SlowConsumerConfig {
+    /// Reports connections `depth` frames behind for `duration`
+    pub fn new(depth: usize, duration: Duration) -> Self {
+        SlowConsumerConfig {
+            depth: depth,
+            duration: duration,
+            disconnect: false,
+        }
+    }
+
+    pub fn disconnect(mut self, enabled: bool) -> Self {
+        self.disconnect = enabled;
+        self
+    }
+
+    /// How often the outgoing queues are checked
+    pub fn interval(&self) -> Duration {
+        cmp::max(self.duration / 2, MIN_INTERVAL)
+    }
+}
+
+/// Outgoing queue of a connection at one check
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backlog {
+    /// Frames queued or held back
+    pub depth: usize,
+    pub stall: Duration,
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::{SlowConsumerConfig, MIN_INTERVAL};
+
+    #[test]
+    fn queues_are_checked_twice_per_duration() {
+        assert_eq!(SlowConsumerConfig::new(10, Duration::from_secs(30)).interval(), Duration::from_secs(15));
+        assert_eq!(SlowConsumerConfig::new(10, Duration::from_millis(500)).interval(), MIN_INTERVAL);
+    }
+}
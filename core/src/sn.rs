@@ -0,0 +1,677 @@
+//! MQTT-SN gateway. Constrained sensors on UDP, or on 802.15.4 behind a
+//! forwarder, connect with MQTT-SN 1.2 and publish into the broker's topic
+//! space. Every sensor is a client of the broker under its SN client id and
+//! goes through authentication, the acl and routing like any other client.
+//! CONNECT connects it, REGISTER maps a topic name to a topic id and PUBLISH
+//! on a registered id or a two character short topic is routed as a publish
+//! at qos 0 or 1. The gateway only carries data from the sensors: wills,
+//! subscriptions, qos 2 and predefined topic ids aren't supported. Sensors
+//! quiet for one and a half times their keep alive are disconnected
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::executor::{self, Notify, Spawn};
+use futures::sync::mpsc::Receiver;
+use futures::{future, Async, Future};
+use mqtt3::{Connect, ConnectReturnCode, PacketIdentifier, Protocol, Publish, QoS};
+use tokio_core::reactor::Handle;
+
+use auth::{Verdict, Verification};
+use broker::Broker;
+use client::{self, Client};
+use codec::Frame;
+use connect;
+use disconnect::DisconnectReason;
+
+const CONNECT: u8 = 0x04;
+const CONNACK: u8 = 0x05;
+const REGISTER: u8 = 0x0a;
+const REGACK: u8 = 0x0b;
+const PUBLISH: u8 = 0x0c;
+const PUBACK: u8 = 0x0d;
+const PINGREQ: u8 = 0x16;
+const PINGRESP: u8 = 0x17;
+const DISCONNECT: u8 = 0x18;
+
+/// How often the sensors' queues are drained and their keep alives checked
+pub const TICK: Duration = Duration::from_secs(1);
+
+/// Protocol id of MQTT-SN 1.2 in CONNECT
+const PROTOCOL_ID: u8 = 0x01;
+
+/// Return codes of CONNACK, REGACK and PUBACK
+pub const ACCEPTED: u8 = 0x00;
+pub const INVALID_TOPIC_ID: u8 = 0x02;
+pub const NOT_SUPPORTED: u8 = 0x03;
+
+const FLAG_DUP: u8 = 0x80;
+const FLAG_RETAIN: u8 = 0x10;
+const FLAG_WILL: u8 = 0x08;
+const FLAG_CLEAN_SESSION: u8 = 0x04;
+
+/// How the topic of a PUBLISH is given
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TopicIdType {
+    /// Id assigned with REGISTER
+    Normal,
+    Predefined,
+    /// Two character topic name in place of the id
+    Short,
+}
+
+/// QoS levels of MQTT-SN. `NoConnection` is qos -1, publishes from sensors
+/// that never connected
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SnQoS {
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+    NoConnection,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnPublish {
+    pub dup: bool,
+    pub qos: SnQoS,
+    pub retain: bool,
+    pub topic_type: TopicIdType,
+    pub topic_id: u16,
+    pub msg_id: u16,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnPacket {
+    Connect {
+        clean_session: bool,
+        will: bool,
+        duration: u16,
+        client_id: String,
+    },
+    Connack(u8),
+    Register {
+        topic_id: u16,
+        msg_id: u16,
+        topic_name: String,
+    },
+    Regack {
+        topic_id: u16,
+        msg_id: u16,
+        code: u8,
+    },
+    Publish(SnPublish),
+    Puback {
+        topic_id: u16,
+        msg_id: u16,
+        code: u8,
+    },
+    Pingreq,
+    Pingresp,
+    Disconnect,
+}
+
+fn u16_at(buf: &[u8], at: usize) -> Option<u16> {
+    if buf.len() < at + 2 {
+        return None;
+    }
+    Some(u16::from(buf[at]) << 8 | u16::from(buf[at + 1]))
+}
+
+/// Decodes one datagram. `None` for truncated, malformed or unsupported
+/// messages, which the gateway drops
+pub fn decode(datagram: &[u8]) -> Option<SnPacket> {
+    // the length is one byte, or 0x01 followed by two bytes
+    let (len, header) = match *datagram.first()? {
+        0x01 => (u16_at(datagram, 1)? as usize, 3),
+        len => (len as usize, 1),
+    };
+    if len != datagram.len() || len <= header {
+        return None;
+    }
+
+    let body = &datagram[header + 1..];
+    let packet = match datagram[header] {
+        CONNECT => {
+            if body.len() < 4 || body[1] != PROTOCOL_ID {
+                return None;
+            }
+            SnPacket::Connect {
+                clean_session: body[0] & FLAG_CLEAN_SESSION != 0,
+                will: body[0] & FLAG_WILL != 0,
+                duration: u16_at(body, 2)?,
+                client_id: String::from_utf8(body[4..].to_vec()).ok()?,
+            }
+        }
+        CONNACK => SnPacket::Connack(*body.first()?),
+        REGISTER => {
+            SnPacket::Register {
+                topic_id: u16_at(body, 0)?,
+                msg_id: u16_at(body, 2)?,
+                topic_name: String::from_utf8(body.get(4..)?.to_vec()).ok()?,
+            }
+        }
+        REGACK => {
+            SnPacket::Regack {
+                topic_id: u16_at(body, 0)?,
+                msg_id: u16_at(body, 2)?,
+                code: *body.get(4)?,
+            }
+        }
+        PUBLISH => {
+            let flags = *body.first()?;
+            SnPacket::Publish(SnPublish {
+                dup: flags & FLAG_DUP != 0,
+                qos: match (flags >> 5) & 0x03 {
+                    0 => SnQoS::AtMostOnce,
+                    1 => SnQoS::AtLeastOnce,
+                    2 => SnQoS::ExactlyOnce,
+                    _ => SnQoS::NoConnection,
+                },
+                retain: flags & FLAG_RETAIN != 0,
+                topic_type: match flags & 0x03 {
+                    0 => TopicIdType::Normal,
+                    1 => TopicIdType::Predefined,
+                    2 => TopicIdType::Short,
+                    _ => return None,
+                },
+                topic_id: u16_at(body, 1)?,
+                msg_id: u16_at(body, 3)?,
+                payload: body.get(5..)?.to_vec(),
+            })
+        }
+        PUBACK => {
+            SnPacket::Puback {
+                topic_id: u16_at(body, 0)?,
+                msg_id: u16_at(body, 2)?,
+                code: *body.get(4)?,
+            }
+        }
+        // a PINGREQ may carry the client id of a sleeping sensor
+        PINGREQ => SnPacket::Pingreq,
+        PINGRESP => SnPacket::Pingresp,
+        // with or without a sleep duration
+        DISCONNECT => SnPacket::Disconnect,
+        _ => return None,
+    };
+    Some(packet)
+}
+
+pub fn encode(packet: &SnPacket) -> Vec<u8> {
+    let mut body = Vec::new();
+    let push_u16 = |body: &mut Vec<u8>, v: u16| body.extend_from_slice(&[(v >> 8) as u8, v as u8]);
+
+    let kind = match *packet {
+        SnPacket::Connect { clean_session, will, duration, ref client_id } => {
+            let mut flags = 0;
+            if clean_session {
+                flags |= FLAG_CLEAN_SESSION;
+            }
+            if will {
+                flags |= FLAG_WILL;
+            }
+            body.extend_from_slice(&[flags, PROTOCOL_ID]);
+            push_u16(&mut body, duration);
+            body.extend_from_slice(client_id.as_bytes());
+            CONNECT
+        }
+        SnPacket::Connack(code) => {
+            body.push(code);
+            CONNACK
+        }
+        SnPacket::Register { topic_id, msg_id, ref topic_name } => {
+            push_u16(&mut body, topic_id);
+            push_u16(&mut body, msg_id);
+            body.extend_from_slice(topic_name.as_bytes());
+            REGISTER
+        }
+        SnPacket::Regack { topic_id, msg_id, code } => {
+            push_u16(&mut body, topic_id);
+            push_u16(&mut body, msg_id);
+            body.push(code);
+            REGACK
+        }
+        SnPacket::Publish(ref publish) => {
+            let mut flags = match publish.qos {
+                SnQoS::AtMostOnce => 0,
+                SnQoS::AtLeastOnce => 1 << 5,
+                SnQoS::ExactlyOnce => 2 << 5,
+                SnQoS::NoConnection => 3 << 5,
+            };
+            flags |= match publish.topic_type {
+                TopicIdType::Normal => 0,
+                TopicIdType::Predefined => 1,
+                TopicIdType::Short => 2,
+            };
+            if publish.dup {
+                flags |= FLAG_DUP;
+            }
+            if publish.retain {
+                flags |= FLAG_RETAIN;
+            }
+            body.push(flags);
+            push_u16(&mut body, publish.topic_id);
+            push_u16(&mut body, publish.msg_id);
+            body.extend_from_slice(&publish.payload);
+            PUBLISH
+        }
+        SnPacket::Puback { topic_id, msg_id, code } => {
+            push_u16(&mut body, topic_id);
+            push_u16(&mut body, msg_id);
+            body.push(code);
+            PUBACK
+        }
+        SnPacket::Pingreq => PINGREQ,
+        SnPacket::Pingresp => PINGRESP,
+        SnPacket::Disconnect => DISCONNECT,
+    };
+
+    let mut datagram = Vec::with_capacity(body.len() + 4);
+    if body.len() + 2 < 256 {
+        datagram.push((body.len() + 2) as u8);
+    } else {
+        let len = body.len() + 4;
+        datagram.extend_from_slice(&[0x01, (len >> 8) as u8, len as u8]);
+    }
+    datagram.push(kind);
+    datagram.extend_from_slice(&body);
+    datagram
+}
+
+/// Lets queues be polled without a task. Ticks poll again anyway
+struct Noop;
+
+impl Notify for Noop {
+    fn notify(&self, _: usize) {}
+}
+
+/// A connected sensor
+struct Sensor {
+    client: Client,
+    /// What the broker sends the client. The gateway answers on its own,
+    /// so this is only drained
+    rx: Spawn<Receiver<Frame>>,
+    /// Registered topic ids
+    topics: HashMap<u16, String>,
+    last_topic_id: u16,
+    keep_alive: Option<Duration>,
+    last_seen: Instant,
+}
+
+/// Answer to a datagram, if it gets one
+pub type Answer = Box<Future<Item = Option<SnPacket>, Error = ()>>;
+
+/// What a datagram leads to
+enum Received {
+    Answer(Option<SnPacket>),
+    /// CONNECT waiting for the authenticator
+    Connect(Connect, Verification),
+}
+
+/// Sensors by the address they send from
+pub struct SnGateway {
+    broker: Broker,
+    /// Event loop the authenticator runs on
+    handle: Handle,
+    sensors: HashMap<SocketAddr, Sensor>,
+    notify: Arc<Noop>,
+}
+
+impl SnGateway {
+    pub fn new(broker: Broker, handle: Handle) -> Self {
+        SnGateway {
+            broker: broker,
+            handle: handle,
+            sensors: HashMap::new(),
+            notify: Arc::new(Noop),
+        }
+    }
+
+    /// Connected sensors
+    pub fn len(&self) -> usize {
+        self.sensors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sensors.is_empty()
+    }
+
+    /// Handles a datagram from `from` to `gateway`. CONNECTs are answered
+    /// once the authenticator decided, everything else right away
+    pub fn handle(gateway: &Rc<RefCell<SnGateway>>, from: SocketAddr, datagram: &[u8]) -> Answer {
+        let (connect, verification) = match gateway.borrow_mut().receive(from, datagram) {
+            Received::Answer(answer) => return Box::new(future::ok(answer)),
+            Received::Connect(connect, verification) => (connect, verification),
+        };
+
+        let gateway = gateway.clone();
+        Box::new(verification.then(move |verdict| {
+            // a check that fell over can't let the sensor in
+            let verdict = verdict.unwrap_or_else(|()| ConnectReturnCode::ServerUnavailable.into());
+            Ok::<_, ()>(Some(gateway.borrow_mut().connected(from, connect, verdict)))
+        }))
+    }
+
+    fn receive(&mut self, from: SocketAddr, datagram: &[u8]) -> Received {
+        let packet = match decode(datagram) {
+            Some(packet) => packet,
+            None => return Received::Answer(None),
+        };
+        let now = self.broker.clock().now();
+        if let Some(sensor) = self.sensors.get_mut(&from) {
+            sensor.last_seen = now;
+        }
+
+        let answer = match packet {
+            SnPacket::Connect { clean_session, will, duration, client_id } => return self.connect(from, clean_session, will, duration, client_id),
+            SnPacket::Register { msg_id, topic_name, .. } => self.register(from, msg_id, topic_name),
+            SnPacket::Publish(publish) => self.publish(from, publish),
+            SnPacket::Pingreq if self.sensors.contains_key(&from) => Some(SnPacket::Pingresp),
+            SnPacket::Disconnect => {
+                if let Some(sensor) = self.sensors.remove(&from) {
+                    self.broker.handle_disconnect(&sensor.client);
+                }
+                Some(SnPacket::Disconnect)
+            }
+            _ => None,
+        };
+
+        if let Some(sensor) = self.sensors.get_mut(&from) {
+            drain(sensor, &self.notify);
+        }
+        Received::Answer(answer)
+    }
+
+    fn connect(&mut self, from: SocketAddr, clean_session: bool, will: bool, duration: u16, client_id: String) -> Received {
+        if will {
+            return Received::Answer(Some(SnPacket::Connack(NOT_SUPPORTED)));
+        }
+
+        if let Some(sensor) = self.sensors.remove(&from) {
+            self.broker.handle_network_disconnect(&sensor.client);
+        }
+
+        let connect = Connect {
+            protocol: Protocol::MQTT(4),
+            keep_alive: duration,
+            client_id: client_id,
+            clean_session: clean_session,
+            last_will: None,
+            username: None,
+            password: None,
+        };
+        let config = self.broker.config();
+        let code = match connect::validate(&connect, &config) {
+            ConnectReturnCode::Accepted => connect::authorize(&connect, !config.allow_anonymous),
+            code => code,
+        };
+        let verification = match code {
+            ConnectReturnCode::Accepted => self.broker.authenticate(&connect, from, &self.handle),
+            code => Verdict::from(code).ready(),
+        };
+        Received::Connect(connect, verification)
+    }
+
+    /// Connects the sensor at `from` once the authenticator accepted it
+    fn connected(&mut self, from: SocketAddr, connect: Connect, verdict: Verdict) -> SnPacket {
+        if verdict.code != ConnectReturnCode::Accepted {
+            return SnPacket::Connack(NOT_SUPPORTED);
+        }
+
+        // another CONNECT from the same address got through first
+        if let Some(sensor) = self.sensors.remove(&from) {
+            self.broker.handle_network_disconnect(&sensor.client);
+        }
+
+        let (tx, rx) = client::outgoing_queue(self.broker.config().outgoing_queue_size);
+        let client = Client::with_clock(&connect.client_id, from, tx, self.broker.clock());
+        client.set_keep_alive(connect.keep_alive);
+        client.set_clean_session(connect.clean_session);
+        client.set_topic_prefixes(verdict.topic_prefixes);
+        self.broker.connect(client.clone());
+
+        let mut sensor = Sensor {
+            keep_alive: client.keep_alive(),
+            client: client,
+            rx: executor::spawn(rx),
+            topics: HashMap::new(),
+            last_topic_id: 0,
+            last_seen: self.broker.clock().now(),
+        };
+        drain(&mut sensor, &self.notify);
+        self.sensors.insert(from, sensor);
+        SnPacket::Connack(ACCEPTED)
+    }
+
+    fn register(&mut self, from: SocketAddr, msg_id: u16, topic_name: String) -> Option<SnPacket> {
+        let sensor = self.sensors.get_mut(&from)?;
+        if topic_name.is_empty() || topic_name.contains(|c| c == '+' || c == '#') {
+            return Some(SnPacket::Regack {
+                            topic_id: 0,
+                            msg_id: msg_id,
+                            code: INVALID_TOPIC_ID,
+                        });
+        }
+
+        let existing = sensor.topics.iter().find(|&(_, name)| *name == topic_name).map(|(&id, _)| id);
+        let topic_id = match existing {
+            Some(id) => id,
+            None => {
+                // 0 and 0xffff are reserved
+                if sensor.topics.len() >= 0xfffe {
+                    return Some(SnPacket::Regack {
+                                    topic_id: 0,
+                                    msg_id: msg_id,
+                                    code: NOT_SUPPORTED,
+                                });
+                }
+                sensor.last_topic_id = sensor.last_topic_id % 0xfffe + 1;
+                sensor.topics.insert(sensor.last_topic_id, topic_name);
+                sensor.last_topic_id
+            }
+        };
+
+        Some(SnPacket::Regack {
+                 topic_id: topic_id,
+                 msg_id: msg_id,
+                 code: ACCEPTED,
+             })
+    }
+
+    fn publish(&mut self, from: SocketAddr, publish: SnPublish) -> Option<SnPacket> {
+        let sensor = self.sensors.get(&from)?;
+        let (topic_id, msg_id) = (publish.topic_id, publish.msg_id);
+        let puback = |code| {
+            Some(SnPacket::Puback {
+                     topic_id: topic_id,
+                     msg_id: msg_id,
+                     code: code,
+                 })
+        };
+
+        let qos = match publish.qos {
+            SnQoS::AtMostOnce => QoS::AtMostOnce,
+            SnQoS::AtLeastOnce => QoS::AtLeastOnce,
+            SnQoS::ExactlyOnce | SnQoS::NoConnection => return puback(NOT_SUPPORTED),
+        };
+        let topic = match publish.topic_type {
+            TopicIdType::Normal => sensor.topics.get(&topic_id).cloned(),
+            TopicIdType::Short => String::from_utf8(vec![(topic_id >> 8) as u8, topic_id as u8]).ok(),
+            TopicIdType::Predefined => None,
+        };
+        let topic = match topic {
+            Some(topic) => topic,
+            None => return puback(INVALID_TOPIC_ID),
+        };
+
+        let publish = Box::new(Publish {
+                                   dup: publish.dup,
+                                   qos: qos,
+                                   retain: publish.retain,
+                                   pid: match qos {
+                                       QoS::AtMostOnce => None,
+                                       _ => Some(PacketIdentifier(msg_id)),
+                                   },
+                                   topic_name: topic,
+                                   payload: Arc::new(publish.payload),
+                               });
+        self.broker.handle_publish(publish, &sensor.client);
+
+        match qos {
+            QoS::AtMostOnce => None,
+            _ => puback(ACCEPTED),
+        }
+    }
+
+    /// Drains the sensors' queues and disconnects the ones that were quiet
+    /// for one and a half times their keep alive. Returns their ids
+    pub fn tick(&mut self) -> Vec<String> {
+        let now = self.broker.clock().now();
+        let expired: Vec<SocketAddr> = self.sensors
+            .iter()
+            .filter(|&(_, sensor)| sensor.keep_alive.map_or(false, |keep_alive| now.duration_since(sensor.last_seen) > keep_alive * 3 / 2))
+            .map(|(&addr, _)| addr)
+            .collect();
+
+        let mut ids = Vec::new();
+        for addr in expired {
+            let sensor = self.sensors.remove(&addr).unwrap();
+            self.broker.disconnect(&sensor.client, DisconnectReason::KeepAliveTimeout);
+            ids.push(sensor.client.id.clone());
+        }
+
+        for sensor in self.sensors.values_mut() {
+            drain(sensor, &self.notify);
+        }
+        ids
+    }
+}
+
+fn drain(sensor: &mut Sensor, notify: &Arc<Noop>) {
+    while let Ok(Async::Ready(Some(_))) = sensor.rx.poll_stream_notify(notify, 0) {}
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::net::SocketAddr;
+    use std::rc::Rc;
+    use std::time::Duration;
+    use mqtt3::{QoS, SubscribeTopic};
+    use futures::Future;
+    use futures::sync::mpsc;
+    use tokio_core::reactor::Core;
+    use broker::Broker;
+    use client::Client;
+    use clock::ManualClock;
+    use codec::Frame;
+    use config::BrokerConfig;
+    use super::{decode, encode, SnGateway, SnPacket, SnPublish, SnQoS, TopicIdType, ACCEPTED, INVALID_TOPIC_ID};
+
+    fn publish(topic_type: TopicIdType, topic_id: u16, qos: SnQoS) -> Vec<u8> {
+        encode(&SnPacket::Publish(SnPublish {
+                                      dup: false,
+                                      qos: qos,
+                                      retain: false,
+                                      topic_type: topic_type,
+                                      topic_id: topic_id,
+                                      msg_id: 7,
+                                      payload: b"21.5".to_vec(),
+                                  }))
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        let connect = SnPacket::Connect {
+            clean_session: true,
+            will: false,
+            duration: 60,
+            client_id: "sensor-1".to_owned(),
+        };
+        assert_eq!(&encode(&connect)[..2], &[14u8, 0x04][..]);
+        assert_eq!(decode(&encode(&connect)), Some(connect));
+
+        let long = SnPacket::Publish(SnPublish {
+                                         dup: true,
+                                         qos: SnQoS::AtLeastOnce,
+                                         retain: false,
+                                         topic_type: TopicIdType::Normal,
+                                         topic_id: 1,
+                                         msg_id: 2,
+                                         payload: vec![0; 300],
+                                     });
+        assert_eq!(encode(&long)[0], 0x01);
+        assert_eq!(decode(&encode(&long)), Some(long));
+
+        // length doesn't match the datagram
+        assert_eq!(decode(&[5, 0x16]), None);
+    }
+
+    #[test]
+    fn sensors_publish_on_registered_and_short_topics() {
+        let clock = ManualClock::new();
+        let broker = Broker::with_clock(BrokerConfig::default(), Rc::new(clock.clone()));
+        let (tx, _rx) = mpsc::channel::<Frame>(8);
+        let subscriber = Client::new("subscriber", "127.0.0.1:80".parse().unwrap(), tx);
+        broker.connect(subscriber.clone());
+        broker.add_subscription_client(SubscribeTopic {
+                                           topic_path: "#".to_owned(),
+                                           qos: QoS::AtMostOnce,
+                                       },
+                                       subscriber.clone());
+
+        let core = Core::new().unwrap();
+        let gateway = Rc::new(RefCell::new(SnGateway::new(broker.clone(), core.handle())));
+        let sensor: SocketAddr = "10.0.0.7:1884".parse().unwrap();
+        let handle = |datagram: Vec<u8>| SnGateway::handle(&gateway, sensor, &datagram).wait().unwrap();
+        let connect = SnPacket::Connect {
+            clean_session: true,
+            will: false,
+            duration: 10,
+            client_id: "sensor-1".to_owned(),
+        };
+        assert_eq!(handle(encode(&connect)), Some(SnPacket::Connack(ACCEPTED)));
+
+        let register = SnPacket::Register {
+            topic_id: 0,
+            msg_id: 1,
+            topic_name: "sensors/1/temperature".to_owned(),
+        };
+        let regack = SnPacket::Regack {
+            topic_id: 1,
+            msg_id: 1,
+            code: ACCEPTED,
+        };
+        assert_eq!(handle(encode(&register)), Some(regack));
+
+        let acked = |code| {
+            Some(SnPacket::Puback {
+                     topic_id: 1,
+                     msg_id: 7,
+                     code: code,
+                 })
+        };
+        assert_eq!(handle(publish(TopicIdType::Normal, 1, SnQoS::AtLeastOnce)), acked(ACCEPTED));
+        assert_eq!(handle(publish(TopicIdType::Normal, 2, SnQoS::AtLeastOnce)).unwrap(),
+                   SnPacket::Puback {
+                       topic_id: 2,
+                       msg_id: 7,
+                       code: INVALID_TOPIC_ID,
+                   });
+        // "ab"
+        assert_eq!(handle(publish(TopicIdType::Short, 0x6162, SnQoS::AtMostOnce)), None);
+
+        assert_eq!(broker.session_stats("sensor-1").unwrap().published, 2);
+        assert_eq!(broker.session_stats("subscriber").unwrap().delivered, 2);
+
+        // quiet for longer than 1.5 times the keep alive
+        clock.advance(Duration::from_secs(16));
+        assert_eq!(gateway.borrow_mut().tick(), vec!["sensor-1"]);
+        assert!(gateway.borrow().is_empty());
+        assert!(broker.get_client("sensor-1").is_none());
+    }
+}
@@ -0,0 +1,150 @@
+//! Soak test hunting for leaks. Cycles connect, subscribe, publish and
+//! disconnect through the broker while sampling the sizes of its maps and
+//! the process RSS. Fails when either keeps growing. Runs for `SOAK_SECS`
+//! seconds (default 60) with `cargo test --release -- --ignored soak`
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use mqtt3::*;
+
+use broker::{Broker, BrokerSizes};
+use client::{self, Client};
+
+/// Client ids are reused from a fixed pool so the per id session stats stay bounded
+const CLIENTS: usize = 100;
+/// Allowed RSS growth over the first sample, after warm up
+const RSS_GROWTH: f64 = 0.2;
+
+/// Resident set size of this process in bytes. Linux only
+fn rss() -> Option<usize> {
+    let mut statm = String::new();
+    File::open("/proc/self/statm")
+        .and_then(|mut f| f.read_to_string(&mut statm))
+        .ok()?;
+    statm
+        .split_whitespace()
+        .nth(1)
+        .and_then(|pages| pages.parse::<usize>().ok())
+        .map(|pages| pages * 4096)
+}
+
+fn publish(topic: &str, qos: QoS, pkid: u16) -> Box<Publish> {
+    Box::new(Publish {
+                 dup: false,
+                 qos: qos,
+                 retain: false,
+                 pid: if qos == QoS::AtMostOnce { None } else { Some(PacketIdentifier(pkid)) },
+                 topic_name: topic.to_owned(),
+                 payload: Arc::new(vec![0; 128]),
+             })
+}
+
+/// One connect -> subscribe -> publish -> disconnect round for every client
+fn cycle(broker: &Broker, round: usize) {
+    let (tx, _rx) = client::outgoing_queue(16);
+    let publisher = Client::new("soak-publisher", "127.0.0.1:80".parse().unwrap(), tx);
+    broker.connect(publisher.clone());
+
+    for i in 0..CLIENTS {
+        let (tx, rx) = client::outgoing_queue(16);
+        let client = Client::new(&format!("soak-{}", i), "127.0.0.1:80".parse().unwrap(), tx);
+        // every tenth client keeps a persistent session
+        client.set_clean_session(i % 10 != 0);
+        broker.connect(client.clone());
+
+        // a per round topic, like request/response topics with unique ids
+        let topic = format!("soak/{}/{}", i, round);
+        let subscribe = Box::new(Subscribe {
+                                     pid: PacketIdentifier(1),
+                                     topics: vec![SubscribeTopic {
+                                                      topic_path: topic.clone(),
+                                                      qos: QoS::AtLeastOnce,
+                                                  },
+                                                  SubscribeTopic {
+                                                      topic_path: "soak/broadcast".to_owned(),
+                                                      qos: QoS::AtMostOnce,
+                                                  }],
+                                 });
+        broker.handle_subscribe(subscribe, &client);
+
+        broker.handle_publish(publish(&topic, QoS::AtLeastOnce, 1), &publisher);
+        broker.handle_publish(publish("soak/broadcast", QoS::AtMostOnce, 0), &publisher);
+        if i % 2 == 0 {
+            broker.handle_puback(PacketIdentifier(1), &client);
+        }
+
+        // persistent sessions keep their subscriptions, so the one off topic has to go
+        if !client.clean_session() {
+            let unsubscribe = Box::new(Unsubscribe {
+                                           pid: PacketIdentifier(2),
+                                           topics: vec![topic.clone()],
+                                       });
+            broker.handle_unsubscribe(unsubscribe, &client);
+        }
+
+        match i % 3 {
+            0 => broker.handle_disconnect(&client),
+            1 => broker.handle_network_disconnect(&client),
+            // the connection drops without the broker hearing about it right away
+            _ => drop(rx),
+        }
+    }
+
+    // takes over the clients that were left dangling
+    for i in (0..CLIENTS).filter(|i| i % 3 == 2) {
+        let (tx, _) = client::outgoing_queue(16);
+        let client = Client::new(&format!("soak-{}", i), "127.0.0.1:80".parse().unwrap(), tx);
+        broker.connect(client.clone());
+        broker.handle_disconnect(&client);
+    }
+
+    broker.handle_disconnect(&publisher);
+}
+
+#[test]
+#[ignore]
+fn soak() {
+    let secs = env::var("SOAK_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(60);
+    let duration = Duration::from_secs(secs);
+
+    let broker = Broker::new();
+
+    // warm up so allocator pools and the session stats are in place
+    for round in 0..10 {
+        cycle(&broker, round);
+    }
+
+    let baseline: BrokerSizes = broker.sizes();
+    let baseline_rss = rss();
+    let start = Instant::now();
+    let mut round = 10;
+
+    while start.elapsed() < duration {
+        cycle(&broker, round);
+        round += 1;
+
+        if round % 100 == 0 {
+            let sizes = broker.sizes();
+            assert_eq!(sizes, baseline, "Broker maps grew after {} rounds", round);
+
+            if let (Some(baseline), Some(now)) = (baseline_rss, rss()) {
+                let growth = (now as f64 - baseline as f64) / baseline as f64;
+                assert!(growth < RSS_GROWTH,
+                        "RSS grew by {:.0}% after {} rounds. {} -> {} bytes",
+                        growth * 100.0,
+                        round,
+                        baseline,
+                        now);
+            }
+        }
+    }
+
+    assert_eq!(broker.sizes(), baseline);
+}
@@ -0,0 +1,360 @@
+//! Storage policies per topic prefix. Messages queued for offline persistent
+//! sessions live in memory by default and are lost with the broker. Prefixes
+//! with the `Wal` policy also have them appended to a write ahead log that's
+//! replayed on startup, and `Archive` prefixes hand every publish to a
+//! connector. High rate telemetry can stay in memory while low rate command
+//! topics pay for durability. A large log can be read in the background
+//! while the listeners start. With session checkpoints the sessions of
+//! connected clients are logged as well, so a crash only loses what changed
+//! since the last checkpoint
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use mqtt3::{Publish, QoS, SubscribeTopic};
+
+use error::{Error, Result};
+use pause::in_subtree;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum StoragePolicy {
+    /// Kept in memory only. Fast, but lost on restart
+    Memory,
+    /// Kept in memory and appended to the write ahead log when queued for an offline session
+    Wal,
+    /// Kept in memory and handed to the archive connector
+    Archive,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageRule {
+    /// Topic subtree the policy applies to
+    pub prefix: String,
+    pub policy: StoragePolicy,
+}
+
+/// Policy of the most specific rule covering `topic`. Topics without a rule stay in memory
+pub fn policy(rules: &[StorageRule], topic: &str) -> StoragePolicy {
+    rules
+        .iter()
+        .filter(|rule| in_subtree(topic, &rule.prefix))
+        .max_by_key(|rule| rule.prefix.len())
+        .map_or(StoragePolicy::Memory, |rule| rule.policy)
+}
+
+/// Destination of publishes on `Archive` prefixes, e.g. a database or another broker
+pub trait Connector {
+    fn archive(&mut self, publish: &Publish) -> Result<()>;
+}
+
+const PARKED: u8 = 0;
+const QUEUED: u8 = 1;
+const RESUMED: u8 = 2;
+
+/// Persistent session rebuilt from the write ahead log
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoggedSession {
+    pub id: String,
+    pub subscriptions: Vec<SubscribeTopic>,
+    /// Publishes with the qos they're to be delivered at
+    pub queue: Vec<(Box<Publish>, QoS)>,
+}
+
+/// Append only log of offline sessions and the `Wal` messages queued for them.
+/// Every record is synced to disk before the write returns
+#[derive(Debug)]
+pub struct Wal {
+    file: File,
+    path: PathBuf,
+}
+
+/// `Wal::open` running on a thread of its own
+pub type Loading = JoinHandle<::std::result::Result<(Wal, Vec<LoggedSession>), String>>;
+
+/// Opens the log at `path` on a thread of its own
+pub fn open_in_background(path: PathBuf) -> Loading {
+    thread::spawn(move || Wal::open(&path).map_err(|e| e.to_string()))
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// Reader over one record
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from(b[0]) << 8 | u16::from(b[1]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4).map(|b| u32::from(b[0]) << 24 | u32::from(b[1]) << 16 | u32::from(b[2]) << 8 | u32::from(b[3]))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u16()? as usize;
+        self.take(len).and_then(|s| String::from_utf8(s.to_vec()).ok())
+    }
+
+    fn qos(&mut self) -> Option<QoS> {
+        self.u8().and_then(|qos| QoS::from_u8(qos).ok())
+    }
+}
+
+impl Wal {
+    /// Opens the log at `path`, creating it if needed, and returns the
+    /// sessions it holds. The log is rewritten with just those sessions so
+    /// it doesn't grow across restarts. A record torn by a crash ends the replay
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<(Wal, Vec<LoggedSession>)> {
+        let path = path.as_ref();
+        let mut log = Vec::new();
+        match File::open(path) {
+            Ok(mut file) => {
+                file.read_to_end(&mut log)?;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        let sessions = Wal::replay(&log).map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+
+        let mut wal = Wal {
+            file: OpenOptions::new().append(true).create(true).open(path)?,
+            path: path.to_owned(),
+        };
+        wal.rewrite(&sessions)?;
+        Ok((wal, sessions))
+    }
+
+    /// Replaces the log with just `sessions`. The new log is written next to
+    /// the old one and renamed over it, so a crash leaves one or the other
+    pub fn rewrite(&mut self, sessions: &[LoggedSession]) -> Result<()> {
+        let tmp = self.path.with_extension("compact");
+        let mut compacted = Wal {
+            file: File::create(&tmp)?,
+            path: tmp.clone(),
+        };
+        for session in sessions {
+            compacted.session(session)?;
+        }
+        ::std::fs::rename(&tmp, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+
+    fn replay(mut log: &[u8]) -> ::std::result::Result<Vec<LoggedSession>, String> {
+        let mut sessions: Vec<LoggedSession> = Vec::new();
+        let mut index: HashMap<String, usize> = HashMap::new();
+
+        while log.len() >= 4 {
+            let len = Fields(log).u32().unwrap() as usize;
+            if log.len() < 4 + len {
+                break;
+            }
+            let mut record = Fields(&log[4..4 + len]);
+            log = &log[4 + len..];
+
+            let invalid = || "corrupt record".to_owned();
+            let kind = record.u8().ok_or_else(&invalid)?;
+            let id = record.string().ok_or_else(&invalid)?;
+
+            match kind {
+                PARKED => {
+                    let count = record.u16().ok_or_else(&invalid)?;
+                    let mut subscriptions = Vec::new();
+                    for _ in 0..count {
+                        subscriptions.push(SubscribeTopic {
+                                               topic_path: record.string().ok_or_else(&invalid)?,
+                                               qos: record.qos().ok_or_else(&invalid)?,
+                                           });
+                    }
+
+                    let session = LoggedSession {
+                        id: id.clone(),
+                        subscriptions: subscriptions,
+                        queue: Vec::new(),
+                    };
+                    match index.get(&id).cloned() {
+                        Some(i) => sessions[i] = session,
+                        None => {
+                            index.insert(id, sessions.len());
+                            sessions.push(session);
+                        }
+                    }
+                }
+                QUEUED => {
+                    let topic = record.string().ok_or_else(&invalid)?;
+                    let qos = record.qos().ok_or_else(&invalid)?;
+                    let len = record.u32().ok_or_else(&invalid)? as usize;
+                    let payload = record.take(len).ok_or_else(&invalid)?;
+
+                    if let Some(&i) = index.get(&id) {
+                        let publish = Box::new(Publish {
+                                                   dup: false,
+                                                   qos: qos,
+                                                   retain: false,
+                                                   pid: None,
+                                                   topic_name: topic,
+                                                   payload: Arc::new(payload.to_vec()),
+                                               });
+                        sessions[i].queue.push((publish, qos));
+                    }
+                }
+                RESUMED => {
+                    if let Some(i) = index.remove(&id) {
+                        sessions.remove(i);
+                        for position in index.values_mut() {
+                            if *position > i {
+                                *position -= 1;
+                            }
+                        }
+                    }
+                }
+                kind => return Err(format!("unknown record kind {}", kind)),
+            }
+        }
+
+        Ok(sessions)
+    }
+
+    fn append(&mut self, kind: u8, id: &str, body: &[u8]) -> Result<()> {
+        let mut record = vec![kind];
+        put_str(&mut record, id);
+        record.extend_from_slice(body);
+
+        let mut framed = (record.len() as u32).to_be_bytes().to_vec();
+        framed.extend_from_slice(&record);
+        self.file.write_all(&framed)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The client `id` went offline with `subscriptions`. Starts its session over
+    pub fn parked(&mut self, id: &str, subscriptions: &[SubscribeTopic]) -> Result<()> {
+        let mut body = (subscriptions.len() as u16).to_be_bytes().to_vec();
+        for s in subscriptions {
+            put_str(&mut body, &s.topic_path);
+            body.push(s.qos.to_u8());
+        }
+        self.append(PARKED, id, &body)
+    }
+
+    /// `publish` was queued for the offline client `id` at `qos`
+    pub fn queued(&mut self, id: &str, publish: &Publish, qos: QoS) -> Result<()> {
+        let mut body = Vec::with_capacity(publish.topic_name.len() + publish.payload.len() + 7);
+        put_str(&mut body, &publish.topic_name);
+        body.push(qos.to_u8());
+        body.extend_from_slice(&(publish.payload.len() as u32).to_be_bytes());
+        body.extend_from_slice(&publish.payload);
+        self.append(QUEUED, id, &body)
+    }
+
+    /// Logs `session` with its queue, replacing whatever was logged for it
+    pub fn session(&mut self, session: &LoggedSession) -> Result<()> {
+        self.parked(&session.id, &session.subscriptions)?;
+        for &(ref publish, qos) in session.queue.iter() {
+            self.queued(&session.id, publish, qos)?;
+        }
+        Ok(())
+    }
+
+    /// The session of `id` was resumed or discarded
+    pub fn resumed(&mut self, id: &str) -> Result<()> {
+        self.append(RESUMED, id, &[])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::sync::Arc;
+    use mqtt3::{Publish, QoS, SubscribeTopic};
+    use super::{policy, StoragePolicy, StorageRule, Wal};
+
+    #[test]
+    fn most_specific_prefix_decides() {
+        let rules = vec![StorageRule {
+                             prefix: "devices".to_owned(),
+                             policy: StoragePolicy::Archive,
+                         },
+                         StorageRule {
+                             prefix: "devices/commands".to_owned(),
+                             policy: StoragePolicy::Wal,
+                         }];
+
+        assert_eq!(policy(&rules, "devices/commands/7"), StoragePolicy::Wal);
+        assert_eq!(policy(&rules, "devices/telemetry/7"), StoragePolicy::Archive);
+        assert_eq!(policy(&rules, "devicesx/7"), StoragePolicy::Memory);
+        assert_eq!(policy(&rules, "hello/mqtt"), StoragePolicy::Memory);
+    }
+
+    #[test]
+    fn sessions_survive_reopening_the_log() {
+        let path = env::temp_dir().join(format!("rumqttd-wal-{}.log", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let subscriptions = vec![SubscribeTopic {
+                                     topic_path: "commands/7".to_owned(),
+                                     qos: QoS::AtLeastOnce,
+                                 }];
+        let publish = Publish {
+            dup: false,
+            qos: QoS::ExactlyOnce,
+            retain: false,
+            pid: None,
+            topic_name: "commands/7".to_owned(),
+            payload: Arc::new(vec![1, 2, 3]),
+        };
+
+        {
+            let (mut wal, sessions) = Wal::open(&path).unwrap();
+            assert!(sessions.is_empty());
+            wal.parked("device-7", &subscriptions).unwrap();
+            wal.queued("device-7", &publish, QoS::AtLeastOnce).unwrap();
+            wal.parked("device-8", &[]).unwrap();
+            wal.resumed("device-8").unwrap();
+        }
+
+        // torn record at the end
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&[0, 0, 0, 9, 1]).unwrap();
+
+        let (_wal, sessions) = Wal::open(&path).unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].id, "device-7");
+        assert_eq!(sessions[0].subscriptions, subscriptions);
+        assert_eq!(sessions[0].queue.len(), 1);
+        assert_eq!(sessions[0].queue[0].0.payload, publish.payload);
+        assert_eq!(sessions[0].queue[0].1, QoS::AtLeastOnce);
+
+        // compacted on open
+        assert_eq!(Wal::open(&path).unwrap().1, sessions);
+        fs::remove_file(&path).unwrap();
+    }
+}
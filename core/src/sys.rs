@@ -0,0 +1,143 @@
+//! `$SYS` diagnostics for device developers. The broker's clock goes out on
+//! `$SYS/broker/time` and every connection's keep alive on
+//! `$SYS/broker/clients/<id>/keepalive`: the negotiated interval, how long
+//! ago its last packet arrived and how long it has left before it's
+//! disconnected. Its traffic goes out on `$SYS/broker/clients/<id>/stats`.
+//! The latest value of each topic is retained and sent to new subscribers
+//! right away. Connections coming and going are announced on
+//! `$SYS/broker/connection/<id>/state`, where only the state of connected
+//! clients is retained. Slow consumers are reported on
+//! `$SYS/broker/clients/<id>/slow`
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clock::millis;
+use leaf;
+use slow::Backlog;
+
+pub const TIME_TOPIC: &str = "$SYS/broker/time";
+
+/// Prefix of the per client topics. Client ids are escaped like leaf origins
+pub const CLIENTS_PREFIX: &str = "$SYS/broker/clients/";
+
+/// Prefix of the connection state topics
+pub const CONNECTION_PREFIX: &str = "$SYS/broker/connection/";
+
+pub fn connection_topic(id: &str) -> String {
+    leaf::wrap(CONNECTION_PREFIX, id, "state")
+}
+
+/// Connection of client `id` from `address` coming online, or going offline for `reason`
+pub fn connection_payload(id: &str, online: bool, clean_session: bool, reason: &str, address: SocketAddr) -> Vec<u8> {
+    json!({
+        "client_id": id,
+        "state": if online { "online" } else { "offline" },
+        "clean_session": clean_session,
+        "reason": reason,
+        "address": address.to_string(),
+    })
+        .to_string()
+        .into_bytes()
+}
+
+pub fn keepalive_topic(id: &str) -> String {
+    leaf::wrap(CLIENTS_PREFIX, id, "keepalive")
+}
+
+pub fn stats_topic(id: &str) -> String {
+    leaf::wrap(CLIENTS_PREFIX, id, "stats")
+}
+
+pub fn slow_topic(id: &str) -> String {
+    leaf::wrap(CLIENTS_PREFIX, id, "slow")
+}
+
+/// Connection that has been `behind` on its outgoing queue for a while
+pub fn slow_payload(id: &str, backlog: Backlog, behind: Duration, disconnected: bool) -> Vec<u8> {
+    json!({
+        "client_id": id,
+        "depth": backlog.depth,
+        "stall_ms": millis(backlog.stall),
+        "behind_ms": millis(behind),
+        "disconnected": disconnected,
+    })
+        .to_string()
+        .into_bytes()
+}
+
+/// Wall clock time and uptime of the broker
+pub fn time_payload(unix_time: Duration, uptime: Duration) -> Vec<u8> {
+    json!({"unix_ms": millis(unix_time), "uptime_ms": millis(uptime)}).to_string().into_bytes()
+}
+
+/// Keep alive of a connection whose last packet arrived `idle` ago. Clients
+/// are disconnected after one and a half keep alive intervals of silence
+pub fn keepalive_payload(keep_alive: Option<Duration>, idle: Duration) -> Vec<u8> {
+    let timeout = keep_alive.map(|keep_alive| keep_alive * 3 / 2);
+    json!({
+        "keep_alive_s": keep_alive.map_or(0, |keep_alive| keep_alive.as_secs()),
+        "last_packet_age_ms": millis(idle),
+        "timeout_ms": timeout.map(millis),
+        "remaining_ms": timeout.map(|timeout| millis(timeout.checked_sub(idle).unwrap_or_default())),
+    })
+        .to_string()
+        .into_bytes()
+}
+
+/// Latest payload of every diagnostics topic
+#[derive(Debug, Default)]
+pub struct Retained {
+    topics: HashMap<String, Arc<Vec<u8>>>,
+}
+
+impl Retained {
+    pub fn new() -> Self {
+        Retained::default()
+    }
+
+    pub fn set(&mut self, topic: String, payload: Arc<Vec<u8>>) {
+        self.topics.insert(topic, payload);
+    }
+
+    pub fn get(&self, topic: &str) -> Option<Arc<Vec<u8>>> {
+        self.topics.get(topic).cloned()
+    }
+
+    pub fn remove(&mut self, topic: &str) {
+        self.topics.remove(topic);
+    }
+
+    pub fn len(&self) -> usize {
+        self.topics.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.topics.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use serde_json::{self, Value};
+    use super::{keepalive_payload, keepalive_topic};
+
+    #[test]
+    fn keepalive_diagnostics_show_the_time_left() {
+        assert_eq!(keepalive_topic("sensor/7"), "$SYS/broker/clients/sensor%2F7/keepalive");
+
+        let payload = keepalive_payload(Some(Duration::from_secs(10)), Duration::from_millis(4500));
+        let v: Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(v["keep_alive_s"], 10);
+        assert_eq!(v["last_packet_age_ms"], 4500);
+        assert_eq!(v["timeout_ms"], 15000);
+        assert_eq!(v["remaining_ms"], 10500);
+
+        let v: Value = serde_json::from_slice(&keepalive_payload(None, Duration::from_secs(600))).unwrap();
+        assert_eq!(v["keep_alive_s"], 0);
+        assert_eq!(v["remaining_ms"], Value::Null);
+    }
+}
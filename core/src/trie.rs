@@ -0,0 +1,276 @@
+//! Subscriptions indexed by the levels of their filters. A publish walks the
+//! trie one topic level at a time, following the exact level along with the
+//! `+` and `#` branches, so a lookup costs O(levels) however many filters
+//! there are. Filters starting with a wildcard don't match `$` topics
+
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+
+use mqtt3::SubscribeTopic;
+
+/// Filters ending at one level, with the levels below it
+struct Node<V> {
+    children: HashMap<String, Node<V>>,
+    /// One entry per qos the filter is subscribed with
+    subscriptions: Vec<(SubscribeTopic, V)>,
+}
+
+impl<V> Node<V> {
+    fn new() -> Self {
+        Node {
+            children: HashMap::new(),
+            subscriptions: Vec::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.subscriptions.is_empty() && self.children.is_empty()
+    }
+
+    fn collect<'a>(&'a self, out: &mut Vec<(&'a SubscribeTopic, &'a V)>) {
+        out.extend(self.subscriptions.iter().map(|&(ref topic, ref value)| (topic, value)));
+        for child in self.children.values() {
+            child.collect(out);
+        }
+    }
+
+    /// Subscriptions under this node matching the topic `levels`. `system`
+    /// is set on the first level of `$` topics, which wildcards skip
+    fn matches<'a>(&'a self, levels: &[&str], system: bool, out: &mut Vec<(&'a SubscribeTopic, &'a V)>) {
+        let (level, rest) = match levels.split_first() {
+            Some((level, rest)) => (*level, rest),
+            None => {
+                out.extend(self.subscriptions.iter().map(|&(ref topic, ref value)| (topic, value)));
+                // `a/#` covers `a` itself
+                if let Some(all) = self.children.get("#") {
+                    out.extend(all.subscriptions.iter().map(|&(ref topic, ref value)| (topic, value)));
+                }
+                return;
+            }
+        };
+
+        if let Some(child) = self.children.get(level) {
+            child.matches(rest, false, out);
+        }
+        if system {
+            return;
+        }
+        if level != "+" {
+            if let Some(child) = self.children.get("+") {
+                child.matches(rest, false, out);
+            }
+        }
+        if level != "#" {
+            if let Some(all) = self.children.get("#") {
+                out.extend(all.subscriptions.iter().map(|&(ref topic, ref value)| (topic, value)));
+            }
+        }
+    }
+
+    /// Removes `topic` from the node at the end of `levels`, dropping the
+    /// nodes left empty on the way back up
+    fn remove(&mut self, levels: &[&str], topic: &SubscribeTopic) -> Option<V> {
+        match levels.split_first() {
+            None => {
+                let index = self.subscriptions.iter().position(|entry| entry.0 == *topic)?;
+                Some(self.subscriptions.remove(index).1)
+            }
+            Some((level, rest)) => {
+                let (removed, empty) = {
+                    let child = self.children.get_mut(*level)?;
+                    let removed = child.remove(rest, topic);
+                    (removed, child.is_empty())
+                };
+                if empty {
+                    self.children.remove(*level);
+                }
+                removed
+            }
+        }
+    }
+
+    /// Keeps the subscriptions `keep` returns true for. Returns how many were dropped
+    fn retain<F: FnMut(&SubscribeTopic, &mut V) -> bool>(&mut self, keep: &mut F) -> usize {
+        let mut dropped = 0;
+        let mut index = 0;
+        while index < self.subscriptions.len() {
+            let kept = {
+                let (ref topic, ref mut value) = self.subscriptions[index];
+                keep(topic, value)
+            };
+            if kept {
+                index += 1;
+            } else {
+                self.subscriptions.remove(index);
+                dropped += 1;
+            }
+        }
+
+        for child in self.children.values_mut() {
+            dropped += child.retain(keep);
+        }
+        self.children.retain(|_, child| !child.is_empty());
+        dropped
+    }
+}
+
+/// Values kept per subscription, i.e. per filter and qos
+pub struct TopicTrie<V> {
+    root: Node<V>,
+    len: usize,
+}
+
+impl<V> TopicTrie<V> {
+    pub fn new() -> Self {
+        TopicTrie {
+            root: Node::new(),
+            len: 0,
+        }
+    }
+
+    /// Number of subscriptions
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Value of the subscription `topic`, inserted with `default` if there's none yet
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, topic: SubscribeTopic, default: F) -> &mut V {
+        let mut node = &mut self.root;
+        for level in topic.topic_path.split('/') {
+            node = node.children.entry(level.to_owned()).or_insert_with(Node::new);
+        }
+
+        match node.subscriptions.iter().position(|entry| entry.0 == topic) {
+            Some(index) => &mut node.subscriptions[index].1,
+            None => {
+                self.len += 1;
+                node.subscriptions.push((topic, default()));
+                &mut node.subscriptions.last_mut().unwrap().1
+            }
+        }
+    }
+
+    /// Value of exactly the subscription `topic`. Wildcards are taken literally
+    pub fn get(&self, topic: &SubscribeTopic) -> Option<&V> {
+        let mut node = &self.root;
+        for level in topic.topic_path.split('/') {
+            node = node.children.get(level)?;
+        }
+        node.subscriptions.iter().find(|entry| entry.0 == *topic).map(|entry| &entry.1)
+    }
+
+    pub fn get_mut(&mut self, topic: &SubscribeTopic) -> Option<&mut V> {
+        let mut node = &mut self.root;
+        for level in topic.topic_path.split('/') {
+            node = node.children.get_mut(level)?;
+        }
+        node.subscriptions.iter_mut().find(|entry| entry.0 == *topic).map(|entry| &mut entry.1)
+    }
+
+    pub fn remove(&mut self, topic: &SubscribeTopic) -> Option<V> {
+        let levels: Vec<&str> = topic.topic_path.split('/').collect();
+        let removed = self.root.remove(&levels, topic);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    pub fn retain<F: FnMut(&SubscribeTopic, &mut V) -> bool>(&mut self, mut keep: F) {
+        self.len -= self.root.retain(&mut keep);
+    }
+
+    /// Every subscription, in no particular order
+    pub fn iter(&self) -> ::std::vec::IntoIter<(&SubscribeTopic, &V)> {
+        let mut all = Vec::with_capacity(self.len);
+        self.root.collect(&mut all);
+        all.into_iter()
+    }
+
+    /// Subscriptions whose filter matches the published `topic`
+    pub fn matches(&self, topic: &str) -> Vec<(&SubscribeTopic, &V)> {
+        let levels: Vec<&str> = topic.split('/').collect();
+        let mut matched = Vec::new();
+        self.root.matches(&levels, topic.starts_with('$'), &mut matched);
+        matched
+    }
+}
+
+impl<V: Debug> Debug for TopicTrie<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+/// True if `filter` has a `+` or `#` level
+pub fn has_wildcards(filter: &str) -> bool {
+    filter.split('/').any(|level| level == "+" || level == "#")
+}
+
+#[cfg(test)]
+mod test {
+    use mqtt3::{QoS, SubscribeTopic};
+    use super::TopicTrie;
+
+    fn topic(filter: &str, qos: QoS) -> SubscribeTopic {
+        SubscribeTopic {
+            topic_path: filter.to_owned(),
+            qos: qos,
+        }
+    }
+
+    fn matched(trie: &TopicTrie<u8>, topic: &str) -> Vec<u8> {
+        let mut values: Vec<u8> = trie.matches(topic).into_iter().map(|(_, value)| *value).collect();
+        values.sort();
+        values
+    }
+
+    #[test]
+    fn wildcard_branches_are_followed() {
+        let mut trie = TopicTrie::new();
+        *trie.get_or_insert_with(topic("sensors/7/temperature", QoS::AtMostOnce), || 0) = 1;
+        *trie.get_or_insert_with(topic("sensors/+/temperature", QoS::AtLeastOnce), || 0) = 2;
+        *trie.get_or_insert_with(topic("sensors/#", QoS::AtMostOnce), || 0) = 3;
+        *trie.get_or_insert_with(topic("#", QoS::AtMostOnce), || 0) = 4;
+        *trie.get_or_insert_with(topic("+/7/humidity", QoS::AtMostOnce), || 0) = 5;
+
+        assert_eq!(matched(&trie, "sensors/7/temperature"), vec![1, 2, 3, 4]);
+        assert_eq!(matched(&trie, "sensors/8/temperature"), vec![2, 3, 4]);
+        assert_eq!(matched(&trie, "sensors/7/humidity"), vec![3, 4, 5]);
+        assert_eq!(matched(&trie, "sensors"), vec![3, 4]);
+        assert_eq!(matched(&trie, "commands/7"), vec![4]);
+        assert_eq!(trie.len(), 5);
+    }
+
+    #[test]
+    fn wildcards_skip_system_topics() {
+        let mut trie = TopicTrie::new();
+        *trie.get_or_insert_with(topic("#", QoS::AtMostOnce), || 0) = 1;
+        *trie.get_or_insert_with(topic("+/broker/time", QoS::AtMostOnce), || 0) = 2;
+        *trie.get_or_insert_with(topic("$SYS/#", QoS::AtMostOnce), || 0) = 3;
+
+        assert_eq!(matched(&trie, "$SYS/broker/time"), vec![3]);
+    }
+
+    #[test]
+    fn removals_prune_empty_levels() {
+        let mut trie = TopicTrie::new();
+        *trie.get_or_insert_with(topic("hello/mqtt", QoS::AtMostOnce), || 0) = 1;
+        *trie.get_or_insert_with(topic("hello/mqtt", QoS::AtLeastOnce), || 0) = 2;
+        *trie.get_or_insert_with(topic("hello/+", QoS::AtMostOnce), || 0) = 3;
+
+        assert_eq!(trie.get(&topic("hello/mqtt", QoS::AtLeastOnce)), Some(&2));
+        assert_eq!(trie.remove(&topic("hello/mqtt", QoS::AtMostOnce)), Some(1));
+        assert_eq!(trie.remove(&topic("hello/mqtt", QoS::AtMostOnce)), None);
+
+        trie.retain(|topic, _| topic.topic_path != "hello/+");
+        assert_eq!(trie.len(), 1);
+        assert_eq!(trie.remove(&topic("hello/mqtt", QoS::AtLeastOnce)), Some(2));
+        assert!(trie.is_empty());
+        assert!(trie.root.is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+//! Grouped publishes for multi-part state updates that mustn't be seen
+//! half done. MQTT 3.1.1 has no user properties to tag messages with, so a
+//! publisher marks the parts of a group by their topic instead:
+//! `$txn/<group>/<topic>` is held back as a publish on `<topic>` until
+//! `$txn/<group>/$commit`, which forwards every part in the order it was
+//! published. Nothing else is routed in between, so subscribers see all of
+//! the group together or none of it. `$txn/<group>/$abort`, a disconnect,
+//! a group outgrowing its limit or a part the acl or the declared topics
+//! refuse discards the parts. Groups are per client
+
+use std::collections::HashMap;
+
+use mqtt3::Publish;
+
+/// Topic prefix of grouped publishes
+pub const TXN_PREFIX: &str = "$txn/";
+
+/// Last level of the publish that makes a group visible
+pub const COMMIT: &str = "$commit";
+
+/// Last level of the publish that discards a group
+pub const ABORT: &str = "$abort";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Marker<'a> {
+    /// Publish on the topic once the group is committed
+    Part(&'a str),
+    Commit,
+    Abort,
+}
+
+/// Group and meaning of a `$txn/` topic. `None` for ordinary topics
+pub fn parse<'a>(topic: &'a str) -> Option<(&'a str, Marker<'a>)> {
+    if !topic.starts_with(TXN_PREFIX) {
+        return None;
+    }
+
+    let rest = &topic[TXN_PREFIX.len()..];
+    let (group, rest) = match rest.find('/') {
+        Some(i) if i > 0 && i + 1 < rest.len() => (&rest[..i], &rest[i + 1..]),
+        _ => return None,
+    };
+
+    let marker = match rest {
+        COMMIT => Marker::Commit,
+        ABORT => Marker::Abort,
+        topic => Marker::Part(topic),
+    };
+    Some((group, marker))
+}
+
+/// Parts held back per client and group
+#[derive(Debug)]
+pub struct OpenGroups {
+    groups: HashMap<(String, String), Vec<Box<Publish>>>,
+    max_messages: usize,
+}
+
+impl OpenGroups {
+    pub fn new(max_messages: usize) -> Self {
+        OpenGroups {
+            groups: HashMap::new(),
+            max_messages: max_messages,
+        }
+    }
+
+    /// Holds `publish` back in the client's group. A group that outgrows the
+    /// limit is discarded and the number of parts it held returned
+    pub fn add(&mut self, client: &str, group: &str, publish: Box<Publish>) -> Result<(), usize> {
+        let key = (client.to_owned(), group.to_owned());
+        let full = {
+            let parts = self.groups.entry(key.clone()).or_insert_with(Vec::new);
+            parts.push(publish);
+            parts.len() > self.max_messages
+        };
+
+        if full {
+            Err(self.groups.remove(&key).map_or(0, |parts| parts.len()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Parts of the group in publish order. Empty for unknown groups
+    pub fn commit(&mut self, client: &str, group: &str) -> Vec<Box<Publish>> {
+        self.groups
+            .remove(&(client.to_owned(), group.to_owned()))
+            .unwrap_or_default()
+    }
+
+    /// Discards a group. Returns the number of parts it held
+    pub fn abort(&mut self, client: &str, group: &str) -> usize {
+        self.commit(client, group).len()
+    }
+
+    /// Discards every open group of `client`
+    pub fn remove_client(&mut self, client: &str) {
+        self.groups.retain(|&(ref id, _), _| id != client);
+    }
+
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use mqtt3::{Publish, QoS};
+    use super::{parse, Marker, OpenGroups};
+
+    fn publish(topic: &str) -> Box<Publish> {
+        Box::new(Publish {
+                     dup: false,
+                     qos: QoS::AtLeastOnce,
+                     retain: false,
+                     pid: None,
+                     topic_name: topic.to_owned(),
+                     payload: Arc::new(vec![]),
+                 })
+    }
+
+    #[test]
+    fn groups_are_held_until_commit() {
+        assert_eq!(parse("$txn/g1/state/a"), Some(("g1", Marker::Part("state/a"))));
+        assert_eq!(parse("$txn/g1/$commit"), Some(("g1", Marker::Commit)));
+        assert_eq!(parse("$txn/g1/$abort"), Some(("g1", Marker::Abort)));
+        assert_eq!(parse("$txn/g1"), None);
+        assert_eq!(parse("$txn//state"), None);
+        assert_eq!(parse("state/a"), None);
+
+        let mut groups = OpenGroups::new(2);
+        groups.add("d7", "g1", publish("state/a")).unwrap();
+        groups.add("d7", "g1", publish("state/b")).unwrap();
+        groups.add("d8", "g1", publish("state/c")).unwrap();
+        let parts: Vec<String> = groups.commit("d7", "g1").into_iter().map(|p| p.topic_name).collect();
+        assert_eq!(parts, vec!["state/a", "state/b"]);
+        assert!(groups.commit("d7", "g1").is_empty());
+
+        groups.add("d8", "g1", publish("state/d")).unwrap();
+        assert_eq!(groups.add("d8", "g1", publish("state/e")), Err(3));
+        assert!(groups.is_empty());
+
+        groups.add("d8", "g2", publish("state/a")).unwrap();
+        groups.remove_client("d8");
+        assert_eq!(groups.abort("d8", "g2"), 0);
+    }
+}
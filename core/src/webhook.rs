@@ -0,0 +1,275 @@
+//! Webhook authentication against an existing identity service. CONNECTs
+//! are POSTed to an HTTP endpoint as
+//!
+//! ```text
+//! {"address":"10.0.0.7","client_id":"d7","password":"secret","username":"device"}
+//! ```
+//!
+//! `200` lets the client in, `401` refuses its credentials and `403` refuses
+//! the client. Any other status, or no answer within the timeout, is sent
+//! back as server unavailable so clients retry later. The request is made
+//! asynchronously on the event loop, and the timeout bounds the whole of it,
+//! name lookup included. Definite answers are cached per client, credentials
+//! and address
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use hyper::{Client, Uri};
+use hyper::client::HttpConnector;
+use mqtt3::ConnectReturnCode;
+use rand;
+use sha2::{Digest, Sha256};
+use tokio_core::reactor::Handle;
+
+use auth::{Authenticator, Verdict, Verification};
+use clock::Clock;
+use error::Result;
+use http;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebhookConfig {
+    /// `http://host[:port][/path]` of the endpoint
+    pub url: String,
+    /// Longest wait for the endpoint's answer, name lookup and connection included
+    pub timeout: Duration,
+    /// How long an answer is reused for the same CONNECT
+    pub cache_ttl: Duration,
+    /// Answers kept in the cache
+    pub cache_size: usize,
+}
+
+impl WebhookConfig {
+    pub fn new(url: &str) -> Self {
+        WebhookConfig {
+            url: url.to_owned(),
+            timeout: Duration::from_secs(2),
+            cache_ttl: Duration::from_secs(60),
+            cache_size: 10_000,
+        }
+    }
+}
+
+/// JSON document describing a CONNECT
+pub fn request_body(client_id: &str, username: Option<&str>, password: Option<&str>, addr: SocketAddr) -> String {
+    let body = json!({
+        "client_id": client_id,
+        "username": username,
+        "password": password,
+        "address": addr.ip().to_string(),
+    });
+    body.to_string()
+}
+
+/// Salted hash of client id, username, password and address of a CONNECT,
+/// so cached passwords aren't kept in the clear
+type Key = [u8; 32];
+
+/// Definite answers of the endpoint
+struct Cache {
+    answers: HashMap<Key, (ConnectReturnCode, Instant)>,
+    ttl: Duration,
+    size: usize,
+}
+
+impl Cache {
+    fn get(&self, key: &Key, now: Instant) -> Option<ConnectReturnCode> {
+        match self.answers.get(key) {
+            Some(&(code, at)) if now.duration_since(at) < self.ttl => Some(code),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, key: Key, code: ConnectReturnCode, now: Instant) {
+        if self.answers.len() >= self.size {
+            let ttl = self.ttl;
+            self.answers.retain(|_, &mut (_, at)| now.duration_since(at) < ttl);
+        }
+        if self.answers.len() >= self.size {
+            self.answers.clear();
+        }
+        self.answers.insert(key, (code, now));
+    }
+}
+
+pub struct WebhookAuth {
+    uri: Uri,
+    timeout: Duration,
+    salt: [u8; 16],
+    clock: Rc<Clock>,
+    /// Made on the first CONNECT, on the event loop it comes in on
+    client: RefCell<Option<Client<HttpConnector>>>,
+    cache: Rc<RefCell<Cache>>,
+}
+
+impl WebhookAuth {
+    pub fn new(config: WebhookConfig, clock: Rc<Clock>) -> Result<Self> {
+        let uri = http::uri(&config.url)?;
+
+        Ok(WebhookAuth {
+               uri: uri,
+               timeout: config.timeout,
+               salt: rand::random(),
+               clock: clock,
+               client: RefCell::new(None),
+               cache: Rc::new(RefCell::new(Cache {
+                                               answers: HashMap::new(),
+                                               ttl: config.cache_ttl,
+                                               size: config.cache_size,
+                                           })),
+           })
+    }
+
+    fn key(&self, client_id: &str, username: Option<&str>, password: Option<&str>, addr: SocketAddr) -> Key {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.salt);
+        // lengths keep the fields apart
+        for field in &[Some(client_id), username, password] {
+            match *field {
+                Some(field) => {
+                    hasher.update(&(field.len() as u64 + 1).to_be_bytes());
+                    hasher.update(field.as_bytes());
+                }
+                None => hasher.update(&0u64.to_be_bytes()),
+            }
+        }
+        hasher.update(addr.ip().to_string().as_bytes());
+
+        let mut key = [0; 32];
+        key.copy_from_slice(&hasher.finalize());
+        key
+    }
+}
+
+impl Authenticator for WebhookAuth {
+    fn authenticate(&self, client_id: &str, username: Option<&str>, password: Option<&str>, addr: SocketAddr, handle: &Handle) -> Verification {
+        let key = self.key(client_id, username, password, addr);
+        if let Some(code) = self.cache.borrow().get(&key, self.clock.now()) {
+            return Verdict::from(code).ready();
+        }
+
+        let client = self.client
+            .borrow_mut()
+            .get_or_insert_with(|| Client::new(handle))
+            .clone();
+        let body = request_body(client_id, username, password, addr);
+        let status = http::send(&client, self.uri.clone(), body, self.timeout, handle);
+
+        let cache = self.cache.clone();
+        let clock = self.clock.clone();
+        let verification = status.then(move |answer| {
+            let code = match answer {
+                Ok(200) => ConnectReturnCode::Accepted,
+                Ok(401) => ConnectReturnCode::BadUsernamePassword,
+                Ok(403) => ConnectReturnCode::NotAuthorized,
+                // other answers, failed requests and the deadline passing. nothing definite to cache
+                _ => return Ok(Verdict::from(ConnectReturnCode::ServerUnavailable)),
+            };
+
+            cache.borrow_mut().insert(key, code, clock.now());
+            Ok::<_, ()>(Verdict::from(code))
+        });
+        Box::new(verification)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use mqtt3::ConnectReturnCode;
+    use tokio_core::reactor::Core;
+    use auth::Authenticator;
+    use clock::ManualClock;
+    use http::check_url;
+    use super::{request_body, WebhookAuth, WebhookConfig};
+
+    /// Head and body of the request on `stream`
+    fn read_request(stream: &mut TcpStream) -> String {
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            let text = String::from_utf8_lossy(&request).into_owned();
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text.lines()
+                    .find(|line| line.to_lowercase().starts_with("content-length:"))
+                    .and_then(|line| line[15..].trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                if request.len() >= end + 4 + length {
+                    return text;
+                }
+            }
+            match stream.read(&mut buf).unwrap() {
+                0 => return text,
+                n => request.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    #[test]
+    fn endpoint_answers_are_honored_and_cached() {
+        assert!(check_url("http://auth:8080").is_ok());
+        assert!(check_url("https://auth/connect").is_err());
+        assert!(check_url("auth/connect").is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/mqtt/connect", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        {
+            let requests = requests.clone();
+            thread::spawn(move || for stream in listener.incoming() {
+                              let mut stream = stream.unwrap();
+                              let request = read_request(&mut stream);
+                              let status = if request.contains("\"password\":\"secret\"") {
+                                  "200 OK"
+                              } else if request.contains("\"username\":null") {
+                                  "403 Forbidden"
+                              } else {
+                                  "401 Unauthorized"
+                              };
+                              requests.lock().unwrap().push(request);
+                              write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+                          });
+        }
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let clock = Rc::new(ManualClock::new());
+        let mut config = WebhookConfig::new(&url);
+        config.cache_ttl = Duration::from_secs(10);
+        let auth = WebhookAuth::new(config, clock.clone()).unwrap();
+        let addr = "10.0.0.7:40000".parse().unwrap();
+        let mut code = |auth: &WebhookAuth, username, password| core.run(auth.authenticate("d7", username, password, addr, &handle)).unwrap().code;
+
+        assert_eq!(code(&auth, Some("device"), Some("secret")), ConnectReturnCode::Accepted);
+        assert_eq!(code(&auth, Some("device"), Some("guess")), ConnectReturnCode::BadUsernamePassword);
+        assert_eq!(code(&auth, None, None), ConnectReturnCode::NotAuthorized);
+        assert_eq!(code(&auth, Some("device"), Some("secret")), ConnectReturnCode::Accepted);
+        assert_eq!(requests.lock().unwrap().len(), 3);
+        assert!(requests.lock().unwrap()[0].starts_with("POST /mqtt/connect HTTP/1.1\r\n"));
+        assert!(requests.lock().unwrap()[0].ends_with(&request_body("d7", Some("device"), Some("secret"), addr)));
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(code(&auth, Some("device"), Some("secret")), ConnectReturnCode::Accepted);
+        assert_eq!(requests.lock().unwrap().len(), 4);
+
+        // nobody listening
+        let unreachable = WebhookAuth::new(WebhookConfig::new("http://127.0.0.1:1/"), clock.clone()).unwrap();
+        assert_eq!(code(&unreachable, Some("device"), Some("secret")), ConnectReturnCode::ServerUnavailable);
+
+        // connection taken but never answered
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = WebhookConfig::new(&format!("http://{}/", silent.local_addr().unwrap()));
+        config.timeout = Duration::from_millis(100);
+        let slow = WebhookAuth::new(config, clock).unwrap();
+        assert_eq!(code(&slow, Some("device"), Some("secret")), ConnectReturnCode::ServerUnavailable);
+    }
+}
@@ -0,0 +1,453 @@
+//! MQTT over WebSocket. The HTTP upgrade is answered by `HandshakeCodec`,
+//! after which `WsCodec` unwraps binary messages into the same packets the
+//! plain TCP listener decodes. MQTT packets aren't aligned to WebSocket
+//! frames, a frame can carry several packets or part of one. Pongs to the
+//! broker's liveness probes come out as PINGRESPs, which clients never send
+
+use std::io::{self, ErrorKind};
+use std::str;
+
+use bytes::{BufMut, BytesMut};
+use tokio_io::codec::{Encoder, Decoder};
+
+use mqtt3::Packet;
+use codec::{MqttCodec, Frame};
+
+/// Subprotocol MQTT clients ask for in the upgrade request
+pub const SUBPROTOCOL: &'static str = "mqtt";
+
+/// Appended to the client's key to get the accept key (RFC 6455 section 1.3)
+const ACCEPT_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Longest upgrade request read before giving up on the connection
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// Answer to an upgrade request
+#[derive(Debug, Clone, PartialEq)]
+pub enum Upgrade {
+    /// Switch to WebSocket. Holds the `Sec-WebSocket-Accept` value
+    Accept(String),
+    /// Answer with 400 and close. Holds the reason
+    Reject(String),
+}
+
+/// Reads the HTTP upgrade request and writes the response
+#[derive(Debug)]
+pub struct HandshakeCodec;
+
+impl Decoder for HandshakeCodec {
+    type Item = Upgrade;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Upgrade>> {
+        let end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(position) => position + 4,
+            None if buf.len() > MAX_REQUEST_SIZE => return Err(io::Error::new(ErrorKind::InvalidData, "WebSocket upgrade request too large")),
+            None => return Ok(None),
+        };
+
+        let request = buf.split_to(end);
+        let upgrade = match str::from_utf8(&request) {
+            Ok(request) => upgrade(request),
+            Err(_) => Upgrade::Reject("upgrade request isn't valid UTF-8".to_owned()),
+        };
+
+        Ok(Some(upgrade))
+    }
+}
+
+impl Encoder for HandshakeCodec {
+    type Item = Upgrade;
+    type Error = io::Error;
+
+    fn encode(&mut self, upgrade: Upgrade, buf: &mut BytesMut) -> io::Result<()> {
+        let response = match upgrade {
+            Upgrade::Accept(key) => {
+                format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\
+                         Sec-WebSocket-Protocol: {}\r\n\r\n",
+                        key,
+                        SUBPROTOCOL)
+            }
+            Upgrade::Reject(reason) => {
+                format!("HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+                        reason.len(),
+                        reason)
+            }
+        };
+
+        buf.extend_from_slice(response.as_bytes());
+        Ok(())
+    }
+}
+
+/// Checks the upgrade request. Any path is accepted but the client has to
+/// offer the `mqtt` subprotocol
+pub fn upgrade(request: &str) -> Upgrade {
+    let mut lines = request.split("\r\n");
+
+    match lines.next().map(|line| line.split(' ').collect::<Vec<_>>()) {
+        Some(ref line) if line.len() == 3 && line[0] == "GET" && line[2] == "HTTP/1.1" => (),
+        _ => return Upgrade::Reject("expected a GET request over HTTP/1.1".to_owned()),
+    }
+
+    let mut key = None;
+    let mut upgrade = false;
+    let mut connection = false;
+    let mut version = false;
+    let mut protocol = false;
+
+    for line in lines.filter(|line| !line.is_empty()) {
+        let (name, value) = match line.find(':') {
+            Some(i) => (line[..i].trim().to_lowercase(), line[i + 1..].trim()),
+            None => return Upgrade::Reject(format!("malformed header {:?}", line)),
+        };
+
+        let has_token = |token: &str| value.split(',').any(|v| v.trim().eq_ignore_ascii_case(token));
+        match name.as_str() {
+            "upgrade" => upgrade = has_token("websocket"),
+            "connection" => connection = has_token("upgrade"),
+            "sec-websocket-version" => version = value == "13",
+            "sec-websocket-protocol" => protocol = protocol || has_token(SUBPROTOCOL),
+            "sec-websocket-key" => key = Some(value.to_owned()),
+            _ => (),
+        }
+    }
+
+    if !upgrade || !connection {
+        return Upgrade::Reject("not a WebSocket upgrade".to_owned());
+    }
+
+    if !version {
+        return Upgrade::Reject("unsupported WebSocket version. Expected 13".to_owned());
+    }
+
+    if !protocol {
+        return Upgrade::Reject(format!("the {:?} subprotocol wasn't offered", SUBPROTOCOL));
+    }
+
+    match key {
+        Some(key) => Upgrade::Accept(accept_key(&key)),
+        None => Upgrade::Reject("missing Sec-WebSocket-Key".to_owned()),
+    }
+}
+
+/// `Sec-WebSocket-Accept` value for a client's `Sec-WebSocket-Key`
+pub fn accept_key(key: &str) -> String {
+    let mut input = key.as_bytes().to_vec();
+    input.extend_from_slice(ACCEPT_GUID.as_bytes());
+    base64(&sha1(&input))
+}
+
+/// MQTT over WebSocket binary messages. Client frames have to be masked,
+/// frames sent by the broker aren't
+pub struct WsCodec {
+    mqtt: MqttCodec,
+    max_packet_size: usize,
+    /// Unmasked message payload that isn't a complete packet yet
+    payload: BytesMut,
+    /// Ping payloads to answer. Pongs go out ahead of the next packet, at the
+    /// latest with the answer to the client's PINGREQ
+    pongs: Vec<BytesMut>,
+}
+
+impl WsCodec {
+    pub fn new(max_packet_size: usize) -> Self {
+        WsCodec {
+            mqtt: MqttCodec::new(max_packet_size),
+            max_packet_size: max_packet_size,
+            payload: BytesMut::new(),
+            pongs: Vec::new(),
+        }
+    }
+}
+
+impl Decoder for WsCodec {
+    type Item = Packet;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Packet>> {
+        loop {
+            if packet_complete(&self.payload) {
+                if let Some(packet) = self.mqtt.decode(&mut self.payload)? {
+                    return Ok(Some(packet));
+                }
+            }
+
+            let (opcode, payload) = match read_frame(buf, self.max_packet_size)? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+
+            match opcode {
+                OP_BINARY | OP_CONTINUATION => {
+                    if self.payload.len() + payload.len() > self.max_packet_size {
+                        return Err(io::Error::new(ErrorKind::InvalidData, "Packet exceeds maximum packet size"));
+                    }
+                    self.payload.extend_from_slice(&payload);
+                }
+                OP_PING => self.pongs.push(payload),
+                OP_PONG => return Ok(Some(Packet::Pingresp)),
+                OP_TEXT => return Err(io::Error::new(ErrorKind::InvalidData, "MQTT over WebSocket needs binary messages")),
+                OP_CLOSE => return Err(io::Error::new(ErrorKind::ConnectionAborted, "WebSocket closed by client")),
+                _ => return Err(io::Error::new(ErrorKind::InvalidData, "Reserved WebSocket opcode")),
+            }
+        }
+    }
+}
+
+impl Encoder for WsCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: Frame, buf: &mut BytesMut) -> io::Result<()> {
+        for pong in self.pongs.drain(..) {
+            write_frame(OP_PONG, &pong, buf);
+        }
+
+        if msg == Frame::Probe {
+            write_frame(OP_PING, &[], buf);
+            return Ok(());
+        }
+
+        let mut packet = BytesMut::new();
+        self.mqtt.encode(msg, &mut packet)?;
+        write_frame(OP_BINARY, &packet, buf);
+        Ok(())
+    }
+}
+
+/// True if `payload` starts with a whole MQTT packet. The packet decoder
+/// treats a partial packet as an error
+fn packet_complete(payload: &[u8]) -> bool {
+    let mut len = 0;
+    for (i, byte) in payload.iter().skip(1).take(4).enumerate() {
+        len += ((byte & 0x7F) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return payload.len() >= 1 + (i + 1) + len;
+        }
+    }
+
+    // the decoder rejects a remaining length longer than 4 bytes
+    payload.len() >= 5
+}
+
+/// Takes the next complete frame off `buf` and returns its opcode and unmasked payload
+fn read_frame(buf: &mut BytesMut, max_payload: usize) -> io::Result<Option<(u8, BytesMut)>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+
+    let opcode = buf[0] & 0x0F;
+    let fin = buf[0] & 0x80 != 0;
+
+    // no extensions are negotiated so the reserved bits have to be clear
+    if buf[0] & 0x70 != 0 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Reserved WebSocket bits set"));
+    }
+
+    if buf[1] & 0x80 == 0 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Unmasked WebSocket frame from client"));
+    }
+
+    let (len, header) = match buf[1] & 0x7F {
+        126 if buf.len() >= 4 => (((buf[2] as usize) << 8) | buf[3] as usize, 4),
+        127 if buf.len() >= 10 => (buf[2..10].iter().fold(0u64, |len, &b| (len << 8) | b as u64) as usize, 10),
+        126 | 127 => return Ok(None),
+        len => (len as usize, 2),
+    };
+
+    // control frames can't be fragmented and carry at most 125 bytes
+    if opcode & 0x8 != 0 && (!fin || len > 125) {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Invalid WebSocket control frame"));
+    }
+
+    if len > max_payload {
+        return Err(io::Error::new(ErrorKind::InvalidData, "WebSocket frame exceeds maximum packet size"));
+    }
+
+    if buf.len() < header + 4 + len {
+        return Ok(None);
+    }
+
+    let frame = buf.split_to(header + 4 + len);
+    let mask = [frame[header], frame[header + 1], frame[header + 2], frame[header + 3]];
+    let mut payload = BytesMut::from(&frame[header + 4..]);
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    Ok(Some((opcode, payload)))
+}
+
+/// Writes a single unmasked frame
+fn write_frame(opcode: u8, payload: &[u8], buf: &mut BytesMut) {
+    buf.reserve(10 + payload.len());
+    buf.put_u8(0x80 | opcode);
+
+    if payload.len() < 126 {
+        buf.put_u8(payload.len() as u8);
+    } else if payload.len() <= 0xFFFF {
+        buf.put_u8(126);
+        buf.put_u16_be(payload.len() as u16);
+    } else {
+        buf.put_u8(127);
+        buf.put_u64_be(payload.len() as u64);
+    }
+
+    buf.extend_from_slice(payload);
+}
+
+fn sha1(input: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut message = input.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    for i in (0..8).rev() {
+        message.push(((input.len() as u64 * 8) >> (i * 8)) as u8);
+    }
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = (block[i * 4] as u32) << 24 | (block[i * 4 + 1] as u32) << 16 | (block[i * 4 + 2] as u32) << 8 | block[i * 4 + 3] as u32;
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+
+            let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut digest = [0u8; 20];
+    for (i, word) in h.iter().enumerate() {
+        for j in 0..4 {
+            digest[i * 4 + j] = (word >> (24 - j * 8)) as u8;
+        }
+    }
+    digest
+}
+
+fn base64(input: &[u8]) -> String {
+    const ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut output = String::new();
+    for chunk in input.chunks(3) {
+        let n = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(ALPHABET[(n >> (18 - i * 6)) as usize & 0x3F] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+    use tokio_io::codec::{Encoder, Decoder};
+    use mqtt3::{Packet, PacketIdentifier};
+    use codec::{self, Frame};
+    use super::{accept_key, upgrade, Upgrade, WsCodec};
+
+    fn masked(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn upgrade_needs_the_mqtt_subprotocol() {
+        // sample handshake from RFC 6455
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        let request = "GET /mqtt HTTP/1.1\r\nHost: broker\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n";
+        assert_eq!(upgrade(&format!("{}Sec-WebSocket-Protocol: mqtt\r\n\r\n", request)),
+                   Upgrade::Accept("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_owned()));
+
+        match upgrade(&format!("{}Sec-WebSocket-Protocol: chat\r\n\r\n", request)) {
+            Upgrade::Reject(_) => (),
+            v => panic!("{:?}", v),
+        }
+
+        match upgrade("GET / HTTP/1.1\r\nHost: broker\r\n\r\n") {
+            Upgrade::Reject(_) => (),
+            v => panic!("{:?}", v),
+        }
+    }
+
+    #[test]
+    fn packets_are_framed_independent_of_websocket_frames() {
+        let mut codec = WsCodec::new(1024);
+        let puback = codec::encode(&Packet::Puback(PacketIdentifier(7))).unwrap();
+        let mut packets = puback.to_vec();
+        packets.extend_from_slice(&codec::encode(&Packet::Pingreq).unwrap());
+
+        // first packet split across two frames, the second packet follows the split
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&masked(0x2, &packets[..1]));
+        buf.extend_from_slice(&masked(0x9, b"hi"));
+        buf.extend_from_slice(&masked(0x0, &packets[1..]));
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Packet::Puback(PacketIdentifier(7))));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Packet::Pingreq));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        // the ping is answered ahead of the next packet
+        let mut out = BytesMut::new();
+        codec.encode(Frame::Packet(Packet::Pingresp), &mut out).unwrap();
+        assert_eq!(&out[..], &[0x8A, 2, b'h', b'i', 0x82, 2, 0xD0, 0]);
+
+        // text and unmasked frames are protocol errors
+        assert!(codec.decode(&mut BytesMut::from(masked(0x1, b"{}"))).is_err());
+        assert!(WsCodec::new(1024).decode(&mut BytesMut::from(vec![0x82, 2, 0xC0, 0])).is_err());
+    }
+
+    #[test]
+    fn probes_are_pings_answered_by_pongs() {
+        let mut codec = WsCodec::new(1024);
+        let mut out = BytesMut::new();
+        codec.encode(Frame::Probe, &mut out).unwrap();
+        assert_eq!(&out[..], &[0x89, 0]);
+
+        let mut buf = BytesMut::from(masked(0xA, b""));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Packet::Pingresp));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+}
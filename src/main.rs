@@ -1,86 +1,1023 @@
+extern crate rumqttd_core;
+#[macro_use]
+extern crate clap;
 extern crate mqtt3;
 extern crate futures;
 extern crate tokio_core;
 extern crate tokio_io;
 extern crate tokio_timer;
-extern crate bytes;
 #[macro_use]
 extern crate slog;
-extern crate slog_term;
-extern crate slog_async;
 #[macro_use]
-extern crate quick_error;
+extern crate serde_json;
+#[cfg(unix)]
+extern crate libc;
 
-pub mod error;
-pub mod codec;
-pub mod broker;
-pub mod client;
+pub mod startup;
+#[cfg(unix)]
+mod signals;
 
 use std::io;
-use std::sync::Arc;
-use std::time::Duration;
+use std::env;
+use std::process;
+use std::cmp;
+use std::net::SocketAddr;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+#[cfg(feature = "metrics")]
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use mqtt3::*;
-use tokio_core::reactor::Core;
-use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Core, Handle};
+use tokio_core::net::{TcpListener, TcpStream, UdpCodec, UdpSocket};
 use tokio_io::AsyncRead;
+use tokio_io::codec::Framed;
 use tokio_timer::{Timer, Interval};
 
 use futures::stream::Stream;
-use futures::Future;
-use futures::sync::mpsc;
+use futures::{future, Future, Sink};
+use futures::future::{Either, Loop};
+use futures::sync::{mpsc, oneshot};
+
+
+use rumqttd_core::{client, codec, connect, debounce, logging};
+use rumqttd_core::{Broker, Client, DisconnectReason, Error, ListenerKind, Verdict};
+use rumqttd_core::codec::{MqttCodec, Frame, Transport};
+#[cfg(feature = "tls")]
+use rumqttd_core::tls::{self, TlsAcceptor};
+#[cfg(feature = "websocket")]
+use rumqttd_core::ws::{HandshakeCodec, Upgrade, WsCodec};
+use rumqttd_core::listener::{ListenerSlots, Slot, Socket};
+use rumqttd_core::leaf::{LEAF_KEEP_ALIVE, RECONNECT_DELAY, UPLINK_CLIENT_ID};
+use rumqttd_core::bridge::{self, Backoff, BRIDGE_CLIENT_ID};
+use rumqttd_core::selftest::SelfTest;
+use rumqttd_core::sim::Simulation;
+use rumqttd_core::sn::{self, SnGateway, SnPacket};
+#[cfg(feature = "connectors")]
+use rumqttd_core::hook::MessageHook;
+use rumqttd_core::observer::PRIMARY_CLIENT_ID;
+use rumqttd_core::fair::{self, Budgeted};
+#[cfg(feature = "password-file")]
+use rumqttd_core::passwd::RELOAD_INTERVAL as PASSWORD_RELOAD_INTERVAL;
+#[cfg(feature = "enrichment")]
+use rumqttd_core::enrich::Enricher;
+#[cfg(feature = "kafka")]
+use rumqttd_core::kafka::KafkaSink;
+use startup::{Args, LogFormat, StartupReport};
+
+type Connection = Framed<Socket, Transport>;
+
+/// Builds without TLS have no handshakes to set up
+#[cfg(not(feature = "tls"))]
+enum TlsAcceptor {}
+
+/// Accepted socket that's ready for the CONNECT, holding its place on the listener
+type Accepted = Box<Future<Item = (Connection, SocketAddr, Slot), Error = io::Error>>;
+
+/// Connection past its CONNECT with the client, its outgoing queue and its place on the listener
+type Welcome = (Connection, Client, mpsc::Receiver<Frame>, Slot);
+
+/// How often a shutdown checks for open flows
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often startup checks whether the write ahead log has been read
+#[cfg(feature = "persistence")]
+const WAL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often persistent sessions past `session_expiry` are discarded
+const SESSION_EXPIRY_TICK: Duration = Duration::from_secs(1);
+
+type Handshake = Box<Future<Item = Welcome, Error = io::Error>>;
+
+/// Datagrams of the MQTT-SN gateway. Decoding is left to the gateway, which
+/// drops what it can't read
+struct SnCodec;
+
+impl UdpCodec for SnCodec {
+    type In = (SocketAddr, Vec<u8>);
+    type Out = (SocketAddr, SnPacket);
+
+    fn decode(&mut self, src: &SocketAddr, buf: &[u8]) -> io::Result<Self::In> {
+        Ok((*src, buf.to_vec()))
+    }
+
+    fn encode(&mut self, (dst, packet): Self::Out, buf: &mut Vec<u8>) -> SocketAddr {
+        buf.extend_from_slice(&sn::encode(&packet));
+        dst
+    }
+}
+
+/// Answers the WebSocket upgrade request and switches the socket to MQTT over WebSocket
+#[cfg(feature = "websocket")]
+fn upgrade(socket: Socket, addr: SocketAddr, slot: Slot, max_packet_size: usize) -> Accepted {
+    let upgrade = socket
+        .framed(HandshakeCodec)
+        .into_future()
+        .map_err(|(e, _)| e)
+        .and_then(move |(upgrade, framed)| {
+            let upgrade = match upgrade {
+                Some(upgrade) => upgrade,
+                None => return Either::A(future::err(io::Error::new(io::ErrorKind::UnexpectedEof, "Closed before the WebSocket upgrade"))),
+            };
+
+            let refused = match upgrade {
+                Upgrade::Reject(ref reason) => Some(format!("Refusing WebSocket upgrade from {}. Reason = {}", addr, reason)),
+                Upgrade::Accept(_) => None,
+            };
+
+            let switched = framed
+                .send(upgrade)
+                .and_then(move |framed| match refused {
+                              Some(reason) => Err(io::Error::new(io::ErrorKind::Other, reason)),
+                              None => {
+                                  let codec = Transport::WebSocket(WsCodec::new(max_packet_size));
+                                  Ok((Framed::from_parts(framed.into_parts(), codec), addr, slot))
+                              }
+                          });
+            Either::B(switched)
+        });
+
+    Box::new(upgrade)
+}
 
-use slog::{Logger, Drain};
+/// Answers the CONNECT with a refusal and fails the handshake
+fn refuse(framed: Connection, code: ConnectReturnCode, reason: String) -> Handshake {
+    let connack = Packet::Connack(Connack {
+                                      session_present: false,
+                                      code: code,
+                                  });
 
-use client::Client;
-use broker::Broker;
-use codec::MqttCodec;
-use error::Error;
+    let refused = framed
+        .send(Frame::Packet(connack))
+        .and_then(move |_| Err(io::Error::new(io::ErrorKind::Other, reason)));
+    Box::new(refused)
+}
+
+/// Handles a CONNECT for a client id that is already connected. The existing
+/// connection is probed and given `grace` to answer, which guards against
+/// false takeovers after NAT rebinding. If it stays silent, the new connection
+/// takes over. Otherwise the new connection is refused
+fn takeover(timer: &Timer, grace: Duration, broker: Broker, existing: Client, welcome: Welcome) -> Handshake {
+    if !broker.probe_for_takeover(&existing) {
+        broker.connect(welcome.1.clone());
+        return Box::new(future::ok(welcome));
+    }
+
+    let handshake = timer
+        .sleep(grace)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        .and_then(move |_| -> Handshake {
+            if broker.survives_takeover(&existing) {
+                refuse(welcome.0,
+                       ConnectReturnCode::RefusedIdentifierRejected,
+                       "Existing connection is still alive. Refusing takeover".to_owned())
+            } else {
+                broker.connect(welcome.1.clone());
+                Box::new(future::ok(welcome))
+            }
+        });
+
+    Box::new(handshake)
+}
+
+/// What sets the upstream links apart: the leaf's link to its hub, the
+/// bridge's link to the remote broker and the observer's link to its primary
+struct Upstream {
+    /// The other end in logs, e.g. `hub`
+    name: &'static str,
+    address: SocketAddr,
+    client_id: &'static str,
+    connect: Packet,
+    /// Sent once the other end accepts the connection
+    subscribe: Option<Packet>,
+    /// Makes the connected link's client known to the broker
+    register: fn(&Broker, Client),
+    /// Handles a packet from the other end
+    incoming: fn(&Broker, Packet, &Client),
+}
+
+/// Wait before an upstream link is connected again
+enum Reconnect {
+    Fixed(Duration),
+    /// Growing while the other end is unreachable, starting over once it accepts
+    Backoff(Backoff),
+}
+
+impl Reconnect {
+    fn next(&mut self) -> Duration {
+        match *self {
+            Reconnect::Fixed(delay) => delay,
+            Reconnect::Backoff(ref mut backoff) => backoff.next(),
+        }
+    }
+
+    fn reset(&mut self) {
+        if let Reconnect::Backoff(ref mut backoff) = *self {
+            backoff.reset();
+        }
+    }
+}
+
+/// Packets from a hub or a remote broker
+fn handle_upstream(broker: &Broker, packet: Packet, client: &Client) {
+    match packet {
+        Packet::Publish(p) => broker.handle_publish(p, client),
+        Packet::Puback(pkid) => broker.handle_puback(pkid, client),
+        Packet::Pubrec(pkid) => broker.handle_pubrec(pkid, client),
+        Packet::Pubrel(pkid) => broker.handle_pubrel(pkid, client),
+        Packet::Pubcomp(pkid) => broker.handle_pubcomp(pkid, client),
+        // subscription acks and ping responses
+        _ => (),
+    }
+}
+
+/// Packets from the primary of an observer. Only its mirrored publishes matter
+fn handle_primary(broker: &Broker, packet: Packet, client: &Client) {
+    if let Packet::Publish(p) = packet {
+        broker.handle_mirrored(p, client);
+    }
+}
+
+/// One connection of an upstream link. Resolves once the connection is gone
+fn upstream_link(handle: &Handle, timer: &Timer, broker: Broker, upstream: Upstream, reconnect: Rc<RefCell<Reconnect>>) -> Box<Future<Item = (), Error = io::Error>> {
+    let handle = handle.clone();
+    let timer = timer.clone();
+    let Upstream { name, address, client_id, connect, subscribe, register, incoming } = upstream;
+    let max_packet_size = broker.config().max_packet_size;
+    let outgoing_queue_size = broker.config().outgoing_queue_size;
+
+    let link = TcpStream::connect(&address, &handle)
+        .and_then(move |socket| {
+                      socket
+                          .framed(MqttCodec::new(max_packet_size))
+                          .send(Frame::Packet(connect))
+                  })
+        .and_then(|framed| framed.into_future().map_err(|(e, _)| e))
+        .and_then(move |(packet, framed)| {
+            match packet {
+                Some(Packet::Connack(ref connack)) if connack.code == ConnectReturnCode::Accepted => (),
+                packet => {
+                    let e = io::Error::new(io::ErrorKind::Other, format!("The {} refused the connection. Reply = {:?}", name, packet));
+                    return Either::A(future::err(e));
+                }
+            }
+            reconnect.borrow_mut().reset();
+
+            let (tx, rx) = client::outgoing_queue(outgoing_queue_size);
+            let client = Client::with_clock(client_id, address, tx, broker.clock());
+            client.set_keep_alive(LEAF_KEEP_ALIVE);
+            register(&broker, client.clone());
+            if let Some(subscribe) = subscribe {
+                if let Err(e) = client.send(subscribe) {
+                    broker.remove_client(client_id);
+                    return Either::A(future::err(io::Error::new(io::ErrorKind::Other, e.to_string())));
+                }
+            }
+
+            // pings keep the link alive at the other end. one that stops answering is given up on
+            {
+                let broker = broker.clone();
+                let client = client.clone();
+
+                let timer_future = timer
+                    .interval(Duration::from_secs(LEAF_KEEP_ALIVE as u64 / 2))
+                    .map_err(|e| Error::from(e))
+                    .for_each(move |_| if broker.check_keep_alive(&client) && client.send(Packet::Pingreq).is_ok() {
+                                  Ok(())
+                              } else {
+                                  Err(Error::Other)
+                              })
+                    .then(|_| Ok(()));
+
+                handle.spawn(timer_future);
+            }
+
+            if let Some(interval) = broker.config().retransmit_interval {
+                let broker = broker.clone();
+                let client = client.clone();
+
+                let timer_future = timer
+                    .interval(interval)
+                    .map_err(|e| Error::from(e))
+                    .for_each(move |_| if broker.retransmit(&client) {
+                                  Ok(())
+                              } else {
+                                  Err(Error::Other)
+                              })
+                    .then(|_| Ok(()));
+
+                handle.spawn(timer_future);
+            }
+
+            let (kill_tx, kill_rx) = oneshot::channel::<()>();
+            client.set_kill_switch(kill_tx);
+
+            let (sender, receiver) = framed.split();
+            let rx = Budgeted::new(rx, broker.config().write_budget(client_id));
+            let tx_future = rx.map_err(|_| Error::Other).forward(sender).then(|_| Ok(()));
+            handle.spawn(tx_future);
+
+            let broker2 = broker.clone();
+            let client2 = client.clone();
+
+            let rx_future = receiver
+                .for_each(move |packet| {
+                              client.update_activity();
+                              incoming(&broker, packet, &client);
+                              Ok(())
+                          })
+                .select2(kill_rx)
+                .then(move |e| {
+                          broker2.handle_network_disconnect(&client2);
+                          match e {
+                              Err(Either::A((e, _))) => Err(e),
+                              _ => Ok(()),
+                          }
+                      });
+
+            Either::B(rx_future)
+        });
+
+    Box::new(link)
+}
+
+/// Keeps an upstream link connected, connecting again after `reconnect`
+/// whenever it drops. `upstream` describes the link for every attempt
+fn upstream_links<F>(handle: &Handle, timer: &Timer, broker: Broker, reconnect: Reconnect, upstream: F) -> Box<Future<Item = (), Error = ()>>
+    where F: Fn(&Broker) -> Upstream + 'static
+{
+    let handle = handle.clone();
+    let timer = timer.clone();
+    let logger = broker.logger();
+    let reconnect = Rc::new(RefCell::new(reconnect));
+
+    let links = future::loop_fn((), move |_| {
+        let timer = timer.clone();
+        let logger = logger.clone();
+        let reconnect = reconnect.clone();
+        let upstream = upstream(&broker);
+        let (name, address) = (upstream.name, upstream.address);
+
+        upstream_link(&handle, &timer, broker.clone(), upstream, reconnect.clone()).then(move |result| {
+            let wait = reconnect.borrow_mut().next();
+            match result {
+                Ok(()) => warn!(logger, "Upstream link closed. Reconnecting in {:?}. Link = {}, Address = {}", wait, name, address),
+                Err(e) => warn!(logger, "Lost the upstream link. Reconnecting in {:?}. Link = {}, Address = {}, Error = {}", wait, name, address, e),
+            }
+
+            timer.sleep(wait).then(|_| Ok::<_, ()>(Loop::Continue::<(), ()>(())))
+        })
+    });
+
+    Box::new(links)
+}
 
 fn main() {
+    // help and version end up here too, and exit successfully
+    let args = Args::parse(env::args()).unwrap_or_else(|e| e.exit());
+    let log_format = args.log_format;
+
+    let broker = match args.builder().and_then(|builder| builder.build().map_err(|e| e.to_string())) {
+        Ok(broker) => broker,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+
     let mut core = Core::new().unwrap();
     let handle = core.handle();
-    let address = "0.0.0.0:1883".parse().unwrap();
+    let logger = broker.logger();
+
+    let mut report = StartupReport::new();
+    for (name, limit) in broker.config().limits() {
+        report.setting(name, limit);
+    }
+    report.feature("catalog", broker.config().catalog);
+    report.feature("selftest", broker.config().selftest.is_some());
+    #[cfg(feature = "tls")]
+    report.feature("client_auth", broker.config().client_auth.is_some());
+    report.feature("leaf", broker.config().leaf.is_some());
+    report.feature("sn_gateway", broker.config().sn_gateway.is_some());
+    report.feature("bridge", broker.config().bridge.is_some());
+    report.feature("message_webhook", broker.config().message_webhook.is_some());
+    report.feature("observer", broker.config().observer.is_some());
+    report.feature("downgrade_report", broker.config().downgrade_report.is_some());
+    report.feature("wal", broker.config().wal_path.is_some());
+    report.feature("batch", broker.config().batch.is_some());
+    report.feature("debounce", !broker.config().debounce.is_empty());
+    report.feature("sys", broker.config().sys_interval.is_some());
+    #[cfg(feature = "metrics")]
+    report.feature("alerts", !broker.config().alerts.is_empty());
+    report.feature("transactions", broker.config().transactions.is_some());
+    report.feature("topic_registry", broker.config().topic_registry.is_some());
+    report.feature("password_file", broker.config().password_file.is_some());
+    #[cfg(feature = "webhook-auth")]
+    report.feature("webhook", broker.config().webhook.is_some());
+    #[cfg(feature = "jwt")]
+    report.feature("jwt", broker.config().jwt.is_some());
+    report.feature("simulation", broker.config().simulation.is_some());
+    report.feature("acl", broker.config().acl.is_some());
+    #[cfg(feature = "enrichment")]
+    report.feature("enrichment", broker.config().enrichment.is_some());
+    #[cfg(feature = "kafka")]
+    report.feature("kafka", broker.config().kafka.is_some());
+
+    // every listener is set up before any is served so the report covers all of them
+    let mut listeners = Vec::new();
+    let broker_config = broker.config();
+    for config in broker_config.listeners.iter() {
+        let bound: Result<(TcpListener, Option<TlsAcceptor>), String> = match config.kind {
+            ListenerKind::Tcp | ListenerKind::WebSocket => {
+                TcpListener::bind(&config.address, &core.handle()).map(|listener| (listener, None)).map_err(|e| e.to_string())
+            }
+            #[cfg(feature = "tls")]
+            ListenerKind::Tls => {
+                let client_auth = broker_config.client_auth.as_ref();
+                match broker_config.tls.as_ref().map(|tls| tls::acceptor(tls, client_auth)) {
+                    Some(Ok(acceptor)) => {
+                        TcpListener::bind(&config.address, &core.handle()).map(|listener| (listener, Some(acceptor))).map_err(|e| e.to_string())
+                    }
+                    Some(Err(e)) => Err(e.to_string()),
+                    None => Err("TLS listeners need a certificate".to_owned()),
+                }
+            }
+            kind => Err(format!("Unsupported listener {:?}", kind)),
+        };
+
+        report.listener(config.address, bound.as_ref().map(|_| ()).map_err(|e| e.clone()));
+        match bound {
+            Ok((listener, acceptor)) => listeners.push((ListenerSlots::with_total(config.clone(), broker.total_slots()), listener, acceptor)),
+            Err(e) => error!(logger, "Unable to start listener {}. Error = {}", config.address, e),
+        }
+    }
+
+    match log_format {
+        LogFormat::Json => println!("{}", report.to_json()),
+        LogFormat::Text => {
+            if report.ok() {
+                let addresses: Vec<String> = broker.config().listeners.iter().map(|l| l.address.to_string()).collect();
+                info!(logger, "rumqttd listening on {}. Config digest = {}", addresses.join(", "), report.digest());
+            }
+        }
+    }
+
+    if !report.ok() {
+        process::exit(1);
+    }
 
-    let decorator = slog_term::TermDecorator::new().build();
-    let drain = slog_term::CompactFormat::new(decorator).build().fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
-    let logger = Logger::root(Arc::new(drain), o!("version" => env!("CARGO_PKG_VERSION")));
+    let timer = Timer::default();
 
-    let listener = TcpListener::bind(&address, &core.handle()).unwrap();
+    // where clients connect from. the lookups run off the event loop
+    #[cfg(feature = "enrichment")]
+    let enricher = match broker.config().enrichment {
+        Some(ref config) => {
+            match Enricher::start(config, broker.clock()) {
+                Ok(enricher) => Some(enricher),
+                Err(e) => {
+                    error!(logger, "Unable to start enrichment. Error = {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+
+    // publishes POSTed to the message webhook. the requests run off the event loop
+    #[cfg(feature = "connectors")]
+    {
+        if let Some(ref url) = broker.config().message_webhook {
+            match MessageHook::start(url, broker.config().message_webhook_retries, logger.clone()) {
+                Ok(hook) => broker.set_message_hook(hook),
+                Err(e) => {
+                    error!(logger, "Unable to start the message webhook. Error = {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+
+    // publishes mirrored into kafka. the producer runs off the event loop
+    #[cfg(feature = "kafka")]
+    {
+        if let Some(ref config) = broker.config().kafka {
+            match KafkaSink::start(config, logger.clone()) {
+                Ok(sink) => broker.set_kafka_sink(sink),
+                Err(e) => {
+                    error!(logger, "Unable to start the kafka sink. Error = {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+
+    // end to end canary through an internal subscriber
+    if let Some(config) = broker.config().selftest {
+        let (selftest, rx) = SelfTest::new(broker.clone(), config);
+        let selftest = Rc::new(RefCell::new(selftest));
+
+        let probes = selftest.clone();
+        let timer_future = timer
+            .interval(config.interval)
+            .map_err(|e| Error::from(e))
+            .for_each(move |_| Ok(probes.borrow_mut().probe()))
+            .then(|_| Ok(()));
+        handle.spawn(timer_future);
+
+        let rx_future = rx.for_each(move |frame| {
+                                         selftest.borrow_mut().receive(frame);
+                                         Ok(())
+                                     });
+        handle.spawn(rx_future);
+    }
+
+    // virtual clients for demos and reproductions
+    if let Some(config) = broker.config().simulation.clone() {
+        info!(logger, "Simulating {} clients", config.clients());
+        let tick = config.tick;
+        let simulation = Rc::new(RefCell::new(Simulation::new(broker.clone(), config)));
+
+        let timer_future = timer
+            .interval(tick)
+            .map_err(|e| Error::from(e))
+            .for_each(move |_| Ok(simulation.borrow_mut().tick()))
+            .then(|_| Ok(()));
+        handle.spawn(timer_future);
+    }
+
+    // sensors publish into the same topic space over MQTT-SN
+    if let Some(addr) = broker.config().sn_gateway {
+        match UdpSocket::bind(&addr, &handle) {
+            Ok(socket) => {
+                info!(logger, "MQTT-SN gateway listening on {}", addr);
+                let gateway = Rc::new(RefCell::new(SnGateway::new(broker.clone(), handle.clone())));
+                let (sink, datagrams) = socket.framed(SnCodec).split();
+                let (answers_tx, answers_rx) = mpsc::unbounded();
+
+                // CONNECTs wait for the authenticator, so every datagram is answered from a task of its own
+                let received = {
+                    let gateway = gateway.clone();
+                    let tasks = handle.clone();
+                    datagrams.for_each(move |(from, datagram)| {
+                        let answers = answers_tx.clone();
+                        let answer = SnGateway::handle(&gateway, from, &datagram).map(move |answer| if let Some(answer) = answer {
+                                                                                          let _ = answers.unbounded_send((from, answer));
+                                                                                      });
+                        tasks.spawn(answer);
+                        Ok(())
+                    })
+                };
+                let error_logger = logger.clone();
+                handle.spawn(received.map_err(move |e| error!(error_logger, "MQTT-SN gateway stopped. Error = {}", e)));
+
+                let answers = answers_rx.map_err(|()| io::Error::new(io::ErrorKind::Other, "Gateway closed"));
+                let error_logger = logger.clone();
+                handle.spawn(sink.send_all(answers)
+                                  .map(|_| ())
+                                  .map_err(move |e| error!(error_logger, "MQTT-SN gateway stopped. Error = {}", e)));
+
+                let logger = logger.clone();
+                let timer_future = timer
+                    .interval(sn::TICK)
+                    .map_err(|e| Error::from(e))
+                    .for_each(move |_| {
+                                  for id in gateway.borrow_mut().tick() {
+                                      info!(logger, "MQTT-SN sensor timed out. ID = {:?}", id);
+                                  }
+                                  Ok(())
+                              })
+                    .then(|_| Ok(()));
+                handle.spawn(timer_future);
+            }
+            Err(e) => error!(logger, "Unable to bind the MQTT-SN gateway on {}. Error = {}", addr, e),
+        }
+    }
+
+    // password file changes apply to new connections without a restart
+    #[cfg(feature = "password-file")]
+    if broker.config().password_file.is_some() {
+        let broker = broker.clone();
+        let logger = logger.clone();
+
+        let timer_future = timer
+            .interval(PASSWORD_RELOAD_INTERVAL)
+            .map_err(|e| Error::from(e))
+            .for_each(move |_| {
+                          match broker.reload_authenticator() {
+                              Ok(true) => info!(logger, "Reloaded the password file"),
+                              Ok(false) => (),
+                              Err(e) => error!(logger, "Unable to reload the password file. Error = {}", e),
+                          }
+                          Ok(())
+                      })
+            .then(|_| Ok(()));
+        handle.spawn(timer_future);
+    }
 
-    let broker = Broker::new();
+    // SIGHUP reads the settings file and flags again and applies what can
+    // change at runtime. Connected clients stay. SIGUSR1 dumps the broker's
+    // state for post-mortem debugging
+    #[cfg(unix)]
+    {
+        signals::install();
+        let broker = broker.clone();
+        let logger = logger.clone();
 
-    let welcomes = listener
-        .incoming()
-        .and_then(|(socket, addr)| {
-            let framed = socket.framed(MqttCodec);
+        let timer_future = timer
+            .interval(signals::POLL_INTERVAL)
+            .map_err(|e| Error::from(e))
+            .for_each(move |_| {
+                          #[cfg(feature = "admin")]
+                          if signals::take(&signals::DUMP) {
+                              if let Err(e) = broker.dump_state() {
+                                  error!(logger, "Unable to dump the broker state. Error = {}", e);
+                              }
+                          }
+                          if !signals::take(&signals::HANGUP) {
+                              return Ok(());
+                          }
+                          match args.builder().and_then(|builder| broker.reload(builder.into_config()).map_err(|e| e.to_string())) {
+                              Ok(reload) => {
+                                  info!(logger,
+                                        "Reloaded the configuration. Changed = {}, Needs restart = {:?}",
+                                        reload.applied.len(),
+                                        reload.needs_restart)
+                              }
+                              Err(e) => error!(logger, "Unable to reload the configuration. Error = {}", e),
+                          }
+                          Ok(())
+                      })
+            .then(|_| Ok(()));
+        handle.spawn(timer_future);
+    }
+
+    // batches that didn't fill up go out on the interval
+    if let Some(batch) = broker.config().batch {
+        let broker = broker.clone();
+
+        let timer_future = timer
+            .interval(batch.interval)
+            .map_err(|e| Error::from(e))
+            .for_each(move |_| Ok(broker.flush_batches()))
+            .then(|_| Ok(()));
+        handle.spawn(timer_future);
+    }
+
+    // held publishes of debounced topics go out once their interval is over.
+    // conflation counts are logged every report interval
+    if let Some(interval) = broker.config().debounce.iter().map(|rule| rule.interval).min() {
+        let debounced = broker.clone();
+
+        let timer_future = timer
+            .interval(interval)
+            .map_err(|e| Error::from(e))
+            .for_each(move |_| Ok(debounced.flush_debounced()))
+            .then(|_| Ok(()));
+        handle.spawn(timer_future);
+
+        let broker = broker.clone();
+        let logger = logger.clone();
+        let timer_future = timer
+            .interval(debounce::REPORT_INTERVAL)
+            .map_err(|e| Error::from(e))
+            .for_each(move |_| {
+                          for (topic, conflated) in broker.conflated() {
+                              info!(logger, "Conflated publishes. Topic = {}, Conflated = {}", topic, conflated);
+                          }
+                          Ok(())
+                      })
+            .then(|_| Ok(()));
+        handle.spawn(timer_future);
+    }
+
+    // $SYS time and keep alive diagnostics for device developers
+    #[cfg(feature = "metrics")]
+    if let Some(interval) = broker.config().sys_interval {
+        let broker = broker.clone();
+
+        let timer_future = timer
+            .interval(interval)
+            .map_err(|e| Error::from(e))
+            .for_each(move |_| {
+                          let unix_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                          broker.publish_diagnostics(unix_time);
+                          Ok(())
+                      })
+            .then(|_| Ok(()));
+        handle.spawn(timer_future);
+    }
+
+    // sessions of a write ahead log read in the background are restored as
+    // soon as it's loaded. connects and offline publishes that come first wait for it
+    #[cfg(feature = "persistence")]
+    if broker.wal_loading() {
+        let loading = broker.clone();
+        let broker = broker.clone();
+
+        let timer_future = timer
+            .interval(WAL_POLL_INTERVAL)
+            .map_err(|e| Error::from(e))
+            .take_while(move |_| Ok(loading.wal_loading()))
+            .for_each(|_| Ok(()))
+            .then(move |_| {
+                      broker.finish_wal_load();
+                      Ok(())
+                  });
+        handle.spawn(timer_future);
+    }
+
+    // connected persistent sessions go to the write ahead log as well
+    #[cfg(feature = "persistence")]
+    if let Some(interval) = broker.config().session_checkpoint {
+        let broker = broker.clone();
+
+        let timer_future = timer
+            .interval(interval)
+            .map_err(|e| Error::from(e))
+            .for_each(move |_| {
+                          broker.checkpoint_sessions();
+                          Ok(())
+                      })
+            .then(|_| Ok(()));
+        handle.spawn(timer_future);
+    }
+
+    // sessions are parked in order, so a tick only looks at the ones due
+    if broker.config().session_expiry.is_some() {
+        let broker = broker.clone();
+
+        let timer_future = timer
+            .interval(SESSION_EXPIRY_TICK)
+            .map_err(|e| Error::from(e))
+            .for_each(move |_| {
+                          broker.expire_sessions();
+                          Ok(())
+                      })
+            .then(|_| Ok(()));
+        handle.spawn(timer_future);
+    }
+
+    // alerting rules over broker metrics. the broker logs and sends out the
+    // alerts that fire or resolve
+    #[cfg(feature = "metrics")]
+    if !broker.config().alerts.is_empty() {
+        let broker = broker.clone();
+        let alert_handle = handle.clone();
+
+        let timer_future = timer
+            .interval(broker.config().alert_interval)
+            .map_err(|e| Error::from(e))
+            .for_each(move |_| {
+                          broker.check_alerts(&alert_handle);
+                          Ok(())
+                      })
+            .then(|_| Ok(()));
+        handle.spawn(timer_future);
+    }
+
+    // subscribers that stay behind on their outgoing queue
+    if let Some(slow) = broker.config().slow_consumer {
+        let broker = broker.clone();
+
+        let timer_future = timer
+            .interval(slow.interval())
+            .map_err(|e| Error::from(e))
+            .for_each(move |_| {
+                          broker.check_slow_consumers();
+                          Ok(())
+                      })
+            .then(|_| Ok(()));
+        handle.spawn(timer_future);
+    }
+
+    // topic/subscriber pairs whose qos never matches. usually a misconfigured device
+    if let Some(interval) = broker.config().downgrade_report {
+        let broker = broker.clone();
+        let logger = logger.clone();
+
+        let timer_future = timer
+            .interval(interval)
+            .map_err(|e| Error::from(e))
+            .for_each(move |_| {
+                          let (mismatches, total) = broker.downgrades();
+                          for m in mismatches {
+                              warn!(logger,
+                                    "Systematic qos downgrade. Topic = {}, Subscriber = {}, Published = {:?}, Granted = {:?}, Downgraded = {}/{}",
+                                    m.topic,
+                                    m.subscriber,
+                                    m.stats.published,
+                                    m.stats.granted,
+                                    m.stats.downgraded,
+                                    m.stats.forwarded);
+                          }
+                          info!(logger, "Qos downgrades so far = {}", total);
+                          Ok(())
+                      })
+            .then(|_| Ok(()));
+        handle.spawn(timer_future);
+    }
+
+    // leaf node. the hub is reconnected to whenever the link drops
+    if let Some(config) = broker.config().leaf.clone() {
+        let links = upstream_links(&handle, &timer, broker.clone(), Reconnect::Fixed(RECONNECT_DELAY), move |_| {
+            Upstream {
+                name: "hub",
+                address: config.hub,
+                client_id: UPLINK_CLIENT_ID,
+                connect: config.connect_packet(),
+                subscribe: None,
+                register: Broker::set_uplink,
+                incoming: handle_upstream,
+            }
+        });
+        handle.spawn(links);
+    }
 
+    // bridge. the remote broker is reconnected to with a growing wait while it's unreachable
+    if let Some(config) = broker.config().bridge.clone() {
+        let links = upstream_links(&handle, &timer, broker.clone(), Reconnect::Backoff(Backoff::new()), move |broker| {
+            Upstream {
+                name: "remote broker",
+                address: config.remote,
+                client_id: BRIDGE_CLIENT_ID,
+                connect: config.connect_packet(),
+                subscribe: bridge::subscribe_packet(&broker.config().bridge_topics),
+                register: Broker::set_bridge,
+                incoming: handle_upstream,
+            }
+        });
+        handle.spawn(links);
+    }
+
+    // read only observer. the primary is reconnected to whenever the link drops
+    if let Some(config) = broker.config().observer.clone() {
+        let links = upstream_links(&handle, &timer, broker.clone(), Reconnect::Fixed(RECONNECT_DELAY), move |_| {
+            Upstream {
+                name: "primary",
+                address: config.primary,
+                client_id: PRIMARY_CLIENT_ID,
+                connect: config.connect_packet(),
+                subscribe: Some(config.subscribe_packet()),
+                register: |broker, client| {
+                    broker.add_client(client);
+                },
+                incoming: handle_primary,
+            }
+        });
+        handle.spawn(links);
+    }
+
+    // all the listeners feed one pipeline. websocket connections join once upgraded
+    let mut incoming: Option<Box<Stream<Item = Accepted, Error = io::Error>>> = None;
+    for (slots, listener, acceptor) in listeners {
+        let logger = logger.clone();
+        let broker = broker.clone();
+        let kind = slots.config().kind;
+        let limiter = broker.clone();
+
+        let accepted = listener
+            .incoming()
+            .filter_map(move |(socket, addr)| {
+                if !limiter.admit_connection(addr) {
+                    debug!(logger, "Connect rate exceeded. Closing connection from {}", addr);
+                    return None;
+                }
+
+                match slots.admit() {
+                    Some(slot) => {
+                        if let Err(e) = socket.set_keepalive(slots.config().probe) {
+                            warn!(logger, "Unable to set TCP keep alive. Address = {}, Error = {}", addr, e);
+                        }
+                        Some((socket, addr, slot))
+                    }
+                    None if slots.total_full() => {
+                        warn!(logger, "Broker is at max_connections. Closing connection from {}", addr);
+                        None
+                    }
+                    None => {
+                        warn!(logger, "Listener {} is full. Closing connection from {}", slots.config().address, addr);
+                        None
+                    }
+                }
+            })
+            .map(move |(socket, addr, slot)| -> Accepted {
+                     // limits of connections follow reloads
+                     let max_packet_size = broker.config().max_packet_size;
+                     match (kind, acceptor.as_ref()) {
+                         #[cfg(feature = "tls")]
+                         (ListenerKind::Tls, Some(acceptor)) => {
+                             let handshake = acceptor
+                                 .accept(socket)
+                                 .map(move |socket| {
+                                          let framed = Socket::Tls(socket).framed(Transport::Tcp(MqttCodec::new(max_packet_size)));
+                                          (framed, addr, slot)
+                                      });
+                             Box::new(handshake)
+                         }
+                         #[cfg(feature = "websocket")]
+                         (ListenerKind::WebSocket, _) => upgrade(Socket::Plain(socket), addr, slot, max_packet_size),
+                         _ => {
+                             let framed = Socket::Plain(socket).framed(Transport::Tcp(MqttCodec::new(max_packet_size)));
+                             Box::new(future::ok((framed, addr, slot)))
+                         }
+                     }
+                 });
+
+        incoming = Some(match incoming {
+                            Some(incoming) => Box::new(incoming.select(accepted)),
+                            None => Box::new(accepted),
+                        });
+    }
+    let incoming = incoming.expect("validated config has a listener");
+
+    let welcomes = incoming
+        .map(|accepted| {
             let broker = broker.clone();
+            let timer = timer.clone();
+            let handle = handle.clone();
 
             // Creates a 'Self' from stream, whose error match to that of and_then's closure
-            let handshake = framed.into_future()
-                                  .map_err(|(err, _)| err) // for accept errors, get error and discard the stream
-                                  .and_then(move |(packet,framed)| { // only accepted connections from here
+            let handshake = accepted.and_then(move |(framed, addr, slot)| framed.into_future()
+                                  .then(move |first| -> Handshake { // only accepted connections from here
+
+                let (packet, framed) = match first {
+                    Ok(first) => first,
+                    // connect from a protocol level we don't support. tell the client before closing
+                    Err((ref e, framed)) if codec::is_unsupported_protocol_version(e) => {
+                        return refuse(framed, ConnectReturnCode::RefusedProtocolVersion, e.to_string());
+                    }
+                    // for accept errors, get error and discard the stream
+                    Err((e, _)) => return Box::new(future::err(e)),
+                };
 
                 let broker = broker.clone();
 
                 if let Some(Packet::Connect(c)) = packet {
-                    // TODO: Do connect packet validation here
-                    let (tx, rx) = mpsc::channel::<Packet>(100);
+                    let code = match connect::validate(&c, &broker.config()) {
+                        ConnectReturnCode::Accepted => connect::authorize(&c, slot.auth_required || !broker.config().allow_anonymous),
+                        code => code,
+                    };
 
-                    let client = Client::new(&c.client_id, addr, tx.clone());
-                    broker.add_client(client.clone());
+                    // mutual TLS connections go by the names in their certificate
+                    #[cfg(feature = "tls")]
+                    let peer = framed.get_ref().peer_certificate();
+                    #[cfg(feature = "tls")]
+                    let client_id = match (peer.as_ref(), broker.config().client_auth.as_ref()) {
+                        (Some(peer), Some(auth)) => connect::authenticate_peer(&c, peer, auth.identity),
+                        _ => Ok(c.client_id.clone()),
+                    };
+                    #[cfg(not(feature = "tls"))]
+                    let client_id: Result<String, ConnectReturnCode> = Ok(c.client_id.clone());
+                    let (code, client_id) = match (code, client_id) {
+                        (ConnectReturnCode::Accepted, Err(code)) => (code, c.client_id.clone()),
+                        (code, client_id) => (code, client_id.unwrap_or_else(|_| c.client_id.clone())),
+                    };
+
+                    let verification = match code {
+                        ConnectReturnCode::Accepted => broker.authenticate(&c, addr, &handle),
+                        code => Verdict::from(code).ready(),
+                    };
+
+                    // the rest of the handshake waits for the authenticator. other connections go on meanwhile
+                    Box::new(verification.then(move |verdict| -> Handshake {
+                        // a check that fell over can't let the client in
+                        let verdict = verdict.unwrap_or_else(|()| ConnectReturnCode::ServerUnavailable.into());
+                        if verdict.code != ConnectReturnCode::Accepted {
+                            let code = verdict.code;
+                            return refuse(framed, code, format!("Refusing connection from {:?}. Code = {:?}", client_id, code));
+                        }
 
-                    Ok((framed, client, rx))
+                        let (tx, rx) = client::outgoing_queue(broker.config().outgoing_queue_size);
+
+                        let client = Client::with_logger(&client_id, addr, tx.clone(), broker.clock(), &broker.logger());
+                        client.set_last_will(c.last_will.clone());
+                        client.set_keep_alive(c.keep_alive);
+                        client.set_clean_session(c.clean_session);
+                        client.set_mqtt31(connect::is_mqtt31(&c));
+                        client.set_username(c.username.clone());
+                        client.set_topic_prefixes(verdict.topic_prefixes);
+                        #[cfg(feature = "tls")]
+                        client.set_peer_certificate(peer);
+
+                        match (broker.get_client(&client_id), broker.config().takeover_grace) {
+                            (Some(existing), Some(grace)) => takeover(&timer, grace, broker, existing, (framed, client, rx, slot)),
+                            _ => {
+                                broker.connect(client.clone());
+                                Box::new(future::ok((framed, client, rx, slot)))
+                            }
+                        }
+                    }))
                 } else {
-                    Err(io::Error::new(io::ErrorKind::Other, "Invalid Handshake Packet"))
+                    Box::new(future::err(io::Error::new(io::ErrorKind::Other, "Invalid Handshake Packet")))
                 }
-            });
+            }));
 
             handshake
 
-        });
+        })
+        // don't let a slow handshake (or a takeover grace period) hold up other connections
+        .buffer_unordered(100);
 
     let server = welcomes
         .map(|w| Some(w))
@@ -94,33 +1031,103 @@ fn main() {
             let broker2 = broker.clone();
 
             // handle each connections n/w send and recv here
-            if let Some((framed, client, rx)) = handshake {
+            if let Some((framed, client, rx, slot)) = handshake {
                 let id1 = client.id.clone();
                 let id2 = client.id.clone();
+                let client2 = client.clone();
+                let client3 = client.clone();
 
                 let (sender, receiver) = framed.split();
 
-                let connack = Packet::Connack(Connack {
-                                                  session_present: false,
-                                                  code: ConnectReturnCode::Accepted,
-                                              });
+                // evict clients that go quiet for longer than their keep alive allows
+                if let Some(keep_alive) = client.keep_alive() {
+                    let broker = broker.clone();
+                    let client = client.clone();
+                    let period = cmp::max(keep_alive / 2, Duration::from_secs(1));
 
-                let _ = client.send(connack);
+                    let timer_future = timer
+                        .interval(period)
+                        .map_err(|e| Error::from(e))
+                        .for_each(move |_| if broker.check_keep_alive(&client) {
+                                      Ok(())
+                                  } else {
+                                      Err(Error::Other)
+                                  })
+                        .then(|_| Ok(()));
 
-                let timer = Timer::default();
-                let interval = timer.interval(Duration::new(10, 0));
+                    handle.spawn(timer_future);
+                }
 
-                let timer_future = interval.for_each(|_| {
-                    //TODO: check for ping requests here
-                    println!("!!!!!");
-                    Ok(())
-                }).then(|_| Ok(()));
+                // probe quiet websocket connections. plain connections only have tcp keep alive
+                if let Some(interval) = slot.probe {
+                    let broker = broker.clone();
+                    let client = client.clone();
 
-                handle.spawn(timer_future);
+                    let timer_future = timer
+                        .interval(interval)
+                        .map_err(|e| Error::from(e))
+                        .for_each(move |_| if broker.probe(&client, interval) {
+                                      Ok(())
+                                  } else {
+                                      Err(Error::Other)
+                                  })
+                        .then(|_| Ok(()));
+
+                    handle.spawn(timer_future);
+                }
+
+                // audit record of where the client comes from. the connection doesn't wait for it
+                #[cfg(feature = "enrichment")]
+                {
+                    if let Some(ref enricher) = enricher {
+                        let logger = logger.clone();
+                        let id = client.id.clone();
+                        let addr = client.addr;
+
+                        let enriched = enricher.enrich(client.clone()).map(move |metadata| {
+                            info!(logger,
+                                  "Connection enriched. ID = {:?}, Address = {}, Host = {:?}, Country = {:?}",
+                                  id,
+                                  addr,
+                                  metadata.host,
+                                  metadata.country)
+                        });
+                        handle.spawn(enriched);
+                    }
+                }
+
+                // resend packets that weren't acknowledged in time
+                if let Some(interval) = broker.config().retransmit_interval {
+                    let broker = broker.clone();
+                    let client = client.clone();
+
+                    let timer_future = timer
+                        .interval(interval)
+                        .map_err(|e| Error::from(e))
+                        .for_each(move |_| if broker.retransmit(&client) {
+                                      Ok(())
+                                  } else {
+                                      Err(Error::Other)
+                                  })
+                        .then(|_| Ok(()));
+
+                    handle.spawn(timer_future);
+                }
+
+                let (kill_tx, kill_rx) = oneshot::channel::<()>();
+                client.set_kill_switch(kill_tx);
 
                 // current connections incoming n/w packets
                 let rx_future = receiver
                     .for_each(move |msg| {
+                        // a newer connection took over this client id
+                        if !broker1.is_current(&client) {
+                            return Err(io::Error::new(io::ErrorKind::Other, "Session taken over"));
+                        }
+
+                        client.update_activity();
+                        trace!(client.logger(), "Packet received"; "packet" => logging::packet_type(&msg), "topic" => logging::topic(&msg));
+
                         match msg {
                             Packet::Publish(p) => broker1.handle_publish(p, &client),
                             Packet::Subscribe(s) => broker1.handle_subscribe(s, &client),
@@ -128,45 +1135,86 @@ fn main() {
                             Packet::Pubrec(pkid) => broker1.handle_pubrec(pkid, &client),
                             Packet::Pubrel(pkid) => broker1.handle_pubrel(pkid, &client),
                             Packet::Pubcomp(pkid) => broker1.handle_pubcomp(pkid, &client),
+                            Packet::Unsubscribe(u) => broker1.handle_unsubscribe(u, &client),
                             Packet::Pingreq => broker1.handle_pingreq(&client),
-                            _ => panic!("Incoming Misc: {:?}", msg),
+                            Packet::Disconnect => broker1.handle_disconnect(&client),
+                            // answer to a liveness probe
+                            Packet::Pingresp => (),
+                            // packets only the broker sends or that belong to the handshake
+                            packet => {
+                                error!(client.logger(), "Unexpected packet. Closing the connection. ID = {:?}, Packet = {:?}", client.id, packet);
+                                broker1.disconnect(&client, DisconnectReason::ProtocolError);
+                                return Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected packet"));
+                            }
                         }
                         Ok(())
                     })
+                    // broker closing the connection (disconnect, takeover, keep alive timeout etc)
+                    .select2(kill_rx)
                     .then(move |e| {
+                              let e = match e {
+                                  Ok(Either::A(_)) => Ok(()),
+                                  Ok(Either::B(_)) | Err(Either::B(_)) => Err("Closed by broker".to_owned()),
+                                  Err(Either::A((e, _))) => Err(e.to_string()),
+                              };
+
                               // network disconnections. remove the client
-                              println!("%%% ERROR = {:?}. TX DISCONNECTION. ID = {:?} %%%", e, id1);
-                              broker2.remove_client(&id1);
+                              debug!(client2.logger(), "Connection ended. ID = {:?}, Error = {:?}", id1, e);
+                              broker2.handle_network_disconnect(&client2);
+
+                              // frees the connection's place on its listener
+                              drop(slot);
                               Ok(())
                           });
 
                 //FIND: what happens to rx_future when socket disconnects
                 handle.spawn(rx_future);
 
-                // current connections outgoing n/w packets
-                let tx_future = rx.map_err(|_| Error::Other)
-                    .map(|r| match r {
-                             Packet::Publish(p) => Packet::Publish(p),
-                             Packet::Connack(c) => Packet::Connack(c),
-                             Packet::Suback(sa) => Packet::Suback(sa),
-                             Packet::Puback(pa) => Packet::Puback(pa),
-                             Packet::Pubrec(prec) => Packet::Pubrec(prec),
-                             Packet::Pubrel(prel) => Packet::Pubrel(prel),
-                             Packet::Pubcomp(pc) => Packet::Pubcomp(pc),
-                             Packet::Pingresp => Packet::Pingresp,
-                             _ => panic!("Outgoing Misc: {:?}", r),
-                         })
+                // current connections outgoing n/w packets. written in turns with the other connections
+                let tx_future = fair::writer(rx, client3, broker.config().write_budget(&id2))
+                    .map_err(|_| Error::Other)
                     .forward(sender)
-                    .then(move |_| {
-                              // forward error. n/w disconnections.
-                              println!("%%% RX DISCONNECTION. ID = {:?} %%%", id2);
-                              Ok(())
-                          });
+                    // forward error. n/w disconnections.
+                    .then(|_| Ok(()));
 
                 handle.spawn(tx_future);
             }
             Ok(())
         });
 
-    core.run(server).unwrap();
+    // SIGTERM and SIGINT stop the listeners. Clients get until the shutdown
+    // timeout to finish their qos 1 and 2 flows before they're closed
+    #[cfg(unix)]
+    let terminated: Box<Future<Item = (), Error = ()>> = {
+        let signalled = timer
+            .interval(signals::POLL_INTERVAL)
+            .map_err(|_| ())
+            .filter(|_| signals::take(&signals::TERMINATE))
+            .into_future()
+            .map(|_| ())
+            .map_err(|_| ());
+        Box::new(signalled)
+    };
+    #[cfg(not(unix))]
+    let terminated: Box<Future<Item = (), Error = ()>> = Box::new(future::empty());
+
+    let _ = core.run(server.select2(terminated));
+    info!(logger, "Shutting down. Open flows = {}", broker.open_flows());
+
+    let deadline = Instant::now() + broker.config().shutdown_timeout;
+    let drained = {
+        let broker = broker.clone();
+        timer
+            .interval(DRAIN_POLL_INTERVAL)
+            .map_err(|_| ())
+            .take_while(move |_| Ok(broker.open_flows() > 0 && Instant::now() < deadline))
+            .for_each(|_| Ok(()))
+    };
+    let _ = core.run(drained);
+
+    let open = broker.open_flows();
+    let closed = broker.shutdown();
+    // lets the closed connections write what's left in their buffers
+    let _ = core.run(timer.sleep(DRAIN_POLL_INTERVAL));
+    info!(logger, "Shut down. Connections closed = {}, Open flows left = {}", closed, open);
 }
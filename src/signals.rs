@@ -0,0 +1,41 @@
+//! Signals the broker acts on. The handlers only raise a flag, the event
+//! loop picks it up on its next poll and does the work itself
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use libc;
+
+/// How often the event loop looks for signals
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// SIGHUP. Reloads the configuration
+pub static HANGUP: AtomicBool = AtomicBool::new(false);
+
+/// SIGTERM or SIGINT. Shuts the broker down
+pub static TERMINATE: AtomicBool = AtomicBool::new(false);
+
+/// SIGUSR1. Dumps the broker's state
+pub static DUMP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    match signal {
+        libc::SIGHUP => HANGUP.store(true, Ordering::SeqCst),
+        libc::SIGUSR1 => DUMP.store(true, Ordering::SeqCst),
+        _ => TERMINATE.store(true, Ordering::SeqCst),
+    }
+}
+
+/// Replaces the default actions, which would end the process on the spot
+pub fn install() {
+    for &signal in [libc::SIGHUP, libc::SIGTERM, libc::SIGINT, libc::SIGUSR1].iter() {
+        unsafe {
+            libc::signal(signal, on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        }
+    }
+}
+
+/// True once per signal raising `flag` since the last call
+pub fn take(flag: &AtomicBool) -> bool {
+    flag.swap(false, Ordering::SeqCst)
+}
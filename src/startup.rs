@@ -0,0 +1,479 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use clap::{self, App, Arg};
+use serde_json;
+use slog::Level;
+
+use rumqttd_core::{Acl, BrokerBuilder, Error, LogOutput, OverflowPolicy, SharePolicy};
+
+/// Help on the settings file, after the flags in `--help`
+const SETTINGS: &str = "Settings: listen, port, log_level, log_output, log_file, log_rotation,
+log_keep, max_connections, connect_rate, connect_rate_per_ip,
+max_packet_size, outgoing_queue_size, outgoing_overflow, max_inflight,
+share_policy, allow_anonymous, allow_mqtt31, password_file, acl_file, alert,
+alert_topic, alert_webhook, message_webhook, message_webhook_filter,
+message_webhook_rate, message_webhook_retries, sn_gateway, bridge,
+bridge_topic.
+
+log_output is terminal or json, one JSON object per line.
+outgoing_overflow is disconnect, drop_oldest or drop_new. share_policy
+picks the member of a `$share/<group>/<filter>` subscription that gets a
+publish, round_robin or least_loaded. log_file logs to a file instead of
+stderr, rotated at a size like 10MB or after an interval like 24h with
+log_keep rotated files kept.
+
+Every `alert = <name> <metric>[/s] <'>'|'<'> <threshold>` line adds a
+rule, e.g. `alert = drops dropped_messages/s > 10`. Metrics are
+connections, subscriptions, offline_sessions, published, delivered,
+dropped_messages, expired_messages, open_flows, open_connections and
+rate_limited_connections.
+
+Connect rates are like `connect_rate = 100/s 500`, a rate per second and
+a burst, as is message_webhook_rate. Every message_webhook_filter line
+adds a filter of the publishes POSTed to the message webhook.
+`bridge = <address> <client id>` connects to a remote broker and every
+`bridge_topic = <filter> <in|out|both> [qos]` line passes a topic to it,
+from it or both ways.
+
+SIGHUP reads the settings file again. Log level, limits, the password
+file and the acl change without a restart. SIGTERM or SIGINT stop the
+listeners and close the connections once their qos flows are done.
+SIGUSR1 writes a gzipped JSON dump of the broker's state to the temporary
+directory.";
+
+/// How the broker reports its startup
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// Human readable banner
+    Text,
+    /// Single JSON document on stdout for orchestration tooling
+    Json,
+}
+
+impl LogFormat {
+    /// Format named by a `--log-format <text|json>` argument
+    pub fn parse(value: Option<&str>) -> Result<LogFormat, String> {
+        match value {
+            Some("text") => Ok(LogFormat::Text),
+            Some("json") => Ok(LogFormat::Json),
+            v => Err(format!("Invalid --log-format {:?}. Expected text or json", v)),
+        }
+    }
+}
+
+fn log_level(value: Option<&str>) -> Result<Level, String> {
+    value
+        .and_then(|v| Level::from_str(v).ok())
+        .ok_or_else(|| format!("Invalid log level {:?}. Expected critical, error, warning, info, debug or trace", value))
+}
+
+/// Flags of the broker binary
+fn app() -> App<'static, 'static> {
+    App::new("rumqttd")
+        .version(crate_version!())
+        .after_help(SETTINGS)
+        .arg(Arg::with_name("config")
+                 .short("c")
+                 .long("config")
+                 .value_name("path")
+                 .help("Settings file of `key = value` lines"))
+        .arg(Arg::with_name("port")
+                 .short("p")
+                 .long("port")
+                 .value_name("port")
+                 .help("Port of the plain TCP listener"))
+        .arg(Arg::with_name("log-level")
+                 .long("log-level")
+                 .value_name("level")
+                 .possible_values(&["critical", "error", "warning", "info", "debug", "trace"])
+                 .help("Lowest level logged"))
+        .arg(Arg::with_name("verbose")
+                 .short("v")
+                 .long("verbose")
+                 .help("Same as --log-level debug"))
+        .arg(Arg::with_name("log-format")
+                 .long("log-format")
+                 .value_name("format")
+                 .possible_values(&["text", "json"])
+                 .default_value("text")
+                 .help("Startup report as text or json"))
+}
+
+/// Command line of the broker binary
+#[derive(Debug, Clone, PartialEq)]
+pub struct Args {
+    pub config: Option<PathBuf>,
+    pub port: Option<u16>,
+    /// `--log-level` wins over `--verbose`
+    pub log_level: Option<Level>,
+    pub log_format: LogFormat,
+}
+
+impl Args {
+    /// Parses the command line, program name first. `--help` and `--version`
+    /// come back as errors of their own kind, to be printed with `exit`
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Args, clap::Error> {
+        let matches = app().get_matches_from_safe(args)?;
+
+        let port = match matches.value_of("port") {
+            Some(_) => Some(value_t!(matches, "port", u16)?),
+            None => None,
+        };
+        let log_level = match matches.value_of("log-level") {
+            Some(_) => Some(value_t!(matches, "log-level", Level)?),
+            None if matches.is_present("verbose") => Some(Level::Debug),
+            None => None,
+        };
+        let log_format = LogFormat::parse(matches.value_of("log-format"))
+            .map_err(|e| clap::Error::with_description(&e, clap::ErrorKind::InvalidValue))?;
+
+        Ok(Args {
+               config: matches.value_of_os("config").map(PathBuf::from),
+               port: port,
+               log_level: log_level,
+               log_format: log_format,
+           })
+    }
+
+    /// Broker settings of the settings file with the flags on top
+    pub fn builder(&self) -> Result<BrokerBuilder, String> {
+        let mut builder = BrokerBuilder::new();
+        if let Some(ref path) = self.config {
+            let mut text = String::new();
+            File::open(path)
+                .and_then(|mut file| file.read_to_string(&mut text))
+                .map_err(|e| format!("Unable to read {}. Error = {}", path.display(), e))?;
+            builder = settings(builder, &text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+
+        if let Some(port) = self.port {
+            builder = builder.listener(SocketAddr::from(([0, 0, 0, 0], port)));
+        }
+        if let Some(level) = self.log_level {
+            builder = builder.log_level(level);
+        }
+        Ok(builder)
+    }
+}
+
+fn log_output(value: &str) -> Result<LogOutput, String> {
+    match value {
+        "terminal" => Ok(LogOutput::Terminal),
+        "json" => Ok(LogOutput::Json),
+        v => Err(format!("invalid log_output {:?}. Expected terminal or json", v)),
+    }
+}
+
+fn overflow_policy(value: &str) -> Result<OverflowPolicy, String> {
+    match value {
+        "disconnect" => Ok(OverflowPolicy::Disconnect),
+        "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+        "drop_new" => Ok(OverflowPolicy::DropNew),
+        v => Err(format!("invalid outgoing_overflow {:?}. Expected disconnect, drop_oldest or drop_new", v)),
+    }
+}
+
+fn share_policy(value: &str) -> Result<SharePolicy, String> {
+    match value {
+        "round_robin" => Ok(SharePolicy::RoundRobin),
+        "least_loaded" => Ok(SharePolicy::LeastLoaded),
+        v => Err(format!("invalid share_policy {:?}. Expected round_robin or least_loaded", v)),
+    }
+}
+
+/// Remote broker and client id of a `bridge = <address> <client id>` line
+fn bridge(value: &str) -> Result<(SocketAddr, &str), String> {
+    let invalid = || format!("invalid bridge {:?}. Expected <address> <client id>", value);
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    if parts.len() != 2 {
+        return Err(invalid());
+    }
+    Ok((parts[0].parse().map_err(|_| invalid())?, parts[1]))
+}
+
+fn value<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid {} {:?}", key, value))
+}
+
+/// Applies a settings file. Every line is `key = value`, `#` starts a comment
+pub fn settings(mut builder: BrokerBuilder, text: &str) -> Result<BrokerBuilder, String> {
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (key, v) = match line.find('=') {
+            Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+            None => return Err(format!("line {}: expected key = value", n + 1)),
+        };
+        let line_error = |e: String| format!("line {}: {}", n + 1, e);
+
+        builder = match key {
+            "listen" => builder.listener(value(key, v).map_err(&line_error)?),
+            "port" => builder.listener(SocketAddr::from(([0, 0, 0, 0], value(key, v).map_err(&line_error)?))),
+            "log_level" => builder.log_level(log_level(Some(v)).map_err(&line_error)?),
+            "log_output" => builder.log_output(log_output(v).map_err(&line_error)?),
+            "log_file" => builder.log_file(v),
+            "log_rotation" => builder.log_rotation(v.parse().map_err(|e: Error| line_error(e.to_string()))?),
+            "log_keep" => builder.log_keep(value(key, v).map_err(&line_error)?),
+            "max_connections" => builder.max_connections(value(key, v).map_err(&line_error)?),
+            "connect_rate" => builder.connect_rate(v.parse().map_err(|e: Error| line_error(e.to_string()))?),
+            "connect_rate_per_ip" => builder.connect_rate_per_ip(v.parse().map_err(|e: Error| line_error(e.to_string()))?),
+            "max_packet_size" => builder.max_packet_size(value(key, v).map_err(&line_error)?),
+            "outgoing_queue_size" => builder.outgoing_queue_size(value(key, v).map_err(&line_error)?),
+            "outgoing_overflow" => builder.outgoing_overflow(overflow_policy(v).map_err(&line_error)?),
+            "max_inflight" => builder.max_inflight(Some(value(key, v).map_err(&line_error)?)),
+            "share_policy" => builder.share_policy(share_policy(v).map_err(&line_error)?),
+            "allow_anonymous" => builder.allow_anonymous(value(key, v).map_err(&line_error)?),
+            "allow_mqtt31" => builder.allow_mqtt31(value(key, v).map_err(&line_error)?),
+            "password_file" => builder.password_file(v),
+            "acl_file" => builder.acl(Acl::load(Path::new(v)).map_err(|e| line_error(e.to_string()))?),
+            #[cfg(feature = "metrics")]
+            "alert" => builder.alert(v.parse().map_err(|e: Error| line_error(e.to_string()))?),
+            "alert_topic" => builder.alert_topic(v),
+            "alert_webhook" => builder.alert_webhook(v),
+            "message_webhook" => builder.message_webhook(v),
+            "message_webhook_filter" => builder.message_webhook_filter(v),
+            "message_webhook_rate" => builder.message_webhook_rate(v.parse().map_err(|e: Error| line_error(e.to_string()))?),
+            "message_webhook_retries" => builder.message_webhook_retries(value(key, v).map_err(&line_error)?),
+            "sn_gateway" => builder.sn_gateway(value(key, v).map_err(&line_error)?),
+            "bridge" => {
+                let (remote, client_id) = bridge(v).map_err(&line_error)?;
+                builder.bridge(remote, client_id)
+            }
+            "bridge_topic" => builder.bridge_topic(v.parse().map_err(|e: Error| line_error(e.to_string()))?),
+            key => return Err(line_error(format!("unknown setting {:?}", key))),
+        };
+    }
+
+    Ok(builder)
+}
+
+/// Listener bind results, effective settings and feature flags collected
+/// while the broker comes up
+#[derive(Debug)]
+pub struct StartupReport {
+    listeners: Vec<(SocketAddr, Result<(), String>)>,
+    settings: Vec<(&'static str, String)>,
+    features: Vec<(&'static str, bool)>,
+}
+
+impl StartupReport {
+    pub fn new() -> Self {
+        StartupReport {
+            listeners: Vec::new(),
+            settings: Vec::new(),
+            features: Vec::new(),
+        }
+    }
+
+    pub fn listener(&mut self, address: SocketAddr, result: Result<(), String>) {
+        self.listeners.push((address, result));
+    }
+
+    pub fn setting<V: ToString>(&mut self, name: &'static str, value: V) {
+        self.settings.push((name, value.to_string()));
+    }
+
+    pub fn feature(&mut self, name: &'static str, enabled: bool) {
+        self.features.push((name, enabled));
+    }
+
+    /// True if every listener bound successfully
+    pub fn ok(&self) -> bool {
+        self.listeners.iter().all(|&(_, ref result)| result.is_ok())
+    }
+
+    /// Digest of the effective settings and features. Identical configurations
+    /// produce identical digests
+    pub fn digest(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        self.settings.hash(&mut hasher);
+        self.features.hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+
+    pub fn to_json(&self) -> String {
+        let listeners: Vec<serde_json::Value> = self.listeners
+            .iter()
+            .map(|&(ref address, ref result)| match *result {
+                     Ok(()) => json!({ "address": address.to_string(), "bound": true }),
+                     Err(ref e) => json!({ "address": address.to_string(), "bound": false, "error": e }),
+                 })
+            .collect();
+
+        let mut settings = serde_json::Map::new();
+        for &(name, ref value) in self.settings.iter() {
+            settings.insert(name.to_owned(), json!(value));
+        }
+
+        let mut features = serde_json::Map::new();
+        for &(name, enabled) in self.features.iter() {
+            features.insert(name.to_owned(), json!(enabled));
+        }
+
+        json!({
+            "event": "startup",
+            "version": env!("CARGO_PKG_VERSION"),
+            "ok": self.ok(),
+            "listeners": listeners,
+            "config_digest": self.digest(),
+            "settings": settings,
+            "features": features,
+        })
+                .to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use serde_json::{self, Value};
+    use slog::Level;
+    use rumqttd_core::{BrokerBuilder, LogOutput, OverflowPolicy, RateLimit, Rotation, SharePolicy};
+    use clap::ErrorKind;
+    use super::{settings, Args, LogFormat, StartupReport};
+
+    fn args(v: &[&str]) -> Vec<String> {
+        Some("rumqttd").iter().chain(v).map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn log_format_is_parsed_from_args() {
+        assert_eq!(Args::parse(args(&[])).unwrap().log_format, LogFormat::Text);
+        assert_eq!(Args::parse(args(&["--log-format", "json"])).unwrap().log_format, LogFormat::Json);
+        assert!(Args::parse(args(&["--log-format", "xml"])).is_err());
+        assert!(Args::parse(args(&["--log-format"])).is_err());
+    }
+
+    #[test]
+    fn flags_are_parsed() {
+        let parsed = Args::parse(args(&["-p", "1884", "--verbose", "--log-level", "warning"])).unwrap();
+        assert_eq!(parsed.port, Some(1884));
+        assert_eq!(parsed.log_level, Some(Level::Warning));
+        assert_eq!(Args::parse(args(&["-v"])).unwrap().log_level, Some(Level::Debug));
+        assert_eq!(Args::parse(args(&["--version"])).unwrap_err().kind, ErrorKind::VersionDisplayed);
+        assert!(Args::parse(args(&["--port", "http"])).is_err());
+        assert!(Args::parse(args(&["--log-level", "loud"])).is_err());
+        assert!(Args::parse(args(&["--listen"])).is_err());
+    }
+
+    #[test]
+    fn flags_override_the_settings_file() {
+        let text = "# quick test\nport = 1885\nmax_packet_size = 4096 # bytes\nallow_anonymous = false\n";
+        let broker = settings(BrokerBuilder::new(), text).unwrap().build().unwrap();
+        assert_eq!(broker.config().listeners[0].address, "0.0.0.0:1885".parse().unwrap());
+        assert_eq!(broker.config().max_packet_size, 4096);
+        assert!(!broker.config().allow_anonymous);
+
+        let parsed = Args::parse(args(&["-p", "1884", "--log-level", "warning"])).unwrap();
+        let broker = parsed.builder().unwrap().build().unwrap();
+        assert_eq!(broker.config().listeners[0].address, "0.0.0.0:1884".parse().unwrap());
+        assert_eq!(broker.config().log_level, Level::Warning);
+    }
+
+    #[test]
+    fn malformed_settings_point_at_their_line() {
+        assert_eq!(settings(BrokerBuilder::new(), "port 1883").err(), Some("line 1: expected key = value".to_owned()));
+        assert!(settings(BrokerBuilder::new(), "\nmax_inflight = many").unwrap_err().starts_with("line 2"));
+        assert!(settings(BrokerBuilder::new(), "qos = 1").is_err());
+    }
+
+    #[test]
+    fn log_settings_are_read() {
+        let broker = settings(BrokerBuilder::new(), "log_output = json").unwrap().build().unwrap();
+        assert_eq!(broker.config().log_output, LogOutput::Json);
+        assert!(settings(BrokerBuilder::new(), "log_output = xml").is_err());
+
+        let broker = settings(BrokerBuilder::new(), "log_rotation = 24h\nlog_keep = 3").unwrap().build().unwrap();
+        assert_eq!(broker.config().log_rotation, Rotation::Interval(Duration::from_secs(86400)));
+        assert_eq!(broker.config().log_keep, 3);
+        assert!(settings(BrokerBuilder::new(), "log_rotation = often").is_err());
+        assert!(settings(BrokerBuilder::new(), "log_file = /nonexistent/rumqttd.log").unwrap().build().is_err());
+    }
+
+    #[test]
+    fn delivery_policies_are_read() {
+        let broker = settings(BrokerBuilder::new(), "outgoing_overflow = drop_oldest").unwrap().build().unwrap();
+        assert_eq!(broker.config().outgoing_overflow, OverflowPolicy::DropOldest);
+        assert!(settings(BrokerBuilder::new(), "outgoing_overflow = block").is_err());
+
+        let broker = settings(BrokerBuilder::new(), "share_policy = least_loaded").unwrap().build().unwrap();
+        assert_eq!(broker.config().share_policy, SharePolicy::LeastLoaded);
+        assert!(settings(BrokerBuilder::new(), "share_policy = random").is_err());
+    }
+
+    #[test]
+    fn connection_limits_are_read() {
+        let broker = settings(BrokerBuilder::new(), "max_connections = 5000").unwrap().build().unwrap();
+        assert_eq!(broker.config().max_connections, Some(5000));
+
+        let broker = settings(BrokerBuilder::new(), "connect_rate_per_ip = 2/s 10").unwrap().build().unwrap();
+        assert_eq!(broker.config().connect_rate_per_ip, Some(RateLimit::new(2, 10)));
+        assert!(settings(BrokerBuilder::new(), "connect_rate = 100").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn alert_rules_are_read() {
+        let text = "alert = drops dropped_messages/s > 10\nalert_topic = alerts/broker\n";
+        let broker = settings(BrokerBuilder::new(), text).unwrap().build().unwrap();
+        assert_eq!(broker.config().alerts[0].name, "drops");
+        assert!(settings(BrokerBuilder::new(), "alert = drops > 10").unwrap_err().contains("invalid alert rule"));
+        assert!(settings(BrokerBuilder::new(), "alert = drops dropped_messages > 10").unwrap().build().is_err());
+    }
+
+    #[test]
+    fn gateway_and_bridge_are_read() {
+        let broker = settings(BrokerBuilder::new(), "sn_gateway = 0.0.0.0:1885").unwrap().build().unwrap();
+        assert_eq!(broker.config().sn_gateway, Some("0.0.0.0:1885".parse().unwrap()));
+
+        let text = "bridge_topic = telemetry/# out 1\nbridge = 10.0.0.1:1883 edge-1\n";
+        let broker = settings(BrokerBuilder::new(), text).unwrap().build().unwrap();
+        assert_eq!(broker.config().bridge.as_ref().map(|b| b.client_id.as_str()), Some("edge-1"));
+        assert_eq!(broker.config().bridge_topics[0].filter, "telemetry/#");
+        assert!(settings(BrokerBuilder::new(), "bridge = 10.0.0.1:1883").is_err());
+        assert!(settings(BrokerBuilder::new(), "bridge_topic = telemetry/# sideways").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "connectors")]
+    fn message_webhook_settings_are_read() {
+        let text = "message_webhook = http://hooks:8080/messages\nmessage_webhook_filter = sensors/#\nmessage_webhook_rate = 10/s 20\n";
+        let broker = settings(BrokerBuilder::new(), text).unwrap().build().unwrap();
+        assert_eq!(broker.config().message_webhook_filters, vec!["sensors/#".to_owned()]);
+        assert_eq!(broker.config().message_webhook_rate, Some(RateLimit::new(10, 20)));
+        assert!(settings(BrokerBuilder::new(), "message_webhook = http://hooks/messages").unwrap().build().is_err());
+    }
+
+    #[test]
+    fn report_serializes_bind_results_and_digest() {
+        let mut report = StartupReport::new();
+        report.listener("0.0.0.0:1883".parse().unwrap(), Ok(()));
+        report.listener("0.0.0.0:8883".parse().unwrap(), Err("address in use".to_owned()));
+        report.setting("max_packet_size", 1024);
+        report.feature("catalog", false);
+
+        let v: Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(v["ok"], Value::Bool(false));
+        assert_eq!(v["listeners"][0]["bound"], Value::Bool(true));
+        assert_eq!(v["listeners"][1]["error"], Value::String("address in use".to_owned()));
+        assert_eq!(v["settings"]["max_packet_size"], Value::String("1024".to_owned()));
+        assert_eq!(v["features"]["catalog"], Value::Bool(false));
+
+        let mut other = StartupReport::new();
+        other.setting("max_packet_size", 1024);
+        other.feature("catalog", false);
+        assert_eq!(report.digest(), other.digest());
+
+        other.feature("catalog", true);
+        assert!(report.digest() != other.digest());
+    }
+}
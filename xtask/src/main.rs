@@ -0,0 +1,239 @@
+//! Development tasks of the workspace
+//!
+//! `cargo xtask bench [--save] [--baseline <path>] [--threshold <percent>] [--runs <n>]`
+//! runs the routing benchmarks of rumqttd-core in release mode and compares
+//! their throughput against a baseline. The best of `runs` (3 by default)
+//! runs counts, which keeps scheduling noise out. It fails when a benchmark
+//! is more than `threshold` percent (10 by default) slower. `--save` stores the
+//! results as the new baseline instead, as does a run without a baseline.
+//! The baseline defaults to `target/bench-baseline.txt` so CI can keep the
+//! one of the main branch in its cache
+//!
+//! `cargo xtask minimal` builds the broker with `--profile minimal
+//! --no-default-features` and compiles the tests without the default
+//! features, so code that only builds with every subsystem in fails in CI
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+const DEFAULT_THRESHOLD: f64 = 10.0;
+const DEFAULT_RUNS: usize = 3;
+
+/// Benchmark name and deliveries per second
+type Results = Vec<(String, f64)>;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Comparison {
+    name: String,
+    baseline: Option<f64>,
+    current: f64,
+}
+
+impl Comparison {
+    /// Change against the baseline in percent
+    fn change(&self) -> Option<f64> {
+        self.baseline.map(|baseline| (self.current - baseline) / baseline * 100.0)
+    }
+
+    fn regressed(&self, threshold: f64) -> bool {
+        self.change().map_or(false, |change| change < -threshold)
+    }
+}
+
+fn workspace() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap().to_owned()
+}
+
+/// `bench <name> <value>` lines of the benchmark output. The test harness
+/// may have put the name of the test in front
+fn parse(output: &str) -> Results {
+    output
+        .lines()
+        .filter_map(|line| {
+                        let line = line.rsplit(" ... ").next().unwrap_or(line);
+                        let fields: Vec<&str> = line.split_whitespace().collect();
+                        match fields.as_slice() {
+                            ["bench", name, value] => value.parse().ok().map(|value| (name.to_string(), value)),
+                            _ => None,
+                        }
+                    })
+        .collect()
+}
+
+fn compare(baseline: &Results, current: &Results) -> Vec<Comparison> {
+    current
+        .iter()
+        .map(|&(ref name, current)| {
+                 Comparison {
+                     name: name.clone(),
+                     baseline: baseline.iter().find(|b| &b.0 == name).map(|b| b.1),
+                     current: current,
+                 }
+             })
+        .collect()
+}
+
+fn cargo() -> Command {
+    let mut command = Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".to_owned()));
+    command.current_dir(workspace());
+    command
+}
+
+fn run_benchmarks() -> Result<Results, String> {
+    let output = cargo()
+        .args(&["test", "--release", "-p", "rumqttd-core", "--lib", "--", "--ignored", "--nocapture", "--test-threads", "1", "bench::"])
+        .output()
+        .map_err(|e| format!("Unable to run cargo. Error = {}", e))?;
+
+    if !output.status.success() {
+        return Err(format!("Benchmarks failed\n{}", String::from_utf8_lossy(&output.stderr)));
+    }
+
+    let results = parse(&String::from_utf8_lossy(&output.stdout));
+    if results.is_empty() {
+        return Err("No benchmark results in the output".to_owned());
+    }
+    Ok(results)
+}
+
+/// Best result of every benchmark over `runs` runs
+fn best_of(runs: usize) -> Result<Results, String> {
+    let mut best = run_benchmarks()?;
+    for _ in 1..runs {
+        for (name, value) in run_benchmarks()? {
+            match best.iter_mut().find(|b| b.0 == name) {
+                Some(b) => b.1 = b.1.max(value),
+                None => best.push((name, value)),
+            }
+        }
+    }
+    Ok(best)
+}
+
+fn save(path: &Path, results: &Results) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+
+    let mut file = File::create(path).map_err(|e| e.to_string())?;
+    for &(ref name, value) in results.iter() {
+        writeln!(file, "bench {} {:.0}", name, value).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn bench<I: Iterator<Item = String>>(mut args: I) -> Result<(), String> {
+    let mut store = false;
+    let mut baseline_path = workspace().join("target").join("bench-baseline.txt");
+    let mut threshold = DEFAULT_THRESHOLD;
+    let mut runs = DEFAULT_RUNS;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--save" => store = true,
+            "--baseline" => baseline_path = args.next().ok_or("--baseline needs a path")?.into(),
+            "--threshold" => {
+                threshold = args.next()
+                    .and_then(|t| t.parse().ok())
+                    .ok_or("--threshold needs a percentage")?
+            }
+            "--runs" => {
+                runs = args.next()
+                    .and_then(|r| r.parse().ok())
+                    .filter(|&r| r > 0)
+                    .ok_or("--runs needs a positive count")?
+            }
+            arg => return Err(format!("Unknown argument {:?}", arg)),
+        }
+    }
+
+    let current = best_of(runs)?;
+
+    let mut text = String::new();
+    let baseline = match File::open(&baseline_path) {
+        Ok(mut file) if !store => {
+            file.read_to_string(&mut text).map_err(|e| e.to_string())?;
+            parse(&text)
+        }
+        _ => {
+            save(&baseline_path, &current)?;
+            println!("Baseline saved to {}", baseline_path.display());
+            return Ok(());
+        }
+    };
+
+    let comparisons = compare(&baseline, &current);
+    for c in comparisons.iter() {
+        match (c.baseline, c.change()) {
+            (Some(baseline), Some(change)) => println!("{:<24} {:>12.0} {:>12.0} {:>+7.1}%", c.name, baseline, c.current, change),
+            _ => println!("{:<24} {:>12} {:>12.0}     new", c.name, "-", c.current),
+        }
+    }
+
+    let regressed: Vec<&str> = comparisons
+        .iter()
+        .filter(|c| c.regressed(threshold))
+        .map(|c| c.name.as_str())
+        .collect();
+    if regressed.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Throughput regressed by more than {}%: {}", threshold, regressed.join(", ")))
+    }
+}
+
+/// Builds without the optional subsystems. Cargo's own output goes to the terminal
+fn minimal() -> Result<(), String> {
+    let steps: [&[&str]; 2] = [&["build", "-p", "rumqttd", "--profile", "minimal", "--no-default-features"],
+                               &["test", "--workspace", "--no-default-features", "--no-run"]];
+    for args in steps.iter() {
+        let status = cargo()
+            .args(*args)
+            .status()
+            .map_err(|e| format!("Unable to run cargo. Error = {}", e))?;
+        if !status.success() {
+            return Err(format!("cargo {} failed", args.join(" ")));
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let result = match args.next().as_ref().map(|t| t.as_str()) {
+        Some("bench") => bench(args),
+        Some("minimal") => minimal(),
+        task => Err(format!("Unknown task {:?}. Tasks: bench, minimal", task)),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compare, parse};
+
+    #[test]
+    fn regressions_beyond_the_threshold_fail() {
+        let baseline = parse("bench fanout_qos0 1000000\nbench unique_topics_qos1 200000\n");
+        let current = parse("running 2 tests\n\
+                             test bench::fanout_qos0 ... bench fanout_qos0 850000\n\
+                             ok\n\
+                             bench unique_topics_qos1 195000\n\
+                             bench retained 5000\n");
+
+        let comparisons = compare(&baseline, &current);
+        assert_eq!(comparisons.len(), 3);
+        assert!(comparisons[0].regressed(10.0));
+        assert!(!comparisons[0].regressed(20.0));
+        assert!(!comparisons[1].regressed(10.0));
+        assert_eq!(comparisons[2].baseline, None);
+        assert!(!comparisons[2].regressed(0.0));
+    }
+}